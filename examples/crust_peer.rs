@@ -106,7 +106,7 @@ use serde_json;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
-use crust::{Config, ConnectionInfoResult, Uid};
+use crust::{Bytes, Config, ConnectionInfoResult, Uid};
 use rand::distributions::{Distribution, Standard};
 use rand::Rng;
 use std::cmp;
@@ -349,7 +349,7 @@ fn main() {
                                 println!(
                                     "\nReceived from {:?} message: {}",
                                     peer_id,
-                                    String::from_utf8(bytes).unwrap_or_else(|_| format!(
+                                    String::from_utf8(bytes.to_vec()).unwrap_or_else(|_| format!(
                                         "non-UTF-8 message of {} bytes",
                                         message_length
                                     ))
@@ -410,8 +410,8 @@ fn main() {
                                     peer_id,
                                 );
                             }
-                            crust::Event::LostPeer(peer_id) => {
-                                println!("\nLost connection to peer {:?}", peer_id);
+                            crust::Event::LostPeer(peer_id, reason) => {
+                                println!("\nLost connection to peer {:?}: {:?}", peer_id, reason);
                                 let mut index = None;
                                 {
                                     let network = unwrap!(network2.lock());
@@ -477,9 +477,9 @@ fn main() {
             let times = cmp::max(1, speed / length);
             let sleep_time = cmp::max(1, 1000 / times);
             for _ in 0..times {
-                unwrap!(unwrap!(service.lock()).send(
+                let _ = unwrap!(unwrap!(service.lock()).send(
                     peer_id,
-                    generate_random_vec_u8(length as usize),
+                    Bytes::from(generate_random_vec_u8(length as usize)),
                     0,
                 ));
                 debug!(
@@ -540,9 +540,9 @@ fn main() {
                     let network = unwrap!(network.lock());
                     match network.get_peer_id(peer_index) {
                         Some(ref mut peer_id) => {
-                            unwrap!(unwrap!(service.lock()).send(
+                            let _ = unwrap!(unwrap!(service.lock()).send(
                                 *peer_id,
-                                message.into_bytes(),
+                                Bytes::from(message),
                                 0,
                             ));
                         }
@@ -551,9 +551,9 @@ fn main() {
                 }
                 UserCommand::SendAll(message) => {
                     let mut network = unwrap!(network.lock());
-                    let msg = message.into_bytes();
+                    let msg = Bytes::from(message);
                     for peer_id in network.nodes.values_mut() {
-                        unwrap!(unwrap!(service.lock()).send(peer_id, msg.clone(), 0));
+                        let _ = unwrap!(unwrap!(service.lock()).send(peer_id, msg.clone(), 0));
                     }
                 }
                 UserCommand::List => {
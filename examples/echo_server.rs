@@ -0,0 +1,139 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Example which runs a standalone Crust echo server.
+//!
+//! Every Crust listener answers external address echo requests, which is how Crust nodes learn
+//! their public endpoints. This example runs a listener that does nothing else, so operators can
+//! host their own echo servers instead of relying on other peers.
+//!
+//! ## Use
+//!
+//! 1. `cargo run --example echo_server -- --port 5483`
+//! 2. The server prints a `hard_coded_contacts` entry for each of its addresses. Put the public
+//!    ones into the Crust config of the nodes which should use this server.
+//! 3. Press Ctrl+C to exit.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(
+    exceeding_bitshifts,
+    mutable_transmutes,
+    no_mangle_const_items,
+    unknown_crate_types,
+    warnings
+)]
+#![deny(
+    bad_style,
+    deprecated,
+    improper_ctypes,
+    missing_docs,
+    non_shorthand_field_patterns,
+    overflowing_literals,
+    plugin_as_library,
+    stable_features,
+    unconditional_recursion,
+    unknown_lints,
+    unsafe_code,
+    unused,
+    unused_allocation,
+    unused_attributes,
+    unused_comparisons,
+    unused_features,
+    unused_parens,
+    while_true
+)]
+#![warn(
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+#![allow(
+    box_pointers,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    variant_size_differences
+)]
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate unwrap;
+use clap;
+use crust;
+use maidsafe_utilities;
+use rand;
+use serde_json;
+
+use clap::{App, Arg};
+use crust::{Config, ConnectionInfoResult, Event, PeerInfo, Uid};
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use std::sync::mpsc::channel;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
+struct UniqueId([u8; 20]);
+impl Uid for UniqueId {}
+
+type Service = crust::Service<UniqueId>;
+
+fn main() {
+    unwrap!(maidsafe_utilities::log::init(true));
+
+    let matches = App::new("echo_server")
+        .about("Runs a Crust listener which only serves external address echo requests.")
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("TCP port to listen on. Random if not given.")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let mut config = Config::default();
+    config.tcp_acceptor_port = matches
+        .value_of("port")
+        .map(|port| unwrap!(port.parse(), "Expected number for <PORT>"));
+
+    let (event_tx, event_rx) = channel();
+    let (category_tx, _category_rx) = channel();
+    let event_sender = MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+
+    let mut service = unwrap!(Service::with_config(
+        event_sender,
+        config,
+        UniqueId(rand::random())
+    ));
+    unwrap!(service.start_listening_tcp());
+
+    for event in event_rx.iter() {
+        match event {
+            Event::ListenerStarted(port) => {
+                println!("Listening on port {}", port);
+                service.prepare_connection_info(0);
+            }
+            Event::ListenerFailed => panic!("Failed to start listener"),
+            Event::ConnectionInfoPrepared(ConnectionInfoResult { result, .. }) => {
+                let info = unwrap!(result, "Failed to prepare connection info");
+                let contacts: Vec<_> = info
+                    .for_direct
+                    .iter()
+                    .map(|addr| PeerInfo::new(*addr, info.our_pk))
+                    .collect();
+                println!("Add these to the \"hard_coded_contacts\" of your Crust config:");
+                println!("{}", unwrap!(serde_json::to_string_pretty(&contacts)));
+            }
+            _ => (),
+        }
+    }
+}
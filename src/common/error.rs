@@ -45,5 +45,10 @@ quick_error! {
         CoreMsgTx {
             display("CoreMessage channel was destroyed")
         }
+        /// A subnet couldn't be parsed from CIDR notation
+        InvalidSubnet(s: String) {
+            description("Invalid subnet")
+            display("Invalid subnet: {}", s)
+        }
     }
 }
@@ -7,34 +7,168 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{BootstrapperRole, NameHash};
+use crate::common::{BootstrapperRole, NameHash, PeerInfo, PowChallenge};
+use bytes::Bytes;
 use safe_crypto::PublicEncryptKey;
+use socket_collection::Priority;
 use std::collections::HashSet;
 use std::net::SocketAddr;
 
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Message<UID> {
     Heartbeat,
-    /// Carries a list of our listener addresses in case remote peer wants to check our
-    /// external reachability.
-    BootstrapRequest(UID, NameHash, BootstrapperRole, PublicEncryptKey),
-    BootstrapGranted(UID),
+    /// Carries our ID, network name hash, protocol version and a list of our listener addresses
+    /// in case remote peer wants to check our external reachability. The flag tells whether we
+    /// take `CompressedData`, see `Config::compression_threshold`.
+    BootstrapRequest(UID, NameHash, u32, BootstrapperRole, PublicEncryptKey, bool),
+    /// Carries the bootstrappee's ID, whether it takes `CompressedData` and, if it lets us resume
+    /// the connection on a new socket, the id to do that with, see `ResumeSession`.
+    BootstrapGranted(UID, bool, Option<u64>),
     BootstrapDenied(BootstrapDenyReason),
     EchoAddrReq(PublicEncryptKey),
     EchoAddrResp(SocketAddr),
     ChooseConnection,
     /// Send this message to initiate connection with remote peer. This message carries our ID,
-    /// network name hash, list of public IP:port pairs and our public key.
-    ConnectRequest(UID, NameHash, HashSet<SocketAddr>, PublicEncryptKey),
-    /// Response of accepted connection that carries remote peer's ID and network name hash.
-    ConnectResponse(UID, NameHash),
-    Data(Vec<u8>),
+    /// network name hash, protocol version, list of public IP:port pairs, our public key and
+    /// whether we take `CompressedData`.
+    ConnectRequest(
+        UID,
+        NameHash,
+        u32,
+        HashSet<SocketAddr>,
+        PublicEncryptKey,
+        bool,
+    ),
+    /// Response of accepted connection that carries remote peer's ID, network name hash,
+    /// protocol version and whether it takes `CompressedData`.
+    ConnectResponse(UID, NameHash, u32, bool),
+    Data(Bytes),
+    /// Data which the receiver has to confirm with `DataAck` carrying the same message id.
+    AckedData(u64, Vec<u8>),
+    DataAck(u64),
+    /// Asks the receiver to answer with a `Pong` carrying the same nonce. Also serves as a
+    /// heartbeat.
+    Ping(u64),
+    Pong(u64),
+    /// Announces that the sender is about to close the connection. The receiver answers with
+    /// `Goodbye` too and closes the connection after that.
+    Goodbye,
+    /// Data which the receiver is expected to answer with a `Response` carrying the same id.
+    Request(u64, Vec<u8>),
+    Response(u64, Vec<u8>),
+    /// Tells the receiver why the sender is dropping it, right before closing the connection.
+    Disconnect(DisconnectReason),
+    /// Answer to a `BootstrapRequest` when the bootstrappee wants a proof of work first. The
+    /// bootstrapper answers with `BootstrapChallengeResponse`.
+    BootstrapChallenge(PowChallenge),
+    BootstrapChallengeResponse(u64),
+    /// A sample of the sender's bootstrap cache. The connection is authenticated, so the
+    /// contacts are known to come from the peer.
+    PeerExchange(Vec<PeerInfo>),
+    /// Sent ahead of the answer to a node's `BootstrapRequest`: which of the node's listeners
+    /// passed the external reachability test.
+    ReachabilityReport(Vec<(SocketAddr, bool)>),
+    /// Asks the receiver to pass connection info on to the given peer as `RelayedConnInfo`. It's
+    /// sealed for that peer by the owner of the given key, so the receiver can't read it.
+    RelayConnInfo(UID, PublicEncryptKey, Vec<u8>),
+    /// Connection info the given peer asked the sender to relay to us via `RelayConnInfo`.
+    RelayedConnInfo(UID, PublicEncryptKey, Vec<u8>),
+    /// LZ4 compressed `Data`, along with its uncompressed length. Only sent to peers which said
+    /// in the handshake that they take it.
+    CompressedData(u32, Vec<u8>),
+    /// Asks the receiver to pass data on to the given peer as `RelayedData`, with the given
+    /// priority. It's sealed for that peer, so the receiver can't read it.
+    RelayData(UID, Priority, Vec<u8>),
+    /// Data the given peer asked the sender to relay to us via `RelayData`.
+    RelayedData(UID, Vec<u8>),
+    /// Announces a stream with the given id, whose data follows as `StreamChunk`s.
+    StreamStart(u64),
+    /// The next chunk of the stream with the given id.
+    StreamChunk(u64, Bytes),
+    /// Ends the stream with the given id. The flag tells whether it's complete, rather than
+    /// broken off by the sender.
+    StreamEnd(u64, bool),
+    /// Lets the sender of the stream with the given id send this many more chunks.
+    StreamCredit(u64, u32),
+    /// Asks the sender to stop sending the stream with the given id.
+    StreamCancel(u64),
+    /// Data sent on the logical channel with the given id, see `Service::open_channel`.
+    ChannelData(u16, Bytes),
+    /// Sent instead of a `BootstrapRequest` to move a bootstrap connection whose socket broke over
+    /// to this socket. Carries our ID, our public key and the session id we were granted. The
+    /// session id is only ever sent encrypted, so only the two peers know it.
+    ResumeSession(UID, PublicEncryptKey, u64),
+    /// Answer to a `ResumeSession`, after which the connection carries on over this socket.
+    SessionResumed,
 }
 
+impl<UID> Message<UID> {
+    /// Returns the user data carried by this message, if any. Relayed data counts, though it's
+    /// still sealed.
+    pub fn payload(&self) -> Option<&[u8]> {
+        match *self {
+            Message::Data(ref data)
+            | Message::StreamChunk(_, ref data)
+            | Message::ChannelData(_, ref data) => Some(data),
+            Message::AckedData(_, ref data)
+            | Message::Request(_, ref data)
+            | Message::Response(_, ref data)
+            | Message::RelayData(_, _, ref data)
+            | Message::RelayedData(_, ref data) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Takes the user data out of this message, if any. Relayed data is sealed and so left out.
+    pub fn into_payload(self) -> Option<Bytes> {
+        match self {
+            Message::Data(data) | Message::StreamChunk(_, data) | Message::ChannelData(_, data) => {
+                Some(data)
+            }
+            Message::AckedData(_, data)
+            | Message::Request(_, data)
+            | Message::Response(_, data) => Some(Bytes::from(data)),
+            _ => None,
+        }
+    }
+}
+
+/// Why a peer refused to let us bootstrap off it, as reported by `BootstrapError::Denied`.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum BootstrapDenyReason {
+    /// The peer belongs to a network with a different name.
     InvalidNameHash,
+    /// The peer couldn't connect back to us, which nodes must allow.
     FailedExternalReachability,
+    /// Our IP isn't on the peer's node whitelist.
     NodeNotWhitelisted,
+    /// Our IP isn't on the peer's client whitelist.
     ClientNotWhitelisted,
+    /// The peer is at its connection limits.
+    TooManyConnections,
+    /// We didn't solve the peer's proof of work challenge.
+    FailedChallenge,
+    /// The peer speaks the given, different version of the crust protocol.
+    IncompatibleProtocolVersion(u32),
+    /// The peer let as many peers of our kind bootstrap off it as it allows for now.
+    Throttled,
+    /// Our IP is in a subnet the peer doesn't let bootstrap off it.
+    ForbiddenSubnet,
+}
+
+/// Why a peer dropped the connection to us, as reported by `LostPeerReason::DroppedByPeer`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// We sent a message bigger than the peer allows.
+    MessageTooLarge,
+    /// We kept sending more than the peer's inbound limits allow.
+    Flooding,
+    /// The peer made room for another connection under its connection limits.
+    ConnectionLimit,
+    /// Our score with the peer dropped too low.
+    LowScore,
+    /// Our IP is no longer whitelisted by the peer.
+    NotWhitelisted,
+    /// The peer blacklisted us.
+    Blacklisted,
 }
@@ -9,18 +9,31 @@
 
 pub use self::core::{spawn_event_loop, Core, CoreMessage, CoreTimer, EventLoop};
 pub use self::error::CommonError;
-pub use self::message::{BootstrapDenyReason, Message};
+pub use self::message::{BootstrapDenyReason, DisconnectReason, Message};
+pub use self::pow::{PowChallenge, MAX_POW_DIFFICULTY};
 pub use self::state::State;
+pub use self::subnet::IpSubnet;
+use mio::net::TcpStream;
+use net2::TcpBuilder;
 use safe_crypto::PublicEncryptKey;
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
+use socket_collection::TcpSock;
 use std::collections::HashSet;
 use std::fmt;
 use std::hash::Hash;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
 pub const HASH_SIZE: usize = 32;
 pub type NameHash = [u8; HASH_SIZE];
+/// Version of the crust wire protocol, exchanged in the bootstrap and connect handshakes. Peers
+/// with a different version are refused. Bump it with every incompatible change to `Message`.
+///
+/// Version 2 added `ReachabilityReport`, the relayed connection info, `CompressedData` and the
+/// compression flags of the handshakes.
+pub const PROTOCOL_VERSION: u32 = 2;
 pub type Result<T> = ::std::result::Result<T, CommonError>;
 
 /// Specify crust user. Behaviour (for example in bootstrap phase) will be different for different
@@ -36,13 +49,26 @@ pub enum CrustUser {
     Client,
 }
 
+/// What a `Node` knows about its own reachability from outside.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ExternalReachability {
+    /// We have no public endpoints, e.g. no echo server responded or our listener is not started.
+    Unknown,
+    /// Our external addresses are in the shared address space (RFC 6598) used by carrier grade
+    /// NATs, so nobody outside can reach us.
+    BehindCgnat,
+    /// Public endpoints echo servers have seen our listener on. These still have to pass the
+    /// external reachability test.
+    Reachable(HashSet<SocketAddr>),
+}
+
 /// Corresponds to `CrustUser` roles and additionally include public endpoints to test for
 /// external reachability.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum BootstrapperRole {
-    /// `Node` peers are usually requested to be externally reachable, hence include their
-    /// public endpoints.
-    Node(HashSet<SocketAddr>),
+    /// `Node` peers are usually requested to be externally reachable, hence include what they
+    /// know about their reachability.
+    Node(ExternalReachability),
     /// `Client` peers don't include any addresses, because they are never tested for external
     /// reachability.
     Client,
@@ -96,7 +122,102 @@ pub fn ipv4_addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
 }
 
+/// TCP socket options applied to peer connections when they are made or accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm, for lower latency at the cost of more packets.
+    #[serde(default)]
+    pub nodelay: bool,
+    /// Enable TCP keepalive probes after the connection was idle for this many seconds.
+    pub keepalive_sec: Option<u64>,
+    /// Size of the socket's send buffer in bytes. If `None`, the OS default is kept.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the socket's receive buffer in bytes. If `None`, the OS default is kept.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Applies the options to the given stream.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(sec) = self.keepalive_sec {
+            stream.set_keepalive(Some(Duration::from_secs(sec)))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Starts connecting to the given address. If `bind_ip` is given and is of the same address
+/// family as `addr`, the socket is bound to it first, so the connection goes out through that
+/// interface. Otherwise the OS picks the local address.
+pub fn connect_tcp(
+    addr: &SocketAddr,
+    bind_ip: Option<IpAddr>,
+    options: &SocketOptions,
+) -> io::Result<TcpSock> {
+    let stream = match bind_ip {
+        Some(ip) if ip.is_ipv4() == addr.is_ipv4() => {
+            let socket = match ip {
+                IpAddr::V4(..) => TcpBuilder::new_v4()?,
+                IpAddr::V6(..) => TcpBuilder::new_v6()?,
+            };
+            let _ = socket.bind(SocketAddr::new(ip, 0))?;
+            TcpStream::connect_stream(socket.to_tcp_stream()?, addr)?
+        }
+        _ => TcpStream::connect(addr)?,
+    };
+    options.apply(&stream)?;
+    Ok(TcpSock::wrap(stream))
+}
+
 mod core;
 mod error;
 mod message;
+mod pow;
 mod state;
+mod subnet;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn connect_tcp_binds_to_requested_ip() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let listener_addr = unwrap!(listener.local_addr());
+
+        let _sock = unwrap!(connect_tcp(
+            &listener_addr,
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            &SocketOptions::default(),
+        ));
+        let (_stream, peer_addr) = unwrap!(listener.accept());
+
+        assert_eq!(peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn socket_options_are_applied() {
+        let listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let stream = unwrap!(TcpStream::connect(&unwrap!(listener.local_addr())));
+
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive_sec: Some(30),
+            ..Default::default()
+        };
+        unwrap!(options.apply(&stream));
+
+        assert!(unwrap!(stream.nodelay()));
+        assert!(unwrap!(stream.keepalive()).is_some());
+    }
+}
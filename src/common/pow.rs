@@ -0,0 +1,98 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use rand;
+use safe_crypto;
+
+/// We refuse to solve challenges harder than this. The bootstrapper solves the challenge on its
+/// event loop, which stalls every other connection meanwhile, so this is kept low enough to be
+/// solved within milliseconds.
+pub const MAX_POW_DIFFICULTY: u8 = 16;
+
+/// Hashcash style challenge: find a number which, hashed together with the nonce, gives a hash
+/// starting with at least `difficulty` zero bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PowChallenge {
+    pub nonce: [u8; 32],
+    pub difficulty: u8,
+}
+
+impl PowChallenge {
+    pub fn new(difficulty: u8) -> Self {
+        PowChallenge {
+            nonce: rand::random(),
+            difficulty,
+        }
+    }
+
+    /// Brute forces the solution. Returns `None` if the challenge is harder than
+    /// `MAX_POW_DIFFICULTY`.
+    pub fn solve(&self) -> Option<u64> {
+        if self.difficulty > MAX_POW_DIFFICULTY {
+            return None;
+        }
+        (0..).find(|solution| self.verify(*solution))
+    }
+
+    pub fn verify(&self, solution: u64) -> bool {
+        let mut data = self.nonce.to_vec();
+        data.extend_from_slice(&solution.to_le_bytes());
+        leading_zero_bits(&safe_crypto::hash(&data)) >= u32::from(self.difficulty)
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff, 0]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0x10, 0]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn solution_is_verified() {
+        let challenge = PowChallenge::new(8);
+        let solution = unwrap!(challenge.solve());
+
+        assert!(challenge.verify(solution));
+        assert!(!(0..solution).any(|wrong| challenge.verify(wrong)));
+    }
+
+    #[test]
+    fn hardest_challenge_is_solved_quickly() {
+        use std::time::{Duration, Instant};
+
+        let challenge = PowChallenge::new(MAX_POW_DIFFICULTY);
+        let started = Instant::now();
+        let solution = unwrap!(challenge.solve());
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(challenge.verify(solution));
+    }
+
+    #[test]
+    fn too_hard_challenge_is_not_solved() {
+        let challenge = PowChallenge::new(MAX_POW_DIFFICULTY + 1);
+        assert_eq!(challenge.solve(), None);
+    }
+}
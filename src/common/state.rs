@@ -8,6 +8,7 @@
 // Software.
 
 use crate::common::Core;
+use bytes::Bytes;
 use mio::{Poll, Ready};
 use std::any::Any;
 
@@ -22,5 +23,5 @@ pub trait State<T> {
 
     fn timeout(&mut self, _core: &mut Core<T>, _poll: &Poll, _timer_id: u8) {}
 
-    fn write(&mut self, _core: &mut Core<T>, _poll: &Poll, _data: Vec<u8>, _priority: Priority) {}
+    fn write(&mut self, _core: &mut Core<T>, _poll: &Poll, _data: Bytes, _priority: Priority) {}
 }
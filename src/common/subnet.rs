@@ -0,0 +1,129 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::CommonError;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. In the config
+/// file it's written as such a string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpSubnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpSubnet {
+    /// Returns whether the given address belongs to this subnet. IPv4 addresses never belong to
+    /// IPv6 subnets and vice versa.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = !0u8 << (8 - remaining_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpSubnet {
+    type Err = CommonError;
+
+    fn from_str(s: &str) -> Result<Self, CommonError> {
+        let invalid = || CommonError::InvalidSubnet(s.to_owned());
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = unwrap!(parts.next()).parse().map_err(|_| invalid())?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+        Ok(IpSubnet { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpSubnet {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpSubnet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpSubnet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_matched_against_the_prefix() {
+        let subnet: IpSubnet = unwrap!("10.1.0.0/12".parse());
+        assert!(subnet.contains(&unwrap!("10.1.2.3".parse())));
+        assert!(subnet.contains(&unwrap!("10.15.255.255".parse())));
+        assert!(!subnet.contains(&unwrap!("10.16.0.0".parse())));
+        assert!(!subnet.contains(&unwrap!("::ffff:10.1.2.3".parse())));
+
+        let subnet: IpSubnet = unwrap!("2001:db8::/32".parse());
+        assert!(subnet.contains(&unwrap!("2001:db8::1".parse())));
+        assert!(!subnet.contains(&unwrap!("2001:db9::1".parse())));
+
+        let subnet: IpSubnet = unwrap!("0.0.0.0/0".parse());
+        assert!(subnet.contains(&unwrap!("1.2.3.4".parse())));
+    }
+
+    #[test]
+    fn invalid_subnets_are_rejected() {
+        assert!("10.0.0.0/33".parse::<IpSubnet>().is_err());
+        assert!("10.0.0/8".parse::<IpSubnet>().is_err());
+        assert!("10.0.0.0/x".parse::<IpSubnet>().is_err());
+    }
+
+    #[test]
+    fn subnets_are_serialised_in_cidr_notation() {
+        let subnet: IpSubnet = unwrap!("192.168.0.0/16".parse());
+        let json = unwrap!(serde_json::to_string(&subnet));
+        assert_eq!(json, "\"192.168.0.0/16\"");
+        assert_eq!(unwrap!(serde_json::from_str::<IpSubnet>(&json)), subnet);
+
+        let single: IpSubnet = unwrap!(serde_json::from_str("\"1.2.3.4\""));
+        assert_eq!(single.to_string(), "1.2.3.4/32");
+    }
+}
@@ -92,11 +92,20 @@ mod main;
 mod nat;
 mod service_discovery;
 
-pub use crate::common::{CrustUser, PeerInfo, Uid};
+pub use crate::common::{
+    BootstrapDenyReason, CrustUser, DisconnectReason, IpSubnet, PeerInfo, SocketOptions, Uid,
+    PROTOCOL_VERSION,
+};
 pub use crate::main::{
-    read_config_file, Config, ConnectionInfoResult, CrustError, Event, PrivConnectionInfo,
-    PubConnectionInfo, Service,
+    override_with_env_vars, read_config_file, BootstrapAdmission, BootstrapError, BootstrapHandle,
+    BootstrapPolicy, Config, ConfigError, ConfigFormat, ConfigProblem, ConnectOrder, ConnectStats,
+    ConnectedPeer, ConnectionInfoResult, CrustError, Event, EvictionPolicy, IncomingStream,
+    ListenerConfig, ListenerState, LostPeerReason, PeerScoring, PeerSetChange, PeerStats,
+    PrivConnectionInfo, PubConnectionInfo, RelayedConnectionInfo, SendToken, Service,
+    ServiceBuilder, ServiceStats, Transport, TypedService,
 };
+pub use crate::nat::{NatInfo, NatType};
+pub use bytes::Bytes;
 pub use socket_collection::Priority;
 
 /// Used to receive events from a `Service`.
@@ -7,28 +7,70 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{CoreTimer, CrustUser, Message, State, Uid};
+use crate::common::{CoreTimer, CrustUser, DisconnectReason, Message, PeerInfo, State, Uid};
+use crate::main::bandwidth_budget::{priority_weight, BandwidthBudget, TokenBucket};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionId, ConnectionMap, Event, EventLoopCore};
-use mio::{Poll, Ready, Token};
+use crate::main::session::{ResumeSession, Session};
+use crate::main::stream::{
+    IncomingStream, OutgoingStream, StreamItem, STREAM_CHUNK_SIZE, STREAM_QUEUE_DEPTH,
+    STREAM_WINDOW,
+};
+use crate::main::{
+    Blacklist, ConnectedPeer, ConnectionId, ConnectionMap, CrustConfig, CrustError, Event,
+    EventLoopCore, EventSender, LostPeerReason, PeerScoring, PeerSetChange, PeerStats,
+    RelayedConnectionInfo, SendToken,
+};
+use bytes::Bytes;
+use mio::{Poll, PollOpt, Ready, Token};
 use mio_extras::timer::Timeout;
-use socket_collection::{Priority, TcpSock};
+use safe_crypto::{PublicEncryptKey, SharedSecretKey};
+use socket_collection::{Priority, SocketError, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(not(test))]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
 #[cfg(not(test))]
-const HEARTBEAT_PERIOD_MS: u64 = 20_000;
+pub const HEARTBEAT_PERIOD_MS: u64 = 20_000;
 
 #[cfg(test)]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 900;
 #[cfg(test)]
-const HEARTBEAT_PERIOD_MS: u64 = 300;
+pub const HEARTBEAT_PERIOD_MS: u64 = 300;
+
+const RATE_LIMIT_TIMER_ID: u8 = 2;
+const GOODBYE_TIMER_ID: u8 = 3;
+const INBOUND_LIMIT_TIMER_ID: u8 = 4;
+const SESSION_TIMER_ID: u8 = 5;
+const REDIAL_TIMER_ID: u8 = 6;
+/// A peer which goes over our inbound limits in more than this many consecutive seconds is
+/// dropped instead of throttled again.
+const MAX_INBOUND_STRIKES: u32 = 3;
+/// How long to wait for the peer to answer our goodbye.
+const GOODBYE_TIMEOUT_SEC: u64 = 5;
+/// Most contacts we send or take from a peer via peer exchange.
+const MAX_PEER_EXCHANGE_CONTACTS: usize = 20;
+/// LZ4 can't compress data to less than about 1/255 of its size, so `CompressedData` claiming a
+/// bigger length than this many times its own is bogus.
+const MAX_LZ4_RATIO: usize = 255;
+/// Room for the serialisation and encryption of a message on top of its payload, see
+/// `Config::max_msg_size`.
+const FRAME_OVERHEAD: usize = 1024;
+/// Room for the nonce, MAC and length prefix which sealing adds to relayed data.
+const SEAL_OVERHEAD: usize = 64;
+/// How many messages of a channel may wait in the send queue at once. The rest waits in the
+/// channel's own queue.
+const CHANNEL_QUEUE_DEPTH: usize = 2;
+/// How often we dial the peer again while resuming a session, giving up on the previous attempt.
+const REDIAL_PERIOD_MS: u64 = 1000;
 
 pub struct ActiveConnection<UID: Uid> {
     token: Token,
@@ -37,8 +79,55 @@ pub struct ActiveConnection<UID: Uid> {
     our_id: UID,
     their_id: UID,
     their_role: CrustUser,
-    event_tx: crate::CrustEventSender<UID>,
+    event_tx: EventSender<UID>,
     heartbeat: Heartbeat,
+    send_queue: SendQueue<UID>,
+    /// Whether the socket still holds data it couldn't write yet. Further data waits in
+    /// `send_queue` until it has, so it can still be cancelled.
+    socket_backlogged: bool,
+    send_queue_limit: Option<usize>,
+    priority_queue_limit: Option<usize>,
+    channel_queue_limit: Option<usize>,
+    max_msg_size: Option<usize>,
+    /// If set, we accept compressed data and compress data for the peer if it does too.
+    compression_threshold: Option<usize>,
+    /// Whether the peer said in the handshake that it accepts compressed data.
+    peer_accepts_compression: bool,
+    inbound_limit: Option<InboundLimit>,
+    scoring: PeerScoring,
+    score: i32,
+    /// Peers banned for their score are put here.
+    blacklist: Blacklist<UID>,
+    rtt: RttEstimator,
+    started: Instant,
+    /// When we last received a message.
+    last_activity: Instant,
+    /// The RTT and the queue depth are filled in on demand.
+    stats: PeerStats,
+    /// Set once goodbyes are being exchanged.
+    closing: Option<Closing>,
+    /// Reported with `Event::LostPeer` on termination.
+    lost_reason: LostPeerReason,
+    recv_paused: bool,
+    /// Whatever the application attached via `Service::set_peer_data`.
+    user_data: Option<Box<Any>>,
+    /// Labels the application tagged the peer with via `Service::add_peer_to_group`.
+    groups: HashSet<String>,
+    peer_exchange: bool,
+    relay_conn_info: bool,
+    /// Peers we reach through this connection's peer, see `Service::connect_via`.
+    relay_routes: HashMap<UID, RelayRoute>,
+    /// Streams we send to the peer, by id, see `Service::send_stream`.
+    outgoing_streams: HashMap<u64, OutgoingStream>,
+    /// Streams the peer sends us, by id, along with how many more chunks we let it send.
+    incoming_streams: HashMap<u64, (mpsc::Sender<StreamItem>, u32)>,
+    /// Logical channels to the peer, by id, see `Service::open_channel`.
+    channels: BTreeMap<u16, Channel>,
+    /// Lets the connection be resumed on a new socket once its socket broke.
+    session: Option<Session>,
+    session_resume_timeout: Option<Duration>,
+    /// Set while the socket is broken and we wait for the session to be resumed.
+    suspended: Option<Suspended>,
 }
 
 impl<UID: Uid> ActiveConnection<UID> {
@@ -46,13 +135,17 @@ impl<UID: Uid> ActiveConnection<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         token: Token,
-        socket: TcpSock,
+        mut socket: TcpSock,
         cm: ConnectionMap<UID>,
+        config: &CrustConfig,
+        blacklist: &Blacklist<UID>,
         our_id: UID,
         their_id: UID,
         their_role: CrustUser,
+        peer_accepts_compression: bool,
+        session: Option<Session>,
         event: Event<UID>,
-        event_tx: crate::CrustEventSender<UID>,
+        event_tx: EventSender<UID>,
     ) {
         trace!(
             "Entered state ActiveConnection: {:?} -> {:?}",
@@ -60,7 +153,49 @@ impl<UID: Uid> ActiveConnection<UID> {
             their_id
         );
 
-        let heartbeat = match Heartbeat::try_new(core, token) {
+        let (
+            period,
+            inactivity_timeout,
+            send_queue_limit,
+            priority_queue_limit,
+            channel_queue_limit,
+            max_msg_size,
+            compression_threshold,
+            inbound_limit,
+            scoring,
+            peer_exchange,
+            relay_conn_info,
+            session_resume_timeout,
+        ) = {
+            let cfg = &unwrap!(config.lock()).cfg;
+            let inbound_limit = match (cfg.inbound_msgs_per_sec, cfg.inbound_bytes_per_sec) {
+                (None, None) => None,
+                (msgs_per_sec, bytes_per_sec) => Some(InboundLimit::new(
+                    msgs_per_sec,
+                    bytes_per_sec,
+                    Instant::now(),
+                )),
+            };
+            (
+                Duration::from_millis(cfg.heartbeat_period_ms.unwrap_or(HEARTBEAT_PERIOD_MS)),
+                Duration::from_millis(cfg.inactivity_timeout_ms.unwrap_or(INACTIVITY_TIMEOUT_MS)),
+                cfg.send_queue_limit,
+                cfg.priority_queue_limit,
+                cfg.channel_queue_limit,
+                cfg.max_msg_size,
+                cfg.compression_threshold,
+                inbound_limit,
+                cfg.peer_scoring,
+                cfg.peer_exchange,
+                cfg.relay_connection_info,
+                cfg.session_resume_timeout_ms.map(Duration::from_millis),
+            )
+        };
+        if let Some(max_msg_size) = max_msg_size {
+            // So that a forged length prefix doesn't make the socket allocate a bigger buffer.
+            socket.set_max_payload_size(max_msg_size.saturating_add(FRAME_OVERHEAD));
+        }
+        let heartbeat = match Heartbeat::try_new(core, token, period, inactivity_timeout) {
             Ok(heartbeat) => heartbeat,
             Err(e) => {
                 debug!(
@@ -69,7 +204,10 @@ impl<UID: Uid> ActiveConnection<UID> {
                     our_id, e, their_id
                 );
                 let _ = poll.deregister(&socket);
-                let _ = event_tx.send(Event::LostPeer(their_id));
+                let _ = event_tx.send(Event::LostPeer(
+                    their_id,
+                    LostPeerReason::IoError(ErrorKind::Other),
+                ));
                 // TODO See if this plays well with ConnectionMap<UID> manipulation below
                 return;
             }
@@ -84,6 +222,36 @@ impl<UID: Uid> ActiveConnection<UID> {
             their_role,
             event_tx,
             heartbeat,
+            send_queue: SendQueue::new(),
+            socket_backlogged: false,
+            send_queue_limit,
+            priority_queue_limit,
+            channel_queue_limit,
+            max_msg_size,
+            compression_threshold,
+            peer_accepts_compression,
+            inbound_limit,
+            scoring,
+            score: 0,
+            blacklist: blacklist.clone(),
+            rtt: Default::default(),
+            started: Instant::now(),
+            last_activity: Instant::now(),
+            stats: Default::default(),
+            closing: None,
+            lost_reason: LostPeerReason::Evicted,
+            recv_paused: false,
+            user_data: None,
+            groups: HashSet::new(),
+            peer_exchange,
+            relay_conn_info,
+            relay_routes: HashMap::new(),
+            outgoing_streams: HashMap::new(),
+            incoming_streams: HashMap::new(),
+            channels: BTreeMap::new(),
+            session: session.filter(|_| session_resume_timeout.is_some()),
+            session_resume_timeout,
+            suspended: None,
         }));
 
         let _ = core.insert_state(token, state.clone());
@@ -99,71 +267,1524 @@ impl<UID: Uid> ActiveConnection<UID> {
                 conn_id.currently_handshaking -= 1;
                 conn_id.active_connection = Some(token);
             }
-            trace!(
-                "Connection Map inserted: {:?} -> {:?}",
-                their_id,
-                guard.get(&their_id)
+            trace!(
+                "Connection Map inserted: {:?} -> {:?}",
+                their_id,
+                guard.get(&their_id)
+            );
+        }
+        let _ = state_mut.event_tx.send(event);
+        state_mut
+            .event_tx
+            .send_peer_set_change(PeerSetChange::Added(their_id, their_role));
+        if state_mut.peer_exchange {
+            state_mut.send_peer_exchange(core, poll);
+            // A failed write has terminated us already.
+            if core.get_state(token).is_none() {
+                return;
+            }
+        }
+        state_mut.read(core, poll);
+    }
+
+    /// Shares the best contacts of our bootstrap cache with the peer.
+    fn send_peer_exchange(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let contacts: Vec<_> = core
+            .user_data()
+            .ranked_peers()
+            .into_iter()
+            .take(MAX_PEER_EXCHANGE_CONTACTS)
+            .collect();
+        if !contacts.is_empty() {
+            self.write(core, poll, Some((Message::PeerExchange(contacts), 0)));
+        }
+    }
+
+    /// Adds the contacts the peer shared with us to our bootstrap cache. They haven't worked for
+    /// us yet, so they rank below the contacts we've used and are dropped once they fail.
+    fn handle_peer_exchange(&mut self, core: &mut EventLoopCore, contacts: Vec<PeerInfo>) {
+        if !self.peer_exchange {
+            return;
+        }
+        let bootstrap_cache = core.user_data_mut();
+        for contact in contacts.into_iter().take(MAX_PEER_EXCHANGE_CONTACTS) {
+            bootstrap_cache.put_untested(contact);
+        }
+        if let Err(e) = bootstrap_cache.commit() {
+            info!("Failed to write bootstrap cache to disk: {}", e);
+        }
+    }
+
+    /// Asks the peer to relay connection info, sealed for `target` by the owner of `sender_pk`.
+    pub fn send_conn_info_for(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        target: UID,
+        sender_pk: PublicEncryptKey,
+        sealed: Vec<u8>,
+    ) {
+        let msg = Message::RelayConnInfo(target, sender_pk, sealed);
+        self.write(core, poll, Some((msg, 0)));
+    }
+
+    /// Passes connection info the peer sealed for `target` on to it, if we relay at all.
+    fn relay_conn_info(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        target: UID,
+        sender_pk: PublicEncryptKey,
+        sealed: Vec<u8>,
+    ) {
+        if !self.relay_conn_info || target == self.their_id {
+            return;
+        }
+        let token = match unwrap!(self.cm.lock()).get(&target) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => token,
+            _ => {
+                debug!(
+                    "{:?} asked us to relay connection info to {:?}, which we aren't connected to",
+                    self.their_id, target
+                );
+                return;
+            }
+        };
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                let msg = Message::RelayedConnInfo(self.their_id, sender_pk, sealed);
+                ac.write(core, poll, Some((msg, 0)));
+            }
+        }
+    }
+
+    /// Lets us reach `peer`, a node or client as given by `kind`, through this connection's peer.
+    /// Data is sealed with `shared_key`, which only we and `peer` know.
+    pub fn add_relay_route(&mut self, peer: UID, kind: CrustUser, shared_key: SharedSecretKey) {
+        let _ = self.relay_routes.insert(
+            peer,
+            RelayRoute {
+                shared_key,
+                kind,
+                since: Instant::now(),
+                stats: Default::default(),
+            },
+        );
+    }
+
+    /// Stops reaching `peer` through this connection's peer. Returns whether we did.
+    pub fn remove_relay_route(&mut self, peer: &UID) -> bool {
+        if self.relay_routes.remove(peer).is_none() {
+            return false;
+        }
+        self.lose_relayed_peer(*peer, LostPeerReason::Evicted);
+        true
+    }
+
+    /// Reports a peer we no longer reach through this connection's peer as lost, unless we are
+    /// connected to it directly.
+    fn lose_relayed_peer(&self, peer: UID, reason: LostPeerReason) {
+        let direct = unwrap!(self.cm.lock())
+            .get(&peer)
+            .map_or(false, |cid| cid.active_connection.is_some());
+        if !direct {
+            let _ = self.event_tx.send(Event::LostPeer(peer, reason));
+        }
+    }
+
+    pub fn relays_to(&self, peer: &UID) -> bool {
+        self.relay_routes.contains_key(peer)
+    }
+
+    /// Sends data to `target` through this connection's peer, see `add_relay_route`.
+    pub fn send_relayed(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        target: UID,
+        data: Bytes,
+        priority: Priority,
+    ) {
+        let sealed = match self.relay_routes.get_mut(&target) {
+            Some(route) => match route.shared_key.encrypt(&data) {
+                Ok(sealed) => {
+                    route.stats.msgs_sent += 1;
+                    route.stats.bytes_sent += data.len() as u64;
+                    sealed
+                }
+                Err(e) => {
+                    debug!(
+                        "{:?} - Failed to seal data for {:?}: {:?}",
+                        self.our_id, target, e
+                    );
+                    return;
+                }
+            },
+            None => return,
+        };
+        let msg = Message::RelayData(target, priority, sealed);
+        self.send(core, poll, send_token, msg, priority, None);
+    }
+
+    /// Passes data the peer sealed for `target` on to it, as far as `Config::relay_bytes_per_sec`
+    /// allows.
+    fn relay_data(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        target: UID,
+        priority: Priority,
+        sealed: Vec<u8>,
+    ) {
+        if target == self.their_id {
+            return;
+        }
+        let token = match unwrap!(self.cm.lock()).get(&target) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => token,
+            _ => {
+                debug!(
+                    "{:?} asked us to relay data to {:?}, which we aren't connected to",
+                    self.their_id, target
+                );
+                return;
+            }
+        };
+        let len = sealed.len();
+        if !BandwidthBudget::with(core, |budget| budget.try_relay(len, Instant::now()))
+            .unwrap_or(false)
+        {
+            debug!(
+                "{:?} - Dropped data {:?} asked us to relay to {:?}",
+                self.our_id, self.their_id, target
+            );
+            return;
+        }
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                let msg = Message::RelayedData(self.their_id, sealed);
+                ac.write(core, poll, Some((msg, priority)));
+            }
+        }
+    }
+
+    /// Opens data `from` sent us through this connection's peer and hands it to the application.
+    fn receive_relayed(&mut self, from: UID, sealed: &[u8]) {
+        let route = match self.relay_routes.get_mut(&from) {
+            Some(route) => route,
+            None => {
+                debug!(
+                    "{:?} - {:?} relayed data from {:?}, which we don't reach through it",
+                    self.our_id, self.their_id, from
+                );
+                return;
+            }
+        };
+        match route.shared_key.decrypt::<Bytes>(sealed) {
+            Ok(data) => {
+                route.stats.msgs_received += 1;
+                route.stats.bytes_received += data.len() as u64;
+                let _ = self
+                    .event_tx
+                    .send(Event::NewMessage(from, route.kind, data));
+            }
+            Err(e) => debug!(
+                "{:?} - Failed to open data relayed from {:?}: {:?}",
+                self.our_id, from, e
+            ),
+        }
+    }
+
+    /// Streams the items of `source` to the peer, see `Service::send_stream`.
+    pub fn send_stream(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        source: Box<Iterator<Item = Bytes> + Send>,
+        priority: Priority,
+    ) {
+        if self.closing.is_some() {
+            debug!(
+                "{:?} - Connection to {:?} is closing, dropping stream.",
+                self.our_id, self.their_id
+            );
+            return;
+        }
+        let id = send_token.0 as u64;
+        let stream = OutgoingStream::new(source, send_token, priority);
+        let _ = self.outgoing_streams.insert(id, stream);
+        self.send_queue.push(Queued {
+            send_token,
+            msg: Message::StreamStart(id),
+            priority,
+            deadline: None,
+        });
+        self.flush_send_queue(core, poll);
+    }
+
+    /// Queues the next chunks of our streams, as far as the peer lets us send them and as long
+    /// as only a few messages of their priority are queued already.
+    fn pump_streams(&mut self) {
+        if self.outgoing_streams.is_empty() {
+            return;
+        }
+        let max_len = self
+            .max_msg_size
+            .map_or(STREAM_CHUNK_SIZE, |max| cmp::min(max, STREAM_CHUNK_SIZE));
+        for (&id, stream) in &mut self.outgoing_streams {
+            while !stream.finished
+                && stream.credit > 0
+                && self.send_queue.len_of(stream.priority) < STREAM_QUEUE_DEPTH
+            {
+                let msg = match stream.next_chunk(max_len) {
+                    Some(chunk) => {
+                        stream.credit -= 1;
+                        Message::StreamChunk(id, chunk)
+                    }
+                    None => {
+                        stream.finished = true;
+                        Message::StreamEnd(id, true)
+                    }
+                };
+                self.send_queue.push(Queued {
+                    send_token: stream.send_token,
+                    msg,
+                    priority: stream.priority,
+                    deadline: None,
+                });
+            }
+        }
+    }
+
+    /// Keeps track of which of our streams the peer knows of, as their first and last messages
+    /// are written.
+    fn note_stream_progress(&mut self, msg: &Message<UID>) {
+        match *msg {
+            Message::StreamStart(id) => {
+                if let Some(stream) = self.outgoing_streams.get_mut(&id) {
+                    stream.started = true;
+                }
+            }
+            Message::StreamEnd(id, _) => {
+                let _ = self.outgoing_streams.remove(&id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Opens the channel with the given id, or changes its priority.
+    pub fn open_channel(&mut self, channel: u16, priority: Priority) {
+        self.channels
+            .entry(channel)
+            .or_insert_with(|| Channel {
+                priority,
+                queue: VecDeque::new(),
+            })
+            .priority = priority;
+    }
+
+    /// Closes the channel with the given id, dropping what it holds back. Returns whether it was
+    /// open.
+    pub fn close_channel(&mut self, channel: u16) -> bool {
+        self.channels.remove(&channel).is_some()
+    }
+
+    /// Sends data on the channel with the given id. Fails if the channel isn't open or is full,
+    /// see `Config::channel_queue_limit`.
+    pub fn send_on_channel(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        channel: u16,
+        data: Bytes,
+    ) -> crate::Res<()> {
+        let limit = self.channel_queue_limit;
+        {
+            let open = match self.channels.get_mut(&channel) {
+                Some(open) => open,
+                None => return Err(CrustError::ChannelNotOpen(channel)),
+            };
+            if limit.map_or(false, |limit| open.queue.len() >= limit) {
+                return Err(CrustError::ChannelFull(channel));
+            }
+            if self.closing.is_some() {
+                debug!(
+                    "{:?} - Connection to {:?} is closing, dropping message.",
+                    self.our_id, self.their_id
+                );
+                return Ok(());
+            }
+            open.queue.push_back((send_token, data));
+        }
+        self.flush_send_queue(core, poll);
+        Ok(())
+    }
+
+    /// Moves messages of our channels to the send queue, as long as only a few of each channel
+    /// are there already.
+    fn pump_channels(&mut self) {
+        for (&id, channel) in &mut self.channels {
+            if channel.queue.is_empty() {
+                continue;
+            }
+            let mut queued = self.send_queue.count(|queued| match queued.msg {
+                Message::ChannelData(queued_id, _) => queued_id == id,
+                _ => false,
+            });
+            while queued < CHANNEL_QUEUE_DEPTH {
+                let (send_token, data) = match channel.queue.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                self.send_queue.push(Queued {
+                    send_token,
+                    msg: Message::ChannelData(id, data),
+                    priority: channel.priority,
+                    deadline: None,
+                });
+                queued += 1;
+            }
+        }
+    }
+
+    /// Hands a stream the peer announced to the application.
+    fn start_incoming_stream(&mut self, core: &EventLoopCore, id: u64) {
+        let (tx, rx) = mpsc::channel();
+        let stream = IncomingStream::new(
+            id,
+            rx,
+            core.sender().clone(),
+            self.token,
+            signal_stream::<UID>,
+        );
+        let _ = self.incoming_streams.insert(id, (tx, STREAM_WINDOW));
+        let _ = self.event_tx.send(Event::NewStream(self.their_id, stream));
+    }
+
+    /// Passes a chunk on to its stream. Returns whether to keep reading.
+    fn receive_stream_chunk(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        id: u64,
+        chunk: Bytes,
+    ) -> bool {
+        let delivered = match self.incoming_streams.get_mut(&id) {
+            // Chunks of a stream we cancelled may still be on their way.
+            None => return true,
+            Some(&mut (_, 0)) => None,
+            Some(&mut (ref tx, ref mut credit)) => {
+                *credit -= 1;
+                Some(tx.send(StreamItem::Chunk(chunk)).is_ok())
+            }
+        };
+        match delivered {
+            Some(true) => true,
+            Some(false) => {
+                // The application dropped the stream.
+                self.cancel_incoming_stream(core, poll, id);
+                core.get_state(self.token).is_some()
+            }
+            None => {
+                debug!(
+                    "{:?} - {:?} sent more of stream {} than we let it",
+                    self.our_id, self.their_id, id
+                );
+                self.cancel_incoming_stream(core, poll, id);
+                if core.get_state(self.token).is_none() {
+                    return false;
+                }
+                let penalty = self.scoring.protocol_error_penalty;
+                self.adjust_score(core, poll, -penalty)
+            }
+        }
+    }
+
+    /// Lets the peer send `credit` more chunks of the stream with the given id, as the
+    /// application took as many.
+    fn grant_stream_credit(&mut self, core: &mut EventLoopCore, poll: &Poll, id: u64, credit: u32) {
+        match self.incoming_streams.get_mut(&id) {
+            Some(&mut (_, ref mut left)) => *left += credit,
+            None => return,
+        }
+        self.write(core, poll, Some((Message::StreamCredit(id, credit), 0)));
+    }
+
+    /// Drops the stream with the given id the peer sends us and asks it to stop sending.
+    fn cancel_incoming_stream(&mut self, core: &mut EventLoopCore, poll: &Poll, id: u64) {
+        if self.incoming_streams.remove(&id).is_some() {
+            self.write(core, poll, Some((Message::StreamCancel(id), 0)));
+        }
+    }
+
+    fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        loop {
+            if self.suspended.is_some() {
+                return;
+            }
+            let res = self.socket.read::<Message<UID>>();
+            if let Ok(Some(_)) = res {
+                self.last_activity = Instant::now();
+            }
+            let payload_len = match res {
+                Ok(Some(Message::CompressedData(len, _))) => Some(len as usize),
+                Ok(Some(ref message)) => message.payload().map(|payload| payload.len()),
+                _ => None,
+            };
+            match res {
+                Ok(Some(ref message)) if self.is_too_large(message) => {
+                    debug!(
+                        "{:?} - Peer {:?} sent a message over our size limit",
+                        self.our_id, self.their_id
+                    );
+                    return self.drop_peer(
+                        core,
+                        poll,
+                        LostPeerReason::MessageTooLarge,
+                        DisconnectReason::MessageTooLarge,
+                    );
+                }
+                Ok(Some(Message::Data(data))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += data.len() as u64;
+                    let _ =
+                        self.event_tx
+                            .send(Event::NewMessage(self.their_id, self.their_role, data));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::CompressedData(len, compressed)))
+                    if self.compression_threshold.is_some() =>
+                {
+                    if let Some(data) = lz4_decompress(&compressed, len) {
+                        self.stats.msgs_received += 1;
+                        self.stats.bytes_received += data.len() as u64;
+                        let _ = self.event_tx.send(Event::NewMessage(
+                            self.their_id,
+                            self.their_role,
+                            Bytes::from(data),
+                        ));
+                    } else {
+                        debug!(
+                            "{:?} - Peer {:?} sent data which doesn't decompress",
+                            self.our_id, self.their_id
+                        );
+                        let penalty = self.scoring.protocol_error_penalty;
+                        if !self.adjust_score(core, poll, -penalty) {
+                            return;
+                        }
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::AckedData(msg_id, data))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += data.len() as u64;
+                    let data = Bytes::from(data);
+                    let _ =
+                        self.event_tx
+                            .send(Event::NewMessage(self.their_id, self.their_role, data));
+                    self.reset_receive_heartbeat(core, poll);
+                    self.write(core, poll, Some((Message::DataAck(msg_id), 0)));
+                    if core.get_state(self.token).is_none() {
+                        // Terminated because the write failed.
+                        return;
+                    }
+                    self.reset_send_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Request(request_id, data))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += data.len() as u64;
+                    let _ = self
+                        .event_tx
+                        .send(Event::NewRequest(self.their_id, request_id, data));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Response(request_id, data))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += data.len() as u64;
+                    let _ = self
+                        .event_tx
+                        .send(Event::NewResponse(self.their_id, request_id, data));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::DataAck(msg_id))) => {
+                    let _ = self
+                        .event_tx
+                        .send(Event::MessageDelivered(self.their_id, msg_id));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Heartbeat)) => {
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Ping(nonce))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.write(core, poll, Some((Message::Pong(nonce), 0)));
+                    if core.get_state(self.token).is_none() {
+                        // Terminated because the write failed.
+                        return;
+                    }
+                }
+                Ok(Some(Message::Goodbye)) => return self.handle_goodbye(core, poll),
+                Ok(Some(Message::Pong(nonce))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if let Some((rtt, true)) = self.rtt.pong(nonce, Instant::now()) {
+                        let _ = self.event_tx.send(Event::PingResponse(self.their_id, rtt));
+                    }
+                }
+                Ok(Some(Message::PeerExchange(contacts))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.handle_peer_exchange(core, contacts);
+                }
+                Ok(Some(Message::RelayConnInfo(target, sender_pk, sealed))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.relay_conn_info(core, poll, target, sender_pk, sealed);
+                }
+                Ok(Some(Message::RelayedConnInfo(from, sender_pk, sealed))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    let info = RelayedConnectionInfo::new(from, self.their_id, sender_pk, sealed);
+                    let _ = self.event_tx.send(Event::ConnectionInfoRelayed(info));
+                }
+                Ok(Some(Message::RelayData(target, priority, sealed))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.relay_data(core, poll, target, priority, sealed);
+                }
+                Ok(Some(Message::RelayedData(from, sealed))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.receive_relayed(from, &sealed);
+                }
+                Ok(Some(Message::ChannelData(channel, data))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += data.len() as u64;
+                    let _ =
+                        self.event_tx
+                            .send(Event::NewChannelMessage(self.their_id, channel, data));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::StreamStart(id))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.start_incoming_stream(core, id);
+                }
+                Ok(Some(Message::StreamChunk(id, chunk))) => {
+                    self.stats.msgs_received += 1;
+                    self.stats.bytes_received += chunk.len() as u64;
+                    self.reset_receive_heartbeat(core, poll);
+                    if !self.receive_stream_chunk(core, poll, id, chunk) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::StreamEnd(id, complete))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if let Some((tx, _)) = self.incoming_streams.remove(&id) {
+                        let _ = tx.send(StreamItem::End(complete));
+                    }
+                }
+                Ok(Some(Message::StreamCredit(id, credit))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if let Some(stream) = self.outgoing_streams.get_mut(&id) {
+                        stream.credit = stream.credit.saturating_add(credit);
+                    }
+                    self.flush_send_queue(core, poll);
+                    if core.get_state(self.token).is_none() {
+                        // Terminated because the write failed.
+                        return;
+                    }
+                }
+                Ok(Some(Message::StreamCancel(id))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if let Some(stream) = self.outgoing_streams.remove(&id) {
+                        let _ = self.send_queue.cancel(stream.send_token);
+                    }
+                }
+                Ok(Some(Message::Disconnect(reason))) => {
+                    debug!(
+                        "{:?} - {:?} dropped us: {:?}",
+                        self.our_id, self.their_id, reason
+                    );
+                    self.lost_reason = LostPeerReason::DroppedByPeer(reason);
+                    return self.terminate(core, poll);
+                }
+                Ok(Some(message)) => {
+                    debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
+                    let penalty = self.scoring.protocol_error_penalty;
+                    if !self.adjust_score(core, poll, -penalty) {
+                        return;
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(None) => return,
+                Err(SocketError::PayloadSizeProhibitive) => {
+                    debug!(
+                        "{:?} - Peer {:?} announced a message over our size limit",
+                        self.our_id, self.their_id
+                    );
+                    return self.drop_peer(
+                        core,
+                        poll,
+                        LostPeerReason::MessageTooLarge,
+                        DisconnectReason::MessageTooLarge,
+                    );
+                }
+                Err(e) => {
+                    debug!("{:?} - Failed to read from socket: {:?}", self.our_id, e);
+                    return self.lose_socket(core, poll, lost_peer_reason(&e));
+                }
+            }
+            if let Some(len) = payload_len {
+                if !self.limit_inbound(core, poll, len) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Counts a received message against the inbound limits. Returns whether to keep reading.
+    fn limit_inbound(&mut self, core: &mut EventLoopCore, poll: &Poll, len: usize) -> bool {
+        let (wait, strikes) = match self.inbound_limit {
+            Some(ref mut limit) => match limit.record(Instant::now(), len) {
+                Some(wait) => (wait, limit.strikes),
+                None => return true,
+            },
+            None => return true,
+        };
+
+        if strikes > MAX_INBOUND_STRIKES {
+            debug!(
+                "{:?} - Dropping {:?} for exceeding our inbound limits",
+                self.our_id, self.their_id
+            );
+            self.drop_peer(
+                core,
+                poll,
+                LostPeerReason::Flooding,
+                DisconnectReason::Flooding,
+            );
+            return false;
+        }
+
+        debug!(
+            "{:?} - Throttling {:?} for {:?}",
+            self.our_id, self.their_id, wait
+        );
+        let _ = self.event_tx.send(Event::PeerThrottled(self.their_id));
+        let penalty = self.scoring.throttled_penalty;
+        if !self.adjust_score(core, poll, -penalty) {
+            return false;
+        }
+        let was_reading = self.is_reading();
+        let timeout = core.set_timeout(wait, CoreTimer::new(self.token, INBOUND_LIMIT_TIMER_ID));
+        if let Some(ref mut limit) = self.inbound_limit {
+            limit.timeout = Some(timeout);
+        }
+        self.update_read_interest(core, poll, was_reading);
+        false
+    }
+
+    /// Stops reading from the socket until `resume_recv` is called. The peer isn't checked for
+    /// inactivity meanwhile, since we wouldn't notice its heartbeats.
+    pub fn pause_recv(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let was_reading = self.is_reading();
+        self.recv_paused = true;
+        self.update_read_interest(core, poll, was_reading);
+    }
+
+    /// Undoes `pause_recv` and reads what arrived meanwhile.
+    pub fn resume_recv(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let was_reading = self.is_reading();
+        self.recv_paused = false;
+        self.update_read_interest(core, poll, was_reading);
+    }
+
+    /// We don't read while paused by the user or throttled by the inbound limits.
+    fn is_reading(&self) -> bool {
+        !self.recv_paused
+            && self
+                .inbound_limit
+                .as_ref()
+                .map_or(true, |limit| limit.timeout.is_none())
+    }
+
+    fn update_read_interest(&mut self, core: &mut EventLoopCore, poll: &Poll, was_reading: bool) {
+        let reading = self.is_reading();
+        if reading == was_reading || self.suspended.is_some() {
+            // The read interest of a new socket is set once it's taken over.
+            return;
+        }
+        let kind = if reading {
+            Ready::readable() | Ready::writable()
+        } else {
+            Ready::writable()
+        };
+        if let Err(e) = poll.reregister(&self.socket, self.token, kind, PollOpt::edge()) {
+            debug!(
+                "{:?} - Failed to change read interest: {:?}",
+                self.our_id, e
+            );
+            self.lost_reason = LostPeerReason::IoError(e.kind());
+            return self.terminate(core, poll);
+        }
+        if reading {
+            self.reset_receive_heartbeat(core, poll);
+            // Data which arrived meanwhile won't trigger another readable event.
+            if core.get_state(self.token).is_some() {
+                self.read(core, poll);
+            }
+        } else {
+            self.heartbeat.pause_receive(core);
+        }
+    }
+
+    fn is_too_large(&self, message: &Message<UID>) -> bool {
+        let len = match *message {
+            Message::CompressedData(len, _) => len as usize,
+            Message::RelayData(_, _, ref sealed) | Message::RelayedData(_, ref sealed) => {
+                sealed.len().saturating_sub(SEAL_OVERHEAD)
+            }
+            ref message => match message.payload() {
+                Some(payload) => payload.len(),
+                None => return false,
+            },
+        };
+        self.max_msg_size.map_or(false, |max| len > max)
+    }
+
+    /// Compresses `Data` over our threshold if the peer accepts compressed data and compressing
+    /// does shrink it.
+    fn compress(&mut self, msg: Message<UID>) -> Message<UID> {
+        let threshold = match self.compression_threshold {
+            Some(threshold) if self.peer_accepts_compression => threshold,
+            _ => return msg,
+        };
+        match msg {
+            Message::Data(data) if data.len() > threshold => match lz4_compress(&data) {
+                Some(compressed) => {
+                    self.stats.bytes_compressed += data.len() as u64;
+                    self.stats.compressed_size += compressed.len() as u64;
+                    Message::CompressedData(data.len() as u32, compressed)
+                }
+                None => Message::Data(data),
+            },
+            msg => msg,
+        }
+    }
+
+    #[cfg(not(test))]
+    /// Helper function that returns a socket address of the connection
+    pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
+        use crate::main::CrustError;
+        self.socket.peer_addr().map_err(CrustError::SocketError)
+    }
+
+    #[cfg(test)]
+    // TODO(nbaksalyar) find a better way to mock connection IPs
+    pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
+        use std::str::FromStr;
+        Ok(unwrap!(FromStr::from_str("192.168.0.1:0")))
+    }
+
+    pub fn peer_kind(&self) -> CrustUser {
+        self.their_role
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    pub fn set_user_data(&mut self, data: Box<Any>) {
+        self.user_data = Some(data);
+    }
+
+    pub fn user_data(&self) -> Option<&Any> {
+        self.user_data.as_ref().map(|data| &**data)
+    }
+
+    /// Returns whether the peer wasn't in the group yet.
+    pub fn add_to_group(&mut self, label: String) -> bool {
+        self.groups.insert(label)
+    }
+
+    /// Returns whether the peer was in the group.
+    pub fn remove_from_group(&mut self, label: &str) -> bool {
+        self.groups.remove(label)
+    }
+
+    pub fn is_in_group(&self, label: &str) -> bool {
+        self.groups.contains(label)
+    }
+
+    pub fn score(&self) -> i32 {
+        self.score
+    }
+
+    /// Adds `delta` to the peer's score. If that drops it to the ban threshold, the peer is
+    /// blacklisted and the connection is terminated. Returns whether the connection is still up.
+    pub fn adjust_score(&mut self, core: &mut EventLoopCore, poll: &Poll, delta: i32) -> bool {
+        self.score = self.score.saturating_add(delta);
+        match self.scoring.ban_threshold {
+            Some(threshold) if self.score <= threshold => (),
+            _ => return true,
+        }
+
+        debug!(
+            "{:?} - Banning {:?} for its score of {}",
+            self.our_id, self.their_id, self.score
+        );
+        let ban_duration = Duration::from_secs(self.scoring.ban_duration_sec);
+        self.blacklist
+            .insert_peer(self.their_id, SystemTime::now() + ban_duration);
+        self.drop_peer(
+            core,
+            poll,
+            LostPeerReason::LowScore,
+            DisconnectReason::LowScore,
+        );
+        false
+    }
+
+    /// Drops the connection to make room for a new one under the connection limits.
+    pub fn evict(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.drop_peer(
+            core,
+            poll,
+            LostPeerReason::ConnectionLimit,
+            DisconnectReason::ConnectionLimit,
+        );
+    }
+
+    /// Terminates the connection if its socket is no longer connected, even though reading or
+    /// writing hasn't failed yet. Returns whether the connection is still up.
+    pub fn check_alive(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        if self.suspended.is_some() {
+            return true;
+        }
+        match self.socket.peer_addr() {
+            Ok(_) => true,
+            Err(e) => {
+                debug!(
+                    "{:?} - Connection to {:?} is dead: {:?}",
+                    self.our_id, self.their_id, e
+                );
+                self.lose_socket(core, poll, lost_peer_reason(&e));
+                core.get_state(self.token).is_some()
+            }
+        }
+    }
+
+    /// Drops the connection because the peer's IP is no longer whitelisted.
+    pub fn drop_not_whitelisted(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.drop_peer(
+            core,
+            poll,
+            LostPeerReason::NotWhitelisted,
+            DisconnectReason::NotWhitelisted,
+        );
+    }
+
+    /// Drops the connection because we blacklisted the peer.
+    pub fn drop_blacklisted(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.drop_peer(
+            core,
+            poll,
+            LostPeerReason::Evicted,
+            DisconnectReason::Blacklisted,
+        );
+    }
+
+    /// Tells the peer why we drop it before closing the connection. If that can't be sent right
+    /// away, nothing else is read from the peer until the connection is closed.
+    fn drop_peer(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        lost_reason: LostPeerReason,
+        reason: DisconnectReason,
+    ) {
+        self.lost_reason = lost_reason;
+        if self.suspended.is_some() {
+            return self.terminate(core, poll);
+        }
+        if let Some(Closing::AwaitingGoodbye(ref timeout)) = self.closing {
+            let _ = core.cancel_timeout(timeout);
+        }
+        match self.socket.write(Some((Message::Disconnect(reason), 0))) {
+            Ok(false) => {
+                let timeout = core.set_timeout(
+                    Duration::from_secs(GOODBYE_TIMEOUT_SEC),
+                    CoreTimer::new(self.token, GOODBYE_TIMER_ID),
+                );
+                self.closing = Some(Closing::Dropping(timeout));
+                let was_reading = self.is_reading();
+                self.recv_paused = true;
+                self.update_read_interest(core, poll, was_reading);
+            }
+            Ok(true) | Err(_) => self.terminate(core, poll),
+        }
+    }
+
+    /// Waits for the session to be resumed on a new socket if there is one, or terminates the
+    /// connection.
+    fn lose_socket(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: LostPeerReason) {
+        if self.suspended.is_some() {
+            return;
+        }
+        if self.closing.is_some() {
+            return self.terminate(core, poll);
+        }
+        self.lost_reason = reason;
+        let timeout = match (self.session.as_ref(), self.session_resume_timeout) {
+            (Some(_), Some(timeout)) => timeout,
+            _ => return self.terminate(core, poll),
+        };
+
+        debug!(
+            "{:?} - Socket to {:?} broke, waiting for the session to be resumed.",
+            self.our_id, self.their_id
+        );
+        // Whatever was still in flight on the socket is lost.
+        let _ = poll.deregister(&self.socket);
+        self.socket = Default::default();
+        self.socket_backlogged = false;
+        self.heartbeat.terminate(core);
+        let deadline = core.set_timeout(timeout, CoreTimer::new(self.token, SESSION_TIMER_ID));
+        self.suspended = Some(Suspended {
+            deadline,
+            redial_timeout: None,
+            attempt: None,
+        });
+        self.redial(core, poll);
+    }
+
+    #[cfg(test)]
+    pub fn break_socket(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.lose_socket(
+            core,
+            poll,
+            LostPeerReason::IoError(ErrorKind::ConnectionReset),
+        );
+    }
+
+    /// Dials the peer again, giving up on the previous attempt, if it's up to us to resume the
+    /// session.
+    fn redial(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let (token, our_id) = (self.token, self.our_id);
+        let (session, suspended) = match (self.session.as_ref(), self.suspended.as_mut()) {
+            (Some(session), Some(suspended)) => (session, suspended),
+            _ => return,
+        };
+        let redial = match session.redial {
+            Some(ref redial) => redial,
+            None => return,
+        };
+        if let Some(attempt) = suspended.attempt.take() {
+            if let Some(state) = core.get_state(attempt) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+        match ResumeSession::<UID>::start(core, poll, token, our_id, session, redial) {
+            Ok(attempt) => suspended.attempt = Some(attempt),
+            Err(e) => debug!("{:?} - Failed to dial {}: {:?}", our_id, redial.addr, e),
+        }
+        suspended.redial_timeout = Some(core.set_timeout(
+            Duration::from_millis(REDIAL_PERIOD_MS),
+            CoreTimer::new(token, REDIAL_TIMER_ID),
+        ));
+    }
+
+    /// Carries on over `socket`, on which the peer agreed to resume the session, see
+    /// `ResumeSession`.
+    pub fn resume(&mut self, core: &mut EventLoopCore, poll: &Poll, socket: TcpSock) {
+        match self.suspended.take() {
+            Some(suspended) => suspended.cancel(core, poll),
+            None => return,
+        }
+        self.take_over(core, poll, socket, false);
+    }
+
+    /// Carries on over `socket`, on which the peer asked to resume the session with the given id.
+    /// The peer may do so before we noticed that the old socket broke. Returns whether the
+    /// session is the peer's, otherwise the socket is dropped.
+    pub fn accept_resumption(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        session_id: u64,
+        their_pk: &PublicEncryptKey,
+        mut socket: TcpSock,
+    ) -> bool {
+        match self.session {
+            Some(ref session)
+                if session.redial.is_none()
+                    && session.id == session_id
+                    && session.their_pk == *their_pk => {}
+            _ => return false,
+        }
+        if self.closing.is_some() {
+            return false;
+        }
+        // Has to come first, the peer hands the socket over to its connection once it's read it.
+        let backlogged = match socket.write(Some((Message::<UID>::SessionResumed, 0))) {
+            Ok(done) => !done,
+            Err(e) => {
+                debug!("{:?} - Failed to resume the session: {:?}", self.our_id, e);
+                return false;
+            }
+        };
+        if let Some(suspended) = self.suspended.take() {
+            suspended.cancel(core, poll);
+        }
+        let _ = poll.deregister(&self.socket);
+        self.take_over(core, poll, socket, backlogged);
+        true
+    }
+
+    /// Makes `socket` the connection's socket and sends and reads what's waiting. `backlogged`
+    /// tells whether the socket still holds data it couldn't write yet.
+    fn take_over(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        mut socket: TcpSock,
+        backlogged: bool,
+    ) {
+        if let Some(max_msg_size) = self.max_msg_size {
+            socket.set_max_payload_size(max_msg_size.saturating_add(FRAME_OVERHEAD));
+        }
+        let kind = if self.is_reading() {
+            Ready::readable() | Ready::writable()
+        } else {
+            Ready::writable()
+        };
+        if let Err(e) = poll.register(&socket, self.token, kind, PollOpt::edge()) {
+            debug!("{:?} - Failed to register socket: {:?}", self.our_id, e);
+            self.lost_reason = LostPeerReason::IoError(e.kind());
+            return self.terminate(core, poll);
+        }
+        debug!(
+            "{:?} - Resumed the session with {:?}.",
+            self.our_id, self.their_id
+        );
+        self.socket = socket;
+        self.socket_backlogged = backlogged;
+        self.reset_send_heartbeat(core, poll);
+        if self.is_reading() {
+            self.reset_receive_heartbeat(core, poll);
+        }
+        self.flush_send_queue(core, poll);
+        if core.get_state(self.token).is_some() && self.is_reading() {
+            self.read(core, poll);
+        }
+    }
+
+    fn write(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Option<(Message<UID>, Priority)>,
+    ) {
+        if self.suspended.is_some() {
+            // Data waits in the send queue meanwhile, so this can only be a control message.
+            return;
+        }
+        if let Some(data) = msg.as_ref().and_then(|&(ref msg, _)| msg.payload()) {
+            self.stats.msgs_sent += 1;
+            self.stats.bytes_sent += data.len() as u64;
+        }
+        let msg = msg.map(|(msg, priority)| (self.compress(msg), priority));
+        match self.socket.write(msg) {
+            Ok(done) => self.socket_backlogged = !done,
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                self.lose_socket(core, poll, lost_peer_reason(&e));
+            }
+        }
+    }
+
+    /// Sends data. If it's still queued at `deadline`, it's dropped instead.
+    pub fn send_data(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        data: Bytes,
+        priority: Priority,
+        deadline: Option<Instant>,
+    ) {
+        self.send(
+            core,
+            poll,
+            send_token,
+            Message::Data(data),
+            priority,
+            deadline,
+        );
+    }
+
+    /// Sends data which the peer confirms with `Event::MessageDelivered` once it has read it.
+    pub fn send_with_ack(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        msg_id: u64,
+        data: Vec<u8>,
+        priority: Priority,
+    ) {
+        self.send(
+            core,
+            poll,
+            send_token,
+            Message::AckedData(msg_id, data),
+            priority,
+            None,
+        );
+    }
+
+    /// Sends a request which the peer is expected to answer with a response carrying the same
+    /// `request_id`.
+    pub fn request(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        request_id: u64,
+        data: Vec<u8>,
+        priority: Priority,
+    ) {
+        self.send(
+            core,
+            poll,
+            send_token,
+            Message::Request(request_id, data),
+            priority,
+            None,
+        );
+    }
+
+    /// Answers the peer's request with the given id.
+    pub fn respond(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        request_id: u64,
+        data: Vec<u8>,
+        priority: Priority,
+    ) {
+        self.send(
+            core,
+            poll,
+            send_token,
+            Message::Response(request_id, data),
+            priority,
+            None,
+        );
+    }
+
+    /// Limits how many bytes of data per second we send to this peer. Messages over the limit are
+    /// queued, see `SendQueue`. `None` removes the limit.
+    pub fn set_rate_limit(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        bytes_per_sec: Option<u64>,
+    ) {
+        match bytes_per_sec {
+            Some(bytes_per_sec) => self.send_queue.set_rate(bytes_per_sec, Instant::now()),
+            None => {
+                self.send_queue.bucket = None;
+                self.send_queue.terminate(core);
+            }
+        }
+        self.flush_send_queue(core, poll);
+    }
+
+    fn send(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        send_token: SendToken,
+        msg: Message<UID>,
+        priority: Priority,
+        deadline: Option<Instant>,
+    ) {
+        if self.closing.is_some() {
+            debug!(
+                "{:?} - Connection to {:?} is closing, dropping message.",
+                self.our_id, self.their_id
             );
+            return;
         }
-        let _ = state_mut.event_tx.send(event);
-        state_mut.read(core, poll);
+        let queue_full = self
+            .send_queue_limit
+            .map_or(false, |limit| self.send_queue.len() >= limit);
+        let priority_full = self
+            .priority_queue_limit
+            .map_or(false, |limit| self.send_queue.len_of(priority) >= limit);
+        if queue_full || priority_full {
+            debug!(
+                "{:?} - Send queue to {:?} is full, dropping message.",
+                self.our_id, self.their_id
+            );
+            if let Some(data) = msg.into_payload() {
+                let _ = self.event_tx.send(Event::WriteBlocked(self.their_id, data));
+            }
+            return;
+        }
+        self.send_queue.push(Queued {
+            send_token,
+            msg,
+            priority,
+            deadline,
+        });
+        self.flush_send_queue(core, poll);
     }
 
-    fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+    /// Writes as many queued messages as the socket, the rate limit and the service's bandwidth
+    /// budget allow and schedules writing the rest.
+    fn flush_send_queue(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let (token, their_role) = (self.token, self.their_role);
         loop {
-            match self.socket.read::<Message<UID>>() {
-                Ok(Some(Message::Data(data))) => {
-                    let _ =
-                        self.event_tx
-                            .send(Event::NewMessage(self.their_id, self.their_role, data));
-                    self.reset_receive_heartbeat(core, poll);
+            if self.socket_backlogged || self.suspended.is_some() {
+                // Resumed once the socket is writable again, or the session is resumed.
+                return;
+            }
+            self.pump_streams();
+            self.pump_channels();
+            let (msg, priority) = {
+                let queue = &mut self.send_queue;
+                if queue.timeout.is_some() {
+                    return;
                 }
-                Ok(Some(Message::Heartbeat)) => {
-                    self.reset_receive_heartbeat(core, poll);
+                let now = Instant::now();
+                let (len, priority, expired) = match queue.front() {
+                    Some(queued) => (
+                        queued.msg.payload().map_or(0, |data| data.len()),
+                        queued.priority,
+                        queued.deadline.map_or(false, |deadline| deadline <= now),
+                    ),
+                    None => {
+                        let _ = BandwidthBudget::with(core, |budget| budget.forget(token));
+                        return;
+                    }
+                };
+                if expired {
+                    let _ = queue.drop_front();
+                    self.stats.expired_msgs += 1;
+                    debug!(
+                        "{:?} - Dropped expired message to {:?}, {} so far.",
+                        self.our_id, self.their_id, self.stats.expired_msgs
+                    );
+                    continue;
                 }
-                Ok(Some(message)) => {
-                    debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
-                    self.reset_receive_heartbeat(core, poll);
+                let wait = queue
+                    .bucket
+                    .as_mut()
+                    .and_then(|bucket| bucket.wait(now))
+                    .or_else(|| {
+                        BandwidthBudget::with(core, |budget| {
+                            budget.try_consume(token, their_role, priority, len, now)
+                        })
+                        .and_then(|wait| wait)
+                    });
+                if let Some(wait) = wait {
+                    let timer = CoreTimer::new(token, RATE_LIMIT_TIMER_ID);
+                    queue.timeout = Some(core.set_timeout(wait, timer));
+                    return;
                 }
-                Ok(None) => return,
-                Err(e) => {
-                    debug!("{:?} - Failed to read from socket: {:?}", self.our_id, e);
-                    return self.terminate(core, poll);
+                if let Some(ref mut bucket) = queue.bucket {
+                    bucket.consume(len);
                 }
+                let queued = unwrap!(queue.pop_front());
+                (queued.msg, queued.priority)
+            };
+            self.note_stream_progress(&msg);
+            self.write(core, poll, Some((msg, priority)));
+            self.reset_send_heartbeat(core, poll);
+            if core.get_state(self.token).is_none() {
+                // Terminated because the write failed.
+                return;
             }
         }
     }
 
-    #[cfg(not(test))]
-    /// Helper function that returns a socket address of the connection
-    pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
-        use crate::main::CrustError;
-        self.socket.peer_addr().map_err(CrustError::SocketError)
+    /// Measures the round trip time to this peer. The result is sent as `Event::PingResponse`.
+    pub fn ping(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.send_ping(core, poll, true);
     }
 
-    #[cfg(test)]
-    // TODO(nbaksalyar) find a better way to mock connection IPs
-    pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
-        use std::str::FromStr;
-        Ok(unwrap!(FromStr::from_str("192.168.0.1:0")))
+    /// Sends everything still held back by the rate limit, then says goodbye and closes the
+    /// connection once the peer answered, or after a timeout. Data sent after this is dropped.
+    pub fn close_graceful(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if self.closing.is_some() {
+            return;
+        }
+        if self.suspended.is_some() {
+            // There's no socket to send anything on.
+            return self.terminate(core, poll);
+        }
+        // The streams are broken off, what's queued of them is still sent.
+        self.outgoing_streams.clear();
+        for (id, channel) in &mut self.channels {
+            for (send_token, data) in channel.queue.drain(..) {
+                self.send_queue.push(Queued {
+                    send_token,
+                    msg: Message::ChannelData(*id, data),
+                    priority: channel.priority,
+                    deadline: None,
+                });
+            }
+        }
+        self.send_queue.terminate(core);
+        let token = self.token;
+        let _ = BandwidthBudget::with(core, |budget| budget.forget(token));
+        let now = Instant::now();
+        while let Some(queued) = self.send_queue.pop_front() {
+            if queued.deadline.map_or(false, |deadline| deadline <= now) {
+                self.stats.expired_msgs += 1;
+                continue;
+            }
+            self.write(core, poll, Some((queued.msg, queued.priority)));
+            if core.get_state(self.token).is_none() {
+                return;
+            }
+        }
+        let timeout = core.set_timeout(
+            Duration::from_secs(GOODBYE_TIMEOUT_SEC),
+            CoreTimer::new(self.token, GOODBYE_TIMER_ID),
+        );
+        self.closing = Some(Closing::AwaitingGoodbye(timeout));
+        self.write(core, poll, Some((Message::Goodbye, 0)));
     }
 
-    pub fn peer_kind(&self) -> CrustUser {
-        self.their_role
+    fn handle_goodbye(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if let Some(Closing::AwaitingGoodbye(_)) = self.closing {
+            return self.terminate(core, poll);
+        }
+        self.closing = Some(Closing::Flushing);
+        self.lost_reason = LostPeerReason::RemoteClosed;
+        match self.socket.write(Some((Message::Goodbye, 0))) {
+            Ok(false) => (),
+            Ok(true) => self.terminate(core, poll),
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                self.terminate(core, poll);
+            }
+        }
     }
 
-    fn write(
+    /// Smoothed round trip time, estimated from the pings which also serve as heartbeats.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.srtt
+    }
+
+    /// Drops the message with the given token if it's still queued, or breaks off the stream
+    /// with the token. Returns whether there was anything left to drop.
+    pub fn cancel_send(
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
-        msg: Option<(Message<UID>, Priority)>,
-    ) {
-        if let Err(e) = self.socket.write(msg) {
-            debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+        send_token: SendToken,
+    ) -> bool {
+        let mut cancelled = self.send_queue.cancel(send_token) as u64;
+        for channel in self.channels.values_mut() {
+            let queued = channel.queue.len();
+            channel.queue.retain(|&(token, _)| token != send_token);
+            cancelled += (queued - channel.queue.len()) as u64;
+        }
+        self.stats.cancelled_msgs += cancelled;
+        let id = send_token.0 as u64;
+        let stream = self.outgoing_streams.remove(&id);
+        if stream.as_ref().map_or(false, |stream| stream.started) {
+            self.write(core, poll, Some((Message::StreamEnd(id, false), 0)));
+        }
+        stream.is_some() || cancelled > 0
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        let held_by_channels: usize = self
+            .channels
+            .values()
+            .map(|channel| channel.queue.len())
+            .sum();
+        PeerStats {
+            queued_msgs: self.send_queue.len() + held_by_channels,
+            rtt: self.rtt.srtt,
+            uptime: self.started.elapsed(),
+            ..self.stats.clone()
         }
     }
 
+    /// Describes the connection, or returns `None` if the socket is already broken.
+    pub fn connected_peer(&self) -> Option<ConnectedPeer<UID>> {
+        Some(ConnectedPeer {
+            id: self.their_id,
+            addr: self.peer_addr().ok()?,
+            relayed: false,
+            kind: self.their_role,
+            connected_since: self.started,
+            stats: self.stats(),
+        })
+    }
+
+    /// Describes the peers we reach through this connection's peer.
+    pub fn relayed_peers(&self) -> Vec<ConnectedPeer<UID>> {
+        let addr = match self.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return Vec::new(),
+        };
+        self.relay_routes
+            .iter()
+            .map(|(&id, route)| ConnectedPeer {
+                id,
+                addr,
+                relayed: true,
+                kind: route.kind,
+                connected_since: route.since,
+                stats: PeerStats {
+                    uptime: route.since.elapsed(),
+                    ..route.stats.clone()
+                },
+            })
+            .collect()
+    }
+
+    fn send_ping(&mut self, core: &mut EventLoopCore, poll: &Poll, requested: bool) {
+        let nonce = self
+            .rtt
+            .ping(Instant::now(), requested, self.heartbeat.inactivity_timeout);
+        self.write(core, poll, Some((Message::Ping(nonce), 0)));
+    }
+
+    /// Changes how often we send heartbeats to this peer.
+    pub fn set_heartbeat_period(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        period: Duration,
+    ) {
+        self.heartbeat.period = period;
+        self.reset_send_heartbeat(core, poll);
+    }
+
+    /// Changes how long this peer may stay silent before we drop the connection.
+    pub fn set_inactivity_timeout(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        inactivity_timeout: Duration,
+    ) {
+        self.heartbeat.inactivity_timeout = inactivity_timeout;
+        self.reset_receive_heartbeat(core, poll);
+    }
+
     fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         if let Err(e) = self.heartbeat.reset_receive(core) {
             debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
@@ -182,20 +1803,48 @@ impl<UID: Uid> ActiveConnection<UID> {
 impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
     fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
         if kind.is_writable() {
+            match self.closing {
+                // Our answer to the peer's goodbye or the reason we drop it is the last thing we
+                // send.
+                Some(Closing::Flushing) | Some(Closing::Dropping(_)) => {
+                    return match self.socket.write::<Message<UID>>(None) {
+                        Ok(false) => (),
+                        Ok(true) | Err(_) => self.terminate(core, poll),
+                    };
+                }
+                _ => (),
+            }
             self.write(core, poll, None);
+            if core.get_state(self.token).is_some() {
+                self.flush_send_queue(core, poll);
+            }
         }
-        if kind.is_readable() {
+        if kind.is_readable() && self.is_reading() {
             self.read(core, poll);
         }
     }
 
-    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, data: Vec<u8>, priority: Priority) {
-        self.write(core, poll, Some((Message::Data(data), priority)));
-        self.reset_send_heartbeat(core, poll);
-    }
-
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.heartbeat.terminate(core);
+        if let Some(suspended) = self.suspended.take() {
+            suspended.cancel(core, poll);
+        }
+        match self.closing {
+            Some(Closing::AwaitingGoodbye(ref timeout)) | Some(Closing::Dropping(ref timeout)) => {
+                let _ = core.cancel_timeout(timeout);
+            }
+            _ => (),
+        }
+        self.send_queue.terminate(core);
+        let token = self.token;
+        let _ = BandwidthBudget::with(core, |budget| budget.forget(token));
+        if let Some(timeout) = self
+            .inbound_limit
+            .as_mut()
+            .and_then(|limit| limit.timeout.take())
+        {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = poll.deregister(&self.socket);
         let _ = core.remove_state(self.token);
 
@@ -214,17 +1863,74 @@ impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
             );
         }
 
-        let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+        let _ = self
+            .event_tx
+            .send(Event::LostPeer(self.their_id, self.lost_reason));
+        self.event_tx
+            .send_peer_set_change(PeerSetChange::Removed(self.their_id, self.lost_reason));
+        let relayed: Vec<_> = self.relay_routes.drain().map(|(peer, _)| peer).collect();
+        for peer in relayed {
+            self.lose_relayed_peer(peer, LostPeerReason::RelayLost);
+        }
     }
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        if timer_id == GOODBYE_TIMER_ID {
+            debug!(
+                "{:?} - Timed out closing the connection to {:?}.",
+                self.our_id, self.their_id
+            );
+            return self.terminate(core, poll);
+        }
+        if timer_id == RATE_LIMIT_TIMER_ID {
+            self.send_queue.timeout = None;
+            return self.flush_send_queue(core, poll);
+        }
+        if timer_id == SESSION_TIMER_ID {
+            debug!(
+                "{:?} - Timed out resuming the session with {:?}.",
+                self.our_id, self.their_id
+            );
+            return self.terminate(core, poll);
+        }
+        if timer_id == REDIAL_TIMER_ID {
+            return self.redial(core, poll);
+        }
+        if timer_id == INBOUND_LIMIT_TIMER_ID {
+            let was_reading = self.is_reading();
+            if let Some(ref mut limit) = self.inbound_limit {
+                limit.timeout = None;
+            }
+            return self.update_read_interest(core, poll, was_reading);
+        }
+        if self.suspended.is_some() {
+            // The heartbeats start again once the session is resumed.
+            return;
+        }
+
         match self.heartbeat.timeout(core, timer_id) {
-            HeartbeatAction::Send => self.write(core, poll, Some((Message::Heartbeat, 0))),
+            HeartbeatAction::Send => {
+                let max_age = self.heartbeat.inactivity_timeout;
+                // Don't pile up pings if the last one is still unanswered.
+                if self.rtt.awaits_heartbeat_pong(Instant::now(), max_age) {
+                    // While we don't read, the pong may well be waiting in the socket.
+                    if self.is_reading() {
+                        let penalty = self.scoring.unanswered_ping_penalty;
+                        if !self.adjust_score(core, poll, -penalty) {
+                            return;
+                        }
+                    }
+                    self.write(core, poll, Some((Message::Heartbeat, 0)))
+                } else {
+                    self.send_ping(core, poll, false)
+                }
+            }
             HeartbeatAction::Terminate => {
                 debug!(
                     "Dropping connection to {:?} due to peer inactivity",
                     self.their_id
                 );
+                self.lost_reason = LostPeerReason::InactivityTimeout;
                 self.terminate(core, poll);
             }
         }
@@ -235,7 +1941,47 @@ impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
     }
 }
 
+/// A logical channel to the peer, see `ActiveConnection::open_channel`.
+struct Channel {
+    priority: Priority,
+    /// Messages waiting for room in the send queue, see `CHANNEL_QUEUE_DEPTH`.
+    queue: VecDeque<(SendToken, Bytes)>,
+}
+
+/// A connection waiting for its session to be resumed, see `ActiveConnection::lose_socket`.
+struct Suspended {
+    /// When we give up and report the peer lost.
+    deadline: Timeout,
+    /// When we dial the peer again, if it's up to us to resume the session.
+    redial_timeout: Option<Timeout>,
+    /// Our attempt to resume the session, see `ResumeSession`.
+    attempt: Option<Token>,
+}
+
+impl Suspended {
+    fn cancel(self, core: &mut EventLoopCore, poll: &Poll) {
+        let _ = core.cancel_timeout(&self.deadline);
+        if let Some(timeout) = self.redial_timeout {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(state) = self.attempt.and_then(|attempt| core.get_state(attempt)) {
+            state.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+/// A peer reached through the connection's peer, see `ActiveConnection::add_relay_route`.
+struct RelayRoute {
+    shared_key: SharedSecretKey,
+    kind: CrustUser,
+    since: Instant,
+    /// The RTT and the queue depth are left out, they are the relay's.
+    stats: PeerStats,
+}
+
 struct Heartbeat {
+    period: Duration,
+    inactivity_timeout: Duration,
     recv_timeout: Timeout,
     recv_timer: CoreTimer,
     send_timeout: Timeout,
@@ -243,15 +1989,21 @@ struct Heartbeat {
 }
 
 impl Heartbeat {
-    fn try_new(core: &mut EventLoopCore, state_id: Token) -> crate::Res<Self> {
+    fn try_new(
+        core: &mut EventLoopCore,
+        state_id: Token,
+        period: Duration,
+        inactivity_timeout: Duration,
+    ) -> crate::Res<Self> {
         let recv_timer = CoreTimer::new(state_id, 0);
-        let recv_timeout =
-            core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), recv_timer);
+        let recv_timeout = core.set_timeout(inactivity_timeout, recv_timer);
 
         let send_timer = CoreTimer::new(state_id, 1);
-        let send_timeout = core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), send_timer);
+        let send_timeout = core.set_timeout(period, send_timer);
 
         Ok(Heartbeat {
+            period,
+            inactivity_timeout,
             recv_timeout,
             recv_timer,
             send_timeout,
@@ -263,25 +2015,24 @@ impl Heartbeat {
         if timer_id == self.recv_timer.timer_id {
             HeartbeatAction::Terminate
         } else {
-            self.send_timeout =
-                core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer);
+            self.send_timeout = core.set_timeout(self.period, self.send_timer);
             HeartbeatAction::Send
         }
     }
 
     fn reset_receive(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.recv_timeout);
-        self.recv_timeout = core.set_timeout(
-            Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-            self.recv_timer,
-        );
+        self.recv_timeout = core.set_timeout(self.inactivity_timeout, self.recv_timer);
         Ok(())
     }
 
+    fn pause_receive(&mut self, core: &mut EventLoopCore) {
+        let _ = core.cancel_timeout(&self.recv_timeout);
+    }
+
     fn reset_send(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.send_timeout);
-        self.send_timeout =
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer);
+        self.send_timeout = core.set_timeout(self.period, self.send_timer);
         Ok(())
     }
 
@@ -295,3 +2046,441 @@ enum HeartbeatAction {
     Send,
     Terminate,
 }
+
+/// Passes what the application did with an `IncomingStream` on to its connection, see
+/// `StreamSignal`.
+fn signal_stream<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    id: u64,
+    taken: Option<u32>,
+) {
+    if let Some(state) = core.get_state(token) {
+        let mut state = state.borrow_mut();
+        if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            match taken {
+                Some(credit) => ac.grant_stream_credit(core, poll, id, credit),
+                None => ac.cancel_incoming_stream(core, poll, id),
+            }
+        }
+    }
+}
+
+fn lost_peer_reason(e: &SocketError) -> LostPeerReason {
+    match *e {
+        SocketError::ZeroByteRead => LostPeerReason::RemoteClosed,
+        SocketError::Io(ref e) => LostPeerReason::IoError(e.kind()),
+        _ => LostPeerReason::IoError(ErrorKind::InvalidData),
+    }
+}
+
+enum Closing {
+    /// We said goodbye first and wait for the peer's answer until the timeout.
+    AwaitingGoodbye(Timeout),
+    /// We answered the peer's goodbye and close the connection once that's sent.
+    Flushing,
+    /// We told the peer why we drop it and close the connection once that's sent or the timeout
+    /// expires.
+    Dropping(Timeout),
+}
+
+/// Keeps track of our pings and estimates the round trip time from the pongs, the same way TCP
+/// smoothes its RTT samples.
+#[derive(Default)]
+struct RttEstimator {
+    next_nonce: u64,
+    /// Pings awaiting a pong: when they were sent and whether they were requested via
+    /// `Service::ping`.
+    pending: HashMap<u64, (Instant, bool)>,
+    srtt: Option<Duration>,
+}
+
+impl RttEstimator {
+    /// Returns the nonce for a new ping. Pings older than `max_age` are forgotten.
+    fn ping(&mut self, now: Instant, requested: bool, max_age: Duration) -> u64 {
+        self.forget_old_pings(now, max_age);
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        let _ = self.pending.insert(nonce, (now, requested));
+        nonce
+    }
+
+    /// Whether a ping sent as heartbeat, and younger than `max_age`, is still unanswered.
+    fn awaits_heartbeat_pong(&mut self, now: Instant, max_age: Duration) -> bool {
+        self.forget_old_pings(now, max_age);
+        self.pending.values().any(|&(_, requested)| !requested)
+    }
+
+    fn forget_old_pings(&mut self, now: Instant, max_age: Duration) {
+        self.pending
+            .retain(|_, &mut (sent, _)| now.duration_since(sent) < max_age);
+    }
+
+    /// Returns the round trip time of the ping with the given nonce and whether it was requested.
+    fn pong(&mut self, nonce: u64, now: Instant) -> Option<(Duration, bool)> {
+        let (sent, requested) = self.pending.remove(&nonce)?;
+        let sample = now.duration_since(sent);
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+        Some((sample, requested))
+    }
+}
+
+/// Counts what the peer sent us within the current one second window.
+struct InboundLimit {
+    msgs_per_sec: Option<u64>,
+    bytes_per_sec: Option<u64>,
+    window_start: Instant,
+    msgs: u64,
+    bytes: u64,
+    /// Whether the peer went over the limits in the current window.
+    over: bool,
+    /// Consecutive windows in which the peer went over the limits.
+    strikes: u32,
+    /// Set while reading is throttled until the window ends.
+    timeout: Option<Timeout>,
+}
+
+impl InboundLimit {
+    fn new(msgs_per_sec: Option<u64>, bytes_per_sec: Option<u64>, now: Instant) -> Self {
+        InboundLimit {
+            msgs_per_sec,
+            bytes_per_sec,
+            window_start: now,
+            msgs: 0,
+            bytes: 0,
+            over: false,
+            strikes: 0,
+            timeout: None,
+        }
+    }
+
+    /// Returns `None` if a message of `len` bytes is within the limits, otherwise how long to
+    /// stop reading until the window ends.
+    fn record(&mut self, now: Instant, len: usize) -> Option<Duration> {
+        let window = Duration::from_secs(1);
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= window {
+            // A window within the limits, or without any message at all, breaks the streak.
+            if !self.over || elapsed >= window * 2 {
+                self.strikes = 0;
+            }
+            self.window_start = now;
+            self.msgs = 0;
+            self.bytes = 0;
+            self.over = false;
+        }
+
+        self.msgs += 1;
+        self.bytes += len as u64;
+        let over = self.msgs_per_sec.map_or(false, |max| self.msgs > max)
+            || self.bytes_per_sec.map_or(false, |max| self.bytes > max);
+        if !over {
+            return None;
+        }
+        if !self.over {
+            self.over = true;
+            self.strikes += 1;
+        }
+        Some(self.window_start + window - now)
+    }
+}
+
+/// Messages held back by our rate limit or the service's bandwidth budget.
+/// Messages crust holds back, because of the rate limit, the service's bandwidth budget or
+/// because the socket hasn't written earlier messages yet. Messages of the same priority go in
+/// the order they were sent. Priorities take turns in rounds, in which each one with queued
+/// messages sends up to its `priority_weight` of them, the most urgent ones first.
+struct SendQueue<UID> {
+    /// `None` unless the peer has a rate limit.
+    bucket: Option<TokenBucket>,
+    /// Queued messages by priority, with how many more of them may go in the current round.
+    classes: BTreeMap<Priority, (VecDeque<Queued<UID>>, u32)>,
+    /// Set while waiting for the allowance to recover.
+    timeout: Option<Timeout>,
+}
+
+struct Queued<UID> {
+    send_token: SendToken,
+    msg: Message<UID>,
+    priority: Priority,
+    deadline: Option<Instant>,
+}
+
+impl<UID> SendQueue<UID> {
+    fn new() -> Self {
+        SendQueue {
+            bucket: None,
+            classes: BTreeMap::new(),
+            timeout: None,
+        }
+    }
+
+    fn set_rate(&mut self, bytes_per_sec: u64, now: Instant) {
+        match self.bucket {
+            Some(ref mut bucket) => bucket.set_rate(bytes_per_sec),
+            None => self.bucket = Some(TokenBucket::new(bytes_per_sec, now)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.classes.values().map(|&(ref msgs, _)| msgs.len()).sum()
+    }
+
+    fn len_of(&self, priority: Priority) -> usize {
+        self.classes
+            .get(&priority)
+            .map_or(0, |&(ref msgs, _)| msgs.len())
+    }
+
+    fn push(&mut self, queued: Queued<UID>) {
+        let priority = queued.priority;
+        self.classes
+            .entry(priority)
+            .or_insert_with(|| (VecDeque::new(), priority_weight(priority)))
+            .0
+            .push_back(queued);
+    }
+
+    /// Returns the message which goes next.
+    fn front(&mut self) -> Option<&Queued<UID>> {
+        let priority = self.next_turn()?;
+        self.classes[&priority].0.front()
+    }
+
+    /// Removes the message which goes next, using up one of its priority's turns.
+    fn pop_front(&mut self) -> Option<Queued<UID>> {
+        let priority = self.next_turn()?;
+        let class = self.classes.get_mut(&priority)?;
+        class.1 -= 1;
+        class.0.pop_front()
+    }
+
+    /// Removes the message which goes next without using up a turn, e.g. because it expired.
+    fn drop_front(&mut self) -> Option<Queued<UID>> {
+        let priority = self.next_turn()?;
+        self.classes.get_mut(&priority)?.0.pop_front()
+    }
+
+    /// Returns how many queued messages `f` holds for.
+    fn count<F>(&self, f: F) -> usize
+    where
+        F: Fn(&Queued<UID>) -> bool,
+    {
+        self.classes
+            .values()
+            .map(|&(ref msgs, _)| msgs.iter().filter(|queued| f(queued)).count())
+            .sum()
+    }
+
+    /// Removes the messages with the given token and returns how many there were.
+    fn cancel(&mut self, send_token: SendToken) -> usize {
+        let queued = self.len();
+        for &mut (ref mut msgs, _) in self.classes.values_mut() {
+            msgs.retain(|queued| queued.send_token != send_token);
+        }
+        queued - self.len()
+    }
+
+    /// Returns the most urgent priority with queued messages and turns left, starting the next
+    /// round if all of them used up their turns.
+    fn next_turn(&mut self) -> Option<Priority> {
+        let with_turn = |classes: &BTreeMap<Priority, (VecDeque<Queued<UID>>, u32)>| {
+            classes
+                .iter()
+                .find(|&(_, &(ref msgs, turns))| !msgs.is_empty() && turns > 0)
+                .map(|(&priority, _)| priority)
+        };
+        if let Some(priority) = with_turn(&self.classes) {
+            return Some(priority);
+        }
+        let idle: Vec<_> = self
+            .classes
+            .iter()
+            .filter(|&(_, &(ref msgs, _))| msgs.is_empty())
+            .map(|(&priority, _)| priority)
+            .collect();
+        for priority in idle {
+            let _ = self.classes.remove(&priority);
+        }
+        for (&priority, &mut (_, ref mut turns)) in &mut self.classes {
+            *turns = priority_weight(priority);
+        }
+        with_turn(&self.classes)
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore) {
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+    }
+}
+
+/// Returns `None` if the data is too big or doesn't shrink.
+fn lz4_compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() > i32::max_value() as usize {
+        return None;
+    }
+    match lz4::block::compress(data, None, false) {
+        Ok(ref compressed) if compressed.len() >= data.len() => None,
+        Ok(compressed) => Some(compressed),
+        Err(e) => {
+            debug!("Failed to compress data: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Returns `None` if the data doesn't decompress to `len` bytes.
+fn lz4_decompress(compressed: &[u8], len: u32) -> Option<Vec<u8>> {
+    let len = len as usize;
+    if len > i32::max_value() as usize || len > compressed.len().saturating_mul(MAX_LZ4_RATIO) {
+        return None;
+    }
+    match lz4::block::decompress(compressed, Some(len as i32)) {
+        Ok(ref data) if data.len() != len => None,
+        Ok(data) => Some(data),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lz4_round_trip() {
+        let data = vec![7; 10_000];
+        let compressed = unwrap!(lz4_compress(&data));
+        assert!(compressed.len() < data.len());
+        assert_eq!(lz4_decompress(&compressed, data.len() as u32), Some(data));
+
+        // Lengths which LZ4 can't produce from the compressed data are refused up front.
+        assert_eq!(lz4_decompress(&compressed, u32::max_value()), None);
+        assert_eq!(lz4_decompress(&compressed, 100), None);
+    }
+
+    #[test]
+    fn lz4_skips_incompressible_data() {
+        let data: Vec<u8> = (0..1_000).map(|_| rand::random()).collect();
+        assert_eq!(lz4_compress(&data), None);
+    }
+
+    fn queued(priority: Priority, id: usize) -> Queued<u64> {
+        Queued {
+            send_token: SendToken(id),
+            msg: Message::Data(Bytes::new()),
+            priority,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn send_queue_lets_every_priority_take_turns() {
+        let mut queue = SendQueue::new();
+        for id in 0..20 {
+            queue.push(queued(0, id));
+        }
+        for id in 20..22 {
+            queue.push(queued(7, id));
+        }
+
+        let order: Vec<_> = (0..22)
+            .map(|_| unwrap!(queue.pop_front()).send_token.0)
+            .collect();
+        let mut expected: Vec<usize> = (0..16).collect();
+        expected.push(20);
+        expected.extend(16..20);
+        expected.push(21);
+        assert_eq!(order, expected);
+        assert!(queue.pop_front().is_none());
+    }
+
+    #[test]
+    fn send_queue_counts_and_cancels_per_token() {
+        let mut queue = SendQueue::new();
+        queue.push(queued(3, 0));
+        queue.push(queued(3, 1));
+        queue.push(queued(5, 1));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.len_of(3), 2);
+        assert_eq!(queue.len_of(4), 0);
+
+        assert_eq!(queue.cancel(SendToken(1)), 2);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(unwrap!(queue.drop_front()).send_token, SendToken(0));
+        assert!(queue.front().is_none());
+    }
+
+    #[test]
+    fn rtt_estimator_smoothes_samples() {
+        let start = Instant::now();
+        let max_age = Duration::from_secs(10);
+        let mut rtt = RttEstimator::default();
+
+        let nonce = rtt.ping(start, true, max_age);
+        let sample = Duration::from_millis(80);
+        assert_eq!(rtt.pong(nonce, start + sample), Some((sample, true)));
+        assert_eq!(rtt.srtt, Some(sample));
+
+        let nonce = rtt.ping(start, false, max_age);
+        let sample = Duration::from_millis(160);
+        assert_eq!(rtt.pong(nonce, start + sample), Some((sample, false)));
+        assert_eq!(rtt.srtt, Some(Duration::from_millis(90)));
+
+        assert_eq!(rtt.pong(nonce, start + sample), None);
+    }
+
+    #[test]
+    fn rtt_estimator_forgets_old_pings() {
+        let start = Instant::now();
+        let max_age = Duration::from_secs(10);
+        let mut rtt = RttEstimator::default();
+
+        let old = rtt.ping(start, false, max_age);
+        assert!(rtt.awaits_heartbeat_pong(start, max_age));
+        assert!(!rtt.awaits_heartbeat_pong(start + max_age, max_age));
+        assert_eq!(rtt.pong(old, start + max_age), None);
+    }
+
+    #[test]
+    fn inbound_limit_counts_messages_and_bytes() {
+        let start = Instant::now();
+        let mut limit = InboundLimit::new(Some(2), Some(100), start);
+
+        assert_eq!(limit.record(start, 10), None);
+        assert_eq!(limit.record(start, 10), None);
+        assert_eq!(limit.record(start, 10), Some(Duration::from_secs(1)));
+        assert_eq!(limit.strikes, 1);
+
+        let next = start + Duration::from_secs(1);
+        assert_eq!(limit.record(next, 101), Some(Duration::from_secs(1)));
+        assert_eq!(limit.strikes, 2);
+    }
+
+    #[test]
+    fn inbound_limit_forgives_well_behaved_windows() {
+        let start = Instant::now();
+        let mut limit = InboundLimit::new(Some(1), None, start);
+
+        assert_eq!(limit.record(start, 0), None);
+        assert!(limit.record(start, 0).is_some());
+        assert_eq!(limit.strikes, 1);
+
+        // An idle window in between.
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limit.record(later, 0), None);
+        assert!(limit.record(later, 0).is_some());
+        assert_eq!(limit.strikes, 1);
+
+        // A window within the limits.
+        let later = later + Duration::from_secs(1);
+        assert_eq!(limit.record(later, 0), None);
+        let later = later + Duration::from_secs(1);
+        assert_eq!(limit.record(later, 0), None);
+        assert_eq!(limit.strikes, 0);
+    }
+}
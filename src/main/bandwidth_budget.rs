@@ -0,0 +1,391 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CrustUser, State};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{EventLoopCore, EventToken};
+use mio::{Poll, Token};
+use socket_collection::Priority;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How long a connection waits before asking again when what's left of the budget is kept for a
+/// connection with a more urgent message.
+const YIELD_MS: u64 = 10;
+
+/// How many messages of the given priority may go per round while messages of several
+/// priorities wait. Every priority gets at least one, so none is starved.
+pub fn priority_weight(priority: Priority) -> u32 {
+    16 >> cmp::min(priority, 4)
+}
+
+/// Service-wide limits of the payload bytes per second we send, see
+/// `Config::upload_bytes_per_sec`. Connections ask it before writing each message they queued
+/// and wait if they are refused. While the budget is short, priorities take turns in rounds, in
+/// which each one gets up to its `priority_weight` of messages and the most urgent ones go first.
+pub struct BandwidthBudget {
+    token: Token,
+    total: Option<TokenBucket>,
+    nodes: Option<TokenBucket>,
+    clients: Option<TokenBucket>,
+    /// Limits the data we pass on between our peers, see `Config::relay_bytes_per_sec`.
+    relayed: Option<TokenBucket>,
+    /// Connections which were refused, with the priority of their next message and their peer's
+    /// kind.
+    waiting: HashMap<Token, (Priority, CrustUser)>,
+    /// Messages let through in the current round, by priority.
+    served: HashMap<Priority, u32>,
+}
+
+impl BandwidthBudget {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        bytes_per_sec: Option<u64>,
+        node_bytes_per_sec: Option<u64>,
+        client_bytes_per_sec: Option<u64>,
+        relay_bytes_per_sec: Option<u64>,
+    ) {
+        trace!("Entered state BandwidthBudget");
+
+        let now = Instant::now();
+        let bucket =
+            |bytes_per_sec: Option<u64>| bytes_per_sec.map(|rate| TokenBucket::new(rate, now));
+        let state = Rc::new(RefCell::new(BandwidthBudget {
+            token,
+            total: bucket(bytes_per_sec),
+            nodes: bucket(node_bytes_per_sec),
+            clients: bucket(client_bytes_per_sec),
+            relayed: bucket(relay_bytes_per_sec),
+            waiting: HashMap::new(),
+            served: HashMap::new(),
+        }));
+        let _ = core.insert_state(token, state);
+    }
+
+    /// Applies `f` to the service's budget. Returns `None` if the service has none.
+    pub fn with<F, R>(core: &EventLoopCore, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut BandwidthBudget) -> R,
+    {
+        let state = core.get_state(EventToken::BandwidthBudget.into())?;
+        let mut state = state.borrow_mut();
+        state.as_any().downcast_mut::<BandwidthBudget>().map(f)
+    }
+
+    /// Returns `None` if the connection with the given token may send `len` bytes to a peer of
+    /// the given kind now, otherwise how long to wait before asking again.
+    pub fn try_consume(
+        &mut self,
+        conn: Token,
+        kind: CrustUser,
+        priority: Priority,
+        len: usize,
+        now: Instant,
+    ) -> Option<Duration> {
+        let shares_total = self.total.is_some();
+        let our_turn = self.has_turn(priority);
+        let other_turn_waiting =
+            self.waiting
+                .iter()
+                .any(|(&token, &(other_priority, other_kind))| {
+                    token != conn
+                        && (shares_total || other_kind == kind)
+                        && self.has_turn(other_priority)
+                        && (other_priority < priority || !our_turn)
+                });
+        if !our_turn && !other_turn_waiting {
+            // Everyone waiting had their turns, start the next round.
+            self.served.clear();
+        }
+
+        let wait = if other_turn_waiting {
+            Some(Duration::from_millis(YIELD_MS))
+        } else {
+            let total_wait = self.total.as_mut().and_then(|bucket| bucket.wait(now));
+            let class_wait = self.class_bucket(kind).and_then(|bucket| bucket.wait(now));
+            cmp::max(total_wait, class_wait)
+        };
+
+        if wait.is_some() {
+            let _ = self.waiting.insert(conn, (priority, kind));
+        } else {
+            let _ = self.waiting.remove(&conn);
+            *self.served.entry(priority).or_insert(0) += 1;
+            if let Some(bucket) = self.total.as_mut() {
+                bucket.consume(len);
+            }
+            if let Some(bucket) = self.class_bucket(kind) {
+                bucket.consume(len);
+            }
+        }
+        wait
+    }
+
+    /// Returns whether we may pass on `len` bytes of data between our peers now. Relaying isn't
+    /// held back but dropped, so the relayed bytes are taken out of the budget if it says yes.
+    pub fn try_relay(&mut self, len: usize, now: Instant) -> bool {
+        match self.relayed {
+            Some(ref mut bucket) if bucket.wait(now).is_none() => {
+                bucket.consume(len);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the given priority may still send in the current round.
+    fn has_turn(&self, priority: Priority) -> bool {
+        self.served
+            .get(&priority)
+            .map_or(true, |&served| served < priority_weight(priority))
+    }
+
+    fn class_bucket(&mut self, kind: CrustUser) -> Option<&mut TokenBucket> {
+        match kind {
+            CrustUser::Node => self.nodes.as_mut(),
+            CrustUser::Client => self.clients.as_mut(),
+        }
+    }
+
+    /// Stops keeping budget for the connection with the given token, e.g. because it's gone or
+    /// has nothing left to send.
+    pub fn forget(&mut self, conn: Token) {
+        let _ = self.waiting.remove(&conn);
+    }
+}
+
+impl State<BootstrapCache> for BandwidthBudget {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Token bucket which allows bursts of up to one second worth of bytes.
+pub struct TokenBucket {
+    bytes_per_sec: u64,
+    /// Bytes we may still send. Goes negative when a message bigger than the allowance is sent,
+    /// so that big messages are not blocked forever.
+    allowance: i64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        let bytes_per_sec = cmp::max(bytes_per_sec, 1);
+        TokenBucket {
+            bytes_per_sec,
+            allowance: bytes_per_sec as i64,
+            last_refill: now,
+        }
+    }
+
+    pub fn set_rate(&mut self, bytes_per_sec: u64) {
+        self.bytes_per_sec = cmp::max(bytes_per_sec, 1);
+        self.allowance = cmp::min(self.allowance, self.bytes_per_sec as i64);
+    }
+
+    /// Returns `None` if there's allowance left, otherwise how long to wait until trying again.
+    /// What's sent is to be `consume`d then.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.allowance > 0 {
+            None
+        } else {
+            let deficit = (1 - self.allowance) as u64;
+            Some(Duration::from_micros(
+                deficit.saturating_mul(1_000_000) / self.bytes_per_sec + 1,
+            ))
+        }
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.allowance -= len as i64;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        let earned = self.bytes_per_sec.saturating_mul(elapsed_us) / 1_000_000;
+        if earned == 0 {
+            return;
+        }
+        self.allowance = cmp::min(
+            self.allowance.saturating_add(earned as i64),
+            self.bytes_per_sec as i64,
+        );
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_allows_one_second_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        assert_eq!(bucket.wait(start), None);
+        bucket.consume(600);
+        assert_eq!(bucket.wait(start), None);
+        bucket.consume(600);
+        let wait = unwrap!(bucket.wait(start));
+        assert!(wait > Duration::from_millis(200) && wait <= Duration::from_millis(202));
+
+        assert_eq!(bucket.wait(start + wait), None);
+    }
+
+    #[test]
+    fn token_bucket_does_not_accumulate_more_than_one_second() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.wait(later), None);
+        bucket.consume(1000);
+        assert!(bucket.wait(later).is_some());
+    }
+
+    fn budget(total: Option<u64>, nodes: Option<u64>, now: Instant) -> BandwidthBudget {
+        BandwidthBudget {
+            token: Token(0),
+            total: total.map(|rate| TokenBucket::new(rate, now)),
+            nodes: nodes.map(|rate| TokenBucket::new(rate, now)),
+            clients: None,
+            relayed: None,
+            waiting: HashMap::new(),
+            served: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn budget_goes_to_the_most_urgent_message_first() {
+        let start = Instant::now();
+        let mut budget = budget(Some(1000), None, start);
+        let (a, b, c) = (Token(1), Token(2), Token(3));
+
+        assert_eq!(budget.try_consume(a, CrustUser::Node, 2, 1000, start), None);
+        assert!(budget
+            .try_consume(b, CrustUser::Node, 5, 100, start)
+            .is_some());
+        assert!(budget
+            .try_consume(c, CrustUser::Client, 1, 100, start)
+            .is_some());
+
+        // Once there's allowance again, `c` gets it although `b` asked first.
+        let later = start + Duration::from_secs(1);
+        assert!(budget
+            .try_consume(b, CrustUser::Node, 5, 100, later)
+            .is_some());
+        assert_eq!(
+            budget.try_consume(c, CrustUser::Client, 1, 100, later),
+            None
+        );
+        assert_eq!(budget.try_consume(b, CrustUser::Node, 5, 100, later), None);
+
+        // A connection which gave up doesn't hold the others back.
+        assert_eq!(budget.try_consume(a, CrustUser::Node, 2, 1000, later), None);
+        assert!(budget
+            .try_consume(c, CrustUser::Node, 0, 100, later)
+            .is_some());
+        budget.forget(c);
+        let later = later + Duration::from_secs(1);
+        assert_eq!(budget.try_consume(b, CrustUser::Node, 5, 100, later), None);
+    }
+
+    #[test]
+    fn budget_still_goes_to_less_urgent_messages_now_and_then() {
+        let start = Instant::now();
+        let mut budget = budget(Some(1000), None, start);
+        let (urgent, bulk) = (Token(1), Token(2));
+
+        assert_eq!(
+            budget.try_consume(urgent, CrustUser::Node, 0, 1000, start),
+            None
+        );
+        let mut now = start;
+        let mut granted = 1;
+        loop {
+            assert!(budget
+                .try_consume(bulk, CrustUser::Node, 5, 1000, now)
+                .is_some());
+            now += Duration::from_secs(1);
+            if budget
+                .try_consume(urgent, CrustUser::Node, 0, 1000, now)
+                .is_some()
+            {
+                break;
+            }
+            granted += 1;
+        }
+
+        // Once the urgent messages had their turns, the bulk message gets the allowance.
+        assert_eq!(granted, priority_weight(0));
+        assert_eq!(
+            budget.try_consume(bulk, CrustUser::Node, 5, 1000, now),
+            None
+        );
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            budget.try_consume(urgent, CrustUser::Node, 0, 1000, now),
+            None
+        );
+    }
+
+    #[test]
+    fn relaying_is_dropped_over_its_budget_only() {
+        let start = Instant::now();
+        let mut budget = budget(Some(100), None, start);
+        assert!(!budget.try_relay(10, start));
+
+        budget.relayed = Some(TokenBucket::new(1000, start));
+        assert!(budget.try_relay(1000, start));
+        assert!(!budget.try_relay(10, start));
+        // Relaying doesn't take from what we may send ourselves.
+        assert_eq!(
+            budget.try_consume(Token(1), CrustUser::Node, 0, 100, start),
+            None
+        );
+        assert!(budget.try_relay(10, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn class_quota_limits_its_kind_only() {
+        let start = Instant::now();
+        let mut budget = budget(None, Some(100), start);
+        let (node, client) = (Token(1), Token(2));
+
+        assert_eq!(
+            budget.try_consume(node, CrustUser::Node, 0, 100, start),
+            None
+        );
+        assert!(budget
+            .try_consume(node, CrustUser::Node, 0, 100, start)
+            .is_some());
+        // Nodes waiting don't hold clients back since they don't share a bucket.
+        assert_eq!(
+            budget.try_consume(client, CrustUser::Client, 5, 10_000, start),
+            None
+        );
+        assert_eq!(
+            budget.try_consume(client, CrustUser::Client, 5, 10_000, start),
+            None
+        );
+    }
+}
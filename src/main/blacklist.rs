@@ -0,0 +1,147 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::Uid;
+use config_file_handler::{self, FileHandler};
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Shared list of peers and IPs we refuse to talk to until their entries expire.
+#[derive(Clone)]
+pub struct Blacklist<UID: Uid> {
+    inner: Arc<Mutex<Inner<UID>>>,
+}
+
+struct Inner<UID> {
+    file_name: Option<OsString>,
+    entries: Entries<UID>,
+}
+
+/// Lists rather than maps, so that any `UID` can be written as JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "UID: Uid")]
+struct Entries<UID> {
+    peers: Vec<(UID, SystemTime)>,
+    ips: Vec<(IpAddr, SystemTime)>,
+}
+
+impl<UID> Default for Entries<UID> {
+    fn default() -> Self {
+        Entries {
+            peers: Vec::new(),
+            ips: Vec::new(),
+        }
+    }
+}
+
+impl<UID: Uid> Blacklist<UID> {
+    /// Constructs the blacklist. If a file name is given, the blacklist is read from that file
+    /// and written back to it on every change.
+    pub fn new(file_name: Option<OsString>) -> Self {
+        let entries = match file_name {
+            Some(ref file_name) => match open_file(file_name).and_then(|fh| fh.read_file()) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    info!("Failed to read blacklist file: {}", e);
+                    Default::default()
+                }
+            },
+            None => Default::default(),
+        };
+        Blacklist {
+            inner: Arc::new(Mutex::new(Inner { file_name, entries })),
+        }
+    }
+
+    /// Blacklists the given peer until `expiry`.
+    pub fn insert_peer(&self, uid: UID, expiry: SystemTime) {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.entries.peers.retain(|&(peer, _)| peer != uid);
+        inner.entries.peers.push((uid, expiry));
+        inner.commit();
+    }
+
+    /// Blacklists the given IP until `expiry`.
+    pub fn insert_ip(&self, ip: IpAddr, expiry: SystemTime) {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.entries.ips.retain(|&(addr, _)| addr != ip);
+        inner.entries.ips.push((ip, expiry));
+        inner.commit();
+    }
+
+    /// Returns whether the given peer is blacklisted.
+    pub fn contains_peer(&self, uid: &UID) -> bool {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.purge_expired(SystemTime::now());
+        inner.entries.peers.iter().any(|&(peer, _)| peer == *uid)
+    }
+
+    /// Returns whether the given IP is blacklisted.
+    pub fn contains_ip(&self, ip: &IpAddr) -> bool {
+        let mut inner = unwrap!(self.inner.lock());
+        inner.purge_expired(SystemTime::now());
+        inner.entries.ips.iter().any(|&(addr, _)| addr == *ip)
+    }
+}
+
+impl<UID: Uid> Inner<UID> {
+    fn purge_expired(&mut self, now: SystemTime) {
+        let (peers, ips) = (self.entries.peers.len(), self.entries.ips.len());
+        self.entries.peers.retain(|&(_, expiry)| expiry > now);
+        self.entries.ips.retain(|&(_, expiry)| expiry > now);
+        if peers != self.entries.peers.len() || ips != self.entries.ips.len() {
+            self.commit();
+        }
+    }
+
+    fn commit(&self) {
+        if let Some(ref file_name) = self.file_name {
+            if let Err(e) = open_file(file_name).and_then(|fh| fh.write_file(&self.entries)) {
+                info!("Failed to write blacklist file: {}", e);
+            }
+        }
+    }
+}
+
+fn open_file<UID: Uid>(
+    file_name: &OsString,
+) -> Result<FileHandler<Entries<UID>>, config_file_handler::Error> {
+    FileHandler::new(file_name, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::utils::{rand_uid, UniqueId};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn entries_expire() {
+        let blacklist = Blacklist::<UniqueId>::new(None);
+        let (peer1, peer2, peer3) = (rand_uid(), rand_uid(), rand_uid());
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let now = SystemTime::now();
+
+        blacklist.insert_peer(peer1, now + Duration::from_secs(60));
+        blacklist.insert_peer(peer2, now);
+        blacklist.insert_ip(ip, now + Duration::from_secs(60));
+
+        assert!(blacklist.contains_peer(&peer1));
+        assert!(!blacklist.contains_peer(&peer2));
+        assert!(!blacklist.contains_peer(&peer3));
+        assert!(blacklist.contains_ip(&ip));
+        assert!(!blacklist.contains_ip(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+
+        blacklist.insert_ip(ip, now);
+        assert!(!blacklist.contains_ip(&ip));
+    }
+}
@@ -10,9 +10,16 @@
 use crate::common::PeerInfo;
 use config_file_handler::{self, FileHandler};
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Once the cache grows beyond this, the lowest ranked peers are dropped.
+const MAX_CACHED_PEERS: usize = 500;
 
 /// Reference-counted bootstrap cache - keeps log of known publicly accessible peers.
 #[derive(Clone)]
@@ -22,7 +29,54 @@ pub struct Cache {
 
 struct Inner {
     file_name: Option<OsString>,
-    peers: HashSet<PeerInfo>,
+    peers: HashMap<PeerInfo, PeerStats>,
+}
+
+/// How well connecting to a cached peer has been going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct PeerStats {
+    /// Seconds since the Unix epoch when we last connected to the peer.
+    #[serde(default)]
+    last_seen: u64,
+    /// Entries written before stats were tracked count as one successful connection.
+    #[serde(default = "one")]
+    successes: u32,
+    #[serde(default)]
+    failures: u32,
+}
+
+impl PeerStats {
+    /// Higher is better: the success rate first, then how recently we've seen the peer.
+    fn rank(&self, other: &PeerStats) -> Ordering {
+        let ours = u64::from(self.successes) * u64::from(other.attempts());
+        let theirs = u64::from(other.successes) * u64::from(self.attempts());
+        ours.cmp(&theirs).then(self.last_seen.cmp(&other.last_seen))
+    }
+
+    fn attempts(&self) -> u32 {
+        self.successes.saturating_add(self.failures)
+    }
+}
+
+/// A single entry of the cache file. Old cache files which only listed peer infos are read as
+/// entries with default stats.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    peer: PeerInfo,
+    #[serde(flatten)]
+    stats: PeerStats,
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
 }
 
 impl Cache {
@@ -51,19 +105,62 @@ impl Cache {
         match self.open_file() {
             Ok(file_handler) => {
                 let mut inner = self.inner.borrow_mut();
-                inner.peers = file_handler.read_file().unwrap_or_else(|e| {
+                let entries: Vec<CacheEntry> = file_handler.read_file().unwrap_or_else(|e| {
                     info!("Failed to read bootstrap cache file: {}", e);
-                    HashSet::new()
-                })
+                    Vec::new()
+                });
+                inner.peers = entries
+                    .into_iter()
+                    .map(|entry| (entry.peer, entry.stats))
+                    .collect();
             }
             Err(e) => info!("Failed to open bootstrap cache file: {}", e),
         }
     }
 
-    /// Inserts given peer to the cache.
+    /// Inserts given peer to the cache or, if it's already there, records another successful
+    /// connection to it.
     pub fn put(&self, peer: PeerInfo) {
         let mut inner = self.inner.borrow_mut();
-        let _ = inner.peers.insert(peer);
+        let now = now_secs();
+        {
+            let stats = inner.peers.entry(peer).or_insert(PeerStats {
+                last_seen: now,
+                successes: 0,
+                failures: 0,
+            });
+            stats.last_seen = now;
+            stats.successes = stats.successes.saturating_add(1);
+        }
+        inner.prune();
+    }
+
+    /// Inserts a peer we heard about but haven't connected to yet, unless it's cached already.
+    /// Such peers rank last and are removed on their first failure.
+    pub fn put_untested(&self, peer: PeerInfo) {
+        let mut inner = self.inner.borrow_mut();
+        let _ = inner.peers.entry(peer).or_insert(PeerStats {
+            last_seen: 0,
+            successes: 0,
+            failures: 0,
+        });
+        inner.prune();
+    }
+
+    /// Records a failed attempt to connect to the given peer. Peers for which at least half the
+    /// attempts failed are removed from the cache.
+    pub fn record_failure(&self, peer: &PeerInfo) {
+        let mut inner = self.inner.borrow_mut();
+        let remove = match inner.peers.get_mut(peer) {
+            Some(stats) => {
+                stats.failures = stats.failures.saturating_add(1);
+                stats.failures >= stats.successes
+            }
+            None => false,
+        };
+        if remove {
+            let _ = inner.peers.remove(peer);
+        }
     }
 
     /// Removes given peer from the cache.
@@ -72,20 +169,49 @@ impl Cache {
         let _ = inner.peers.remove(peer);
     }
 
-    /// Writes bootstrap cache to disk.
+    /// Writes bootstrap cache to disk. The cache is written to a temporary file first which then
+    /// replaces the old one, so a crash midway never leaves a truncated cache behind.
     pub fn commit(&self) -> crate::Res<()> {
         let file_handler = self.open_file()?;
         let inner = self.inner.borrow();
-        file_handler.write_file(&inner.peers)?;
+        let entries: Vec<_> = inner
+            .peers
+            .iter()
+            .map(|(peer, stats)| CacheEntry {
+                peer: *peer,
+                stats: *stats,
+            })
+            .collect();
+        let contents = serde_json::to_vec_pretty(&entries).map_err(io::Error::from)?;
+
+        let path = file_handler.path();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&contents)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
         Ok(())
     }
 
     /// Returns current snapshot of peers in the cache.
     pub fn peers(&self) -> HashSet<PeerInfo> {
-        self.inner.borrow().peers.clone()
+        self.inner.borrow().peers.keys().cloned().collect()
+    }
+
+    /// Returns the cached peers ordered from the most to the least promising one.
+    pub fn ranked_peers(&self) -> Vec<PeerInfo> {
+        self.inner
+            .borrow()
+            .ranked()
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect()
     }
 
-    fn open_file(&self) -> crate::Res<FileHandler<HashSet<PeerInfo>>> {
+    fn open_file(&self) -> crate::Res<FileHandler<Vec<CacheEntry>>> {
         let inner = self.inner.borrow_mut();
         let fname = inner
             .file_name
@@ -96,6 +222,27 @@ impl Cache {
     }
 }
 
+impl Inner {
+    fn ranked(&self) -> Vec<(PeerInfo, PeerStats)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(peer, stats)| (*peer, *stats))
+            .collect();
+        peers.sort_by(|(_, stats1), (_, stats2)| stats2.rank(stats1));
+        peers
+    }
+
+    fn prune(&mut self) {
+        if self.peers.len() <= MAX_CACHED_PEERS {
+            return;
+        }
+        for (peer, _) in self.ranked().into_iter().skip(MAX_CACHED_PEERS) {
+            let _ = self.peers.remove(&peer);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -189,6 +336,48 @@ mod tests {
             assert!(cache.peers().is_empty());
         }
 
+        #[test]
+        fn peers_are_removed_once_half_the_attempts_failed() {
+            let cache = Cache::new(None);
+            let peer = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+            cache.put(peer);
+            cache.put(peer);
+
+            cache.record_failure(&peer);
+            assert!(cache.peers().contains(&peer));
+
+            cache.record_failure(&peer);
+            assert!(cache.peers().is_empty());
+        }
+
+        #[test]
+        fn ranked_peers_puts_the_most_reliable_peers_first() {
+            let cache = Cache::new(None);
+            let reliable = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+            let flaky = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 5, 5000));
+            cache.put(flaky);
+            cache.put(flaky);
+            cache.record_failure(&flaky);
+            cache.put(reliable);
+
+            assert_eq!(cache.ranked_peers(), vec![reliable, flaky]);
+        }
+
+        #[test]
+        fn untested_peers_rank_last_and_go_on_first_failure() {
+            let cache = Cache::new(None);
+            let used = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+            let untested = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 5, 5000));
+            cache.put_untested(untested);
+            cache.put(used);
+            cache.put_untested(used);
+
+            assert_eq!(cache.ranked_peers(), vec![used, untested]);
+
+            cache.record_failure(&untested);
+            assert_eq!(cache.ranked_peers(), vec![used]);
+        }
+
         mod commit {
             use super::*;
 
@@ -0,0 +1,188 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::try_peer::TryPeer;
+use super::BOOTSTRAP_TIMEOUT_SEC;
+use crate::common::{
+    BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid,
+};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::session::Session;
+use crate::main::{
+    ActiveConnection, Blacklist, BootstrapError, ConnectionMap, CrustConfig, Event, EventLoopCore,
+    EventSender,
+};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+use socket_collection::TcpSock;
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Dials one known listener directly, without exchanging connection info first, and handshakes
+/// with it the way bootstrapping does.
+pub struct DirectConnect<UID: Uid> {
+    token: Token,
+    child: Option<Token>,
+    timeout: Timeout,
+    peer: PeerInfo,
+    cm: ConnectionMap<UID>,
+    config: CrustConfig,
+    peer_blacklist: Blacklist<UID>,
+    our_uid: UID,
+    event_tx: EventSender<UID>,
+}
+
+impl<UID: Uid> DirectConnect<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        peer: PeerInfo,
+        name_hash: NameHash,
+        our_uid: UID,
+        our_role: BootstrapperRole,
+        cm: ConnectionMap<UID>,
+        config: CrustConfig,
+        peer_blacklist: Blacklist<UID>,
+        event_tx: EventSender<UID>,
+        our_pk: PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+    ) -> crate::Res<()> {
+        let token = core.get_new_token();
+        let (bind_ip, socket_options, accepts_compression) = {
+            let cfg = &unwrap!(config.lock()).cfg;
+            (
+                cfg.bind_ip,
+                cfg.socket_options,
+                cfg.compression_threshold.is_some(),
+            )
+        };
+
+        let finish = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
+            if let Some(state) = core.get_state(token) {
+                let mut state = state.borrow_mut();
+                if let Some(direct) = state.as_any().downcast_mut::<DirectConnect<UID>>() {
+                    direct.handle_result(core, poll, child, res);
+                }
+            }
+        };
+        let child = TryPeer::start(
+            core,
+            poll,
+            peer,
+            bind_ip,
+            &socket_options,
+            our_uid,
+            name_hash,
+            our_role,
+            our_pk,
+            our_sk,
+            accepts_compression,
+            Box::new(finish),
+        )?;
+
+        let timeout = core.set_timeout(
+            Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC),
+            CoreTimer::new(token, 0),
+        );
+        let state = Rc::new(RefCell::new(DirectConnect {
+            token,
+            child: Some(child),
+            timeout,
+            peer,
+            cm,
+            config,
+            peer_blacklist,
+            our_uid,
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    fn handle_result(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        child: Token,
+        res: Result<
+            (
+                TcpSock,
+                PeerInfo,
+                UID,
+                Vec<(SocketAddr, bool)>,
+                bool,
+                Option<Session>,
+            ),
+            (PeerInfo, Option<BootstrapDenyReason>),
+        >,
+    ) {
+        self.child = None;
+        self.terminate(core, poll);
+        match res {
+            Ok((socket, peer_info, peer_id, _reachability, peer_accepts_compression, session)) => {
+                ActiveConnection::start(
+                    core,
+                    poll,
+                    child,
+                    socket,
+                    self.cm.clone(),
+                    &self.config,
+                    &self.peer_blacklist,
+                    self.our_uid,
+                    peer_id,
+                    CrustUser::Node,
+                    peer_accepts_compression,
+                    session,
+                    Event::DirectConnectSuccess(peer_id, peer_info.addr),
+                    self.event_tx.clone(),
+                )
+            }
+            Err((_, opt_reason)) => {
+                let error = match opt_reason {
+                    Some(reason) => BootstrapError::Denied(reason),
+                    None => BootstrapError::Unreachable,
+                };
+                debug!("Failed to connect to {}: {:?}", self.peer.addr, error);
+                let _ = self
+                    .event_tx
+                    .send(Event::DirectConnectFailure(self.peer.addr, error));
+            }
+        }
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for DirectConnect<UID> {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        debug!("Connecting to {} timed out", self.peer.addr);
+        self.terminate(core, poll);
+        let _ = self.event_tx.send(Event::DirectConnectFailure(
+            self.peer.addr,
+            BootstrapError::Unreachable,
+        ));
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if let Some(child) = self.child.take() {
+            if let Some(state) = core.get_state(child) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
@@ -8,15 +8,24 @@
 // Software.
 
 mod cache;
+mod direct_connect;
+mod policy;
 mod try_peer;
 
 pub use self::cache::Cache;
+pub use self::direct_connect::DirectConnect;
+pub use self::policy::BootstrapPolicy;
 use self::try_peer::TryPeer;
 use crate::common::{
-    BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid,
+    BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, NameHash, PeerInfo, SocketOptions,
+    State, Uid,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ActiveConnection, ConnectionMap, CrustConfig, CrustError, Event, EventLoopCore};
+use crate::main::session::Session;
+use crate::main::{
+    ActiveConnection, Blacklist, BootstrapError, ConnectionMap, CrustConfig, CrustError, Event,
+    EventLoopCore, EventSender,
+};
 use crate::service_discovery::ServiceDiscovery;
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
@@ -26,36 +35,78 @@ use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::TcpSock;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::sync::mpsc::{self, Receiver};
 use std::time::Duration;
 
 const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
 const SERVICE_DISCOVERY_TIMEOUT_SEC: u64 = 1;
+/// How often service discovery responses arriving after the bootstrap has begun are checked for.
+const LATE_DISCOVERY_CHECK_MS: u64 = 500;
 const BOOTSTRAP_TIMER_ID: u8 = 0;
 const SERVICE_DISCOVERY_TIMER_ID: u8 = BOOTSTRAP_TIMER_ID + 1;
+const RETRY_TIMER_ID: u8 = SERVICE_DISCOVERY_TIMER_ID + 1;
 const MAX_CONTACTS_EXPECTED: usize = 1500;
 
+static NEXT_HANDLE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Identifies a `Service::start_bootstrap` call, so that the bootstrapping it started can be
+/// cancelled via `Service::cancel_bootstrap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BootstrapHandle(usize);
+
+impl BootstrapHandle {
+    /// Returns a handle different from all the ones returned before.
+    pub fn unique() -> Self {
+        BootstrapHandle(NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 /// Connection bootstrap state that
 ///
 /// 1. attempts service discovery,
 /// 2. if no peers are found, tries cached ones,
 /// 3. if no success again, tries peers hard coded in the config.
+///
+/// LAN peers service discovery finds after that are tried next, ahead of the remaining ones.
+///
+/// Up to `Config::bootstrap_fan_out` peers are tried at once. The first one to accept us wins and
+/// the remaining attempts are cancelled. If all of them fail, the whole procedure is repeated as
+/// the `BootstrapPolicy` allows.
 pub struct Bootstrap<UID: Uid> {
     token: Token,
+    service_discovery_token: Token,
     cm: ConnectionMap<UID>,
     peers: Vec<PeerInfo>,
+    pending_peers: VecDeque<PeerInfo>,
+    /// Addresses of the peers tried or queued during the current attempt. Empty until the attempt
+    /// begins.
+    known_peers: HashSet<SocketAddr>,
+    fan_out: usize,
+    tried: usize,
+    report_progress: bool,
+    policy: BootstrapPolicy,
+    attempt: u32,
+    retry_timeout: Option<Timeout>,
+    config: CrustConfig,
+    blacklist: HashSet<SocketAddr>,
+    peer_blacklist: Blacklist<UID>,
+    bind_ip: Option<IpAddr>,
+    socket_options: SocketOptions,
     name_hash: NameHash,
     our_uid: UID,
     our_role: BootstrapperRole,
-    event_tx: crate::CrustEventSender<UID>,
+    event_tx: EventSender<UID>,
     sd_meta: Option<ServiceDiscMeta>,
     bs_timer: CoreTimer,
     bs_timeout: Timeout,
     children: HashSet<Token>,
+    /// Handles of the `Service::start_bootstrap` calls this bootstrapping serves.
+    handles: Vec<BootstrapHandle>,
     self_weak: Weak<RefCell<Bootstrap<UID>>>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
@@ -75,9 +126,11 @@ impl<UID: Uid> Bootstrap<UID> {
         cm: ConnectionMap<UID>,
         config: CrustConfig,
         blacklist: HashSet<SocketAddr>,
+        peer_blacklist: Blacklist<UID>,
+        policy: BootstrapPolicy,
         token: Token,
         service_discovery_token: Token,
-        event_tx: crate::CrustEventSender<UID>,
+        event_tx: EventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
     ) -> crate::Res<()> {
@@ -92,11 +145,38 @@ impl<UID: Uid> Bootstrap<UID> {
             }
         };
 
-        let peers = shuffled_bootstrap_peers(core.user_data().peers(), config.clone(), blacklist);
+        let (bind_ip, socket_options, fan_out, report_progress) = {
+            let cfg = &unwrap!(config.lock()).cfg;
+            (
+                cfg.bind_ip,
+                cfg.socket_options,
+                cfg.bootstrap_fan_out.unwrap_or(usize::max_value()),
+                cfg.report_bootstrap_progress,
+            )
+        };
+        let peers = bootstrap_peers(
+            core.user_data().ranked_peers(),
+            config.clone(),
+            blacklist.clone(),
+        );
         let state = Rc::new(RefCell::new(Self {
             token,
+            service_discovery_token,
             cm,
             peers,
+            pending_peers: VecDeque::new(),
+            known_peers: HashSet::new(),
+            fan_out,
+            tried: 0,
+            report_progress,
+            policy,
+            attempt: 1,
+            retry_timeout: None,
+            config,
+            blacklist,
+            peer_blacklist,
+            bind_ip,
+            socket_options,
             name_hash,
             our_uid,
             our_role,
@@ -105,6 +185,7 @@ impl<UID: Uid> Bootstrap<UID> {
             bs_timer,
             bs_timeout,
             children: HashSet::with_capacity(MAX_CONTACTS_EXPECTED),
+            handles: Vec::new(),
             self_weak: Weak::new(),
             our_pk,
             our_sk: our_sk.clone(),
@@ -121,14 +202,87 @@ impl<UID: Uid> Bootstrap<UID> {
         Ok(())
     }
 
+    /// Makes `cancel` accept the given handle.
+    pub fn add_handle(&mut self, handle: BootstrapHandle) {
+        self.handles.push(handle);
+    }
+
+    /// Aborts bootstrapping with `Event::BootstrapCancelled` and returns `true`, if it serves the
+    /// given handle.
+    pub fn cancel(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        handle: BootstrapHandle,
+    ) -> bool {
+        if !self.handles.contains(&handle) {
+            return false;
+        }
+        self.terminate(core, poll);
+        let _ = self.event_tx.send(Event::BootstrapCancelled);
+        true
+    }
+
     fn begin_bootstrap(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        let peers = mem::replace(&mut self.peers, Vec::new());
+        let mut peers = mem::replace(&mut self.peers, Vec::new());
+        {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            peers.retain(|peer| cfg.is_bootstrap_ip_allowed(&peer.addr.ip()));
+        }
         if peers.is_empty() {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            return self.fail(core, poll);
+        }
+
+        self.known_peers = peers.iter().map(|peer| peer.addr).collect();
+        self.pending_peers = peers.into();
+        self.tried = 0;
+        self.try_pending_peers(core, poll);
+        self.report_progress(None);
+        self.maybe_terminate(core, poll);
+    }
+
+    /// Queues the peers service discovery found after the attempt began, unless we know them
+    /// already.
+    fn add_discovered_peers(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        peers: Vec<PeerInfo>,
+    ) {
+        let new_peers: Vec<_> = {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            let blacklist = &self.blacklist;
+            let known_peers = &mut self.known_peers;
+            peers
+                .into_iter()
+                .filter(|peer| {
+                    !blacklist.contains(&peer.addr) && cfg.is_bootstrap_ip_allowed(&peer.addr.ip())
+                })
+                .filter(|peer| known_peers.insert(peer.addr))
+                .collect()
+        };
+        if new_peers.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Service discovery found {} more peers to bootstrap off",
+            new_peers.len()
+        );
+        // Peers on our LAN are the cheapest to reach, so they jump the queue.
+        for peer in new_peers.into_iter().rev() {
+            self.pending_peers.push_front(peer);
         }
+        self.try_pending_peers(core, poll);
+    }
 
-        for peer in peers {
+    /// Tries the next pending peers until `fan_out` attempts are in flight.
+    fn try_pending_peers(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        while self.children.len() < self.fan_out {
+            let peer = match self.pending_peers.pop_front() {
+                Some(peer) => peer,
+                None => break,
+            };
             let self_weak = self.self_weak.clone();
             let finish = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
@@ -140,17 +294,22 @@ impl<UID: Uid> Bootstrap<UID> {
                 core,
                 poll,
                 peer,
+                self.bind_ip,
+                &self.socket_options,
                 self.our_uid,
                 self.name_hash,
                 self.our_role.clone(),
                 self.our_pk,
                 &self.our_sk,
+                unwrap!(self.config.lock())
+                    .cfg
+                    .compression_threshold
+                    .is_some(),
                 Box::new(finish),
             ) {
                 let _ = self.children.insert(child);
             }
         }
-        self.maybe_terminate(core, poll);
     }
 
     fn handle_result(
@@ -158,22 +317,42 @@ impl<UID: Uid> Bootstrap<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Result<(TcpSock, PeerInfo, UID), (PeerInfo, Option<BootstrapDenyReason>)>,
+        res: Result<
+            (
+                TcpSock,
+                PeerInfo,
+                UID,
+                Vec<(SocketAddr, bool)>,
+                bool,
+                Option<Session>,
+            ),
+            (PeerInfo, Option<BootstrapDenyReason>),
+        >,
     ) {
         let _ = self.children.remove(&child);
         match res {
-            Ok((socket, peer_info, peer_id)) => {
+            Ok((socket, peer_info, peer_id, reachability, peer_accepts_compression, session)) => {
                 self.terminate(core, poll);
+                cache_peer_info(core, peer_info, &self.config);
+                if !reachability.is_empty() {
+                    let _ = self
+                        .event_tx
+                        .send(Event::ListenersReachability(reachability));
+                }
                 return ActiveConnection::start(
                     core,
                     poll,
                     child,
                     socket,
                     self.cm.clone(),
+                    &self.config,
+                    &self.peer_blacklist,
                     self.our_uid,
                     peer_id,
                     // Note; We bootstrap only to Nodes
                     CrustUser::Node,
+                    peer_accepts_compression,
+                    session,
                     Event::BootstrapConnect(peer_id, peer_info.addr),
                     self.event_tx.clone(),
                 );
@@ -181,11 +360,24 @@ impl<UID: Uid> Bootstrap<UID> {
             Err((bad_peer, opt_reason)) => {
                 {
                     let bootstrap_cache = core.user_data_mut();
-                    bootstrap_cache.remove(&bad_peer);
+                    // A peer which denied us will keep doing so, whereas one we couldn't reach
+                    // might just be offline for a while, and one which throttled us might have
+                    // room again soon.
+                    match opt_reason {
+                        Some(BootstrapDenyReason::Throttled) | None => {
+                            bootstrap_cache.record_failure(&bad_peer)
+                        }
+                        Some(_) => bootstrap_cache.remove(&bad_peer),
+                    }
                     if let Err(e) = bootstrap_cache.commit() {
                         info!("Failed to write bootstrap cache to disk: {}", e);
                     }
                 }
+                self.tried += 1;
+                let last_error = match opt_reason.clone() {
+                    Some(reason) => BootstrapError::Denied(reason),
+                    None => BootstrapError::Unreachable,
+                };
 
                 if let Some(reason) = opt_reason {
                     let (err_msg, is_err_fatal) = match reason {
@@ -200,8 +392,26 @@ impl<UID: Uid> Bootstrap<UID> {
                         BootstrapDenyReason::ClientNotWhitelisted => {
                             ("Our Client is not whitelisted", false)
                         }
+                        BootstrapDenyReason::TooManyConnections => {
+                            ("Bootstrappee has no room for more connections", false)
+                        }
+                        BootstrapDenyReason::FailedChallenge => (
+                            "We failed the bootstrappee's proof of work challenge",
+                            false,
+                        ),
+                        BootstrapDenyReason::IncompatibleProtocolVersion(_) => {
+                            ("Bootstrappee speaks a different protocol version", false)
+                        }
+                        BootstrapDenyReason::Throttled => {
+                            ("Bootstrappee accepts no more bootstraps for now", false)
+                        }
+                        BootstrapDenyReason::ForbiddenSubnet => (
+                            "Our IP is in a subnet the bootstrappee doesn't allow",
+                            false,
+                        ),
                     };
                     if is_err_fatal {
+                        // Retrying won't help, our peers will keep denying us for the same reason.
                         error!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
                         self.terminate(core, poll);
                         let _ = self.event_tx.send(Event::BootstrapFailed);
@@ -213,16 +423,75 @@ impl<UID: Uid> Bootstrap<UID> {
                         );
                     }
                 }
+                self.report_progress(Some(last_error));
             }
         }
         self.maybe_terminate(core, poll);
     }
 
+    fn report_progress(&self, last_error: Option<BootstrapError>) {
+        if !self.report_progress {
+            return;
+        }
+        let _ = self.event_tx.send(Event::BootstrapProgress {
+            tried: self.tried,
+            remaining: self.children.len() + self.pending_peers.len(),
+            last_error,
+        });
+    }
+
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.try_pending_peers(core, poll);
         if self.children.is_empty() {
             error!("Bootstrapper has no active children left - bootstrap has failed");
+            self.fail(core, poll);
+        }
+    }
+
+    /// Gives up with `Event::BootstrapFailed` if this was the last attempt the policy allows, or
+    /// schedules the next one otherwise.
+    fn fail(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if self.attempt >= self.policy.max_attempts {
             self.terminate(core, poll);
             let _ = self.event_tx.send(Event::BootstrapFailed);
+            return;
+        }
+
+        self.terminate_children(core, poll);
+        self.pending_peers.clear();
+        if let Some(sd_meta) = self.sd_meta.take() {
+            let _ = core.cancel_timeout(&sd_meta.timeout);
+        }
+        let _ = core.cancel_timeout(&self.bs_timeout);
+
+        let delay = self.policy.delay(self.attempt);
+        info!(
+            "Bootstrap attempt {} failed, retrying in {:?}",
+            self.attempt, delay
+        );
+        self.retry_timeout =
+            Some(core.set_timeout(delay, CoreTimer::new(self.token, RETRY_TIMER_ID)));
+        let _ = self
+            .event_tx
+            .send(Event::BootstrapRetrying(self.attempt, delay));
+    }
+
+    fn retry(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.retry_timeout = None;
+        self.attempt += 1;
+        self.known_peers.clear();
+        self.bs_timeout =
+            core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), self.bs_timer);
+        self.peers = bootstrap_peers(
+            core.user_data().ranked_peers(),
+            self.config.clone(),
+            self.blacklist.clone(),
+        );
+        self.sd_meta = seek_peers(core, self.service_discovery_token, self.token)
+            .ok()
+            .map(|(rx, timeout)| ServiceDiscMeta { rx, timeout });
+        if self.sd_meta.is_none() {
+            self.begin_bootstrap(core, poll);
         }
     }
 
@@ -241,17 +510,33 @@ impl<UID: Uid> Bootstrap<UID> {
 impl<UID: Uid> State<BootstrapCache> for Bootstrap<UID> {
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
         if timer_id == self.bs_timer.timer_id {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            return self.fail(core, poll);
+        }
+        if timer_id == RETRY_TIMER_ID {
+            return self.retry(core, poll);
         }
 
-        let rx = unwrap!(self.sd_meta.take()).rx;
+        let mut discovered = Vec::new();
+        if let Some(ref sd_meta) = self.sd_meta {
+            while let Ok(listeners) = sd_meta.rx.try_recv() {
+                discovered.extend(listeners);
+            }
+        }
 
-        while let Ok(listeners) = rx.try_recv() {
-            self.peers.extend(listeners);
+        if self.known_peers.is_empty() {
+            self.peers.extend(discovered);
+            self.begin_bootstrap(core, poll);
+        } else {
+            self.add_discovered_peers(core, poll, discovered);
         }
 
-        self.begin_bootstrap(core, poll);
+        // Keep listening for late responses for as long as the attempt is in progress.
+        if let Some(ref mut sd_meta) = self.sd_meta {
+            sd_meta.timeout = core.set_timeout(
+                Duration::from_millis(LATE_DISCOVERY_CHECK_MS),
+                CoreTimer::new(self.token, SERVICE_DISCOVERY_TIMER_ID),
+            );
+        }
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
@@ -259,6 +544,9 @@ impl<UID: Uid> State<BootstrapCache> for Bootstrap<UID> {
         if let Some(sd_meta) = self.sd_meta.take() {
             let _ = core.cancel_timeout(&sd_meta.timeout);
         }
+        if let Some(timeout) = self.retry_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = core.remove_state(self.token);
         let _ = core.cancel_timeout(&self.bs_timeout);
     }
@@ -315,18 +603,16 @@ fn seek_peers(
     }
 }
 
-/// Peers from bootstrap cache and hard coded contacts are shuffled individually.
-fn shuffled_bootstrap_peers(
-    cached_peers: HashSet<PeerInfo>,
+/// Cached peers come first, in the order given, followed by the shuffled hard coded contacts.
+fn bootstrap_peers(
+    cached_peers: Vec<PeerInfo>,
     config: CrustConfig,
     blacklist: HashSet<SocketAddr>,
 ) -> Vec<PeerInfo> {
     let mut peers = Vec::with_capacity(MAX_CONTACTS_EXPECTED);
     let mut rng = rand::thread_rng();
 
-    let mut cached: Vec<_> = cached_peers.iter().cloned().collect();
-    cached.shuffle(&mut rng);
-    peers.extend(cached);
+    peers.extend(cached_peers);
 
     let mut hard_coded = unwrap!(config.lock()).cfg.hard_coded_contacts.clone();
     hard_coded.shuffle(&mut rng);
@@ -395,7 +681,7 @@ mod tests {
         }
     }
 
-    mod shuffled_bootstrap_peers {
+    mod bootstrap_peers {
         use super::*;
 
         #[test]
@@ -405,10 +691,9 @@ mod tests {
             let mut config = Config::default();
             config.hard_coded_contacts = vec![peer1];
             let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
-            let mut cached_peers = HashSet::new();
-            let _ = cached_peers.insert(peer2);
+            let cached_peers = vec![peer2];
 
-            let peers = shuffled_bootstrap_peers(cached_peers, config, Default::default());
+            let peers = bootstrap_peers(cached_peers, config, Default::default());
 
             assert_eq!(peers.len(), 2);
             assert!(peers.contains(&peer1));
@@ -422,12 +707,11 @@ mod tests {
             let mut config = Config::default();
             config.hard_coded_contacts = vec![peer1];
             let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
-            let mut cached_peers = HashSet::new();
-            let _ = cached_peers.insert(peer2);
+            let cached_peers = vec![peer2];
             let mut blacklisted = HashSet::new();
             let _ = blacklisted.insert(ipv4_addr(1, 2, 3, 4, 4000));
 
-            let peers = shuffled_bootstrap_peers(cached_peers, config, blacklisted);
+            let peers = bootstrap_peers(cached_peers, config, blacklisted);
 
             assert_eq!(peers.len(), 1);
             assert!(peers.contains(&peer2));
@@ -436,12 +720,13 @@ mod tests {
 
     mod bootstrap {
         use super::*;
+        use crate::tests::utils::{get_event_sender, rand_uid, UniqueId};
+        use safe_crypto::gen_encrypt_keypair;
+        use std::collections::HashMap;
 
         mod handle_result {
             use super::*;
-            use crate::tests::utils::{get_event_sender, rand_uid, UniqueId};
-            use safe_crypto::gen_encrypt_keypair;
-            use std::collections::HashMap;
+            use std::net::TcpListener;
 
             mod when_result_is_error {
                 use super::*;
@@ -472,6 +757,8 @@ mod tests {
                         conn_map,
                         config,
                         HashSet::new(),
+                        Blacklist::new(None),
+                        Default::default(),
                         token,
                         dummy_service_discovery_token,
                         event_tx,
@@ -522,6 +809,8 @@ mod tests {
                         conn_map,
                         config,
                         HashSet::new(),
+                        Blacklist::new(None),
+                        Default::default(),
                         token,
                         dummy_service_discovery_token,
                         event_tx,
@@ -543,7 +832,242 @@ mod tests {
                     let state = core.get_state(token);
                     assert!(state.is_some());
                 }
+
+                #[test]
+                fn next_pending_peer_is_tried_when_fan_out_is_reached() {
+                    // Listeners which never accept, so that our attempts stay in flight.
+                    let listeners: Vec<_> = (0..3)
+                        .map(|_| unwrap!(TcpListener::bind("127.0.0.1:0")))
+                        .collect();
+                    let peers: Vec<_> = listeners
+                        .iter()
+                        .map(|listener| peer_info_with_rand_key(unwrap!(listener.local_addr())))
+                        .collect();
+                    let mut core = test_core(test_bootstrap_cache());
+                    let poll = unwrap!(Poll::new());
+
+                    let mut config = Config::default();
+                    config.hard_coded_contacts = peers.clone();
+                    config.bootstrap_fan_out = Some(1);
+                    let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+                    let dummy_service_discovery_token = Token(9999);
+
+                    let (our_pk, our_sk) = gen_encrypt_keypair();
+                    let (event_tx, _event_rx) = get_event_sender();
+                    let token = Token(1);
+                    let conn_map = Arc::new(Mutex::new(HashMap::new()));
+
+                    unwrap!(Bootstrap::start(
+                        &mut core,
+                        &poll,
+                        [1; 32],
+                        rand_uid(),
+                        BootstrapperRole::Client,
+                        conn_map,
+                        config,
+                        HashSet::new(),
+                        Blacklist::new(None),
+                        Default::default(),
+                        token,
+                        dummy_service_discovery_token,
+                        event_tx,
+                        our_pk,
+                        &our_sk
+                    ));
+
+                    let state = unwrap!(core.get_state(token));
+                    let mut state = state.borrow_mut();
+                    let bootstrap_state =
+                        unwrap!(state.as_any().downcast_mut::<Bootstrap<UniqueId>>());
+                    assert_eq!(bootstrap_state.children.len(), 1);
+                    assert_eq!(bootstrap_state.pending_peers.len(), 2);
+
+                    let child = *unwrap!(bootstrap_state.children.iter().next());
+                    let tried = unwrap!(peers
+                        .iter()
+                        .find(|peer| !bootstrap_state.pending_peers.contains(peer)));
+                    bootstrap_state.handle_result(&mut core, &poll, child, Err((*tried, None)));
+
+                    assert_eq!(bootstrap_state.children.len(), 1);
+                    assert_eq!(bootstrap_state.pending_peers.len(), 1);
+                }
+
+                #[test]
+                fn progress_is_reported_when_contacts_fail() {
+                    let listeners: Vec<_> = (0..2)
+                        .map(|_| unwrap!(TcpListener::bind("127.0.0.1:0")))
+                        .collect();
+                    let peers: Vec<_> = listeners
+                        .iter()
+                        .map(|listener| peer_info_with_rand_key(unwrap!(listener.local_addr())))
+                        .collect();
+                    let mut core = test_core(test_bootstrap_cache());
+                    let poll = unwrap!(Poll::new());
+
+                    let mut config = Config::default();
+                    config.hard_coded_contacts = peers.clone();
+                    config.report_bootstrap_progress = true;
+                    let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+
+                    let (our_pk, our_sk) = gen_encrypt_keypair();
+                    let (event_tx, event_rx) = get_event_sender();
+                    let token = Token(1);
+
+                    unwrap!(Bootstrap::start(
+                        &mut core,
+                        &poll,
+                        [1; 32],
+                        rand_uid(),
+                        BootstrapperRole::Client,
+                        Arc::new(Mutex::new(HashMap::new())),
+                        config,
+                        HashSet::new(),
+                        Blacklist::new(None),
+                        Default::default(),
+                        token,
+                        Token(9999),
+                        event_tx,
+                        our_pk,
+                        &our_sk
+                    ));
+
+                    match unwrap!(event_rx.try_recv()) {
+                        Event::BootstrapProgress {
+                            tried: 0,
+                            remaining: 2,
+                            last_error: None,
+                        } => (),
+                        event => panic!("Unexpected event: {:?}", event),
+                    }
+
+                    let state = unwrap!(core.get_state(token));
+                    let mut state = state.borrow_mut();
+                    let bootstrap_state =
+                        unwrap!(state.as_any().downcast_mut::<Bootstrap<UniqueId>>());
+                    let child = *unwrap!(bootstrap_state.children.iter().next());
+                    bootstrap_state.handle_result(
+                        &mut core,
+                        &poll,
+                        child,
+                        Err((peers[0], Some(BootstrapDenyReason::TooManyConnections))),
+                    );
+
+                    match unwrap!(event_rx.try_recv()) {
+                        Event::BootstrapProgress {
+                            tried: 1,
+                            remaining: 1,
+                            last_error: Some(BootstrapError::Denied(reason)),
+                        } => assert_eq!(reason, BootstrapDenyReason::TooManyConnections),
+                        event => panic!("Unexpected event: {:?}", event),
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn peers_discovered_after_start_are_tried_first() {
+            let listeners: Vec<_> = (0..3)
+                .map(|_| unwrap!(std::net::TcpListener::bind("127.0.0.1:0")))
+                .collect();
+            let peers: Vec<_> = listeners
+                .iter()
+                .map(|listener| peer_info_with_rand_key(unwrap!(listener.local_addr())))
+                .collect();
+            let mut core = test_core(test_bootstrap_cache());
+            let poll = unwrap!(Poll::new());
+
+            let mut config = Config::default();
+            config.hard_coded_contacts = peers[..2].to_vec();
+            config.bootstrap_fan_out = Some(1);
+            let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let (event_tx, _event_rx) = get_event_sender();
+            let token = Token(1);
+
+            unwrap!(Bootstrap::start(
+                &mut core,
+                &poll,
+                [1; 32],
+                rand_uid(),
+                BootstrapperRole::Client,
+                Arc::new(Mutex::new(HashMap::new())),
+                config,
+                HashSet::new(),
+                Blacklist::new(None),
+                Default::default(),
+                token,
+                Token(9999),
+                event_tx,
+                our_pk,
+                &our_sk
+            ));
+
+            let state = unwrap!(core.get_state(token));
+            let mut state = state.borrow_mut();
+            let bootstrap_state = unwrap!(state.as_any().downcast_mut::<Bootstrap<UniqueId>>());
+            assert_eq!(bootstrap_state.pending_peers.len(), 1);
+
+            // Pretend service discovery is running and answers late, repeating a peer we know.
+            let (obs, rx) = mpsc::channel();
+            let timeout = core.set_timeout(
+                Duration::from_secs(SERVICE_DISCOVERY_TIMEOUT_SEC),
+                CoreTimer::new(token, SERVICE_DISCOVERY_TIMER_ID),
+            );
+            bootstrap_state.sd_meta = Some(ServiceDiscMeta { rx, timeout });
+            unwrap!(obs.send(vec![peers[2], peers[0]]));
+            bootstrap_state.timeout(&mut core, &poll, SERVICE_DISCOVERY_TIMER_ID);
+
+            assert_eq!(bootstrap_state.pending_peers.len(), 2);
+            assert_eq!(bootstrap_state.pending_peers.front(), Some(&peers[2]));
+            assert!(bootstrap_state.sd_meta.is_some());
+        }
+
+        #[test]
+        fn failed_attempt_is_retried_as_policy_allows() {
+            let mut core = test_core(test_bootstrap_cache());
+            let poll = unwrap!(Poll::new());
+            let config = Arc::new(Mutex::new(ConfigWrapper::new(Config::default())));
+            let policy = BootstrapPolicy {
+                max_attempts: 2,
+                backoff: Duration::from_secs(1),
+                jitter: Duration::from_secs(0),
+            };
+            let (our_pk, our_sk) = gen_encrypt_keypair();
+            let (event_tx, event_rx) = get_event_sender();
+            let token = Token(1);
+
+            // Without any contacts every attempt fails right away.
+            unwrap!(Bootstrap::start(
+                &mut core,
+                &poll,
+                [1; 32],
+                rand_uid(),
+                BootstrapperRole::Client,
+                Arc::new(Mutex::new(HashMap::new())),
+                config,
+                HashSet::new(),
+                Blacklist::new(None),
+                policy,
+                token,
+                Token(9999),
+                event_tx,
+                our_pk,
+                &our_sk
+            ));
+
+            match unwrap!(event_rx.try_recv()) {
+                Event::BootstrapRetrying(1, delay) => assert_eq!(delay, Duration::from_secs(1)),
+                event => panic!("Unexpected event: {:?}", event),
+            }
+            let state = unwrap!(core.get_state(token));
+
+            state.borrow_mut().timeout(&mut core, &poll, RETRY_TIMER_ID);
+
+            match unwrap!(event_rx.try_recv()) {
+                Event::BootstrapFailed => (),
+                event => panic!("Unexpected event: {:?}", event),
             }
+            assert!(core.get_state(token).is_none());
         }
     }
 }
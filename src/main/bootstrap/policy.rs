@@ -0,0 +1,86 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use rand::{self, Rng};
+use std::time::Duration;
+
+/// The backoff stops doubling after this many failed attempts.
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+/// How bootstrapping is retried when all contacts fail, see
+/// `Service::start_bootstrap_with_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootstrapPolicy {
+    /// How many times to try bootstrapping before giving up with `Event::BootstrapFailed`.
+    pub max_attempts: u32,
+    /// How long to wait before the second attempt. The delay doubles after every further failed
+    /// attempt.
+    pub backoff: Duration,
+    /// Up to this much is randomly added to every delay, so that clients which lost the network
+    /// at the same time don't all retry at once.
+    pub jitter: Duration,
+}
+
+impl BootstrapPolicy {
+    /// Returns how long to wait after the given failed attempt, counting from 1, before trying
+    /// again.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(MAX_BACKOFF_DOUBLINGS);
+        let backoff = self.backoff * (1 << doublings);
+        let jitter_ms = self.jitter.as_secs() * 1000 + u64::from(self.jitter.subsec_millis());
+        if jitter_ms == 0 {
+            return backoff;
+        }
+        backoff + Duration::from_millis(rand::thread_rng().gen_range(0, jitter_ms + 1))
+    }
+}
+
+impl Default for BootstrapPolicy {
+    /// A single attempt, i.e. no retries.
+    fn default() -> Self {
+        BootstrapPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_secs(1),
+            jitter: Duration::from_secs(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_after_every_attempt() {
+        let policy = BootstrapPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(2),
+            jitter: Duration::from_secs(0),
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(2), Duration::from_secs(4));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn jitter_is_added_to_the_delay() {
+        let policy = BootstrapPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            jitter: Duration::from_millis(500),
+        };
+
+        for _ in 0..10 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(1));
+            assert!(delay <= Duration::from_millis(1500));
+        }
+    }
+}
@@ -8,9 +8,11 @@
 // Software.
 
 use crate::common::{
-    BootstrapDenyReason, BootstrapperRole, Message, NameHash, PeerInfo, State, Uid,
+    connect_tcp, BootstrapDenyReason, BootstrapperRole, Message, NameHash, PeerInfo, SocketOptions,
+    State, Uid, PROTOCOL_VERSION,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::session::{Redial, Session};
 use crate::main::EventLoopCore;
 use mio::{Poll, PollOpt, Ready, Token};
 use safe_crypto::{PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
@@ -18,6 +20,7 @@ use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
 
 pub type Finish<UID> = Box<
@@ -25,11 +28,23 @@ pub type Finish<UID> = Box<
         &mut EventLoopCore,
         &Poll,
         Token,
-        Result<(TcpSock, PeerInfo, UID), (PeerInfo, Option<BootstrapDenyReason>)>,
+        Result<
+            (
+                TcpSock,
+                PeerInfo,
+                UID,
+                Vec<(SocketAddr, bool)>,
+                bool,
+                Option<Session>,
+            ),
+            (PeerInfo, Option<BootstrapDenyReason>),
+        >,
     ),
 >;
 
-/// Sends bootstrap request to a one specific address and waits for response.
+/// Sends bootstrap request to a one specific address and waits for response. On success, `finish`
+/// gets the socket, the peer, its ID, the reachability it reported, whether it takes compressed
+/// data and the session to resume the connection with, if the peer granted one.
 pub struct TryPeer<UID: Uid> {
     token: Token,
     peer: PeerInfo,
@@ -37,6 +52,11 @@ pub struct TryPeer<UID: Uid> {
     request: Option<(Message<UID>, Priority)>,
     finish: Finish<UID>,
     shared_key: SharedSecretKey,
+    /// Reported by the peer if it tested our external reachability.
+    reachability: Vec<(SocketAddr, bool)>,
+    bind_ip: Option<IpAddr>,
+    socket_options: SocketOptions,
+    our_pk: PublicEncryptKey,
 }
 
 impl<UID: Uid> TryPeer<UID> {
@@ -44,14 +64,17 @@ impl<UID: Uid> TryPeer<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         peer: PeerInfo,
+        bind_ip: Option<IpAddr>,
+        socket_options: &SocketOptions,
         our_uid: UID,
         name_hash: NameHash,
         our_role: BootstrapperRole,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
+        accepts_compression: bool,
         finish: Finish<UID>,
     ) -> crate::Res<Token> {
-        let mut socket = TcpSock::connect(&peer.addr)?;
+        let mut socket = connect_tcp(&peer.addr, bind_ip, socket_options)?;
         socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(peer.pub_key))?;
         let shared_key = our_sk.shared_secret(&peer.pub_key);
         socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone()))?;
@@ -69,11 +92,22 @@ impl<UID: Uid> TryPeer<UID> {
             peer,
             socket,
             request: Some((
-                Message::BootstrapRequest(our_uid, name_hash, our_role, our_pk),
+                Message::BootstrapRequest(
+                    our_uid,
+                    name_hash,
+                    PROTOCOL_VERSION,
+                    our_role,
+                    our_pk,
+                    accepts_compression,
+                ),
                 0,
             )),
             finish,
             shared_key,
+            reachability: Vec::new(),
+            bind_ip,
+            socket_options: *socket_options,
+            our_pk,
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -94,7 +128,7 @@ impl<UID: Uid> TryPeer<UID> {
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::BootstrapGranted(peer_uid))) => {
+            Ok(Some(Message::BootstrapGranted(peer_uid, peer_accepts_compression, session_id))) => {
                 let _ = core.remove_state(self.token);
                 let token = self.token;
 
@@ -102,7 +136,26 @@ impl<UID: Uid> TryPeer<UID> {
                 match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
                 {
                     Ok(_) => {
-                        let data = (socket, self.peer, peer_uid);
+                        let reachability = mem::replace(&mut self.reachability, Vec::new());
+                        let session = session_id.map(|id| Session {
+                            id,
+                            their_pk: self.peer.pub_key,
+                            redial: Some(Redial {
+                                addr: self.peer.addr,
+                                bind_ip: self.bind_ip,
+                                socket_options: self.socket_options,
+                                our_pk: self.our_pk,
+                                shared_key: self.shared_key.clone(),
+                            }),
+                        });
+                        let data = (
+                            socket,
+                            self.peer,
+                            peer_uid,
+                            reachability,
+                            peer_accepts_compression,
+                            session,
+                        );
                         (*self.finish)(core, poll, token, Ok(data));
                     }
                     Err(e) => {
@@ -114,6 +167,25 @@ impl<UID: Uid> TryPeer<UID> {
             Ok(Some(Message::BootstrapDenied(reason))) => {
                 self.handle_error(core, poll, Some(reason))
             }
+            Ok(Some(Message::ReachabilityReport(reachability))) => {
+                self.reachability = reachability;
+                // The answer might have arrived together with the report.
+                self.read(core, poll)
+            }
+            Ok(Some(Message::BootstrapChallenge(challenge))) => match challenge.solve() {
+                Some(solution) => self.write(
+                    core,
+                    poll,
+                    Some((Message::BootstrapChallengeResponse(solution), 0)),
+                ),
+                None => {
+                    debug!(
+                        "Bootstrappee's challenge is too hard: {} bits",
+                        challenge.difficulty
+                    );
+                    self.handle_error(core, poll, Some(BootstrapDenyReason::FailedChallenge))
+                }
+            },
             Ok(None) => (),
             Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
         }
@@ -7,22 +7,261 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::PeerInfo;
+use crate::common::{IpSubnet, PeerInfo, SocketOptions, MAX_POW_DIFFICULTY};
+use crate::main::{
+    CrustError, EvictionPolicy, PeerScoring, HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS,
+};
 use config_file_handler::{self, FileHandler};
+use serde_json;
 use std::collections::HashSet;
+use std::env;
 use std::ffi::OsString;
-use std::net::IpAddr;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 
-#[cfg(test)]
-use std::path::PathBuf;
+/// How deep config files may include each other, see `Config::include`.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Extensions of the config files we look for, in order of preference.
+const CONFIG_FILE_EXTENSIONS: &[&str] = &["config", "toml", "yaml", "yml"];
+
+/// Prefix of the environment variables which override settings of the config file.
+const ENV_VAR_PREFIX: &str = "CRUST_";
+
+/// Settings which are only read when the `Service` or its listeners start.
+const RESTART_REQUIRED_SETTINGS: &[&str] = &[
+    "tcp_acceptor_port",
+    "extra_tcp_acceptor_ports",
+    "listeners",
+    "enable_ipv6",
+    "bind_ip",
+    "disable_igd",
+    "disable_external_reachability_test",
+    "force_acceptor_port_in_ext_ep",
+    "service_discovery_port",
+    "service_discovery_listener_port",
+    "bootstrap_cache_name",
+    "blacklist_file_name",
+    "key_file_name",
+    "upload_bytes_per_sec",
+    "node_upload_bytes_per_sec",
+    "client_upload_bytes_per_sec",
+    "relay_bytes_per_sec",
+    "network_name",
+    "log_level",
+];
+
+/// Invokes the given macro with the names of all `Config` fields.
+macro_rules! for_each_setting {
+    ($callback:ident) => {
+        $callback!(
+            include,
+            hard_coded_contacts,
+            tcp_acceptor_port,
+            extra_tcp_acceptor_ports,
+            listeners,
+            enable_ipv6,
+            bind_ip,
+            disable_igd,
+            disable_external_reachability_test,
+            report_connect_stats,
+            report_bootstrap_progress,
+            peer_exchange,
+            relay_connection_info,
+            relay_bytes_per_sec,
+            http_proxy,
+            connect_order,
+            heartbeat_period_ms,
+            inactivity_timeout_ms,
+            session_resume_timeout_ms,
+            send_queue_limit,
+            priority_queue_limit,
+            channel_queue_limit,
+            max_msg_size,
+            compression_threshold,
+            inbound_msgs_per_sec,
+            inbound_bytes_per_sec,
+            upload_bytes_per_sec,
+            node_upload_bytes_per_sec,
+            client_upload_bytes_per_sec,
+            max_connections,
+            max_connections_per_ip,
+            max_node_connections,
+            max_client_connections,
+            eviction_policy,
+            handshake_timeout_sec,
+            max_pending_handshakes,
+            inbound_conn_attempts_per_ip,
+            client_bootstraps_per_minute,
+            node_bootstraps_per_minute,
+            bootstrap_pow_difficulty,
+            peer_scoring,
+            socket_options,
+            force_acceptor_port_in_ext_ep,
+            service_discovery_port,
+            service_discovery_listener_port,
+            bootstrap_cache_name,
+            bootstrap_fan_out,
+            blacklist_file_name,
+            key_file_name,
+            whitelisted_node_ips,
+            whitelisted_client_ips,
+            forbidden_subnets,
+            allowed_subnets,
+            network_name,
+            log_level
+        )
+    };
+}
 
 /// Crust configuration settings
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Config files to take settings from, e.g. a read-only one with the network's contacts that
+    /// is shared by all nodes. They are merged in order, with later files and then this one
+    /// overriding earlier ones, see `read_config_file`. Relative paths are relative to this file.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
     /// Direct contacts one should connect to
     pub hard_coded_contacts: Vec<PeerInfo>,
     /// Port for TCP acceptor
     pub tcp_acceptor_port: Option<u16>,
+    /// Ports for further TCP acceptors, started alongside the one on `tcp_acceptor_port`. Their
+    /// addresses are advertised too. 0 picks an ephemeral port.
+    #[serde(default)]
+    pub extra_tcp_acceptor_ports: Vec<u16>,
+    /// Listeners to start, each with its own transport, address and, optionally, the address to
+    /// advertise it on. If not empty, these replace the listeners on `tcp_acceptor_port` and
+    /// `extra_tcp_acceptor_ports`.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Also accept TCP connections over IPv6. When enabled, an IPv6-only acceptor is bound to the
+    /// same port as the IPv4 one and our IPv6 interface addresses are advertised alongside the
+    /// IPv4 ones. Listeners bound to an IPv6 address accept IPv6 only.
+    #[serde(default)]
+    pub enable_ipv6: bool,
+    /// Local IP address to use for outgoing connections and for the TCP acceptor, e.g. to pick
+    /// between a VPN and a LAN interface on multi-homed hosts. If `None`, the OS chooses.
+    pub bind_ip: Option<IpAddr>,
+    /// Don't ask routers for port mappings via UPnP IGD, NAT-PMP or PCP. By default a mapping is
+    /// requested for the TCP acceptor and the resulting external address is advertised in our
+    /// connection info. NAT-PMP and PCP are only used if the router doesn't support IGD and,
+    /// since we need to find the default gateway for them, on Linux only.
+    #[serde(default)]
+    pub disable_igd: bool,
+    /// Accept peers which claim to be nodes as nodes, without first checking that we can connect
+    /// back to them, e.g. on a LAN. `Service::set_ext_reachability_test` overrides this while the
+    /// service runs.
+    #[serde(default)]
+    pub disable_external_reachability_test: bool,
+    /// Send `Event::ConnectStats` after every connection attempt made via `Service::connect`.
+    #[serde(default)]
+    pub report_connect_stats: bool,
+    /// Send `Event::BootstrapProgress` while bootstrapping.
+    #[serde(default)]
+    pub report_bootstrap_progress: bool,
+    /// Send the best contacts of our bootstrap cache to every new peer and add the contacts
+    /// peers send us to the cache, so that it doesn't depend on the hard coded contacts alone.
+    #[serde(default)]
+    pub peer_exchange: bool,
+    /// Pass on the connection info our peers send each other through us via
+    /// `Service::send_connection_info_via`.
+    #[serde(default)]
+    pub relay_connection_info: bool,
+    /// Maximum number of bytes per second of data we pass on between our peers, for all of them
+    /// together, see `Service::connect_via`. Data over the limit is dropped. If `None`, we don't
+    /// relay data at all.
+    pub relay_bytes_per_sec: Option<u64>,
+    /// HTTP proxy to tunnel outgoing connections through, using the `CONNECT` method, when direct
+    /// connections to a peer fail.
+    pub http_proxy: Option<SocketAddr>,
+    /// Whether `Service::connect` tries to reach a peer directly or through `http_proxy` first,
+    /// and which way wins if both get through. Ignored without `http_proxy`.
+    #[serde(default)]
+    pub connect_order: ConnectOrder,
+    /// How often to send heartbeats to idle peers, in milliseconds. If `None`, 20 seconds.
+    pub heartbeat_period_ms: Option<u64>,
+    /// Drop peers we haven't heard from for this long, in milliseconds. If `None`, 2 minutes.
+    pub inactivity_timeout_ms: Option<u64>,
+    /// How long a bootstrap connection waits to be resumed on a new socket after its socket
+    /// broke, in milliseconds, e.g. while a laptop changes networks. The bootstrapper dials the
+    /// bootstrappee's listener again meanwhile, and data queued for the peer is kept. The peer is
+    /// only reported lost if the session isn't resumed in time. Both peers need this set. If
+    /// `None`, a broken socket loses the peer right away.
+    pub session_resume_timeout_ms: Option<u64>,
+    /// Maximum number of messages queued for a peer which is over its rate limit or whose socket
+    /// can't keep up. Further messages are dropped and returned via `Event::WriteBlocked`. If
+    /// `None`, the queue is unbounded.
+    pub send_queue_limit: Option<usize>,
+    /// Like `send_queue_limit`, but for the messages of each priority separately, so that a
+    /// backlog of one priority doesn't crowd out the others.
+    pub priority_queue_limit: Option<usize>,
+    /// Maximum number of messages a channel opened via `Service::open_channel` holds back, on
+    /// top of the few it has in the send queue. Sending more on it fails with
+    /// `CrustError::ChannelFull`. If `None`, the channels are unbounded.
+    pub channel_queue_limit: Option<usize>,
+    /// Maximum size in bytes of a message sent to or received from a peer. Sending a bigger
+    /// message fails and a peer which sends one is dropped. Received messages are checked the
+    /// moment their length prefix is read, before their buffer is allocated. If `None`, only the
+    /// socket's own limit applies.
+    pub max_msg_size: Option<usize>,
+    /// Compress data messages bigger than this many bytes with LZ4 when sending them to peers
+    /// which set it too. Peers tell each other whether they set it in the handshake, so a change
+    /// applies to new connections only. If `None`, nothing is compressed.
+    pub compression_threshold: Option<usize>,
+    /// Maximum number of messages per second a peer may send us. A peer over the limit is
+    /// throttled and dropped if it keeps going over it. If `None`, there is no limit.
+    pub inbound_msgs_per_sec: Option<u64>,
+    /// Like `inbound_msgs_per_sec`, but limits payload bytes per second.
+    pub inbound_bytes_per_sec: Option<u64>,
+    /// Maximum number of payload bytes per second we send to all peers together. While there's
+    /// less to go round than the connections want to send, priorities take turns like in a
+    /// peer's send queue, see `Service::set_rate_limit`. Heartbeats and other control messages
+    /// aren't held back. If `None`, there is no limit.
+    pub upload_bytes_per_sec: Option<u64>,
+    /// Like `upload_bytes_per_sec`, but limits what we send to nodes only.
+    pub node_upload_bytes_per_sec: Option<u64>,
+    /// Like `upload_bytes_per_sec`, but limits what we send to clients only.
+    pub client_upload_bytes_per_sec: Option<u64>,
+    /// Maximum number of connections to peers. If `None`, there is no limit.
+    pub max_connections: Option<usize>,
+    /// Maximum number of connections to peers with the same IP address.
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of connections to nodes.
+    pub max_node_connections: Option<usize>,
+    /// Maximum number of connections to clients.
+    pub max_client_connections: Option<usize>,
+    /// What to do when a peer connecting to us would exceed one of the connection limits.
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+    /// Drop peers which haven't finished their handshake with us after this many seconds. If
+    /// `None`, 10 minutes.
+    pub handshake_timeout_sec: Option<u64>,
+    /// Maximum number of handshakes with incoming connections in flight at once. When a new
+    /// connection would exceed it, the oldest pending handshake is dropped. If `None`, there is
+    /// no limit.
+    pub max_pending_handshakes: Option<usize>,
+    /// Maximum number of incoming connections per minute from the same IP address. Further ones
+    /// are refused until the minute is over. If `None`, there is no limit.
+    pub inbound_conn_attempts_per_ip: Option<u32>,
+    /// Maximum number of clients we let bootstrap off us per minute. Further ones are denied with
+    /// `BootstrapDenyReason::Throttled` until the minute is over. If `None`, there is no limit.
+    pub client_bootstraps_per_minute: Option<u32>,
+    /// Like `client_bootstraps_per_minute`, but for nodes.
+    pub node_bootstraps_per_minute: Option<u32>,
+    /// Make peers which want to bootstrap off us solve a proof of work challenge with this many
+    /// leading zero bits first, to make flooding us with bootstrap requests expensive. Each extra
+    /// bit doubles the work. Peers refuse challenges over 16 bits. If `None`, there is no
+    /// challenge.
+    pub bootstrap_pow_difficulty: Option<u8>,
+    /// How peers are scored for misbehaving and when they are banned for it.
+    #[serde(default)]
+    pub peer_scoring: PeerScoring,
+    /// TCP options for peer connections, e.g. to disable Nagle's algorithm.
+    #[serde(default)]
+    pub socket_options: SocketOptions,
     /// Force usage of `tcp_acceptor_port` as our router mapped port. Normally if there is a port
     /// forwarding, crust will find out what the external world sees our local tcp acceptor
     /// endpoint as and include this information in our connection info that we share with others.
@@ -42,40 +281,703 @@ pub struct Config {
     pub service_discovery_listener_port: Option<u16>,
     /// File for bootstrap cache
     pub bootstrap_cache_name: Option<OsString>,
+    /// Maximum number of bootstrap contacts to try at once. Whenever an attempt fails, the next
+    /// contact is tried. If `None`, all contacts are tried at once.
+    pub bootstrap_fan_out: Option<usize>,
+    /// File to keep the blacklist of `Service::blacklist_peer` and `Service::blacklist_ip` in
+    /// across restarts. If `None`, the blacklist is kept in memory only.
+    pub blacklist_file_name: Option<OsString>,
+    /// File to keep our encryption keypair in, sealed with the passphrase given to
+    /// `Service::with_passphrase`. If `None`, `<exe name>.crust.keys` is used.
+    pub key_file_name: Option<OsString>,
     /// Whitelisted nodes who are allowed to bootstrap off us or to connect to us
     pub whitelisted_node_ips: Option<HashSet<IpAddr>>,
     /// Whitelisted clients who are allowed to bootstrap off us
     pub whitelisted_client_ips: Option<HashSet<IpAddr>>,
+    /// Subnets in CIDR notation, e.g. `"10.0.0.0/8"`, whose peers may neither bootstrap off us
+    /// nor be bootstrapped off by us.
+    #[serde(default)]
+    pub forbidden_subnets: Vec<IpSubnet>,
+    /// If set, only peers in these subnets may bootstrap off us or be bootstrapped off by us,
+    /// unless `forbidden_subnets` rules them out.
+    pub allowed_subnets: Option<Vec<IpSubnet>>,
     /// Network ID
     ///
     /// This is a mechanism to prevent nodes from different decentralized
     /// networks to connect to each other (issue #209)
+    ///
+    /// Its hash is part of every handshake and of service discovery, so several networks can
+    /// share the same ports and bootstrap contacts without talking to each other. Peers of other
+    /// networks are denied with `BootstrapDenyReason::InvalidNameHash`. Any string works, e.g. the
+    /// hex encoded genesis key of the network.
     pub network_name: Option<String>,
+    /// Most verbose log level to keep, one of `off`, `error`, `warn`, `info`, `debug` or `trace`,
+    /// e.g. `CRUST_LOG_LEVEL=debug` to debug a deployment without changing its config file. It's
+    /// set via `log::set_max_level` when the `Service` starts, so it applies to the logs of the
+    /// whole application. The application's logger may filter further. If `None`, the level is
+    /// left as it is.
+    pub log_level: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
+            include: vec![],
             hard_coded_contacts: vec![],
             tcp_acceptor_port: None,
+            extra_tcp_acceptor_ports: vec![],
+            listeners: vec![],
+            enable_ipv6: false,
+            bind_ip: None,
+            disable_igd: false,
+            disable_external_reachability_test: false,
+            report_connect_stats: false,
+            report_bootstrap_progress: false,
+            peer_exchange: false,
+            relay_connection_info: false,
+            relay_bytes_per_sec: None,
+            http_proxy: None,
+            connect_order: Default::default(),
+            heartbeat_period_ms: None,
+            inactivity_timeout_ms: None,
+            session_resume_timeout_ms: None,
+            send_queue_limit: None,
+            priority_queue_limit: None,
+            channel_queue_limit: None,
+            max_msg_size: None,
+            compression_threshold: None,
+            inbound_msgs_per_sec: None,
+            inbound_bytes_per_sec: None,
+            upload_bytes_per_sec: None,
+            node_upload_bytes_per_sec: None,
+            client_upload_bytes_per_sec: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_node_connections: None,
+            max_client_connections: None,
+            eviction_policy: Default::default(),
+            handshake_timeout_sec: None,
+            max_pending_handshakes: None,
+            inbound_conn_attempts_per_ip: None,
+            client_bootstraps_per_minute: None,
+            node_bootstraps_per_minute: None,
+            bootstrap_pow_difficulty: None,
+            peer_scoring: Default::default(),
+            socket_options: Default::default(),
             force_acceptor_port_in_ext_ep: false,
             service_discovery_port: None,
             service_discovery_listener_port: None,
             bootstrap_cache_name: None,
+            bootstrap_fan_out: None,
+            blacklist_file_name: None,
+            key_file_name: None,
             whitelisted_node_ips: None,
             whitelisted_client_ips: None,
+            forbidden_subnets: Vec::new(),
+            allowed_subnets: None,
             network_name: None,
+            log_level: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the listeners to start, the main one first: `listeners` if it isn't empty,
+    /// otherwise one TCP listener on `tcp_acceptor_port` and one on each of
+    /// `extra_tcp_acceptor_ports`.
+    pub fn listener_configs(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let main_port = self.tcp_acceptor_port.unwrap_or(0);
+        Some(main_port)
+            .into_iter()
+            .chain(self.extra_tcp_acceptor_ports.iter().cloned())
+            .map(ListenerConfig::tcp)
+            .collect()
+    }
+
+    /// Returns whether `forbidden_subnets` and `allowed_subnets` let us bootstrap off the given IP
+    /// or let it bootstrap off us.
+    pub fn is_bootstrap_ip_allowed(&self, ip: &IpAddr) -> bool {
+        if self
+            .forbidden_subnets
+            .iter()
+            .any(|subnet| subnet.contains(ip))
+        {
+            return false;
+        }
+        self.allowed_subnets.as_ref().map_or(true, |subnets| {
+            subnets.iter().any(|subnet| subnet.contains(ip))
+        })
+    }
+
+    /// Returns the names of the settings which differ between the two configs.
+    pub fn changed_settings(&self, other: &Config) -> Vec<&'static str> {
+        macro_rules! changed_settings {
+            ($($setting:ident),*) => {{
+                // Destructured so that a new setting can't be left out here.
+                let Config { $(ref $setting),* } = *self;
+                let mut changed = Vec::new();
+                $(
+                    if *$setting != other.$setting {
+                        changed.push(stringify!($setting));
+                    }
+                )*
+                changed
+            }};
+        }
+
+        for_each_setting!(changed_settings)
+    }
+
+    /// Takes the given settings over from `other`, see `changed_settings`, and leaves the others
+    /// as they are.
+    pub fn take_settings(&mut self, other: &Config, settings: &[&str]) {
+        macro_rules! take_settings {
+            ($($setting:ident),*) => {{
+                let Config { $(ref $setting),* } = *other;
+                $(
+                    if settings.contains(&stringify!($setting)) {
+                        self.$setting = Clone::clone($setting);
+                    }
+                )*
+            }};
+        }
+
+        for_each_setting!(take_settings)
+    }
+
+    /// Returns whether a change to the given setting only takes effect once the `Service`, or for
+    /// the listener settings its listeners, are started again. All other settings are read
+    /// whenever they are needed, so changes apply to new connections, handshakes and bootstraps
+    /// right away.
+    pub fn requires_restart(setting: &str) -> bool {
+        RESTART_REQUIRED_SETTINGS.contains(&setting)
+    }
+
+    /// Checks for settings which can't work, e.g. limits of zero, heartbeats which are too rare to
+    /// prevent the inactivity timeout, hard coded contacts which `forbidden_subnets` rule out or a
+    /// client whitelist which the disabled external reachability test makes pointless. Lists every
+    /// problem found, see `ConfigError`. `read_config_file` and every `Service` constructor call
+    /// this.
+    pub fn validate(&self) -> crate::Res<()> {
+        let mut problems = Vec::new();
+
+        let heartbeat_period_ms = self.heartbeat_period_ms.unwrap_or(HEARTBEAT_PERIOD_MS);
+        let inactivity_timeout_ms = self.inactivity_timeout_ms.unwrap_or(INACTIVITY_TIMEOUT_MS);
+        if heartbeat_period_ms == 0 {
+            problems.push(ConfigProblem::new("heartbeat_period_ms", "must not be 0"));
+        } else if heartbeat_period_ms >= inactivity_timeout_ms {
+            problems.push(ConfigProblem::new(
+                "heartbeat_period_ms",
+                "must be less than inactivity_timeout_ms",
+            ));
+        }
+
+        let non_zero = [
+            ("handshake_timeout_sec", self.handshake_timeout_sec),
+            ("session_resume_timeout_ms", self.session_resume_timeout_ms),
+            ("max_msg_size", self.max_msg_size.map(|size| size as u64)),
+            ("inbound_msgs_per_sec", self.inbound_msgs_per_sec),
+            ("inbound_bytes_per_sec", self.inbound_bytes_per_sec),
+            ("upload_bytes_per_sec", self.upload_bytes_per_sec),
+            ("node_upload_bytes_per_sec", self.node_upload_bytes_per_sec),
+            (
+                "client_upload_bytes_per_sec",
+                self.client_upload_bytes_per_sec,
+            ),
+            ("relay_bytes_per_sec", self.relay_bytes_per_sec),
+            (
+                "max_connections",
+                self.max_connections.map(|max| max as u64),
+            ),
+            (
+                "bootstrap_fan_out",
+                self.bootstrap_fan_out.map(|n| n as u64),
+            ),
+            (
+                "service_discovery_port",
+                self.service_discovery_port.map(u64::from),
+            ),
+        ];
+        for &(path, value) in &non_zero {
+            if value == Some(0) {
+                problems.push(ConfigProblem::new(path, "must not be 0"));
+            }
+        }
+
+        if let Some(difficulty) = self.bootstrap_pow_difficulty {
+            if difficulty > MAX_POW_DIFFICULTY {
+                problems.push(ConfigProblem::new(
+                    "bootstrap_pow_difficulty",
+                    format!("peers refuse challenges over {} bits", MAX_POW_DIFFICULTY),
+                ));
+            }
+        }
+        if self.peer_scoring.ban_threshold.is_some() && self.peer_scoring.ban_duration_sec == 0 {
+            problems.push(ConfigProblem::new(
+                "peer_scoring.ban_duration_sec",
+                "must not be 0 while ban_threshold is set",
+            ));
+        }
+
+        let mut extra_ports = HashSet::with_capacity(self.extra_tcp_acceptor_ports.len());
+        for (i, &port) in self.extra_tcp_acceptor_ports.iter().enumerate() {
+            if port == 0 {
+                continue;
+            }
+            let path = format!("extra_tcp_acceptor_ports[{}]", i);
+            if self.tcp_acceptor_port == Some(port) {
+                problems.push(ConfigProblem::new(path, "is the tcp_acceptor_port already"));
+            } else if !extra_ports.insert(port) {
+                problems.push(ConfigProblem::new(path, "is listed more than once"));
+            }
+        }
+        if !self.listeners.is_empty() {
+            if self.tcp_acceptor_port.is_some() {
+                problems.push(ConfigProblem::new(
+                    "tcp_acceptor_port",
+                    "must not be set as well as listeners, which replace it",
+                ));
+            }
+            if !self.extra_tcp_acceptor_ports.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "extra_tcp_acceptor_ports",
+                    "must not be set as well as listeners, which replace it",
+                ));
+            }
+        }
+        let mut bound = HashSet::with_capacity(self.listeners.len());
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.port != 0 && !bound.insert((listener.ip.or(self.bind_ip), listener.port)) {
+                problems.push(ConfigProblem::new(
+                    format!("listeners[{}].port", i),
+                    "is used by another listener on the same address already",
+                ));
+            }
+            if let Some(end) = listener.port_range_end {
+                if listener.port == 0 || end < listener.port {
+                    problems.push(ConfigProblem::new(
+                        format!("listeners[{}].port_range_end", i),
+                        "must not be less than port, which must not be 0",
+                    ));
+                }
+            }
+            if let Some(addr) = listener.external_addr {
+                if addr.ip().is_unspecified() || addr.port() == 0 {
+                    problems.push(ConfigProblem::new(
+                        format!("listeners[{}].external_addr", i),
+                        format!("{} can't be connected to", addr),
+                    ));
+                }
+            }
+        }
+        if self.force_acceptor_port_in_ext_ep
+            && self
+                .listener_configs()
+                .iter()
+                .all(|listener| listener.port == 0)
+        {
+            problems.push(ConfigProblem::new(
+                "force_acceptor_port_in_ext_ep",
+                "requires a tcp_acceptor_port",
+            ));
+        }
+
+        for (i, contact) in self.hard_coded_contacts.iter().enumerate() {
+            let path = format!("hard_coded_contacts[{}].addr", i);
+            if contact.addr.ip().is_unspecified() || contact.addr.port() == 0 {
+                problems.push(ConfigProblem::new(
+                    path,
+                    format!("{} can't be connected to", contact.addr),
+                ));
+            } else if !self.is_bootstrap_ip_allowed(&contact.addr.ip()) {
+                problems.push(ConfigProblem::new(
+                    path,
+                    format!(
+                        "{} is ruled out by forbidden_subnets or allowed_subnets",
+                        contact.addr
+                    ),
+                ));
+            }
+        }
+        if let Some(proxy) = self.http_proxy {
+            if proxy.ip().is_unspecified() || proxy.port() == 0 {
+                problems.push(ConfigProblem::new(
+                    "http_proxy",
+                    format!("{} can't be connected to", proxy),
+                ));
+            }
+        }
+        if let Some(ip) = self.bind_ip {
+            if ip.is_multicast() {
+                problems.push(ConfigProblem::new(
+                    "bind_ip",
+                    "must not be a multicast address",
+                ));
+            }
+        }
+        if self
+            .allowed_subnets
+            .as_ref()
+            .map_or(false, |subnets| subnets.is_empty())
+        {
+            problems.push(ConfigProblem::new(
+                "allowed_subnets",
+                "rules out every peer, leave it out to allow all of them",
+            ));
+        }
+        let whitelists = [
+            ("whitelisted_node_ips", &self.whitelisted_node_ips),
+            ("whitelisted_client_ips", &self.whitelisted_client_ips),
+        ];
+        for &(path, whitelist) in &whitelists {
+            if whitelist.as_ref().map_or(false, |ips| ips.is_empty()) {
+                problems.push(ConfigProblem::new(
+                    path,
+                    "rules out every peer, leave it out to allow all of them",
+                ));
+            }
+        }
+        // Without the reachability test nothing stops a client from claiming to be a node.
+        if self.disable_external_reachability_test
+            && self.whitelisted_client_ips.is_some()
+            && self.whitelisted_node_ips.is_none()
+        {
+            problems.push(ConfigProblem::new(
+                "whitelisted_client_ips",
+                "can be bypassed by claiming to be a node while \
+                 disable_external_reachability_test is set, unless whitelisted_node_ips is set too",
+            ));
+        }
+
+        if let Some(ref level) = self.log_level {
+            if level.parse::<log::LevelFilter>().is_err() {
+                problems.push(ConfigProblem::new(
+                    "log_level",
+                    "must be one of off, error, warn, info, debug or trace",
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(CrustError::InvalidConfig(ConfigError { problems }))
+        }
+    }
+}
+
+/// Transport a listener accepts connections over.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum Transport {
+    Tcp,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Tcp
+    }
+}
+
+/// Order of the ways `Service::connect` tries to reach a peer, see `Config::connect_order`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum ConnectOrder {
+    /// Tunnel through the HTTP proxy only once all direct attempts failed.
+    DirectFirst,
+    /// Connect directly only once all tunnels failed.
+    TunnelFirst,
+    /// Start direct attempts and tunnels at once. A tunnel which gets through first waits until
+    /// the direct attempts failed before its handshake, so a direct connection wins if there is
+    /// one.
+    ParallelPreferDirect,
+    /// Like `ParallelPreferDirect`, but a tunnel wins if there is one.
+    ParallelPreferTunnel,
+}
+
+impl Default for ConnectOrder {
+    fn default() -> Self {
+        ConnectOrder::DirectFirst
+    }
+}
+
+/// One of the listeners in `Config::listeners`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ListenerConfig {
+    #[serde(default)]
+    pub transport: Transport,
+    /// Local IP address to listen on, either IPv4 or IPv6. If `None`, `Config::bind_ip`.
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    /// 0 picks an ephemeral port.
+    pub port: u16,
+    /// If set, the listener is bound to the first free port from `port` up to and including this
+    /// one, e.g. to run several instances on one host with the same config. The port picked is
+    /// reported by `Event::ListenerStarted` and `Service::addresses`.
+    #[serde(default)]
+    pub port_range_end: Option<u16>,
+    /// Address to advertise instead of the ones found via our interfaces and port mapping, e.g.
+    /// the public address of the host a container's port is forwarded from.
+    #[serde(default)]
+    pub external_addr: Option<SocketAddr>,
+}
+
+impl ListenerConfig {
+    /// TCP listener on the given port, without any overrides.
+    pub fn tcp(port: u16) -> Self {
+        ListenerConfig {
+            transport: Transport::Tcp,
+            ip: None,
+            port,
+            port_range_end: None,
+            external_addr: None,
+        }
+    }
+}
+
+/// Everything wrong with a config, see `Config::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl ConfigError {
+    /// Error with just the one problem.
+    pub fn new<P: Into<String>, R: Into<String>>(path: P, reason: R) -> Self {
+        ConfigError {
+            problems: vec![ConfigProblem::new(path, reason)],
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                write!(formatter, "; ")?;
+            }
+            write!(formatter, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// A setting which can't work, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// Where the setting is in the JSON config file, e.g. `hard_coded_contacts[0].addr`.
+    pub path: String,
+    pub reason: String,
+}
+
+impl ConfigProblem {
+    pub fn new<P: Into<String>, R: Into<String>>(path: P, reason: R) -> Self {
+        ConfigProblem {
+            path: path.into(),
+            reason: reason.into(),
         }
     }
 }
 
-/// Reads the default crust config file.
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} {}", self.path, self.reason)
+    }
+}
+
+/// Reads the default crust config file and checks it with `Config::validate`. Settings can be
+/// overridden by environment variables, see `override_with_env_vars`.
+///
+/// The file is `<executable>.crust.config` in JSON, or `<executable>.crust.toml`,
+/// `<executable>.crust.yaml` or `<executable>.crust.yml`, see `ConfigFormat`. It's looked for next
+/// to the executable, in the user's app directory and in the system cache directory, in that
+/// order.
+///
+/// The files listed in `Config::include` are merged in deterministically: a setting of the
+/// including file replaces the included one, also when it's `null`. Settings which are objects,
+/// like `peer_scoring`, are merged setting by setting, but lists are replaced as a whole.
 pub fn read_config_file() -> crate::Res<Config> {
-    let file_handler = FileHandler::new(&get_file_name()?, false)?;
-    let cfg = file_handler.read_file()?;
+    let (path, format) = match find_config_file() {
+        Some(found) => found,
+        None => {
+            let file_handler = FileHandler::<Config>::new(&get_file_name()?, false)?;
+            (file_handler.path().to_path_buf(), ConfigFormat::Json)
+        }
+    };
+    let settings = read_settings(&path, format, 0)?;
+    let cfg = serde_json::from_value(settings)
+        .map_err(|e| CrustError::ConfigParse(format, e.to_string()))?;
+    let cfg = override_with_env_vars(cfg, env::vars())?;
+    cfg.validate()?;
     Ok(cfg)
 }
 
+/// Reads the settings of the given config file, with its includes merged in.
+fn read_settings(path: &Path, format: ConfigFormat, depth: usize) -> crate::Res<serde_json::Value> {
+    let mut settings = format.parse_settings(&fs::read_to_string(path)?)?;
+    let include = match settings.as_object_mut().and_then(|s| s.remove("include")) {
+        Some(include) => include,
+        None => return Ok(settings),
+    };
+    let includes: Vec<PathBuf> = serde_json::from_value(include.clone())
+        .map_err(|e| CrustError::ConfigParse(format, e.to_string()))?;
+
+    let mut merged = serde_json::Value::Object(Default::default());
+    for include_path in includes {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(CrustError::InvalidConfig(ConfigError::new(
+                "include",
+                format!(
+                    "nests more than {} files deep, it might include itself",
+                    MAX_INCLUDE_DEPTH
+                ),
+            )));
+        }
+        let include_path = path
+            .parent()
+            .map_or_else(|| include_path.clone(), |dir| dir.join(&include_path));
+        let include_format = ConfigFormat::from_path(&include_path).ok_or_else(|| {
+            CrustError::InvalidConfig(ConfigError::new(
+                "include",
+                format!("{} isn't a config file", include_path.display()),
+            ))
+        })?;
+        merge_settings(
+            &mut merged,
+            read_settings(&include_path, include_format, depth + 1)?,
+        );
+    }
+    merge_settings(&mut merged, settings);
+    merged["include"] = include;
+    Ok(merged)
+}
+
+/// Overrides `settings` with `overrides`, see `read_config_file`.
+fn merge_settings(settings: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (settings, overrides) {
+        (serde_json::Value::Object(settings), serde_json::Value::Object(overrides)) => {
+            for (name, value) in overrides {
+                merge_settings(
+                    settings.entry(name).or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+        (settings, overrides) => *settings = overrides,
+    }
+}
+
+/// Format of a config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Tells the format by the file's extension: `config` or `json`, `toml`, and `yaml` or `yml`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "config" | "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Parses a config written in this format. The settings are the same in every format.
+    /// `Config::include` isn't followed, as there is no file to be relative to, see
+    /// `read_config_file`.
+    pub fn parse(self, contents: &str) -> crate::Res<Config> {
+        serde_json::from_value(self.parse_settings(contents)?)
+            .map_err(|e| CrustError::ConfigParse(self, e.to_string()))
+    }
+
+    /// Parses the settings in a format independent way, so they can be merged.
+    fn parse_settings(self, contents: &str) -> crate::Res<serde_json::Value> {
+        let parsed = match self {
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(|e| CrustError::ConfigParse(self, e))
+    }
+}
+
+/// Returns the first config file found in the directories `config_file_handler` looks in, and its
+/// format.
+fn find_config_file() -> Option<(PathBuf, ConfigFormat)> {
+    let stem = config_file_handler::exe_file_stem().ok()?;
+    let dirs = vec![
+        config_file_handler::current_bin_dir(),
+        config_file_handler::user_app_dir(),
+        config_file_handler::system_cache_dir(),
+    ];
+    for dir in dirs.into_iter().filter_map(Result::ok) {
+        for extension in CONFIG_FILE_EXTENSIONS {
+            let mut name = stem.clone();
+            name.push(".crust.");
+            name.push(extension);
+            let path = dir.join(name);
+            if path.is_file() {
+                let format = ConfigFormat::from_path(&path)?;
+                return Some((path, format));
+            }
+        }
+    }
+    None
+}
+
+/// Overrides the settings for which there is a `CRUST_<SETTING>` variable, e.g.
+/// `CRUST_TCP_ACCEPTOR_PORT=5483`. The value is read like the setting in the config file, so
+/// `CRUST_HARD_CODED_CONTACTS` takes the same JSON list. Values which aren't JSON are taken as
+/// strings, e.g. `CRUST_NETWORK_NAME=test_net`. Variables which don't name a setting are ignored.
+pub fn override_with_env_vars<I>(mut config: Config, vars: I) -> crate::Res<Config>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut overrides: Vec<_> = vars
+        .into_iter()
+        .filter(|&(ref var, _)| var.starts_with(ENV_VAR_PREFIX))
+        .collect();
+    if overrides.is_empty() {
+        return Ok(config);
+    }
+    // Applied in a fixed order so that errors don't depend on the order of the environment.
+    overrides.sort();
+
+    let mut json = unwrap!(serde_json::to_value(&config));
+    for (var, value) in overrides {
+        let setting = var[ENV_VAR_PREFIX.len()..].to_lowercase();
+        if json.get(&setting).is_none() {
+            continue;
+        }
+        let parsed = serde_json::from_str::<serde_json::Value>(&value).ok();
+        let candidates = parsed
+            .into_iter()
+            .chain(Some(serde_json::Value::String(value.clone())));
+        let mut overridden = None;
+        for candidate in candidates {
+            let mut new_json = json.clone();
+            new_json[&setting] = candidate;
+            if let Ok(new_config) = serde_json::from_value(new_json.clone()) {
+                json = new_json;
+                overridden = Some(new_config);
+                break;
+            }
+        }
+        config = overridden.ok_or_else(|| {
+            CrustError::InvalidConfig(ConfigError::new(
+                setting,
+                format!("can't be set to {:?} via {}", value, var),
+            ))
+        })?;
+    }
+    Ok(config)
+}
+
 /// Writes a Crust config file **for use by tests and examples**.
 ///
 /// The file is written to the [`current_bin_dir()`](file_handler/fn.current_bin_dir.html)
@@ -86,7 +988,6 @@ pub fn read_config_file() -> crate::Res<Config> {
 #[cfg(test)]
 #[allow(dead_code)]
 pub fn write_config_file(hard_coded_contacts: Option<Vec<PeerInfo>>) -> crate::Res<PathBuf> {
-    use serde_json;
     use std::io::Write;
 
     let mut config = Config::default();
@@ -115,7 +1016,8 @@ fn get_file_name() -> crate::Res<OsString> {
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use super::{override_with_env_vars, Config};
+    use crate::main::CrustError;
     use serde_json;
     use std::io::Read;
     use std::path::Path;
@@ -139,4 +1041,275 @@ mod tests {
             panic!(format!("CrustError parsing sample.config: {:?}", what));
         }
     }
+
+    #[test]
+    fn forbidden_subnets_take_precedence_over_allowed_ones() {
+        let mut config = Config::default();
+        assert!(config.is_bootstrap_ip_allowed(&unwrap!("10.1.2.3".parse())));
+
+        config.allowed_subnets = Some(vec![unwrap!("10.0.0.0/8".parse())]);
+        config.forbidden_subnets = vec![unwrap!("10.1.0.0/16".parse())];
+        assert!(config.is_bootstrap_ip_allowed(&unwrap!("10.2.3.4".parse())));
+        assert!(!config.is_bootstrap_ip_allowed(&unwrap!("10.1.2.3".parse())));
+        assert!(!config.is_bootstrap_ip_allowed(&unwrap!("192.168.0.1".parse())));
+    }
+
+    #[test]
+    fn validate_rejects_unworkable_settings() {
+        assert!(Config::default().validate().is_ok());
+
+        let mut config = Config::default();
+        config.heartbeat_period_ms = Some(1_000);
+        config.inactivity_timeout_ms = Some(1_000);
+        match config.validate() {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        let mut config = Config::default();
+        config.tcp_acceptor_port = Some(5483);
+        config.extra_tcp_acceptor_ports = vec![5484, 5483];
+        assert!(config.validate().is_err());
+        config.extra_tcp_acceptor_ports = vec![0, 0, 5484];
+        assert!(config.validate().is_ok());
+
+        let mut config = Config::default();
+        config.max_msg_size = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_lists_every_problem() {
+        use crate::common::{ipv4_addr, IpSubnet, PeerInfo};
+        use safe_crypto::gen_encrypt_keypair;
+        use std::str::FromStr;
+
+        let (pk, _) = gen_encrypt_keypair();
+        let mut config = Config::default();
+        config.hard_coded_contacts = vec![
+            PeerInfo::new(ipv4_addr(10, 0, 0, 1, 5483), pk),
+            PeerInfo::new(ipv4_addr(0, 0, 0, 0, 5483), pk),
+            PeerInfo::new(ipv4_addr(192, 168, 0, 1, 5483), pk),
+        ];
+        config.forbidden_subnets = vec![unwrap!(IpSubnet::from_str("192.168.0.0/16"))];
+        config.bootstrap_pow_difficulty = Some(30);
+        config.max_connections = Some(0);
+
+        let problems = match config.validate() {
+            Err(CrustError::InvalidConfig(e)) => e.problems,
+            res => panic!("Unexpected result {:?}", res),
+        };
+        let paths: Vec<_> = problems.iter().map(|problem| &problem.path[..]).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "max_connections",
+                "bootstrap_pow_difficulty",
+                "hard_coded_contacts[1].addr",
+                "hard_coded_contacts[2].addr",
+            ]
+        );
+    }
+
+    #[test]
+    fn client_whitelist_is_not_bypassed_without_reachability_test() {
+        use std::collections::HashSet;
+        use std::net::IpAddr;
+        use std::str::FromStr;
+
+        let ip = unwrap!(IpAddr::from_str("8.8.8.8"));
+        let mut config = Config::default();
+        config.whitelisted_client_ips = Some(vec![ip].into_iter().collect());
+        unwrap!(config.validate());
+
+        config.disable_external_reachability_test = true;
+        match config.validate() {
+            Err(CrustError::InvalidConfig(e)) => {
+                let paths: Vec<_> = e.problems.iter().map(|problem| &problem.path[..]).collect();
+                assert_eq!(paths, vec!["whitelisted_client_ips"]);
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        config.whitelisted_node_ips = Some(vec![ip].into_iter().collect());
+        unwrap!(config.validate());
+
+        config.whitelisted_node_ips = Some(HashSet::new());
+        match config.validate() {
+            Err(CrustError::InvalidConfig(e)) => {
+                let paths: Vec<_> = e.problems.iter().map(|problem| &problem.path[..]).collect();
+                assert_eq!(paths, vec!["whitelisted_node_ips"]);
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn toml_and_yaml_configs_are_parsed_like_json() {
+        use super::{ConfigFormat, ListenerConfig};
+        use std::net::IpAddr;
+        use std::path::Path;
+        use std::str::FromStr;
+
+        let toml = r#"
+            hard_coded_contacts = []
+            force_acceptor_port_in_ext_ep = false
+            network_name = "test_net"
+            whitelisted_node_ips = ["8.8.8.8"]
+
+            [[listeners]]
+            port = 5483
+            port_range_end = 5490
+        "#;
+        let yaml = r#"
+            hard_coded_contacts: []
+            force_acceptor_port_in_ext_ep: false
+            network_name: test_net
+            whitelisted_node_ips: ["8.8.8.8"]
+            listeners:
+              - port: 5483
+                port_range_end: 5490
+        "#;
+        let json = r#"{
+            "hard_coded_contacts": [],
+            "force_acceptor_port_in_ext_ep": false,
+            "network_name": "test_net",
+            "whitelisted_node_ips": ["8.8.8.8"],
+            "listeners": [{"port": 5483, "port_range_end": 5490}]
+        }"#;
+
+        let mut expected = Config::default();
+        expected.network_name = Some("test_net".to_owned());
+        expected.whitelisted_node_ips = Some(
+            vec![unwrap!(IpAddr::from_str("8.8.8.8"))]
+                .into_iter()
+                .collect(),
+        );
+        expected.listeners = vec![ListenerConfig {
+            port_range_end: Some(5490),
+            ..ListenerConfig::tcp(5483)
+        }];
+
+        for &(name, contents) in &[
+            ("crust.crust.toml", toml),
+            ("crust.crust.yml", yaml),
+            ("crust.crust.config", json),
+        ] {
+            let format = unwrap!(ConfigFormat::from_path(Path::new(name)));
+            assert_eq!(unwrap!(format.parse(contents)), expected);
+        }
+
+        assert_eq!(ConfigFormat::from_path(Path::new("crust.crust.ini")), None);
+        match ConfigFormat::Toml.parse("hard_coded_contacts = 1") {
+            Err(CrustError::ConfigParse(ConfigFormat::Toml, _)) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn included_configs_are_merged() {
+        use super::{read_settings, ConfigFormat};
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("crust_include_{}", std::process::id()));
+        unwrap!(fs::create_dir_all(&dir));
+        let base = r#"
+            hard_coded_contacts = []
+            force_acceptor_port_in_ext_ep = false
+            network_name = "test_net"
+            tcp_acceptor_port = 5483
+            extra_tcp_acceptor_ports = [5484, 5485]
+
+            [peer_scoring]
+            throttled_penalty = 1
+            protocol_error_penalty = 2
+            unanswered_ping_penalty = 3
+            ban_duration_sec = 4
+        "#;
+        unwrap!(fs::write(dir.join("base.crust.toml"), base));
+        let node = r#"{
+            "include": ["base.crust.toml"],
+            "tcp_acceptor_port": null,
+            "extra_tcp_acceptor_ports": [6000],
+            "peer_scoring": {"ban_duration_sec": 60}
+        }"#;
+        let node_path = dir.join("node.crust.config");
+        unwrap!(fs::write(&node_path, node));
+
+        let settings = unwrap!(read_settings(&node_path, ConfigFormat::Json, 0));
+        let config: Config = unwrap!(serde_json::from_value(settings));
+        assert_eq!(config.network_name, Some("test_net".to_owned()));
+        assert_eq!(config.tcp_acceptor_port, None);
+        assert_eq!(config.extra_tcp_acceptor_ports, vec![6000]);
+        assert_eq!(config.peer_scoring.throttled_penalty, 1);
+        assert_eq!(config.peer_scoring.ban_duration_sec, 60);
+        assert_eq!(
+            config.include,
+            vec![Path::new("base.crust.toml").to_path_buf()]
+        );
+
+        let looping_path = dir.join("looping.crust.config");
+        unwrap!(fs::write(
+            &looping_path,
+            r#"{"include": ["looping.crust.config"]}"#
+        ));
+        match read_settings(&looping_path, ConfigFormat::Json, 0) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        unwrap!(fs::remove_dir_all(&dir));
+    }
+
+    #[test]
+    fn env_vars_override_settings() {
+        let vars = vec![
+            ("CRUST_TCP_ACCEPTOR_PORT".to_owned(), "5483".to_owned()),
+            ("CRUST_NETWORK_NAME".to_owned(), "test_net".to_owned()),
+            ("CRUST_HARD_CODED_CONTACTS".to_owned(), "[]".to_owned()),
+            ("CRUST_LOG_LEVEL".to_owned(), "debug".to_owned()),
+            ("CRUST_NOT_A_SETTING".to_owned(), "1".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ];
+        let config = unwrap!(override_with_env_vars(Config::default(), vars));
+        assert_eq!(config.tcp_acceptor_port, Some(5483));
+        assert_eq!(config.network_name, Some("test_net".to_owned()));
+        assert!(config.hard_coded_contacts.is_empty());
+        assert_eq!(config.log_level, Some("debug".to_owned()));
+        unwrap!(config.validate());
+
+        let vars = vec![("CRUST_LOG_LEVEL".to_owned(), "loud".to_owned())];
+        let config = unwrap!(override_with_env_vars(Config::default(), vars));
+        match config.validate() {
+            Err(CrustError::InvalidConfig(e)) => assert_eq!(e.problems[0].path, "log_level"),
+            res => panic!("Unexpected result {:?}", res),
+        }
+
+        // Numbers are taken as strings where the setting is one.
+        let vars = vec![("CRUST_NETWORK_NAME".to_owned(), "42".to_owned())];
+        let config = unwrap!(override_with_env_vars(Config::default(), vars));
+        assert_eq!(config.network_name, Some("42".to_owned()));
+
+        let vars = vec![("CRUST_TCP_ACCEPTOR_PORT".to_owned(), "70000".to_owned())];
+        match override_with_env_vars(Config::default(), vars) {
+            Err(CrustError::InvalidConfig(_)) => (),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn changed_settings_are_listed_by_name() {
+        let config = Config::default();
+        assert!(config.changed_settings(&config.clone()).is_empty());
+
+        let mut new_config = config.clone();
+        new_config.tcp_acceptor_port = Some(5483);
+        new_config.inbound_msgs_per_sec = Some(100);
+        assert_eq!(
+            config.changed_settings(&new_config),
+            vec!["tcp_acceptor_port", "inbound_msgs_per_sec"]
+        );
+        assert!(Config::requires_restart("tcp_acceptor_port"));
+        assert!(!Config::requires_restart("inbound_msgs_per_sec"));
+    }
 }
@@ -9,7 +9,10 @@
 
 use crate::common::{CoreTimer, CrustUser, State, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{read_config_file, ActiveConnection, ConnectionMap, CrustConfig, EventLoopCore};
+use crate::main::{
+    read_config_file, ActiveConnection, Config, ConnectionMap, CrustConfig, Event, EventLoopCore,
+    EventSender,
+};
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use std::any::Any;
@@ -25,6 +28,7 @@ pub struct ConfigRefresher<UID: Uid> {
     timeout: Timeout,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
+    event_tx: EventSender<UID>,
 }
 
 impl<UID: Uid> ConfigRefresher<UID> {
@@ -33,6 +37,7 @@ impl<UID: Uid> ConfigRefresher<UID> {
         token: Token,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
+        event_tx: EventSender<UID>,
     ) -> crate::Res<()> {
         trace!("Entered state ConfigRefresher");
 
@@ -45,6 +50,7 @@ impl<UID: Uid> ConfigRefresher<UID> {
             timeout,
             cm,
             config,
+            event_tx,
         }));
         let _ = core.insert_state(token, state);
 
@@ -72,71 +78,100 @@ impl<UID: Uid> State<BootstrapCache> for ConfigRefresher<UID> {
             }
         };
 
-        let whitelisted_node_ips = config.whitelisted_node_ips.clone();
-        let whitelisted_client_ips = config.whitelisted_client_ips.clone();
+        reload_config(core, poll, &self.cm, &self.config, &self.event_tx, config);
+    }
 
-        if !unwrap!(self.config.lock()).check_for_refresh_and_reset_modified(config)
-            || (whitelisted_node_ips.is_none() && whitelisted_client_ips.is_none())
-        {
-            return;
-        }
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
 
+/// Takes over the new config, drops the peers which aren't whitelisted by it any more and reports
+/// what changed via `Event::ConfigReloaded`.
+pub fn reload_config<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+    event_tx: &EventSender<UID>,
+    new_config: Config,
+) {
+    let changed = unwrap!(config.lock()).refresh(new_config);
+    if changed.is_empty() {
+        return;
+    }
+
+    if changed
+        .iter()
+        .any(|&setting| setting == "whitelisted_node_ips" || setting == "whitelisted_client_ips")
+    {
         trace!(
             "Crust config has been updated - going to purge any nodes or clients that are no \
              longer whitelisted"
         );
-
-        // Peers collected to avoid keeping the mutex lock alive which might lead to deadlock
-        let peers_to_terminate: Vec<_> = unwrap!(self.cm.lock())
-            .values()
-            .filter_map(|cid| {
-                cid.active_connection
-                    .and_then(|token| core.get_state(token))
-                    .and_then(|peer| {
-                        let should_drop = {
-                            let mut state = peer.borrow_mut();
-                            let ac = match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
-                                Some(ac) => ac,
-                                None => {
-                                    warn!(
-                                        "Token reserved for ActiveConnection has something else."
-                                    );
-                                    return None;
-                                }
-                            };
-                            match ac.peer_addr() {
-                                Err(e) => {
-                                    debug!(
-                                        "Could not obtain Peer IP: {:?} - dropping this peer.",
-                                        e
-                                    );
-                                    true
-                                }
-                                Ok(s) => match ac.peer_kind() {
-                                    CrustUser::Node => whitelisted_node_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                    CrustUser::Client => whitelisted_client_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                },
-                            }
-                        };
-                        if should_drop {
-                            Some(peer)
-                        } else {
-                            None
-                        }
-                    })
-            })
-            .collect();
-
-        for peer in peers_to_terminate {
-            peer.borrow_mut().terminate(core, poll);
-        }
+        drop_non_whitelisted(core, poll, cm, config);
     }
 
-    fn as_any(&mut self) -> &mut Any {
-        self
+    let (requires_restart, applied): (Vec<_>, Vec<_>) = changed
+        .into_iter()
+        .partition(|setting| Config::requires_restart(setting));
+    let _ = event_tx.send(Event::ConfigReloaded {
+        applied,
+        requires_restart,
+    });
+}
+
+/// Drops all active connections to peers which aren't whitelisted for their kind, see
+/// `ConfigWrapper::whitelisted_node_ips`. A `None` whitelist allows everyone.
+pub fn drop_non_whitelisted<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+) {
+    let (whitelisted_node_ips, whitelisted_client_ips) = {
+        let guard = unwrap!(config.lock());
+        (
+            guard.whitelisted_node_ips().cloned(),
+            guard.whitelisted_client_ips().cloned(),
+        )
+    };
+
+    // Tokens collected to avoid keeping the mutex lock alive which might lead to deadlock
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    for token in tokens {
+        let peer = match core.get_state(token) {
+            Some(peer) => peer,
+            None => continue,
+        };
+        let mut state = peer.borrow_mut();
+        let ac = match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            Some(ac) => ac,
+            None => {
+                warn!("Token reserved for ActiveConnection has something else.");
+                continue;
+            }
+        };
+        let should_drop = match ac.peer_addr() {
+            Err(e) => {
+                debug!("Could not obtain Peer IP: {:?} - dropping this peer.", e);
+                true
+            }
+            Ok(s) => match ac.peer_kind() {
+                CrustUser::Node => whitelisted_node_ips
+                    .as_ref()
+                    .map_or(false, |ips| !ips.contains(&s.ip())),
+                CrustUser::Client => whitelisted_client_ips
+                    .as_ref()
+                    .map_or(false, |ips| !ips.contains(&s.ip())),
+            },
+        };
+        if should_drop {
+            ac.drop_not_whitelisted(core, poll);
+        }
     }
 }
@@ -7,7 +7,7 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{Message, NameHash, State, Uid};
+use crate::common::{Message, NameHash, State, Uid, PROTOCOL_VERSION};
 use crate::main::bootstrap::Cache as BootstrapCache;
 use crate::main::{ConnectionId, ConnectionMap, EventLoopCore};
 use mio::{Poll, PollOpt, Ready, Token};
@@ -22,8 +22,9 @@ use std::net::SocketAddr;
 use std::rc::Rc;
 
 /// When connection messages are exchanged a callback is called with these parameters.
-/// A new mio `Token` is assigned to the given socket.
-pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpSock>)>;
+/// A new mio `Token` is assigned to the given socket, which comes with whether the peer takes
+/// compressed data.
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<(TcpSock, bool)>)>;
 
 /// Exchanges connect messages.
 pub struct ExchangeMsg<UID: Uid> {
@@ -49,6 +50,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
         our_pk: PublicEncryptKey,
         shared_key: SharedSecretKey,
         our_global_direct_listeners: HashSet<SocketAddr>,
+        accepts_compression: bool,
         finish: Finish,
     ) -> crate::Res<Token> {
         let token = core.get_new_token();
@@ -83,7 +85,14 @@ impl<UID: Uid> ExchangeMsg<UID> {
             socket,
             cm,
             msg: Some((
-                Message::ConnectRequest(our_id, name_hash, our_global_direct_listeners, our_pk),
+                Message::ConnectRequest(
+                    our_id,
+                    name_hash,
+                    PROTOCOL_VERSION,
+                    our_global_direct_listeners,
+                    our_pk,
+                    accepts_compression,
+                ),
                 0,
             )),
             shared_key,
@@ -108,8 +117,24 @@ impl<UID: Uid> ExchangeMsg<UID> {
 
     fn receive_response(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::ConnectResponse(their_uid, name_hash))) => {
-                if their_uid != self.expected_id || name_hash != self.expected_nh {
+            Ok(Some(Message::ConnectResponse(
+                their_uid,
+                name_hash,
+                their_version,
+                peer_accepts_compression,
+            ))) => {
+                if their_uid != self.expected_id {
+                    return self.handle_error(core, poll);
+                }
+                if name_hash != self.expected_nh {
+                    warn!("{:?} belongs to a different network.", their_uid);
+                    return self.handle_error(core, poll);
+                }
+                if their_version != PROTOCOL_VERSION {
+                    warn!(
+                        "{:?} speaks protocol version {}, we speak {}.",
+                        their_uid, their_version, PROTOCOL_VERSION
+                    );
                     return self.handle_error(core, poll);
                 }
                 let _ = core.remove_state(self.token);
@@ -118,13 +143,19 @@ impl<UID: Uid> ExchangeMsg<UID> {
                 let mut socket = mem::replace(&mut self.socket, Default::default());
                 match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
                 {
-                    Ok(_) => (*self.finish)(core, poll, token, Some(socket)),
+                    Ok(_) => {
+                        (*self.finish)(core, poll, token, Some((socket, peer_accepts_compression)))
+                    }
                     Err(e) => {
                         warn!("Failed to set socket encrypt context: {}", e);
                         self.handle_error(core, poll);
                     }
                 }
             }
+            Ok(Some(Message::BootstrapDenied(reason))) => {
+                warn!("{:?} denied the connection: {:?}", self.expected_id, reason);
+                self.handle_error(core, poll)
+            }
             Ok(None) => (),
             Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
         }
@@ -0,0 +1,240 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::State;
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::EventLoopCore;
+use mio::net::TcpStream;
+use mio::{Poll, PollOpt, Ready, Token};
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Proxies are not expected to send long responses to `CONNECT`. Anything bigger than this is
+/// treated as a failure.
+const MAX_RESPONSE_HEADER_SIZE: usize = 8 * 1024;
+
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpStream>)>;
+
+/// Opens a tunnel to the given address through an HTTP proxy using the `CONNECT` method.
+///
+/// On success the raw stream is handed over to `finish`, deregistered from the event loop.
+pub struct HttpTunnel {
+    token: Token,
+    /// Taken out once the tunnel is established.
+    stream: Option<TcpStream>,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    finish: Finish,
+}
+
+impl HttpTunnel {
+    pub fn start(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        proxy: SocketAddr,
+        target: SocketAddr,
+        finish: Finish,
+    ) -> crate::Res<Token> {
+        let stream = TcpStream::connect(&proxy)?;
+        let token = core.get_new_token();
+
+        poll.register(
+            &stream,
+            token,
+            Ready::writable() | Ready::readable(),
+            PollOpt::edge(),
+        )?;
+
+        let state = Self {
+            token,
+            stream: Some(stream),
+            request: connect_request(&target),
+            response: Vec::new(),
+            finish,
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return,
+        };
+        while !self.request.is_empty() {
+            match stream.write(&self.request) {
+                Ok(0) => return self.handle_error(core, poll),
+                Ok(n) => {
+                    let _ = self.request.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    debug!("Failed to send CONNECT request to HTTP proxy: {}", e);
+                    return self.handle_error(core, poll);
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let mut buf = [0; 1024];
+        loop {
+            let res = match self.stream {
+                Some(ref mut stream) => stream.read(&mut buf),
+                None => return,
+            };
+            match res {
+                Ok(0) => return self.handle_error(core, poll),
+                Ok(n) => self.response.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Failed to read HTTP proxy response: {}", e);
+                    return self.handle_error(core, poll);
+                }
+            }
+
+            match parse_response(&self.response) {
+                Ok(Some(())) => return self.handle_success(core, poll),
+                Ok(None) => (),
+                Err(()) => {
+                    debug!(
+                        "HTTP proxy refused to tunnel: {:?}",
+                        String::from_utf8_lossy(&self.response)
+                    );
+                    return self.handle_error(core, poll);
+                }
+            }
+        }
+    }
+
+    fn handle_success(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.terminate(core, poll);
+        let stream = self.stream.take();
+        (*self.finish)(core, poll, self.token, stream);
+    }
+
+    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.terminate(core, poll);
+        (*self.finish)(core, poll, self.token, None);
+    }
+}
+
+impl State<BootstrapCache> for HttpTunnel {
+    fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
+        if kind.is_writable() || kind.is_readable() {
+            if kind.is_writable() {
+                self.write(core, poll);
+            }
+            if kind.is_readable() {
+                self.read(core, poll);
+            }
+            return;
+        }
+
+        debug!(
+            "Considering the following event to indicate dirupted connection: {:?}",
+            kind
+        );
+        self.handle_error(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        if let Some(ref stream) = self.stream {
+            let _ = poll.deregister(stream);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn connect_request(target: &SocketAddr) -> Vec<u8> {
+    format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Connection: Keep-Alive\r\n\r\n",
+        target
+    )
+    .into_bytes()
+}
+
+/// Returns `Ok(None)` if the response header is not complete yet, `Ok(Some(()))` if the proxy
+/// established the tunnel and `Err(())` otherwise.
+///
+/// The remote peer never speaks first, so any data following the header is a protocol violation.
+fn parse_response(response: &[u8]) -> Result<Option<()>, ()> {
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if response.len() > MAX_RESPONSE_HEADER_SIZE => return Err(()),
+        None => return Ok(None),
+    };
+    if header_end != response.len() {
+        return Err(());
+    }
+
+    let status_line = response.split(|&b| b == b'\r').next().unwrap_or(&[]);
+    let mut parts = status_line.split(|&b| b == b' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with(b"HTTP/1.") && status == b"200" => {
+            Ok(Some(()))
+        }
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+
+    #[test]
+    fn connect_request_names_target() {
+        let request = connect_request(&ipv4_addr(1, 2, 3, 4, 443));
+        assert_eq!(
+            unwrap!(String::from_utf8(request)),
+            "CONNECT 1.2.3.4:443 HTTP/1.1\r\nHost: 1.2.3.4:443\r\nProxy-Connection: Keep-Alive\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_response_accepts_established_tunnel() {
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 Connection established"),
+            Ok(None)
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 Connection established\r\n\r\n"),
+            Ok(Some(()))
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.0 200 OK\r\nProxy-Agent: test\r\n\r\n"),
+            Ok(Some(()))
+        );
+    }
+
+    #[test]
+    fn parse_response_rejects_errors() {
+        assert_eq!(
+            parse_response(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"),
+            Err(())
+        );
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n\r\nextra"), Err(()));
+        assert_eq!(
+            parse_response(&[b'a'; MAX_RESPONSE_HEADER_SIZE + 1]),
+            Err(())
+        );
+    }
+}
@@ -8,40 +8,76 @@
 // Software.
 
 mod exchange_msg;
+mod http_tunnel;
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid};
+use self::http_tunnel::HttpTunnel;
+use crate::common::{connect_tcp, CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid};
 use crate::main::bootstrap;
 use crate::main::{
-    ActiveConnection, ConnectionCandidate, ConnectionMap, CrustConfig, CrustError, Event,
-    EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    ActiveConnection, Blacklist, ConnectOrder, ConnectStats, ConnectionCandidate, ConnectionMap,
+    CrustConfig, CrustError, Event, EventLoopCore, EventSender, PrivConnectionInfo,
+    PubConnectionInfo,
 };
+use crate::nat::ip_addr_is_global;
+use mio::net::TcpStream;
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use safe_crypto::{PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
 use socket_collection::{DecryptContext, EncryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const TIMEOUT_SEC: u64 = 60;
+const CONNECT_TIMER_ID: u8 = 0;
+const STAGGER_TIMER_ID: u8 = CONNECT_TIMER_ID + 1;
+/// Delay between connection attempts when racing IPv6 and IPv4 addresses (RFC 8305 recommends
+/// 250 ms).
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
 
 /// Atempts multiple connections to remote peer, but yields the first successful one.
+///
+/// If the peer advertises both IPv6 and IPv4 addresses, they are attempted alternately with a
+/// short stagger (Happy Eyeballs) instead of all at once. Otherwise all addresses are attempted
+/// simultaneously. If the peer seems to be behind the same NAT as we are, its private addresses
+/// are attempted first.
+///
+/// If an HTTP proxy is configured, the peer's global addresses are attempted through `CONNECT`
+/// tunnels as well, before, after or alongside the direct attempts depending on
+/// `Config::connect_order`.
 pub struct Connect<UID: Uid> {
     token: Token,
     timeout: Timeout,
+    stagger_timeout: Option<Timeout>,
+    order: ConnectOrder,
+    /// Direct attempts which haven't started yet because tunnels go first: the addresses to
+    /// attempt immediately and the ones to attempt one by one.
+    direct_addrs: Option<(Vec<SocketAddr>, VecDeque<SocketAddr>)>,
+    pending_addrs: VecDeque<SocketAddr>,
+    tunnel_addrs: Vec<SocketAddr>,
+    /// Sockets which got through the way that loses under the connect order, with the peer
+    /// address and whether they are tunnelled. They wait for the attempts of the other way.
+    held: Vec<(TcpSock, SocketAddr, bool)>,
+    started: Instant,
+    stats: ConnectStats,
     cm: ConnectionMap<UID>,
     our_nh: NameHash,
     our_id: UID,
     their_id: UID,
     self_weak: Weak<RefCell<Connect<UID>>>,
-    children: HashSet<Token>,
-    event_tx: crate::CrustEventSender<UID>,
+    /// Ongoing attempts, with whether they are tunnelled.
+    children: HashMap<Token, bool>,
+    event_tx: EventSender<UID>,
     our_pk: PublicEncryptKey,
+    our_sk: SecretEncryptKey,
+    their_pk: PublicEncryptKey,
     config: CrustConfig,
+    blacklist: Blacklist<UID>,
     our_global_direct_listeners: HashSet<SocketAddr>,
 }
 
@@ -53,11 +89,12 @@ impl<UID: Uid> Connect<UID> {
         their_ci: PubConnectionInfo<UID>,
         cm: ConnectionMap<UID>,
         our_nh: NameHash,
-        event_tx: crate::CrustEventSender<UID>,
+        event_tx: EventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
         our_global_direct_listeners: HashSet<SocketAddr>,
         config: CrustConfig,
+        blacklist: Blacklist<UID>,
     ) -> crate::Res<()> {
         let their_id = their_ci.id;
         let their_direct = their_ci.for_direct;
@@ -68,51 +105,223 @@ impl<UID: Uid> Connect<UID> {
         }
 
         let token = core.get_new_token();
+        let (tunnel_addrs, order) = {
+            let cfg = &unwrap!(config.lock()).cfg;
+            if cfg.http_proxy.is_some() {
+                let tunnel_addrs = their_direct
+                    .iter()
+                    .filter(|addr| ip_addr_is_global(&addr.ip()))
+                    .cloned()
+                    .collect();
+                (tunnel_addrs, cfg.connect_order)
+            } else {
+                (Vec::new(), ConnectOrder::DirectFirst)
+            }
+        };
+        let direct_addrs = match same_nat_order(&their_direct, &our_global_direct_listeners) {
+            Some(ordered) => ordered,
+            None => happy_eyeballs_order(their_direct),
+        };
 
         let state = Rc::new(RefCell::new(Self {
             token,
-            timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
+            timeout: core.set_timeout(
+                Duration::from_secs(TIMEOUT_SEC),
+                CoreTimer::new(token, CONNECT_TIMER_ID),
+            ),
+            stagger_timeout: None,
+            order,
+            direct_addrs: Some(direct_addrs),
+            pending_addrs: VecDeque::new(),
+            tunnel_addrs,
+            held: Vec::new(),
+            started: Instant::now(),
+            stats: Default::default(),
             cm,
             our_nh,
             our_id: our_ci.id,
             their_id,
             self_weak: Weak::new(),
-            children: HashSet::with_capacity(their_direct.len()),
+            children: HashMap::new(),
             event_tx,
             our_pk,
+            our_sk: our_sk.clone(),
+            their_pk: their_ci.our_pk,
             our_global_direct_listeners,
             config,
+            blacklist,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
-        let their_pk = their_ci.our_pk;
-        let sockets = their_direct
-            .into_iter()
-            .filter_map(|addr| {
-                let info = PeerInfo::new(addr, their_pk);
-                TcpSock::connect(&addr).map(|sock| (sock, info)).ok()
-            })
-            .collect::<Vec<_>>();
-
-        for (mut socket, peer_info) in sockets {
-            let shared_key = our_sk.shared_secret(&their_ci.our_pk);
-            match (
-                socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(their_ci.our_pk)),
-                socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())),
-            ) {
-                (Ok(_), Ok(_)) => state
-                    .borrow_mut()
-                    .exchange_msg(core, poll, socket, peer_info, shared_key),
-                res => warn!("Failed to set encrypt/decrypt context: {:?}", res),
+        let _ = core.insert_state(token, state.clone());
+
+        let mut state_mut = state.borrow_mut();
+        match order {
+            ConnectOrder::DirectFirst => state_mut.start_direct(core, poll),
+            ConnectOrder::TunnelFirst => state_mut.tunnel_via_proxy(core, poll),
+            ConnectOrder::ParallelPreferDirect | ConnectOrder::ParallelPreferTunnel => {
+                state_mut.start_direct(core, poll);
+                state_mut.tunnel_via_proxy(core, poll);
             }
         }
-
-        let _ = core.insert_state(token, state);
+        state_mut.maybe_terminate(core, poll);
 
         Ok(())
     }
 
+    /// Dials the addresses to attempt immediately and schedules the others.
+    fn start_direct(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let (immediate, pending_addrs) = match self.direct_addrs.take() {
+            Some(direct_addrs) => direct_addrs,
+            None => return,
+        };
+        self.pending_addrs = pending_addrs;
+        for addr in immediate {
+            self.dial(core, poll, addr);
+        }
+        self.schedule_next_attempt(core);
+    }
+
+    fn dial(&mut self, core: &mut EventLoopCore, poll: &Poll, addr: SocketAddr) {
+        self.stats.direct_attempts.push(addr);
+        let (bind_ip, socket_options) = {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            (cfg.bind_ip, cfg.socket_options)
+        };
+        match connect_tcp(&addr, bind_ip, &socket_options) {
+            Ok(socket) => self.got_through(core, poll, socket, addr, false),
+            Err(e) => debug!("Failed to connect to {}: {:?}", addr, e),
+        }
+    }
+
+    /// Starts the handshake over a socket to the peer, unless it has to wait for the attempts
+    /// which win over it under the connect order.
+    fn got_through(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        socket: TcpSock,
+        addr: SocketAddr,
+        tunnelled: bool,
+    ) {
+        if self.loses_to_others(tunnelled) && self.winners_in_flight() {
+            return self.held.push((socket, addr, tunnelled));
+        }
+        self.handshake(core, poll, socket, addr, tunnelled)
+    }
+
+    /// Whether a connection that went this way loses to one that went the other way.
+    fn loses_to_others(&self, tunnelled: bool) -> bool {
+        match self.order {
+            ConnectOrder::ParallelPreferDirect => tunnelled,
+            ConnectOrder::ParallelPreferTunnel => !tunnelled,
+            ConnectOrder::DirectFirst | ConnectOrder::TunnelFirst => false,
+        }
+    }
+
+    /// Whether attempts which win over the held sockets are still going on.
+    fn winners_in_flight(&self) -> bool {
+        match self.order {
+            ConnectOrder::ParallelPreferDirect => {
+                self.direct_addrs.is_some()
+                    || !self.pending_addrs.is_empty()
+                    || self.children.values().any(|&tunnelled| !tunnelled)
+            }
+            ConnectOrder::ParallelPreferTunnel => {
+                !self.tunnel_addrs.is_empty() || self.children.values().any(|&tunnelled| tunnelled)
+            }
+            ConnectOrder::DirectFirst | ConnectOrder::TunnelFirst => false,
+        }
+    }
+
+    fn handshake(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        mut socket: TcpSock,
+        addr: SocketAddr,
+        tunnelled: bool,
+    ) {
+        let peer_info = PeerInfo::new(addr, self.their_pk);
+        let shared_key = self.our_sk.shared_secret(&self.their_pk);
+        match (
+            socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(self.their_pk)),
+            socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())),
+        ) {
+            (Ok(_), Ok(_)) => {
+                self.exchange_msg(core, poll, socket, peer_info, shared_key, tunnelled)
+            }
+            res => warn!("Failed to set encrypt/decrypt context: {:?}", res),
+        }
+    }
+
+    /// Arms the stagger timer if there are any addresses we haven't tried yet.
+    fn schedule_next_attempt(&mut self, core: &mut EventLoopCore) {
+        if let Some(timeout) = self.stagger_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if !self.pending_addrs.is_empty() {
+            self.stagger_timeout = Some(core.set_timeout(
+                Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS),
+                CoreTimer::new(self.token, STAGGER_TIMER_ID),
+            ));
+        }
+    }
+
+    fn dial_next_pending(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if let Some(addr) = self.pending_addrs.pop_front() {
+            self.dial(core, poll, addr);
+        }
+        self.schedule_next_attempt(core);
+    }
+
+    /// Starts tunnelling to every remaining peer address through the configured HTTP proxy.
+    fn tunnel_via_proxy(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let proxy = match unwrap!(self.config.lock()).cfg.http_proxy {
+            Some(proxy) => proxy,
+            None => return self.tunnel_addrs.clear(),
+        };
+
+        for addr in mem::replace(&mut self.tunnel_addrs, Vec::new()) {
+            let self_weak = self.self_weak.clone();
+            let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
+                if let Some(self_rc) = self_weak.upgrade() {
+                    self_rc
+                        .borrow_mut()
+                        .handle_tunnel(core, poll, child, res, addr);
+                }
+            };
+
+            match HttpTunnel::start(core, poll, proxy, addr, Box::new(handler)) {
+                Ok(child) => {
+                    self.stats.tunnelled_attempts.push(addr);
+                    let _ = self.children.insert(child, true);
+                }
+                Err(e) => debug!("Failed to connect to HTTP proxy {}: {:?}", proxy, e),
+            }
+        }
+    }
+
+    fn handle_tunnel(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        child: Token,
+        res: Option<TcpStream>,
+        addr: SocketAddr,
+    ) {
+        let _ = self.children.remove(&child);
+        if let Some(stream) = res {
+            let socket_options = unwrap!(self.config.lock()).cfg.socket_options;
+            if let Err(e) = socket_options.apply(&stream) {
+                debug!("Failed to set socket options: {:?}", e);
+            }
+            self.got_through(core, poll, TcpSock::wrap(stream), addr, true);
+        }
+        self.maybe_terminate(core, poll);
+    }
+
     fn exchange_msg(
         &mut self,
         core: &mut EventLoopCore,
@@ -120,13 +329,14 @@ impl<UID: Uid> Connect<UID> {
         socket: TcpSock,
         peer_info: PeerInfo,
         shared_key: SharedSecretKey,
+        tunnelled: bool,
     ) {
         let self_weak = self.self_weak.clone();
         let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
             if let Some(self_rc) = self_weak.upgrade() {
                 self_rc
                     .borrow_mut()
-                    .handle_exchange_msg(core, poll, child, res, peer_info);
+                    .handle_exchange_msg(core, poll, child, res, peer_info, tunnelled);
             }
         };
 
@@ -141,11 +351,14 @@ impl<UID: Uid> Connect<UID> {
             self.our_pk,
             shared_key,
             self.our_global_direct_listeners.clone(),
+            unwrap!(self.config.lock())
+                .cfg
+                .compression_threshold
+                .is_some(),
             Box::new(handler),
         ) {
-            let _ = self.children.insert(child);
+            let _ = self.children.insert(child, tunnelled);
         }
-        self.maybe_terminate(core, poll);
     }
 
     fn handle_exchange_msg(
@@ -153,18 +366,25 @@ impl<UID: Uid> Connect<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Option<TcpSock>,
+        res: Option<(TcpSock, bool)>,
         peer_info: PeerInfo,
+        tunnelled: bool,
     ) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
+        if let Some((socket, peer_accepts_compression)) = res {
             bootstrap::cache_peer_info(core, peer_info, &self.config);
             let self_weak = self.self_weak.clone();
+            let their_addr = peer_info.addr;
             let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc
-                        .borrow_mut()
-                        .handle_connection_candidate(core, poll, child, res);
+                    self_rc.borrow_mut().handle_connection_candidate(
+                        core,
+                        poll,
+                        child,
+                        res,
+                        their_addr,
+                        peer_accepts_compression,
+                    );
                 }
             };
 
@@ -178,10 +398,10 @@ impl<UID: Uid> Connect<UID> {
                 self.their_id,
                 Box::new(handler),
             ) {
-                let _ = self.children.insert(child);
+                let _ = self.children.insert(child, tunnelled);
             }
         } else {
-            self.remove_peer_from_cache(core, &peer_info);
+            self.record_failure_in_cache(core, &peer_info);
         }
         self.maybe_terminate(core, poll);
     }
@@ -192,9 +412,12 @@ impl<UID: Uid> Connect<UID> {
         poll: &Poll,
         child: Token,
         res: Option<TcpSock>,
+        their_addr: SocketAddr,
+        peer_accepts_compression: bool,
     ) {
         let _ = self.children.remove(&child);
         if let Some(socket) = res {
+            self.stats.connected_via = Some(their_addr);
             self.terminate(core, poll);
             return ActiveConnection::start(
                 core,
@@ -202,10 +425,14 @@ impl<UID: Uid> Connect<UID> {
                 child,
                 socket,
                 self.cm.clone(),
+                &self.config,
+                &self.blacklist,
                 self.our_id,
                 self.their_id,
                 // Note; We connect only to Nodes
                 CrustUser::Node,
+                peer_accepts_compression,
+                None,
                 Event::ConnectSuccess(self.their_id),
                 self.event_tx.clone(),
             );
@@ -213,22 +440,39 @@ impl<UID: Uid> Connect<UID> {
         self.maybe_terminate(core, poll);
     }
 
-    fn remove_peer_from_cache(&self, core: &mut EventLoopCore, peer_info: &PeerInfo) {
+    fn record_failure_in_cache(&self, core: &mut EventLoopCore, peer_info: &PeerInfo) {
         let bootstrap_cache = core.user_data_mut();
-        bootstrap_cache.remove(peer_info);
+        bootstrap_cache.record_failure(peer_info);
         if let Err(e) = bootstrap_cache.commit() {
             info!("Failed to write bootstrap cache to disk: {}", e);
         }
     }
 
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if self.children.is_empty() {
-            self.terminate(core, poll);
+        loop {
+            if !self.held.is_empty() && !self.winners_in_flight() {
+                for (socket, addr, tunnelled) in mem::replace(&mut self.held, Vec::new()) {
+                    self.handshake(core, poll, socket, addr, tunnelled);
+                }
+            }
+            if !self.children.is_empty() {
+                return;
+            }
+            // Don't wait for the stagger delay if all ongoing attempts have already failed.
+            if !self.pending_addrs.is_empty() {
+                self.dial_next_pending(core, poll);
+            } else if self.direct_addrs.is_some() {
+                self.start_direct(core, poll);
+            } else if !self.tunnel_addrs.is_empty() {
+                self.tunnel_via_proxy(core, poll);
+            } else {
+                return self.terminate(core, poll);
+            }
         }
     }
 
     fn terminate_children(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        for child in self.children.drain() {
+        for (child, _) in self.children.drain() {
             let child = match core.get_state(child) {
                 Some(state) => state,
                 None => continue,
@@ -240,17 +484,39 @@ impl<UID: Uid> Connect<UID> {
 }
 
 impl<UID: Uid> State<bootstrap::Cache> for Connect<UID> {
-    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        if timer_id == STAGGER_TIMER_ID {
+            self.stagger_timeout = None;
+            self.dial_next_pending(core, poll);
+            return self.maybe_terminate(core, poll);
+        }
+
         debug!("Connect to peer {:?} timed out", self.their_id);
         self.terminate(core, poll);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.terminate_children(core, poll);
+        self.direct_addrs = None;
+        self.pending_addrs.clear();
+        self.tunnel_addrs.clear();
+        self.held.clear();
 
         let _ = core.cancel_timeout(&self.timeout);
+        if let Some(timeout) = self.stagger_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = core.remove_state(self.token);
 
+        let mut stats = mem::replace(&mut self.stats, Default::default());
+        stats.duration = self.started.elapsed();
+        debug!("Connect to peer {:?} finished: {:?}", self.their_id, stats);
+        if unwrap!(self.config.lock()).cfg.report_connect_stats {
+            let _ = self
+                .event_tx
+                .send(Event::ConnectStats(self.their_id, stats));
+        }
+
         if !unwrap!(self.cm.lock()).contains_key(&self.their_id) {
             let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
         }
@@ -261,10 +527,125 @@ impl<UID: Uid> State<bootstrap::Cache> for Connect<UID> {
     }
 }
 
+/// Splits peer addresses into the ones to be attempted immediately and the ones to be attempted
+/// one by one after a stagger delay.
+///
+/// When both address families are present, addresses are interleaved starting with IPv6, as
+/// described in RFC 8305, and only the first one is attempted immediately.
+fn happy_eyeballs_order(addrs: Vec<SocketAddr>) -> (Vec<SocketAddr>, VecDeque<SocketAddr>) {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6());
+    if v6.is_empty() || v4.is_empty() {
+        v6.extend(v4);
+        return (v6.into_iter().collect(), VecDeque::new());
+    }
+
+    let mut ordered = VecDeque::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.pop_front(), v4.pop_front()) {
+            (None, None) => break,
+            (a, b) => {
+                ordered.extend(a);
+                ordered.extend(b);
+            }
+        }
+    }
+    let first = unwrap!(ordered.pop_front());
+    (vec![first], ordered)
+}
+
+/// If the peer shares an external IP with us, it's most likely behind the same NAT. Then its
+/// private addresses are the ones to try, while its external addresses only work if the NAT
+/// supports hairpinning. Hence the private addresses are attempted immediately and the external
+/// ones only one by one after a stagger delay.
+///
+/// Returns `None` if the peer doesn't seem to be behind the same NAT.
+fn same_nat_order(
+    their_addrs: &[SocketAddr],
+    our_global_addrs: &HashSet<SocketAddr>,
+) -> Option<(Vec<SocketAddr>, VecDeque<SocketAddr>)> {
+    let is_behind_our_nat = their_addrs.iter().any(|their_addr| {
+        ip_addr_is_global(&their_addr.ip())
+            && our_global_addrs
+                .iter()
+                .any(|our_addr| our_addr.ip() == their_addr.ip())
+    });
+    if !is_behind_our_nat {
+        return None;
+    }
+
+    let (global, private): (VecDeque<_>, Vec<_>) = their_addrs
+        .iter()
+        .cloned()
+        .partition(|addr| ip_addr_is_global(&addr.ip()));
+    if private.is_empty() {
+        return None;
+    }
+    Some((private, global))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    mod same_nat_order {
+        use super::*;
+        use crate::common::ipv4_addr;
+
+        #[test]
+        fn private_addresses_go_first_when_sharing_external_ip() {
+            let their_private = ipv4_addr(192, 168, 0, 3, 4000);
+            let their_global = ipv4_addr(1, 2, 3, 4, 5000);
+            let our_global = vec![ipv4_addr(1, 2, 3, 4, 6000)].into_iter().collect();
+
+            let (immediate, pending) =
+                unwrap!(same_nat_order(&[their_global, their_private], &our_global));
+
+            assert_eq!(immediate, vec![their_private]);
+            assert_eq!(pending.into_iter().collect::<Vec<_>>(), vec![their_global]);
+        }
+
+        #[test]
+        fn peers_behind_other_nats_are_not_reordered() {
+            let their_addrs = [ipv4_addr(192, 168, 0, 3, 4000), ipv4_addr(1, 2, 3, 4, 5000)];
+            let our_global = vec![ipv4_addr(5, 6, 7, 8, 6000)].into_iter().collect();
+
+            assert!(same_nat_order(&their_addrs, &our_global).is_none());
+        }
+    }
+
+    mod happy_eyeballs_order {
+        use super::*;
+        use crate::common::ipv4_addr;
+        use std::str::FromStr;
+
+        #[test]
+        fn single_family_addresses_are_all_attempted_immediately() {
+            let addrs = vec![ipv4_addr(1, 2, 3, 4, 4000), ipv4_addr(1, 2, 3, 5, 5000)];
+
+            let (immediate, pending) = happy_eyeballs_order(addrs.clone());
+
+            assert_eq!(immediate, addrs);
+            assert!(pending.is_empty());
+        }
+
+        #[test]
+        fn mixed_families_are_interleaved_starting_with_ipv6() {
+            let v6_a = unwrap!(SocketAddr::from_str("[2001:db8::1]:4000"));
+            let v6_b = unwrap!(SocketAddr::from_str("[2001:db8::2]:4000"));
+            let v4_a = ipv4_addr(1, 2, 3, 4, 4000);
+            let v4_b = ipv4_addr(1, 2, 3, 5, 5000);
+
+            let (immediate, pending) = happy_eyeballs_order(vec![v4_a, v4_b, v6_a, v6_b]);
+
+            assert_eq!(immediate, vec![v6_a]);
+            assert_eq!(
+                pending.into_iter().collect::<Vec<_>>(),
+                vec![v4_a, v6_b, v4_b]
+            );
+        }
+    }
+
     mod connect {
         use super::*;
         use crate::common::ipv4_addr;
@@ -275,7 +656,7 @@ mod tests {
         };
         use crate::Config;
         use safe_crypto::gen_encrypt_keypair;
-        use std::collections::HashMap;
+        use std::net::TcpListener;
         use std::sync::{Arc, Mutex};
 
         fn test_priv_conn_info() -> (PrivConnectionInfo<UniqueId>, SecretEncryptKey) {
@@ -289,7 +670,7 @@ mod tests {
         }
 
         #[test]
-        fn remove_peer_from_cache_does_what_it_says() {
+        fn record_failure_in_cache_does_what_it_says() {
             let cached_peer = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
             let bootstrap_cache = test_bootstrap_cache();
             bootstrap_cache.put(cached_peer);
@@ -317,6 +698,7 @@ mod tests {
                 &our_sk,
                 Default::default(),
                 config,
+                Blacklist::new(None),
             ));
 
             let connect_state_token = Token(0);
@@ -324,10 +706,67 @@ mod tests {
             let mut state = state.borrow_mut();
             let connect_state = unwrap!(state.as_any().downcast_mut::<Connect<UniqueId>>());
 
-            connect_state.remove_peer_from_cache(&mut core, &cached_peer);
+            connect_state.record_failure_in_cache(&mut core, &cached_peer);
 
             let cached_peers = core.user_data().peers();
             assert!(cached_peers.is_empty());
         }
+
+        /// Returns the direct and the tunnelled attempts made right after starting.
+        fn attempts_on_start(order: ConnectOrder) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+            let proxy = unwrap!(TcpListener::bind("127.0.0.1:0"));
+            let mut core = test_core(test_bootstrap_cache());
+            let poll = unwrap!(Poll::new());
+
+            let (our_ci, our_sk) = test_priv_conn_info();
+            let our_pk = our_ci.our_pk;
+            let (their_ci, _) = test_priv_conn_info();
+            let mut config = Config::default();
+            config.http_proxy = Some(unwrap!(proxy.local_addr()));
+            config.connect_order = order;
+            let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+
+            let (event_tx, _event_rx) = get_event_sender();
+            unwrap!(Connect::start(
+                &mut core,
+                &poll,
+                our_ci,
+                their_ci.to_pub_connection_info(),
+                Arc::new(Mutex::new(HashMap::new())),
+                [1; 32],
+                event_tx,
+                our_pk,
+                &our_sk,
+                Default::default(),
+                config,
+                Blacklist::new(None),
+            ));
+
+            let state = unwrap!(core.get_state(Token(0)));
+            let mut state = state.borrow_mut();
+            let connect_state = unwrap!(state.as_any().downcast_mut::<Connect<UniqueId>>());
+            (
+                connect_state.stats.direct_attempts.clone(),
+                connect_state.stats.tunnelled_attempts.clone(),
+            )
+        }
+
+        #[test]
+        fn connect_order_decides_what_is_attempted_first() {
+            let addr = ipv4_addr(1, 2, 3, 4, 4000);
+
+            assert_eq!(
+                attempts_on_start(ConnectOrder::DirectFirst),
+                (vec![addr], vec![])
+            );
+            assert_eq!(
+                attempts_on_start(ConnectOrder::TunnelFirst),
+                (vec![], vec![addr])
+            );
+            assert_eq!(
+                attempts_on_start(ConnectOrder::ParallelPreferDirect),
+                (vec![addr], vec![addr])
+            );
+        }
     }
 }
@@ -0,0 +1,151 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CoreTimer, State, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{ActiveConnection, ConnectionMap, EventLoopCore};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::rc::Rc;
+use std::time::Duration;
+
+const AUDIT_INTERVAL_SEC: u64 = 10;
+
+/// Periodically checks that the active connections in the connection map are still alive, so
+/// that `Service::is_connected` doesn't report peers whose socket is dead but which haven't been
+/// dropped yet, e.g. because we are waiting for the inactivity timeout.
+pub struct ConnectionAuditor<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    timeout: Timeout,
+    cm: ConnectionMap<UID>,
+}
+
+impl<UID: Uid> ConnectionAuditor<UID> {
+    pub fn start(core: &mut EventLoopCore, token: Token, cm: ConnectionMap<UID>) -> crate::Res<()> {
+        trace!("Entered state ConnectionAuditor");
+
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(Duration::from_secs(AUDIT_INTERVAL_SEC), timer);
+
+        let state = Rc::new(RefCell::new(ConnectionAuditor {
+            token,
+            timer,
+            timeout,
+            cm,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for ConnectionAuditor<UID> {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(Duration::from_secs(AUDIT_INTERVAL_SEC), self.timer);
+        audit(core, poll, &self.cm);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Drops active connections whose socket is dead and removes map entries which point at
+/// connections that no longer exist.
+fn audit<UID: Uid>(core: &mut EventLoopCore, poll: &Poll, cm: &ConnectionMap<UID>) {
+    // Collected to avoid keeping the mutex lock alive while terminating.
+    let conns: Vec<_> = unwrap!(cm.lock())
+        .iter()
+        .filter_map(|(uid, cid)| cid.active_connection.map(|token| (*uid, token)))
+        .collect();
+
+    for (uid, token) in conns {
+        let is_stale = match core.get_state(token) {
+            Some(state) => {
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    Some(ac) => {
+                        let _ = ac.check_alive(core, poll);
+                        false
+                    }
+                    None => true,
+                }
+            }
+            None => true,
+        };
+        if !is_stale {
+            continue;
+        }
+
+        warn!(
+            "Connection map entry of {:?} has no active connection.",
+            uid
+        );
+        let mut guard = unwrap!(cm.lock());
+        if let Entry::Occupied(mut oe) = guard.entry(uid) {
+            if oe.get().active_connection == Some(token) {
+                oe.get_mut().active_connection = None;
+                if oe.get().currently_handshaking == 0 {
+                    let _ = oe.remove();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main::ConnectionId;
+    use crate::tests::utils::{rand_uid, test_bootstrap_cache, test_core, UniqueId};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn entries_without_active_connection_are_removed() {
+        let mut core = test_core(test_bootstrap_cache());
+        let poll = unwrap!(Poll::new());
+        let cm: ConnectionMap<UniqueId> = Arc::new(Mutex::new(HashMap::new()));
+        let (peer1, peer2) = (rand_uid(), rand_uid());
+        {
+            let mut guard = unwrap!(cm.lock());
+            let _ = guard.insert(
+                peer1,
+                ConnectionId {
+                    active_connection: Some(Token(100)),
+                    currently_handshaking: 0,
+                },
+            );
+            let _ = guard.insert(
+                peer2,
+                ConnectionId {
+                    active_connection: Some(Token(101)),
+                    currently_handshaking: 1,
+                },
+            );
+        }
+
+        audit(&mut core, &poll, &cm);
+
+        let guard = unwrap!(cm.lock());
+        assert!(guard.get(&peer1).is_none());
+        let cid = unwrap!(guard.get(&peer2));
+        assert_eq!(cid.active_connection, None);
+        assert_eq!(cid.currently_handshaking, 1);
+    }
+}
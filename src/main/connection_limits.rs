@@ -0,0 +1,225 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CrustUser, Uid};
+use crate::main::{ActiveConnection, Config, ConnectionMap, EventLoopCore};
+use mio::Poll;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// What to do with a new peer which would exceed one of the connection limits of the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Refuse the new peer.
+    Reject,
+    /// Drop the connection we haven't received anything on for the longest time to make room.
+    LeastRecentlyActive,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Reject
+    }
+}
+
+/// What we need to know about an active connection to apply the limits.
+#[derive(Debug, Clone, Copy)]
+struct Conn<T> {
+    id: T,
+    ip: Option<IpAddr>,
+    kind: CrustUser,
+    last_activity: Instant,
+}
+
+/// Makes room for a new connection from `ip` of the given kind, dropping active connections if
+/// the eviction policy allows it. Returns whether the new connection may be established.
+pub fn make_room<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &Config,
+    ip: Option<IpAddr>,
+    kind: CrustUser,
+) -> bool {
+    if config.max_connections.is_none()
+        && config.max_connections_per_ip.is_none()
+        && config.max_node_connections.is_none()
+        && config.max_client_connections.is_none()
+    {
+        return true;
+    }
+
+    // Tokens collected to avoid keeping the mutex lock alive while evicting.
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+    let conns: Vec<_> = tokens
+        .into_iter()
+        .filter_map(|token| {
+            let state = core.get_state(token)?;
+            let mut state = state.borrow_mut();
+            let ac = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+            Some(Conn {
+                id: token,
+                ip: ac.peer_addr().ok().map(|addr| addr.ip()),
+                kind: ac.peer_kind(),
+                last_activity: ac.last_activity(),
+            })
+        })
+        .collect();
+
+    let to_evict = match pick_evictions(config, &conns, ip, kind) {
+        Some(to_evict) => to_evict,
+        None => return false,
+    };
+    for token in to_evict {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                ac.evict(core, poll);
+            }
+        }
+    }
+
+    true
+}
+
+/// Returns the connections to drop so that a new one fits in the limits, or `None` if it doesn't
+/// fit at all.
+fn pick_evictions<T: Copy>(
+    config: &Config,
+    conns: &[Conn<T>],
+    ip: Option<IpAddr>,
+    kind: CrustUser,
+) -> Option<Vec<T>> {
+    let kind_limit = match kind {
+        CrustUser::Node => config.max_node_connections,
+        CrustUser::Client => config.max_client_connections,
+    };
+    let limits: [(Option<usize>, &Fn(&Conn<T>) -> bool); 3] = [
+        (config.max_connections_per_ip, &|conn| {
+            ip.is_some() && conn.ip == ip
+        }),
+        (kind_limit, &|conn| conn.kind == kind),
+        (config.max_connections, &|_| true),
+    ];
+
+    let mut remaining = conns.to_vec();
+    let mut evicted = Vec::new();
+    for &(limit, counts) in &limits {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => continue,
+        };
+        while remaining.iter().filter(|conn| counts(*conn)).count() >= limit {
+            if config.eviction_policy == EvictionPolicy::Reject {
+                return None;
+            }
+            let idlest = remaining
+                .iter()
+                .enumerate()
+                .filter(|&(_, conn)| counts(conn))
+                .min_by_key(|&(_, conn)| conn.last_activity)
+                .map(|(i, _)| i);
+            match idlest {
+                Some(i) => evicted.push(remaining.swap_remove(i).id),
+                // The limit is 0.
+                None => return None,
+            }
+        }
+    }
+
+    Some(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn conn(id: u8, ip: u8, kind: CrustUser, age_sec: u64, now: Instant) -> Conn<u8> {
+        Conn {
+            id,
+            ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, ip))),
+            kind,
+            last_activity: now - Duration::from_secs(age_sec),
+        }
+    }
+
+    #[test]
+    fn reject_policy_refuses_peers_over_the_limits() {
+        let now = Instant::now();
+        let conns = [
+            conn(1, 1, CrustUser::Node, 0, now),
+            conn(2, 2, CrustUser::Client, 0, now),
+        ];
+        let new_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let mut config = Config::default();
+
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            Some(vec![])
+        );
+
+        config.max_connections = Some(3);
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            Some(vec![])
+        );
+
+        config.max_connections_per_ip = Some(1);
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            None
+        );
+        assert_eq!(
+            pick_evictions(&config, &conns, None, CrustUser::Node),
+            Some(vec![])
+        );
+
+        config.max_client_connections = Some(1);
+        assert_eq!(
+            pick_evictions(&config, &conns, None, CrustUser::Client),
+            None
+        );
+    }
+
+    #[test]
+    fn least_recently_active_policy_evicts_idlest_peers() {
+        let now = Instant::now();
+        let conns = [
+            conn(1, 1, CrustUser::Node, 30, now),
+            conn(2, 1, CrustUser::Node, 10, now),
+            conn(3, 2, CrustUser::Client, 60, now),
+        ];
+        let new_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let mut config = Config::default();
+        config.eviction_policy = EvictionPolicy::LeastRecentlyActive;
+
+        config.max_connections = Some(3);
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            Some(vec![3])
+        );
+
+        config.max_connections_per_ip = Some(1);
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            Some(vec![1, 2])
+        );
+
+        config.max_connections = Some(0);
+        assert_eq!(
+            pick_evictions(&config, &conns, new_ip, CrustUser::Node),
+            None
+        );
+    }
+}
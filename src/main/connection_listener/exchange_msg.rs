@@ -7,28 +7,32 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use super::BootstrapQuota;
 use crate::common::{
-    ipv4_addr, BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, Message, NameHash,
-    PeerInfo, State, Uid,
+    ipv4_addr, BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, ExternalReachability,
+    Message, NameHash, PeerInfo, PowChallenge, State, Uid, PROTOCOL_VERSION,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::session::Session;
 use crate::main::{
-    read_config_file, ActiveConnection, ConnectionCandidate, ConnectionId, ConnectionMap,
-    CrustConfig, Event, EventLoopCore,
+    connection_limits, read_config_file, ActiveConnection, Blacklist, BootstrapAdmission,
+    ConnectionCandidate, ConnectionId, ConnectionMap, CrustConfig, Event, EventLoopCore,
+    EventSender,
 };
 use crate::nat::{ip_addr_is_global, GetExtAddr};
 use mio::{Poll, PollOpt, Ready, Token};
 use mio_extras::timer::Timeout;
+use rand;
 use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::{RefCell, RefMut};
 use std::collections::hash_map::Entry;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::rc::{Rc, Weak};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 10 * 60;
 const CHECK_REACHABILITY_TIMEOUT_SEC: u64 = 3;
@@ -45,18 +49,40 @@ pub struct ExchangeMsg<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
-    event_tx: crate::CrustEventSender<UID>,
+    blacklist: Blacklist<UID>,
+    event_tx: EventSender<UID>,
     name_hash: NameHash,
     next_state: NextState<UID>,
     our_uid: UID,
     socket: TcpSock,
     timeout: Timeout,
-    reachability_children: HashSet<Token>,
+    /// External reachability tests in flight and the address each one tests.
+    reachability_children: HashMap<Token, SocketAddr>,
+    /// Finished tests of a bootstrapping node's listeners, with whether they passed.
+    reachability_results: Vec<(SocketAddr, bool)>,
+    /// Set once one of the bootstrapping node's listeners passed the test.
+    reachable_uid: Option<UID>,
     accept_bootstrap: bool,
     test_ext_reachability: bool,
+    challenge: Option<PendingChallenge<UID>>,
+    bootstrap_quota: Rc<RefCell<BootstrapQuota>>,
     self_weak: Weak<RefCell<ExchangeMsg<UID>>>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    /// Whether the peer said in its request that it takes compressed data.
+    peer_accepts_compression: bool,
+    /// The key of the peer bootstrapping off us.
+    their_pk: Option<PublicEncryptKey>,
+    /// Granted to the peer bootstrapping off us, see `Config::session_resume_timeout_ms`.
+    session: Option<Session>,
+}
+
+/// A bootstrap request which is on hold until the bootstrapper solves our challenge.
+struct PendingChallenge<UID> {
+    challenge: PowChallenge,
+    their_uid: UID,
+    their_role: BootstrapperRole,
+    their_pk: PublicEncryptKey,
 }
 
 impl<UID: Uid> ExchangeMsg<UID> {
@@ -74,11 +100,13 @@ impl<UID: Uid> ExchangeMsg<UID> {
         name_hash: NameHash,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
-        event_tx: crate::CrustEventSender<UID>,
+        blacklist: Blacklist<UID>,
+        event_tx: EventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
         test_ext_reachability: bool,
-    ) -> crate::Res<()> {
+        bootstrap_quota: Rc<RefCell<BootstrapQuota>>,
+    ) -> crate::Res<Token> {
         let token = core.get_new_token();
 
         let kind = Ready::readable();
@@ -93,34 +121,58 @@ impl<UID: Uid> ExchangeMsg<UID> {
             token,
             cm,
             config,
+            blacklist,
             event_tx,
             name_hash,
             next_state: NextState::None,
             our_uid,
             socket,
             timeout,
-            reachability_children: HashSet::with_capacity(4),
+            reachability_children: HashMap::with_capacity(4),
+            reachability_results: Vec::new(),
+            reachable_uid: None,
             accept_bootstrap,
             test_ext_reachability,
+            challenge: None,
+            bootstrap_quota,
             self_weak: Default::default(),
             our_pk,
             our_sk: our_sk.clone(),
+            peer_accepts_compression: false,
+            their_pk: None,
+            session: None,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
         let _ = core.insert_state(token, state);
 
-        Ok(())
+        Ok(token)
     }
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::BootstrapRequest(their_uid, name_hash, their_role, their_pk))) => {
+            Ok(Some(Message::BootstrapRequest(
+                their_uid,
+                name_hash,
+                their_version,
+                their_role,
+                their_pk,
+                accepts_compression,
+            ))) => {
+                self.peer_accepts_compression = accepts_compression;
                 if !self.accept_bootstrap {
                     trace!("Bootstrapping off us is not allowed");
                     return self.terminate(core, poll);
                 }
+                if their_version != PROTOCOL_VERSION {
+                    trace!(
+                        "Rejecting Bootstrapper with protocol version {}.",
+                        their_version
+                    );
+                    let reason = BootstrapDenyReason::IncompatibleProtocolVersion(PROTOCOL_VERSION);
+                    return self.deny(core, poll, their_pk, reason);
+                }
 
                 match self.validate_peer_uid(their_uid) {
                     Ok(their_uid) => self.handle_bootstrap_req(
@@ -129,7 +181,23 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     Err(()) => self.terminate(core, poll),
                 }
             }
-            Ok(Some(Message::ConnectRequest(their_uid, name_hash, their_addrs, their_pk))) => {
+            Ok(Some(Message::ConnectRequest(
+                their_uid,
+                name_hash,
+                their_version,
+                their_addrs,
+                their_pk,
+                accepts_compression,
+            ))) => {
+                self.peer_accepts_compression = accepts_compression;
+                if their_version != PROTOCOL_VERSION {
+                    trace!(
+                        "Rejecting connection with protocol version {}.",
+                        their_version
+                    );
+                    let reason = BootstrapDenyReason::IncompatibleProtocolVersion(PROTOCOL_VERSION);
+                    return self.deny(core, poll, their_pk, reason);
+                }
                 match self.validate_peer_uid(their_uid) {
                     Ok(their_uid) => {
                         self.handle_connect(core, poll, their_uid, name_hash, their_addrs, their_pk)
@@ -137,9 +205,20 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     Err(()) => self.terminate(core, poll),
                 }
             }
+            Ok(Some(Message::ResumeSession(their_uid, their_pk, session_id))) => {
+                match self.validate_peer_uid(their_uid) {
+                    Ok(their_uid) => {
+                        self.handle_resume_session(core, poll, their_uid, their_pk, session_id)
+                    }
+                    Err(()) => self.terminate(core, poll),
+                }
+            }
             Ok(Some(Message::EchoAddrReq(their_pk))) => {
                 self.handle_echo_addr_req(core, poll, their_pk)
             }
+            Ok(Some(Message::BootstrapChallengeResponse(solution))) => {
+                self.handle_challenge_response(core, poll, solution)
+            }
             Ok(Some(message)) => {
                 trace!("Unexpected message in direct connect: {:?}", message);
                 self.terminate(core, poll)
@@ -163,14 +242,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
     ) {
         if !self.is_valid_name_hash(name_hash) {
             trace!("Rejecting Bootstrapper with an invalid name hash.");
-            return self.write(
-                core,
-                poll,
-                Some((
-                    Message::BootstrapDenied(BootstrapDenyReason::InvalidNameHash),
-                    0,
-                )),
-            );
+            return self.deny(core, poll, their_pk, BootstrapDenyReason::InvalidNameHash);
         }
 
         if !self.use_authed_encryption(their_pk) {
@@ -189,8 +261,103 @@ impl<UID: Uid> ExchangeMsg<UID> {
             return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
         }
 
-        if let BootstrapperRole::Node(their_addrs) = their_role {
+        match self.socket.peer_addr() {
+            Ok(addr) => {
+                if !unwrap!(self.config.lock())
+                    .cfg
+                    .is_bootstrap_ip_allowed(&addr.ip())
+                {
+                    trace!("Bootstrapper is in a forbidden subnet. Denying bootstrap.");
+                    let reason = BootstrapDenyReason::ForbiddenSubnet;
+                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "Could not obtain IP Address of peer: {:?}. Denying handshake.",
+                    e
+                );
+                return self.terminate(core, poll);
+            }
+        }
+
+        match self.check_bootstrap_filter(&their_pk, their_role.as_crust_role()) {
+            Some(BootstrapAdmission::Accept) => (),
+            Some(BootstrapAdmission::Reject(reason)) => {
+                trace!(
+                    "Bootstrapper rejected by the bootstrap filter: {:?}",
+                    reason
+                );
+                return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+            }
+            None => return self.terminate(core, poll),
+        }
+
+        let difficulty = unwrap!(self.config.lock()).cfg.bootstrap_pow_difficulty;
+        if let Some(difficulty) = difficulty {
+            let challenge = PowChallenge::new(difficulty);
+            self.challenge = Some(PendingChallenge {
+                challenge,
+                their_uid,
+                their_role,
+                their_pk,
+            });
+            return self.write(
+                core,
+                poll,
+                Some((Message::BootstrapChallenge(challenge), 0)),
+            );
+        }
+
+        self.finish_bootstrap_req(core, poll, their_uid, their_role, their_pk)
+    }
+
+    fn handle_challenge_response(&mut self, core: &mut EventLoopCore, poll: &Poll, solution: u64) {
+        let pending = match self.challenge.take() {
+            Some(pending) => pending,
+            None => {
+                trace!("Unexpected challenge response.");
+                return self.terminate(core, poll);
+            }
+        };
+        if !pending.challenge.verify(solution) {
+            debug!("Bootstrapper failed our challenge. Denying bootstrap.");
+            let reason = BootstrapDenyReason::FailedChallenge;
+            return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+        }
+
+        self.finish_bootstrap_req(
+            core,
+            poll,
+            pending.their_uid,
+            pending.their_role,
+            pending.their_pk,
+        )
+    }
+
+    /// Continues with a bootstrap request which passed all checks so far.
+    fn finish_bootstrap_req(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_uid: UID,
+        their_role: BootstrapperRole,
+        their_pk: PublicEncryptKey,
+    ) {
+        self.their_pk = Some(their_pk);
+        if let BootstrapperRole::Node(their_reachability) = their_role {
             if self.test_ext_reachability {
+                let their_addrs = match their_reachability {
+                    ExternalReachability::Reachable(their_addrs) => their_addrs,
+                    ExternalReachability::BehindCgnat | ExternalReachability::Unknown => {
+                        debug!(
+                            "Bootstrapper can't be externally reachable: {:?}. Denying bootstrap.",
+                            their_reachability
+                        );
+                        let reason = BootstrapDenyReason::FailedExternalReachability;
+                        return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    }
+                };
                 let on_check_reachability_result =
                     |mut state: RefMut<ExchangeMsg<UID>>,
                      core: &mut EventLoopCore,
@@ -235,17 +402,13 @@ impl<UID: Uid> ExchangeMsg<UID> {
             }
         };
 
-        let res = match peer_kind {
-            CrustUser::Node => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_node_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-            CrustUser::Client => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_client_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
+        let res = {
+            let guard = unwrap!(self.config.lock());
+            let whitelisted_ips = match peer_kind {
+                CrustUser::Node => guard.whitelisted_node_ips(),
+                CrustUser::Client => guard.whitelisted_client_ips(),
+            };
+            whitelisted_ips.map_or(true, |ips| ips.contains(&peer_ip))
         };
 
         if !res {
@@ -255,6 +418,29 @@ impl<UID: Uid> ExchangeMsg<UID> {
         res
     }
 
+    /// Returns `None` if the peer's address can't be obtained.
+    fn check_bootstrap_filter(
+        &self,
+        their_pk: &PublicEncryptKey,
+        peer_kind: CrustUser,
+    ) -> Option<BootstrapAdmission> {
+        // Cloned so that the filter doesn't run with the config locked.
+        let filter = match unwrap!(self.config.lock()).bootstrap_filter.clone() {
+            Some(filter) => filter,
+            None => return Some(BootstrapAdmission::Accept),
+        };
+        match self.socket.peer_addr() {
+            Ok(addr) => Some((*filter)(their_pk, addr.ip(), peer_kind)),
+            Err(e) => {
+                debug!(
+                    "Could not obtain IP Address of peer: {:?}. Denying handshake.",
+                    e
+                );
+                None
+            }
+        }
+    }
+
     fn handle_check_reachability(
         &mut self,
         core: &mut EventLoopCore,
@@ -262,18 +448,36 @@ impl<UID: Uid> ExchangeMsg<UID> {
         child: Token,
         res: Result<UID, ()>,
     ) {
-        let _ = self.reachability_children.remove(&child);
+        if let Some(addr) = self.reachability_children.remove(&child) {
+            self.reachability_results.push((addr, res.is_ok()));
+        }
         if let Ok(their_uid) = res {
-            self.terminate_childern(core, poll);
-            return self.send_bootstrap_grant(core, poll, their_uid, CrustUser::Node);
+            self.reachable_uid = Some(their_uid);
         }
-        if self.reachability_children.is_empty() {
-            trace!(
-                "Bootstrapper failed to pass requisite condition of external recheability. \
-                 Denying bootstrap."
-            );
-            let reason = BootstrapDenyReason::FailedExternalReachability;
-            self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+        if !self.reachability_children.is_empty() {
+            return;
+        }
+
+        // Queued ahead of our answer, so that the node learns which of its listeners work.
+        let results = mem::replace(&mut self.reachability_results, Vec::new());
+        if let Err(e) = self
+            .socket
+            .write(Some((Message::ReachabilityReport(results), 0)))
+        {
+            debug!("Failed to send reachability report: {:?}", e);
+            return self.terminate(core, poll);
+        }
+
+        match self.reachable_uid.take() {
+            Some(their_uid) => self.send_bootstrap_grant(core, poll, their_uid, CrustUser::Node),
+            None => {
+                trace!(
+                    "Bootstrapper failed to pass requisite condition of external recheability. \
+                     Denying bootstrap."
+                );
+                let reason = BootstrapDenyReason::FailedExternalReachability;
+                self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+            }
         }
     }
 
@@ -284,11 +488,51 @@ impl<UID: Uid> ExchangeMsg<UID> {
         their_uid: UID,
         peer_kind: CrustUser,
     ) {
+        let limit = {
+            let config = unwrap!(self.config.lock());
+            match peer_kind {
+                CrustUser::Client => config.cfg.client_bootstraps_per_minute,
+                CrustUser::Node => config.cfg.node_bootstraps_per_minute,
+            }
+        };
+        if !self
+            .bootstrap_quota
+            .borrow_mut()
+            .allows(peer_kind, limit, Instant::now())
+        {
+            debug!(
+                "Bootstrap quota for {:?}s reached. Denying bootstrap.",
+                peer_kind
+            );
+            let reason = BootstrapDenyReason::Throttled;
+            return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+        }
+
+        if !self.make_room(core, poll, peer_kind) {
+            debug!("Connection limits reached. Denying bootstrap.");
+            let reason = BootstrapDenyReason::TooManyConnections;
+            return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+        }
+
+        self.bootstrap_quota.borrow_mut().record(peer_kind);
         self.enter_handshaking_mode(their_uid);
 
-        let our_uid = self.our_uid;
+        let resume = unwrap!(self.config.lock())
+            .cfg
+            .session_resume_timeout_ms
+            .is_some();
+        self.session = match self.their_pk {
+            Some(their_pk) if resume => Some(Session {
+                id: rand::random(),
+                their_pk,
+                redial: None,
+            }),
+            _ => None,
+        };
+        let session_id = self.session.as_ref().map(|session| session.id);
+        let msg = Message::BootstrapGranted(self.our_uid, self.accepts_compression(), session_id);
         self.next_state = NextState::ActiveConnection(their_uid, peer_kind);
-        self.write(core, poll, Some((Message::BootstrapGranted(our_uid), 0)))
+        self.write(core, poll, Some((msg, 0)))
     }
 
     fn handle_connect(
@@ -302,7 +546,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
     ) {
         if !self.is_valid_name_hash(name_hash) {
             trace!("Invalid name hash given. Denying connection.");
-            return self.terminate(core, poll);
+            return self.deny(core, poll, their_pk, BootstrapDenyReason::InvalidNameHash);
         }
 
         self.try_update_crust_config();
@@ -365,12 +609,72 @@ impl<UID: Uid> ExchangeMsg<UID> {
 
     /// Sends response to incoming connection.
     fn send_connect_grant(&mut self, core: &mut EventLoopCore, poll: &Poll, their_uid: UID) {
+        if !self.make_room(core, poll, CrustUser::Node) {
+            debug!("Connection limits reached. Denying connect request.");
+            return self.terminate(core, poll);
+        }
+
         self.enter_handshaking_mode(their_uid);
         self.next_state = NextState::ConnectionCandidate(their_uid);
-        let msg = Message::ConnectResponse(self.our_uid, self.name_hash);
+        let msg = Message::ConnectResponse(
+            self.our_uid,
+            self.name_hash,
+            PROTOCOL_VERSION,
+            self.accepts_compression(),
+        );
         self.write(core, poll, Some((msg, 0)));
     }
 
+    /// Whether we take compressed data, see `Config::compression_threshold`.
+    fn accepts_compression(&self) -> bool {
+        unwrap!(self.config.lock())
+            .cfg
+            .compression_threshold
+            .is_some()
+    }
+
+    /// Applies the connection limits to the peer we are handshaking with.
+    fn make_room(&self, core: &mut EventLoopCore, poll: &Poll, peer_kind: CrustUser) -> bool {
+        let peer_ip = self.socket.peer_addr().ok().map(|addr| addr.ip());
+        let config = unwrap!(self.config.lock());
+        connection_limits::make_room(core, poll, &self.cm, &config.cfg, peer_ip, peer_kind)
+    }
+
+    /// Hands the socket over to our connection to the peer, if the peer has the session it asks
+    /// to resume.
+    fn handle_resume_session(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_uid: UID,
+        their_pk: PublicEncryptKey,
+        session_id: u64,
+    ) {
+        if !self.use_authed_encryption(their_pk) {
+            trace!("Failed to set authenticated encryption context.");
+            return self.terminate(core, poll);
+        }
+        let token = unwrap!(self.cm.lock())
+            .get(&their_uid)
+            .and_then(|cid| cid.active_connection);
+        // Leaves the connection map alone, since we never entered handshaking mode.
+        self.terminate(core, poll);
+        let socket = mem::replace(&mut self.socket, Default::default());
+        let mut resumed = false;
+        if let Some(state) = token.and_then(|token| core.get_state(token)) {
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                resumed = ac.accept_resumption(core, poll, session_id, &their_pk, socket);
+            }
+        }
+        if !resumed {
+            debug!(
+                "{:?} asked to resume a session we don't have with it.",
+                their_uid
+            );
+        }
+    }
+
     fn handle_echo_addr_req(
         &mut self,
         core: &mut EventLoopCore,
@@ -432,17 +736,22 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     }
                 };
 
+            let local_addr = if their_listener.is_ipv6() {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+            } else {
+                ipv4_addr(0, 0, 0, 0, 0)
+            };
             if let Ok(child) = GetExtAddr::<UID, BootstrapCache>::start(
                 core,
                 poll,
-                ipv4_addr(0, 0, 0, 0, 0),
+                local_addr,
                 &PeerInfo::new(their_listener, their_pk),
                 self.our_pk,
                 &self.our_sk,
                 Some(CHECK_REACHABILITY_TIMEOUT_SEC),
                 Box::new(finish),
             ) {
-                let _ = self.reachability_children.insert(child);
+                let _ = self.reachability_children.insert(child, their_listener);
             }
         }
     }
@@ -482,6 +791,21 @@ impl<UID: Uid> ExchangeMsg<UID> {
         );
     }
 
+    /// Tells the peer why we refuse the handshake before closing the connection.
+    fn deny(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_pk: PublicEncryptKey,
+        reason: BootstrapDenyReason,
+    ) {
+        // The peer only reads messages encrypted for it.
+        if !self.use_authed_encryption(their_pk) {
+            return self.terminate(core, poll);
+        }
+        self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+    }
+
     fn is_valid_name_hash(&self, name_hash: NameHash) -> bool {
         self.name_hash == name_hash
     }
@@ -491,11 +815,18 @@ impl<UID: Uid> ExchangeMsg<UID> {
             debug!("Accepted connection from ourselves");
             return Err(());
         }
+        if self.blacklist.contains_peer(&their_uid) {
+            debug!("Refusing handshake with blacklisted peer {:?}", their_uid);
+            return Err(());
+        }
 
         Ok(their_uid)
     }
 
     fn try_update_crust_config(&self) {
+        if !unwrap!(self.config.lock()).watch_file {
+            return;
+        }
         match read_config_file() {
             Ok(cfg) => unwrap!(self.config.lock()).check_for_update_and_mark_modified(cfg),
             Err(e) => debug!("Could not read Crust config file: {:?}", e),
@@ -523,6 +854,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
 
         match self.socket.write(msg) {
+            // Once our challenge is out, we wait for the answer.
+            Ok(true) if self.challenge.is_some() => (),
             Ok(true) => self.done(core, poll),
             Ok(false) => (),
             Err(e) => {
@@ -538,6 +871,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
 
         let our_uid = self.our_uid;
         let event_tx = self.event_tx.clone();
+        let peer_accepts_compression = self.peer_accepts_compression;
+        let session = self.session.take();
 
         match self.next_state {
             NextState::ActiveConnection(their_uid, peer_kind) => {
@@ -548,15 +883,21 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     self.token,
                     socket,
                     self.cm.clone(),
+                    &self.config,
+                    &self.blacklist,
                     our_uid,
                     their_uid,
                     peer_kind,
+                    peer_accepts_compression,
+                    session,
                     Event::BootstrapAccept(their_uid, peer_kind),
                     event_tx,
                 );
             }
             NextState::ConnectionCandidate(their_uid) => {
                 let cm = self.cm.clone();
+                let config = self.config.clone();
+                let blacklist = self.blacklist.clone();
                 let handler = move |core: &mut EventLoopCore, poll: &Poll, token, res| {
                     if let Some(socket) = res {
                         ActiveConnection::start(
@@ -565,11 +906,15 @@ impl<UID: Uid> ExchangeMsg<UID> {
                             token,
                             socket,
                             cm.clone(),
+                            &config,
+                            &blacklist,
                             our_uid,
                             their_uid,
                             // Note; We enter ConnectionCandidate only with
                             //       Nodes
                             CrustUser::Node,
+                            peer_accepts_compression,
+                            None,
                             Event::ConnectSuccess(their_uid),
                             event_tx.clone(),
                         );
@@ -593,7 +938,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
     }
 
     fn terminate_childern(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        for child in self.reachability_children.drain() {
+        for (child, _) in self.reachability_children.drain() {
             core.get_state(child)
                 .map_or((), |c| c.borrow_mut().terminate(core, poll));
         }
@@ -10,41 +10,143 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{NameHash, PeerInfo, State, Uid};
+use crate::common::{CoreMessage, CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionMap, CrustConfig, Event, EventLoopCore};
+use crate::main::{
+    Blacklist, ConnectionMap, CrustConfig, Event, EventLoopCore, EventSender, ListenerConfig,
+    Transport,
+};
 use crate::nat::ip_addr_is_global;
-use crate::nat::{MappedTcpSocket, MappingContext};
+use crate::nat::{MappedTcpSocket, MappingContext, PortMapping};
+use get_if_addrs;
+use maidsafe_utilities::thread;
 use mio::net::TcpListener;
 use mio::{Poll, PollOpt, Ready, Token};
+use mio_extras::timer::Timeout;
 use net2::TcpBuilder;
 use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::{DecryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const LISTENER_BACKLOG: i32 = 100;
+/// How often to check whether our local IP addresses have changed, e.g. when switching from
+/// Wi-Fi to ethernet or bringing a VPN up.
+const NETWORK_CHECK_INTERVAL_SEC: u64 = 10;
+/// Length of the window `inbound_conn_attempts_per_ip` of the config applies to.
+const CONN_ATTEMPTS_WINDOW_SEC: u64 = 60;
+/// Length of the window `client_bootstraps_per_minute` and `node_bootstraps_per_minute` of the
+/// config apply to.
+const BOOTSTRAP_QUOTA_WINDOW_SEC: u64 = 60;
+/// Delay before starting a failed listener again. It doubles with every further failure, up to
+/// `MAX_RESTART_DELAY_SEC`.
+const RESTART_DELAY_SEC: u64 = 1;
+const MAX_RESTART_DELAY_SEC: u64 = 64;
+
+/// What a listener does with the connections it accepts, see `set_accept_bootstrap` and
+/// `set_ext_reachability_test`.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerSettings {
+    pub accept_bootstrap: bool,
+    pub test_ext_reachability: bool,
+}
+
+impl Default for ListenerSettings {
+    fn default() -> Self {
+        ListenerSettings {
+            accept_bootstrap: false,
+            test_ext_reachability: true,
+        }
+    }
+}
+
+/// Everything a listener is started with.
+#[derive(Clone)]
+struct ListenerParams<UID: Uid> {
+    timeout_sec: Option<u64>,
+    port: u16,
+    forced_port: Option<u16>,
+    /// See `ListenerConfig::port_range_end`.
+    port_range_end: Option<u16>,
+    /// Advertised instead of the mapped addresses, see `ListenerConfig::external_addr`.
+    external_addr: Option<SocketAddr>,
+    /// Also listen on IPv6, see `Config::enable_ipv6`. Only done next to an IPv4 listener.
+    ipv6: bool,
+    /// What we bind to, unspecified IPv4 if neither `ListenerConfig::ip` nor `Config::bind_ip` is
+    /// set.
+    ip: IpAddr,
+    our_uid: UID,
+    name_hash: NameHash,
+    cm: ConnectionMap<UID>,
+    config: CrustConfig,
+    blacklist: Blacklist<UID>,
+    mc: Arc<MappingContext>,
+    /// Shared with the other listeners of the service.
+    our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
+    token: Token,
+    settings: ListenerSettings,
+    event_tx: EventSender<UID>,
+    our_pk: PublicEncryptKey,
+    our_sk: SecretEncryptKey,
+    /// How often starting the listener failed in a row.
+    attempt: u32,
+}
+
+impl<UID: Uid> ListenerParams<UID> {
+    fn listener_config(&self, port: u16) -> ListenerConfig {
+        ListenerConfig {
+            transport: Transport::Tcp,
+            ip: if self.ip.is_unspecified() {
+                None
+            } else {
+                Some(self.ip)
+            },
+            port,
+            port_range_end: self.port_range_end,
+            external_addr: self.external_addr,
+        }
+    }
+}
 
 /// Accepts connections and transitions each connection into `ExchangeMsg` state.
 /// Optionally will make `ExchangeMsg` to test for peer external reachability. This behavior
 /// is enabled by default.
+///
+/// When our local IP addresses change, the listener port is mapped again and our advertised
+/// listener addresses are updated, followed by `Event::ExternalAddressChanged`. The port is also
+/// mapped again once port mappings leased via NAT-PMP or PCP are half way through their lease.
+/// Port mappings are removed from the routers again when the listener is terminated.
+///
+/// If the listener fails to start, or the interface it's bound to goes away, it reports
+/// `Event::ListenerFailed` and is started again by `ListenerRetry`.
 pub struct ConnectionListener<UID: Uid> {
     token: Token,
-    cm: ConnectionMap<UID>,
-    config: CrustConfig,
-    event_tx: crate::CrustEventSender<UID>,
     listener: TcpListener,
-    name_hash: NameHash,
-    our_uid: UID,
-    timeout_sec: Option<u64>,
-    accept_bootstrap: bool,
-    our_pk: PublicEncryptKey,
-    our_sk: SecretEncryptKey,
-    test_ext_reachability: bool,
+    /// Optional IPv6-only listener bound to the same port. It's registered with its own token
+    /// which maps to this same state.
+    listener_v6: Option<(TcpListener, Token)>,
+    /// Tokens of the handshakes we started, oldest first. Some may have finished already.
+    handshakes: VecDeque<Token>,
+    attempts: ConnectionAttempts,
+    /// Shared with our `ExchangeMsg` states, which grant the bootstraps.
+    bootstrap_quota: Rc<RefCell<BootstrapQuota>>,
+    /// The part of the shared `our_listeners` this listener is reachable on.
+    advertised: Rc<RefCell<Vec<PeerInfo>>>,
+    /// Replaced by `remap`.
+    port_mappings: Rc<RefCell<Vec<PortMapping>>>,
+    local_port: u16,
+    our_ips: Vec<IpAddr>,
+    network_check_timeout: Timeout,
+    /// What we were started with, to start again if our interface goes away. Also holds our
+    /// current settings.
+    params: ListenerParams<UID>,
 }
 
 impl<UID: Uid> ConnectionListener<UID> {
@@ -52,156 +154,295 @@ impl<UID: Uid> ConnectionListener<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         handshake_timeout_sec: Option<u64>,
-        port: u16,
+        listener: ListenerConfig,
         force_include_port: bool,
+        ipv6: bool,
+        bind_ip: Option<IpAddr>,
         our_uid: UID,
         name_hash: NameHash,
         cm: ConnectionMap<UID>,
         config: CrustConfig,
+        blacklist: Blacklist<UID>,
         mc: Arc<MappingContext>,
         our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
         token: Token,
-        event_tx: crate::CrustEventSender<UID>,
+        settings: ListenerSettings,
+        event_tx: EventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: SecretEncryptKey,
     ) {
-        let event_tx_0 = event_tx.clone();
-        let our_sk2 = our_sk.clone();
+        let port = listener.port;
+        let ip = listener
+            .ip
+            .or(bind_ip)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let forced_port = if force_include_port && port != 0 {
+            Some(port)
+        } else {
+            None
+        };
+        let params = ListenerParams {
+            timeout_sec: handshake_timeout_sec,
+            port,
+            forced_port,
+            port_range_end: listener.port_range_end,
+            external_addr: listener.external_addr,
+            ipv6,
+            ip,
+            our_uid,
+            name_hash,
+            cm,
+            config,
+            blacklist,
+            mc,
+            our_listeners,
+            token,
+            settings,
+            event_tx,
+            our_pk,
+            our_sk,
+            attempt: 0,
+        };
+        Self::start_with(core, poll, params);
+    }
+
+    fn start_with(core: &mut EventLoopCore, poll: &Poll, params: ListenerParams<UID>) {
+        let port = match params.port_range_end {
+            Some(end) => match first_free_port(params.ip, params.port, end) {
+                Some(port) => port,
+                None => {
+                    let error = format!("No port from {} to {} is free", params.port, end);
+                    return ListenerRetry::start(core, params, error);
+                }
+            },
+            None => params.port,
+        };
+        let our_ifv6s = if params.ipv6 && params.ip.is_ipv4() {
+            Some(params.mc.ifv6s().clone())
+        } else {
+            None
+        };
+        let (ip, mc, our_pk, our_sk) = (
+            params.ip,
+            params.mc.clone(),
+            params.our_pk,
+            params.our_sk.clone(),
+        );
+        let failed_params = params.clone();
 
         let finish = move |core: &mut EventLoopCore,
                            poll: &Poll,
                            socket,
-                           mut mapped_addrs: Vec<SocketAddr>| {
-            let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
-            if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
-                let global_addrs: Vec<_> = mapped_addrs
-                    .iter()
-                    .filter_map(|s| {
-                        if ip_addr_is_global(&s.ip()) {
-                            let mut s = *s;
-                            s.set_port(port);
-                            Some(s)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-                mapped_addrs.extend(global_addrs);
-            }
+                           mapped_addrs: Vec<SocketAddr>,
+                           port_mappings| {
+            let mapped_addrs =
+                advertised_addrs(mapped_addrs, params.external_addr, params.forced_port);
+            let retry_params = params.clone();
             if let Err(e) = Self::handle_mapped_socket(
                 core,
                 poll,
-                handshake_timeout_sec,
                 socket,
                 mapped_addrs,
-                our_uid,
-                name_hash,
-                cm,
-                config,
-                our_listeners,
-                token,
-                event_tx.clone(),
-                our_pk,
-                our_sk,
+                port_mappings,
+                our_ifv6s,
+                params,
             ) {
                 error!("TCP Listener failed to handle mapped socket: {:?}", e);
-                let _ = event_tx.send(Event::ListenerFailed);
+                ListenerRetry::start(core, retry_params, e.to_string());
             }
         };
 
         if let Err(e) =
-            MappedTcpSocket::<_, UID, _>::start(core, poll, port, &mc, our_pk, &our_sk2, finish)
+            MappedTcpSocket::<_, UID, _>::start(core, poll, ip, port, &mc, our_pk, &our_sk, finish)
         {
             error!("Error starting tcp_listening_socket: {:?}", e);
-            let _ = event_tx_0.send(Event::ListenerFailed);
+            ListenerRetry::start(core, failed_params, e.to_string());
         }
     }
 
     pub fn set_accept_bootstrap(&mut self, accept: bool) {
-        self.accept_bootstrap = accept;
+        self.params.settings.accept_bootstrap = accept;
     }
 
     /// Enables/disables peer external reachability test.
     pub fn set_ext_reachability_test(&mut self, test: bool) {
-        self.test_ext_reachability = test;
+        self.params.settings.test_ext_reachability = test;
+    }
+
+    pub fn settings(&self) -> ListenerSettings {
+        self.params.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut ListenerSettings {
+        &mut self.params.settings
+    }
+
+    /// The port we actually listen on, also when we were asked to listen on port 0.
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// What to start the listener with to listen just like it does now.
+    pub fn listener_config(&self) -> ListenerConfig {
+        self.params.listener_config(self.local_port)
     }
 
     fn handle_mapped_socket(
         core: &mut EventLoopCore,
         poll: &Poll,
-        timeout_sec: Option<u64>,
         socket: TcpBuilder,
-        mapped_addrs: Vec<SocketAddr>,
-        our_uid: UID,
-        name_hash: NameHash,
-        cm: ConnectionMap<UID>,
-        config: CrustConfig,
-        our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
-        token: Token,
-        event_tx: crate::CrustEventSender<UID>,
-        our_pk: PublicEncryptKey,
-        our_sk: SecretEncryptKey,
+        mut mapped_addrs: Vec<SocketAddr>,
+        port_mappings: Vec<PortMapping>,
+        our_ifv6s: Option<Vec<Ipv6Addr>>,
+        params: ListenerParams<UID>,
     ) -> crate::Res<()> {
+        let token = params.token;
         let listener = socket.listen(LISTENER_BACKLOG)?;
         let local_addr = listener.local_addr()?;
 
         let listener = TcpListener::from_std(listener)?;
         poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
 
-        *unwrap!(our_listeners.lock()) = mapped_addrs
+        let listener_v6 = match our_ifv6s {
+            Some(our_ifv6s) => match bind_ipv6_listener(local_addr.port()) {
+                Ok(listener_v6) => {
+                    let token_v6 = core.get_new_token();
+                    poll.register(&listener_v6, token_v6, Ready::readable(), PollOpt::edge())?;
+                    mapped_addrs.extend(ipv6_listener_addrs(our_ifv6s, local_addr.port()));
+                    Some((listener_v6, token_v6))
+                }
+                Err(e) => {
+                    info!(
+                        "Failed to start IPv6 TCP listener, accepting IPv4 only: {}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let advertised: Vec<_> = mapped_addrs
             .into_iter()
-            .map(|addr| PeerInfo::new(addr, our_pk))
+            .map(|addr| PeerInfo::new(addr, params.our_pk))
             .collect();
+        unwrap!(params.our_listeners.lock()).extend(advertised.iter().cloned());
 
+        let network_check_timeout = core.set_timeout(
+            Duration::from_secs(NETWORK_CHECK_INTERVAL_SEC),
+            CoreTimer::new(token, 0),
+        );
+        let token_v6 = listener_v6.as_ref().map(|&(_, token_v6)| token_v6);
+        let event_tx = params.event_tx.clone();
         let state = Self {
             token,
-            cm,
-            config,
-            event_tx: event_tx.clone(),
             listener,
-            name_hash,
-            our_uid,
-            timeout_sec,
-            accept_bootstrap: false,
-            our_pk,
-            our_sk,
-            test_ext_reachability: true,
+            listener_v6,
+            handshakes: VecDeque::new(),
+            attempts: ConnectionAttempts::new(Instant::now()),
+            bootstrap_quota: Rc::new(RefCell::new(BootstrapQuota::new(Instant::now()))),
+            advertised: Rc::new(RefCell::new(advertised)),
+            port_mappings: Rc::new(RefCell::new(port_mappings)),
+            local_port: local_addr.port(),
+            our_ips: local_ips(),
+            network_check_timeout,
+            params: ListenerParams {
+                attempt: 0,
+                ..params
+            },
         };
 
-        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+        let state = Rc::new(RefCell::new(state));
+        let _ = core.insert_state(token, state.clone());
+        if let Some(token_v6) = token_v6 {
+            let _ = core.insert_state(token_v6, state);
+        }
         let _ = event_tx.send(Event::ListenerStarted(local_addr.port()));
 
         Ok(())
     }
 
-    fn accept(&self, core: &mut EventLoopCore, poll: &Poll) {
+    fn accept(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let mut handshakes = mem::replace(&mut self.handshakes, VecDeque::new());
+        let mut attempts =
+            mem::replace(&mut self.attempts, ConnectionAttempts::new(Instant::now()));
+        self.accept_from(&self.listener, &mut handshakes, &mut attempts, core, poll);
+        if let Some((ref listener_v6, _)) = self.listener_v6 {
+            self.accept_from(listener_v6, &mut handshakes, &mut attempts, core, poll);
+        }
+        self.handshakes = handshakes;
+        self.attempts = attempts;
+    }
+
+    fn accept_from(
+        &self,
+        listener: &TcpListener,
+        handshakes: &mut VecDeque<Token>,
+        attempts: &mut ConnectionAttempts,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+    ) {
         loop {
-            match self.listener.accept() {
-                Ok((socket, _)) => {
+            match listener.accept() {
+                Ok((socket, addr)) => {
+                    if self.params.blacklist.contains_ip(&addr.ip()) {
+                        debug!("Refusing connection from blacklisted IP {}", addr.ip());
+                        continue;
+                    }
+                    let max_attempts = unwrap!(self.params.config.lock())
+                        .cfg
+                        .inbound_conn_attempts_per_ip;
+                    if let Some(max_attempts) = max_attempts {
+                        let count = attempts.record(addr.ip(), Instant::now());
+                        if count > max_attempts {
+                            if count == max_attempts + 1 {
+                                warn!(
+                                    "{} connected more than {} times in {} seconds - refusing its \
+                                     connections for the rest of the window",
+                                    addr.ip(),
+                                    max_attempts,
+                                    CONN_ATTEMPTS_WINDOW_SEC
+                                );
+                                let _ = self
+                                    .params
+                                    .event_tx
+                                    .send(Event::InboundConnRateLimited(addr.ip()));
+                            }
+                            continue;
+                        }
+                    }
+                    let socket_options = unwrap!(self.params.config.lock()).cfg.socket_options;
+                    if let Err(e) = socket_options.apply(&socket) {
+                        debug!("Failed to set socket options: {:?}", e);
+                    }
                     let mut socket = TcpSock::wrap(socket);
                     if let Err(e) = socket.set_decrypt_ctx(DecryptContext::anonymous_decrypt(
-                        self.our_pk,
-                        self.our_sk.clone(),
+                        self.params.our_pk,
+                        self.params.our_sk.clone(),
                     )) {
                         warn!("Failed to set decryption context: {}", e);
                         continue;
                     }
-                    if let Err(e) = ExchangeMsg::start(
+                    self.make_room_for_handshake(handshakes, core, poll);
+                    match ExchangeMsg::start(
                         core,
                         poll,
-                        self.timeout_sec,
+                        self.params.timeout_sec,
                         socket,
-                        self.accept_bootstrap,
-                        self.our_uid,
-                        self.name_hash,
-                        self.cm.clone(),
-                        self.config.clone(),
-                        self.event_tx.clone(),
-                        self.our_pk,
-                        &self.our_sk,
-                        self.test_ext_reachability,
+                        self.params.settings.accept_bootstrap,
+                        self.params.our_uid,
+                        self.params.name_hash,
+                        self.params.cm.clone(),
+                        self.params.config.clone(),
+                        self.params.blacklist.clone(),
+                        self.params.event_tx.clone(),
+                        self.params.our_pk,
+                        &self.params.our_sk,
+                        self.params.settings.test_ext_reachability,
+                        self.bootstrap_quota.clone(),
                     ) {
-                        debug!("Error accepting direct connection: {:?}", e);
+                        Ok(token) => handshakes.push_back(token),
+                        Err(e) => debug!("Error accepting direct connection: {:?}", e),
                     }
                 }
                 Err(ref e)
@@ -216,6 +457,142 @@ impl<UID: Uid> ConnectionListener<UID> {
             }
         }
     }
+
+    /// Forgets finished handshakes and, if we are at the configured limit of pending ones, drops
+    /// the oldest to make room for a new one.
+    fn make_room_for_handshake(
+        &self,
+        handshakes: &mut VecDeque<Token>,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+    ) {
+        handshakes.retain(|token| {
+            core.get_state(*token).map_or(false, |state| {
+                state
+                    .borrow_mut()
+                    .as_any()
+                    .downcast_mut::<ExchangeMsg<UID>>()
+                    .is_some()
+            })
+        });
+
+        let max_pending = match unwrap!(self.params.config.lock())
+            .cfg
+            .max_pending_handshakes
+        {
+            Some(max_pending) => max_pending,
+            None => return,
+        };
+        while handshakes.len() >= max_pending {
+            let token = match handshakes.pop_front() {
+                Some(token) => token,
+                None => return,
+            };
+            debug!("Too many pending handshakes - dropping the oldest one");
+            if let Some(state) = core.get_state(token) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+    }
+
+    /// Builds a fresh `MappingContext` off the event loop, since looking for gateways blocks,
+    /// and then maps our listener port again.
+    fn refresh_mapping(&self, core: &mut EventLoopCore) {
+        let (search_igd, stuns) = {
+            let config = &unwrap!(self.params.config.lock()).cfg;
+            (!config.disable_igd, config.hard_coded_contacts.clone())
+        };
+        let tx = core.sender().clone();
+        let token = self.token;
+
+        let _ = thread::named("Crust-Mapping-Context", move || {
+            let mut mc = match MappingContext::try_new(search_igd) {
+                Ok(mc) => mc,
+                Err(e) => {
+                    debug!("Failed to refresh mapping context: {}", e);
+                    return;
+                }
+            };
+            mc.add_peer_stuns(stuns);
+
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => return,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(listener) = state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                    listener.remap(core, poll, &mc);
+                }
+            }));
+        });
+    }
+
+    fn remap(&self, core: &mut EventLoopCore, poll: &Poll, mc: &MappingContext) {
+        let forced_port = self.params.forced_port;
+        let external_addr = self.params.external_addr;
+        let v6_addrs = if self.listener_v6.is_some() {
+            ipv6_listener_addrs(mc.ifv6s().clone(), self.local_port)
+        } else {
+            Vec::new()
+        };
+        let our_listeners = self.params.our_listeners.clone();
+        let advertised = self.advertised.clone();
+        let port_mappings = Rc::downgrade(&self.port_mappings);
+        let event_tx = self.params.event_tx.clone();
+        let our_pk = self.params.our_pk;
+
+        let finish = move |_: &mut EventLoopCore,
+                           _: &Poll,
+                           _socket: TcpBuilder,
+                           mapped_addrs: Vec<SocketAddr>,
+                           new_mappings: Vec<PortMapping>| {
+            // Only the listener keeps the mappings alive, so it was terminated meanwhile.
+            let port_mappings = match port_mappings.upgrade() {
+                Some(port_mappings) => port_mappings,
+                None => {
+                    for mapping in new_mappings {
+                        mapping.release();
+                    }
+                    return;
+                }
+            };
+            let mut mapped_addrs = advertised_addrs(mapped_addrs, external_addr, forced_port);
+            mapped_addrs.extend(v6_addrs);
+            let mut mappings = port_mappings.borrow_mut();
+            for mapping in mem::replace(&mut *mappings, new_mappings) {
+                if !mappings.iter().any(|new| new.is_same(&mapping)) {
+                    mapping.release();
+                }
+            }
+            let new_listeners: Vec<_> = mapped_addrs
+                .into_iter()
+                .map(|addr| PeerInfo::new(addr, our_pk))
+                .collect();
+
+            let mut advertised = advertised.borrow_mut();
+            if *advertised != new_listeners {
+                let mut our_listeners = unwrap!(our_listeners.lock());
+                our_listeners.retain(|listener| !advertised.contains(listener));
+                our_listeners.extend(new_listeners.iter().cloned());
+                *advertised = new_listeners;
+                let _ = event_tx.send(Event::ExternalAddressChanged);
+            }
+        };
+
+        if let Err(e) = MappedTcpSocket::<_, UID, _>::start(
+            core,
+            poll,
+            self.params.ip,
+            self.local_port,
+            mc,
+            self.params.our_pk,
+            &self.params.our_sk,
+            finish,
+        ) {
+            debug!("Failed to map listener port again: {:?}", e);
+        }
+    }
 }
 
 impl<UID: Uid> State<BootstrapCache> for ConnectionListener<UID> {
@@ -225,9 +602,109 @@ impl<UID: Uid> State<BootstrapCache> for ConnectionListener<UID> {
         }
     }
 
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.network_check_timeout = core.set_timeout(
+            Duration::from_secs(NETWORK_CHECK_INTERVAL_SEC),
+            CoreTimer::new(self.token, 0),
+        );
+
+        let our_ips = local_ips();
+        let lost_ip =
+            Some(self.params.ip).filter(|ip| !ip.is_unspecified() && !our_ips.contains(ip));
+        if let Some(lost_ip) = lost_ip {
+            // Come back on the same port once the interface is back.
+            let params = ListenerParams {
+                port: self.local_port,
+                ..self.params.clone()
+            };
+            self.terminate(core, poll);
+            return ListenerRetry::start(core, params, format!("{} went away", lost_ip));
+        }
+        if our_ips != self.our_ips {
+            info!("Local IP addresses changed, mapping listener port again.");
+            self.our_ips = our_ips;
+            self.refresh_mapping(core);
+        } else if self
+            .port_mappings
+            .borrow()
+            .iter()
+            .any(PortMapping::needs_renewal)
+        {
+            debug!("Renewing port mappings of the listener.");
+            self.refresh_mapping(core);
+        }
+    }
+
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let advertised = self.advertised.borrow();
+        unwrap!(self.params.our_listeners.lock()).retain(|listener| !advertised.contains(listener));
+        for mapping in self.port_mappings.borrow_mut().drain(..) {
+            mapping.release();
+        }
+        let _ = core.cancel_timeout(&self.network_check_timeout);
         let _ = poll.deregister(&self.listener);
         let _ = core.remove_state(self.token);
+        if let Some((ref listener_v6, token_v6)) = self.listener_v6 {
+            let _ = poll.deregister(listener_v6);
+            let _ = core.remove_state(token_v6);
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Takes the place of a listener which failed, under the same token, until it's time to start it
+/// again.
+pub struct ListenerRetry<UID: Uid> {
+    timeout: Timeout,
+    params: ListenerParams<UID>,
+}
+
+impl<UID: Uid> ListenerRetry<UID> {
+    fn start(core: &mut EventLoopCore, params: ListenerParams<UID>, error: String) {
+        let addr = SocketAddr::new(params.ip, params.port);
+        let delay = restart_delay(params.attempt);
+        warn!(
+            "TCP listener on {} failed: {}. Starting it again in {:?}.",
+            addr, error, delay
+        );
+        let _ = params.event_tx.send(Event::ListenerFailed(addr, error));
+
+        let token = params.token;
+        let timeout = core.set_timeout(delay, CoreTimer::new(token, 0));
+        let state = Rc::new(RefCell::new(ListenerRetry { timeout, params }));
+        let _ = core.insert_state(token, state);
+    }
+
+    /// What the listener is going to be started with.
+    pub fn listener_config(&self) -> ListenerConfig {
+        self.params.listener_config(self.params.port)
+    }
+
+    pub fn settings(&self) -> ListenerSettings {
+        self.params.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut ListenerSettings {
+        &mut self.params.settings
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for ListenerRetry<UID> {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        let _ = core.remove_state(self.params.token);
+        let params = ListenerParams {
+            attempt: self.params.attempt + 1,
+            ..self.params.clone()
+        };
+        ConnectionListener::start_with(core, poll, params);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.params.token);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -235,15 +712,170 @@ impl<UID: Uid> State<BootstrapCache> for ConnectionListener<UID> {
     }
 }
 
+fn restart_delay(attempt: u32) -> Duration {
+    let factor = 1 << attempt.min(6);
+    Duration::from_secs((RESTART_DELAY_SEC * factor).min(MAX_RESTART_DELAY_SEC))
+}
+
+/// Counts incoming connections per IP in fixed windows of `CONN_ATTEMPTS_WINDOW_SEC`.
+struct ConnectionAttempts {
+    window_start: Instant,
+    counts: HashMap<IpAddr, u32>,
+}
+
+impl ConnectionAttempts {
+    fn new(now: Instant) -> Self {
+        ConnectionAttempts {
+            window_start: now,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Records a connection from `ip` and returns how many there were in the current window.
+    fn record(&mut self, ip: IpAddr, now: Instant) -> u32 {
+        if now.duration_since(self.window_start) >= Duration::from_secs(CONN_ATTEMPTS_WINDOW_SEC) {
+            self.window_start = now;
+            self.counts.clear();
+        }
+        let count = self.counts.entry(ip).or_insert(0);
+        *count += 1;
+        *count
+    }
+}
+
+/// Counts the bootstraps we granted to clients and nodes in fixed windows of
+/// `BOOTSTRAP_QUOTA_WINDOW_SEC`.
+struct BootstrapQuota {
+    window_start: Instant,
+    clients: u32,
+    nodes: u32,
+}
+
+impl BootstrapQuota {
+    fn new(now: Instant) -> Self {
+        BootstrapQuota {
+            window_start: now,
+            clients: 0,
+            nodes: 0,
+        }
+    }
+
+    /// Returns whether granting one more bootstrap to a peer of the given kind stays within
+    /// `limit` in the current window.
+    fn allows(&mut self, peer_kind: CrustUser, limit: Option<u32>, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(BOOTSTRAP_QUOTA_WINDOW_SEC)
+        {
+            *self = BootstrapQuota::new(now);
+        }
+        limit.map_or(true, |limit| *self.count(peer_kind) < limit)
+    }
+
+    /// Records a bootstrap granted to a peer of the given kind.
+    fn record(&mut self, peer_kind: CrustUser) {
+        *self.count(peer_kind) += 1;
+    }
+
+    fn count(&mut self, peer_kind: CrustUser) -> &mut u32 {
+        match peer_kind {
+            CrustUser::Client => &mut self.clients,
+            CrustUser::Node => &mut self.nodes,
+        }
+    }
+}
+
+/// Returns the first port from `start` to `end` we can bind a TCP listener to. Done right before
+/// binding the actual listener, so it's unlikely the port is taken in between.
+fn first_free_port(ip: IpAddr, start: u16, end: u16) -> Option<u16> {
+    (start..=end).find(|&port| StdTcpListener::bind((ip, port)).is_ok())
+}
+
+/// Returns the addresses to advertise the main listener on: `external_addr` if it's configured,
+/// otherwise the mapped addresses, see `include_forced_port`.
+fn advertised_addrs(
+    mut mapped_addrs: Vec<SocketAddr>,
+    external_addr: Option<SocketAddr>,
+    forced_port: Option<u16>,
+) -> Vec<SocketAddr> {
+    if let Some(external_addr) = external_addr {
+        return vec![external_addr];
+    }
+    mapped_addrs.retain(|addr| match addr.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => !is_unicast_link_local(&ip),
+    });
+    if let Some(port) = forced_port {
+        include_forced_port(&mut mapped_addrs, port);
+    }
+    mapped_addrs
+}
+
+/// Makes sure our global addresses are also advertised with the forced port, see
+/// `Config::force_acceptor_port_in_ext_ep`.
+fn include_forced_port(mapped_addrs: &mut Vec<SocketAddr>, port: u16) {
+    let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
+    if !mapped_addrs.iter().any(checker) {
+        let global_addrs: Vec<_> = mapped_addrs
+            .iter()
+            .filter_map(|s| {
+                if ip_addr_is_global(&s.ip()) {
+                    let mut s = *s;
+                    s.set_port(port);
+                    Some(s)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        mapped_addrs.extend(global_addrs);
+    }
+}
+
+/// Our sorted local IP addresses, used to detect network changes.
+fn local_ips() -> Vec<IpAddr> {
+    let mut ips: Vec<_> = match get_if_addrs::get_if_addrs() {
+        Ok(ifs) => ifs.into_iter().map(|interface| interface.ip()).collect(),
+        Err(e) => {
+            debug!("Failed to get local interface addresses: {}", e);
+            Vec::new()
+        }
+    };
+    ips.sort();
+    ips
+}
+
+fn ipv6_listener_addrs(ifv6s: Vec<Ipv6Addr>, port: u16) -> Vec<SocketAddr> {
+    ifv6s
+        .into_iter()
+        .filter(|ip| !is_unicast_link_local(ip))
+        .map(|ip| SocketAddr::new(IpAddr::V6(ip), port))
+        .collect()
+}
+
+/// Binds an IPv6-only listener to the given port, so it can coexist with the IPv4 one.
+fn bind_ipv6_listener(port: u16) -> io::Result<TcpListener> {
+    let socket = TcpBuilder::new_v6()?;
+    let _ = socket.only_v6(true)?;
+    let _ = socket.reuse_address(true)?;
+    let _ = socket.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))?;
+    TcpListener::from_std(socket.listen(LISTENER_BACKLOG)?)
+}
+
+/// Link-local IPv6 addresses (fe80::/10) are only usable together with a scope ID which we can't
+/// advertise, hence such addresses are not shared with peers.
+fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
 #[cfg(test)]
 mod tests {
     use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
     use super::*;
     use crate::common::{
-        self, BootstrapperRole, CoreMessage, CrustUser, Message, NameHash, HASH_SIZE,
+        self, BootstrapDenyReason, BootstrapperRole, CoreMessage, CrustUser, Message, NameHash,
+        HASH_SIZE, PROTOCOL_VERSION,
     };
     use crate::main::bootstrap::Cache as BootstrapCache;
-    use crate::main::{Event, EventLoop};
+    use crate::main::{Config, ConfigWrapper, Event, EventLoop};
     use crate::nat::MappingContext;
     use crate::tests::UniqueId;
     use maidsafe_utilities::event_sender::MaidSafeEventCategory;
@@ -278,6 +910,10 @@ mod tests {
     }
 
     fn start_listener(accept_bootstrap: bool) -> Listener {
+        start_listener_with_config(accept_bootstrap, Default::default())
+    }
+
+    fn start_listener_with_config(accept_bootstrap: bool, config: Config) -> Listener {
         let el = unwrap!(common::spawn_event_loop(
             LISTENER_TOKEN + 1,
             Some("Connection Listener Test"),
@@ -285,12 +921,15 @@ mod tests {
         ));
 
         let (event_tx, event_rx) = mpsc::channel();
-        let crust_sender =
-            crate::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
+        let crust_sender = EventSender::new(crate::CrustEventSender::new(
+            event_tx,
+            MaidSafeEventCategory::Crust,
+            mpsc::channel().0,
+        ));
 
         let cm = Arc::new(Mutex::new(HashMap::new()));
-        let mc = Arc::new(unwrap!(MappingContext::try_new(), "Could not get MC"));
-        let config = Arc::new(Mutex::new(Default::default()));
+        let mc = Arc::new(unwrap!(MappingContext::try_new(true), "Could not get MC"));
+        let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
         let listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
         let (our_pk, our_sk) = gen_encrypt_keypair();
 
@@ -302,15 +941,19 @@ mod tests {
                     core,
                     poll,
                     Some(HANDSHAKE_TIMEOUT_SEC),
-                    0,
+                    ListenerConfig::tcp(0),
                     false,
+                    false,
+                    None,
                     uid,
                     NAME_HASH,
                     cm,
                     config,
+                    Blacklist::new(None),
                     mc,
                     listeners_clone,
                     Token(LISTENER_TOKEN),
+                    Default::default(),
                     crust_sender,
                     our_pk,
                     our_sk,
@@ -371,6 +1014,27 @@ mod tests {
     }
 
     fn bootstrap(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
+        match send_bootstrap_request(name_hash, PROTOCOL_VERSION, our_uid, listener) {
+            Message::BootstrapGranted(peer_uid, _, _) => assert_eq!(peer_uid, listener.uid),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::BootstrapAccept(peer_id, peer_kind) => {
+                assert_eq!(peer_id, our_uid);
+                assert_eq!(peer_kind, CrustUser::Client);
+            }
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    /// Sends a `BootstrapRequest` and returns the listener's answer.
+    fn send_bootstrap_request(
+        name_hash: NameHash,
+        version: u32,
+        our_uid: UniqueId,
+        listener: &Listener,
+    ) -> Message<UniqueId> {
         const SOCKET_TOKEN: Token = Token(0);
         let el = unwrap!(Poll::new());
 
@@ -381,11 +1045,17 @@ mod tests {
         unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key)));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge(),));
 
-        let message =
-            Message::BootstrapRequest(our_uid, name_hash, BootstrapperRole::Client, our_pk);
+        let message = Message::BootstrapRequest(
+            our_uid,
+            name_hash,
+            version,
+            BootstrapperRole::Client,
+            our_pk,
+            false,
+        );
 
         let mut events = Events::with_capacity(16);
-        let msg = 'event_loop: loop {
+        'event_loop: loop {
             let _ = unwrap!(el.poll(&mut events, None));
             for ev in events.iter() {
                 match ev.token() {
@@ -401,30 +1071,25 @@ mod tests {
                             ));
                         }
                         if ev.readiness().is_readable() {
-                            let msg: Message<UniqueId> = unwrap!(unwrap!(sock.read()));
-                            break 'event_loop msg;
+                            break 'event_loop unwrap!(unwrap!(sock.read()));
                         }
                     }
                     _ => panic!("Unexpected event"),
                 }
             }
-        };
-
-        match msg {
-            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
-            msg => panic!("Unexpected message: {:?}", msg),
-        }
-
-        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::BootstrapAccept(peer_id, peer_kind) => {
-                assert_eq!(peer_id, our_uid);
-                assert_eq!(peer_kind, CrustUser::Client);
-            }
-            event => panic!("Unexpected event notification: {:?}", event),
         }
     }
 
     fn connect(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
+        connect_with_version(name_hash, PROTOCOL_VERSION, our_uid, listener)
+    }
+
+    fn connect_with_version(
+        name_hash: NameHash,
+        version: u32,
+        our_uid: UniqueId,
+        listener: &Listener,
+    ) {
         const SOCKET_TOKEN: Token = Token(0);
         let el = unwrap!(Poll::new());
 
@@ -435,7 +1100,14 @@ mod tests {
         unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge()));
 
-        let message = Message::ConnectRequest(our_uid, name_hash, Default::default(), our_pk);
+        let message = Message::ConnectRequest(
+            our_uid,
+            name_hash,
+            version,
+            Default::default(),
+            our_pk,
+            false,
+        );
 
         let mut events = Events::with_capacity(16);
         'event_loop: loop {
@@ -456,9 +1128,10 @@ mod tests {
                         if ev.readiness().is_readable() {
                             let msg: Message<UniqueId> = unwrap!(unwrap!(sock.read()));
                             let their_uid = match msg {
-                                Message::ConnectResponse(peer_uid, peer_hash) => {
+                                Message::ConnectResponse(peer_uid, peer_hash, peer_version, _) => {
                                     assert_eq!(peer_uid, listener.uid);
                                     assert_eq!(peer_hash, NAME_HASH);
+                                    assert_eq!(peer_version, PROTOCOL_VERSION);
 
                                     unwrap!(sock.set_encrypt_ctx(EncryptContext::authenticated(
                                         shared_key
@@ -486,6 +1159,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn link_local_ipv6_addresses_are_detected() {
+        use std::str::FromStr;
+
+        assert!(is_unicast_link_local(&unwrap!(Ipv6Addr::from_str(
+            "fe80::1"
+        ))));
+        assert!(!is_unicast_link_local(&unwrap!(Ipv6Addr::from_str(
+            "2001:db8::1"
+        ))));
+        assert!(!is_unicast_link_local(&Ipv6Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn forced_port_is_added_to_global_addresses() {
+        let mut addrs = vec![
+            common::ipv4_addr(192, 168, 0, 2, 5000),
+            common::ipv4_addr(1, 2, 3, 4, 6000),
+        ];
+        include_forced_port(&mut addrs, 5483);
+        assert_eq!(
+            addrs,
+            vec![
+                common::ipv4_addr(192, 168, 0, 2, 5000),
+                common::ipv4_addr(1, 2, 3, 4, 6000),
+                common::ipv4_addr(1, 2, 3, 4, 5483),
+            ]
+        );
+
+        include_forced_port(&mut addrs, 5483);
+        assert_eq!(addrs.len(), 3);
+    }
+
     #[test]
     fn bootstrap_with_correct_parameters() {
         let listener = start_listener(true);
@@ -531,6 +1237,27 @@ mod tests {
         connect(NAME_HASH_2, uid, &listener);
     }
 
+    #[test]
+    fn bootstrap_with_incompatible_protocol_version() {
+        let listener = start_listener(true);
+        let uid = rand::random();
+        match send_bootstrap_request(NAME_HASH, PROTOCOL_VERSION + 1, uid, &listener) {
+            Message::BootstrapDenied(reason) => assert_eq!(
+                reason,
+                BootstrapDenyReason::IncompatibleProtocolVersion(PROTOCOL_VERSION)
+            ),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn connect_with_incompatible_protocol_version() {
+        let listener = start_listener(true);
+        let uid = rand::random();
+        connect_with_version(NAME_HASH, PROTOCOL_VERSION + 1, uid, &listener);
+    }
+
     #[test]
     #[should_panic]
     fn bootstrap_with_invalid_pub_key() {
@@ -545,6 +1272,86 @@ mod tests {
         connect(NAME_HASH, listener.uid, &listener);
     }
 
+    #[test]
+    fn connection_attempts_are_counted_per_window() {
+        use std::str::FromStr;
+
+        let start = Instant::now();
+        let ip1 = unwrap!(IpAddr::from_str("10.0.0.1"));
+        let ip2 = unwrap!(IpAddr::from_str("10.0.0.2"));
+        let mut attempts = ConnectionAttempts::new(start);
+
+        assert_eq!(attempts.record(ip1, start), 1);
+        assert_eq!(attempts.record(ip1, start + Duration::from_secs(1)), 2);
+        assert_eq!(attempts.record(ip2, start + Duration::from_secs(2)), 1);
+
+        let next_window = start + Duration::from_secs(CONN_ATTEMPTS_WINDOW_SEC);
+        assert_eq!(attempts.record(ip1, next_window), 1);
+        assert_eq!(attempts.record(ip2, next_window), 1);
+    }
+
+    #[test]
+    fn bootstrap_quota_is_kept_per_peer_kind_and_window() {
+        let start = Instant::now();
+        let mut quota = BootstrapQuota::new(start);
+
+        assert!(quota.allows(CrustUser::Client, Some(1), start));
+        quota.record(CrustUser::Client);
+        assert!(!quota.allows(CrustUser::Client, Some(1), start));
+        assert!(quota.allows(CrustUser::Client, None, start));
+        assert!(quota.allows(CrustUser::Node, Some(1), start));
+
+        let next_window = start + Duration::from_secs(BOOTSTRAP_QUOTA_WINDOW_SEC);
+        assert!(quota.allows(CrustUser::Client, Some(1), next_window));
+    }
+
+    #[test]
+    fn clients_over_bootstrap_quota_are_throttled() {
+        let mut config = Config::default();
+        config.client_bootstraps_per_minute = Some(1);
+        let listener = start_listener_with_config(true, config);
+
+        bootstrap(NAME_HASH, rand::random(), &listener);
+        match send_bootstrap_request(NAME_HASH, PROTOCOL_VERSION, rand::random(), &listener) {
+            Message::BootstrapDenied(reason) => assert_eq!(reason, BootstrapDenyReason::Throttled),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn bootstrappers_in_forbidden_subnets_are_denied() {
+        let mut config = Config::default();
+        config.forbidden_subnets = vec![unwrap!("127.0.0.0/8".parse())];
+        let listener = start_listener_with_config(true, config);
+
+        match send_bootstrap_request(NAME_HASH, PROTOCOL_VERSION, rand::random(), &listener) {
+            Message::BootstrapDenied(reason) => {
+                assert_eq!(reason, BootstrapDenyReason::ForbiddenSubnet)
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn oldest_pending_handshake_is_dropped_at_limit() {
+        let mut config = Config::default();
+        config.max_pending_handshakes = Some(1);
+        let listener = start_listener_with_config(true, config);
+
+        let mut idle_stream = connect_to_listener(&listener);
+        std::thread::sleep(Duration::from_millis(100));
+        let _other_stream = connect_to_listener(&listener);
+
+        let mut buf = [0; 1];
+        match idle_stream.read(&mut buf) {
+            Ok(0) => (),
+            res => panic!("Expected the idle handshake to be dropped, got {:?}", res),
+        }
+
+        let uid = rand::random();
+        bootstrap(NAME_HASH, uid, &listener);
+    }
+
     #[test]
     fn invalid_msg_terminates_connection() {
         let listener = start_listener(true);
@@ -8,6 +8,7 @@
 // Software.
 
 use crate::common;
+use crate::main::{ConfigError, ConfigFormat};
 use crate::nat;
 use crate::service_discovery;
 use config_file_handler;
@@ -85,6 +86,11 @@ quick_error! {
             description("Requested connection to self")
             display("Requested connection to self")
         }
+        /// Connection info string is malformed or of an unsupported version.
+        InvalidConnectionInfo {
+            description("Invalid connection info")
+            display("Invalid connection info")
+        }
         /// Listener is not initialised yet.
         ListenerNotIntialised {
             description("Listener is not initialised yet")
@@ -96,6 +102,42 @@ quick_error! {
             cause(e)
             from()
         }
+        /// Peer is blacklisted.
+        PeerBlacklisted {
+            description("Peer is blacklisted")
+            display("Peer is blacklisted")
+        }
+        /// Message is bigger than `max_msg_size` in the config.
+        MessageTooLarge(size: usize, max: usize) {
+            description("Message too large")
+            display("Message of {} bytes exceeds the limit of {} bytes", size, max)
+        }
+        /// The config file isn't valid JSON, TOML or YAML, or doesn't hold the settings we expect.
+        ConfigParse(format: ConfigFormat, reason: String) {
+            description("Config file parsing error")
+            display("Could not parse {:?} config file: {}", format, reason)
+        }
+        /// The config doesn't make sense, see `Config::validate`.
+        InvalidConfig(e: ConfigError) {
+            description("Invalid config")
+            display("Invalid config: {}", e)
+        }
+        /// The peer given to `Service::connect_via` as the relay is a client, and only nodes relay.
+        RelayNotANode {
+            description("Relay is not a node")
+            display("Relay is not a node")
+        }
+        /// No channel with the given id was opened to the peer via `Service::open_channel`.
+        ChannelNotOpen(channel: u16) {
+            description("Channel not open")
+            display("Channel {} is not open", channel)
+        }
+        /// The channel with the given id holds as many messages back as `channel_queue_limit` in
+        /// the config allows.
+        ChannelFull(channel: u16) {
+            description("Channel full")
+            display("Channel {} is full", channel)
+        }
         /// Crypto error.
         Crypto(e: safe_crypto::Error) {
             display("Crypto error: {}", e)
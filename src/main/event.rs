@@ -7,10 +7,61 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use super::ConnectionInfoResult;
+use super::{ConnectStats, ConnectionInfoResult, IncomingStream, RelayedConnectionInfo};
 
-use crate::common::{CrustUser, Uid};
-use std::net::SocketAddr;
+use crate::common::{BootstrapDenyReason, CrustUser, DisconnectReason, Uid};
+use crate::nat::NatInfo;
+use bytes::Bytes;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Why the connection to a peer was lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostPeerReason {
+    /// The peer didn't send anything within the inactivity timeout.
+    InactivityTimeout,
+    /// The peer closed the connection, either after saying goodbye or abruptly.
+    RemoteClosed,
+    /// Reading from or writing to the connection failed.
+    IoError(io::ErrorKind),
+    /// We closed the connection, e.g. via `Service::disconnect`.
+    Evicted,
+    /// The peer sent a message bigger than `max_msg_size` in our config.
+    MessageTooLarge,
+    /// The peer kept sending more than `inbound_msgs_per_sec` or `inbound_bytes_per_sec` in our
+    /// config allow, even though it was throttled.
+    Flooding,
+    /// We dropped the peer to make room for a new one under the connection limits of our config.
+    ConnectionLimit,
+    /// The peer's score dropped to the ban threshold of our config.
+    LowScore,
+    /// The peer's IP was removed from the whitelist.
+    NotWhitelisted,
+    /// The peer dropped us deliberately and told us why.
+    DroppedByPeer(DisconnectReason),
+    /// We reached the peer through a common peer, see `Service::connect_via`, and lost the
+    /// connection to that one.
+    RelayLost,
+}
+
+/// A change to the set of peers we are connected to, see `Service::peer_set_changes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSetChange<UID: Uid> {
+    /// We connected to the peer, which has the given role.
+    Added(UID, CrustUser),
+    /// We lost the connection to the peer.
+    Removed(UID, LostPeerReason),
+}
+
+/// Why trying to bootstrap off a contact failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootstrapError {
+    /// We couldn't connect to the contact or it didn't complete the handshake.
+    Unreachable,
+    /// The contact refused to let us bootstrap off it.
+    Denied(BootstrapDenyReason),
+}
 
 /// Enum representing different events that will be sent over the asynchronous channel to the user
 /// of this module.
@@ -22,21 +73,103 @@ pub enum Event<UID: Uid> {
     BootstrapConnect(UID, SocketAddr),
     /// Invoked when we failed to connect to all bootstrap contacts.
     BootstrapFailed,
+    /// Invoked when bootstrapping was aborted via `Service::cancel_bootstrap`.
+    BootstrapCancelled,
+    /// Invoked right before `Event::BootstrapConnect` if the bootstrappee tested our external
+    /// reachability. Lists which of our listeners it could connect to.
+    ListenersReachability(Vec<(SocketAddr, bool)>),
+    /// Invoked while bootstrapping when we start contacting the bootstrap contacts and whenever
+    /// one of them fails, if enabled via `report_bootstrap_progress` in the config.
+    BootstrapProgress {
+        /// How many contacts failed so far in the current attempt.
+        tried: usize,
+        /// How many contacts we are still trying or have yet to try.
+        remaining: usize,
+        /// Why the contact which failed last didn't work out. `None` before any failed.
+        last_error: Option<BootstrapError>,
+    },
+    /// Invoked when a bootstrap attempt failed and, as the `BootstrapPolicy` allows, another one
+    /// will be made after the given delay. Passes the number of the failed attempt, counting from
+    /// 1.
+    BootstrapRetrying(u32, Duration),
+    /// Invoked when we lost our last node connection and, as enabled via
+    /// `Service::enable_auto_rebootstrap`, bootstrap again.
+    RebootstrapStarted,
+    /// Invoked when bootstrapping again, after `Event::RebootstrapStarted`, connected us to a
+    /// node.
+    RebootstrapSucceeded,
+    /// Invoked when bootstrapping again, after `Event::RebootstrapStarted`, didn't connect us to
+    /// any node. We try again once a node connection is made and lost.
+    RebootstrapFailed,
     /// Invoked when we are ready to listen for incomming connection. Contains
     /// the listening port.
     ListenerStarted(u16),
-    /// Invoked when listener failed to start.
-    ListenerFailed,
+    /// Invoked when a listener failed to start, or stopped working because the interface it's
+    /// bound to went away. Passes the address it was meant to listen on and what went wrong. The
+    /// listener is started again after a while, see `Service::start_listening_tcp`.
+    ListenerFailed(SocketAddr, String),
+    /// Invoked when a changed config was taken over, either because the config file changed or
+    /// via `Service::reload_config`. Names the settings which changed.
+    ConfigReloaded {
+        /// Settings which are in effect now, e.g. whitelists and rate limits. Settings of
+        /// individual connections, like the rate limits, apply to new connections.
+        applied: Vec<&'static str>,
+        /// Settings which only take effect once the `Service`, or its listeners, are started
+        /// again, see `Config::requires_restart`.
+        requires_restart: Vec<&'static str>,
+    },
     /// Invoked as a result to the call of `Service::prepare_contact_info`.
     ConnectionInfoPrepared(ConnectionInfoResult<UID>),
+    /// Invoked when a peer sent us its connection info through a common peer via
+    /// `Service::send_connection_info_via`.
+    ConnectionInfoRelayed(RelayedConnectionInfo<UID>),
     /// Invoked when connection to a new peer has been established.
     ConnectSuccess(UID),
     /// Invoked when connection to a new peer has failed.
     ConnectFailure(UID),
+    /// Invoked when `Service::connect_to_addr` connected us to the peer listening on the given
+    /// address.
+    DirectConnectSuccess(UID, SocketAddr),
+    /// Invoked when `Service::connect_to_addr` couldn't connect us to the given address.
+    DirectConnectFailure(SocketAddr, BootstrapError),
+    /// Invoked when a `Service::connect` attempt finishes, whether it succeeded or not. Only sent
+    /// if `Config::report_connect_stats` is enabled.
+    ConnectStats(UID, ConnectStats),
     /// Invoked when a peer disconnects or can no longer be contacted.
-    LostPeer(UID),
+    LostPeer(UID, LostPeerReason),
     /// Invoked when a new message is received. Passes the message.
-    NewMessage(UID, CrustUser, Vec<u8>),
+    NewMessage(UID, CrustUser, Bytes),
+    /// Invoked when a message sent on a channel via `Service::send_on_channel` is received.
+    /// Passes the channel id and the message.
+    NewChannelMessage(UID, u16, Bytes),
+    /// Invoked when the peer confirmed receipt of a message sent via `Service::send_with_ack`.
+    /// Passes the message id given to it.
+    MessageDelivered(UID, u64),
+    /// Invoked when the peer sent a request via `Service::request`. Passes the request id, which
+    /// the answer given via `Service::respond` has to carry, and the request.
+    NewRequest(UID, u64, Vec<u8>),
+    /// Invoked when the peer answered our request. Passes the request id and the response.
+    NewResponse(UID, u64, Vec<u8>),
+    /// Invoked when the peer starts streaming data to us via `Service::send_stream`. The data is
+    /// read from the given stream.
+    NewStream(UID, IncomingStream),
+    /// Invoked when the peer went over our inbound limits and we stopped reading from it for
+    /// the rest of the second. A peer which keeps doing so is dropped.
+    PeerThrottled(UID),
+    /// Invoked when an IP address connects to us more often than allowed by the config's
+    /// `inbound_conn_attempts_per_ip` and we start refusing its connections for the rest of the
+    /// minute.
+    InboundConnRateLimited(IpAddr),
+    /// Invoked when the peer answered a `Service::ping`. Passes the round trip time.
+    PingResponse(UID, Duration),
     /// Invoked when trying to sending a too large data.
     WriteMsgSizeProhibitive(UID, Vec<u8>),
+    /// Invoked when a message was dropped because the peer's send queue is full. Passes the
+    /// message.
+    WriteBlocked(UID, Bytes),
+    /// Invoked as a result to the call of `Service::nat_info`.
+    NatInfo(NatInfo),
+    /// Invoked when our listener addresses changed because our local IP addresses did, e.g. after
+    /// switching networks. Connection info prepared before this should be prepared again.
+    ExternalAddressChanged,
 }
@@ -0,0 +1,159 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::Uid;
+use crate::main::{Event, PeerSetChange};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use maidsafe_utilities::event_sender::{EventSenderError, MaidSafeEventCategory};
+use std::sync::{Arc, Mutex};
+
+/// What the states of a `Service` report to the application through: its events, which go to
+/// the stream handed out by `Service::event_stream` while there is one and to the observer the
+/// service was constructed with otherwise, and the changes to the set of connected peers, see
+/// `Service::peer_set_changes`.
+pub struct EventSender<UID: Uid> {
+    observer: crate::CrustEventSender<UID>,
+    stream_tx: Arc<Mutex<Option<UnboundedSender<Event<UID>>>>>,
+    peer_set_txs: Arc<Mutex<Vec<UnboundedSender<PeerSetChange<UID>>>>>,
+}
+
+impl<UID: Uid> EventSender<UID> {
+    pub fn new(observer: crate::CrustEventSender<UID>) -> Self {
+        EventSender {
+            observer,
+            stream_tx: Arc::new(Mutex::new(None)),
+            peer_set_txs: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn send(
+        &self,
+        event: Event<UID>,
+    ) -> Result<(), EventSenderError<MaidSafeEventCategory, Event<UID>>> {
+        let event = {
+            let mut stream_tx = unwrap!(self.stream_tx.lock());
+            let event = match *stream_tx {
+                Some(ref tx) => match tx.unbounded_send(event) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e.into_inner(),
+                },
+                None => event,
+            };
+            // The stream was dropped, so the observer gets the events again.
+            *stream_tx = None;
+            event
+        };
+        self.observer.send(event)
+    }
+
+    /// Sends the events to the returned stream from now on, instead of to the observer or the
+    /// stream returned before.
+    pub fn event_stream(&self) -> UnboundedReceiver<Event<UID>> {
+        let (tx, rx) = mpsc::unbounded();
+        *unwrap!(self.stream_tx.lock()) = Some(tx);
+        rx
+    }
+
+    /// Passes the change on to every stream handed out by `peer_set_changes` which wasn't
+    /// dropped yet.
+    pub fn send_peer_set_change(&self, change: PeerSetChange<UID>) {
+        unwrap!(self.peer_set_txs.lock()).retain(|tx| tx.unbounded_send(change).is_ok());
+    }
+
+    pub fn peer_set_changes(&self) -> UnboundedReceiver<PeerSetChange<UID>> {
+        let (tx, rx) = mpsc::unbounded();
+        unwrap!(self.peer_set_txs.lock()).push(tx);
+        rx
+    }
+}
+
+impl<UID: Uid> Clone for EventSender<UID> {
+    fn clone(&self) -> Self {
+        EventSender {
+            observer: self.observer.clone(),
+            stream_tx: self.stream_tx.clone(),
+            peer_set_txs: self.peer_set_txs.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{get_event_sender, UniqueId};
+    use crate::{CrustUser, LostPeerReason};
+    use futures::{Future, Stream};
+
+    #[test]
+    fn events_go_to_the_observer_once_the_stream_is_dropped() {
+        let (observer, event_rx) = get_event_sender();
+        let event_tx = EventSender::<UniqueId>::new(observer);
+
+        let _ = event_tx.send(Event::BootstrapFailed);
+        let stream = event_tx.event_stream();
+        let _ = event_tx.send(Event::BootstrapCancelled);
+        drop(stream);
+        let _ = event_tx.send(Event::RebootstrapFailed);
+
+        match unwrap!(event_rx.try_recv()) {
+            Event::BootstrapFailed => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        match unwrap!(event_rx.try_recv()) {
+            Event::RebootstrapFailed => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn new_event_stream_replaces_the_previous_one() {
+        let (observer, _event_rx) = get_event_sender();
+        let event_tx = EventSender::<UniqueId>::new(observer);
+
+        let stream0 = event_tx.event_stream();
+        let _ = event_tx.send(Event::BootstrapFailed);
+        let stream1 = event_tx.event_stream();
+        let _ = event_tx.send(Event::BootstrapCancelled);
+        drop(event_tx);
+
+        match unwrap!(stream0.collect().wait()).as_slice() {
+            [Event::BootstrapFailed] => (),
+            events => panic!("Unexpected events: {:?}", events),
+        }
+        match unwrap!(stream1.collect().wait()).as_slice() {
+            [Event::BootstrapCancelled] => (),
+            events => panic!("Unexpected events: {:?}", events),
+        }
+    }
+
+    #[test]
+    fn peer_set_changes_reach_every_live_stream() {
+        let (observer, _event_rx) = get_event_sender();
+        let event_tx = EventSender::<UniqueId>::new(observer);
+        let rx0 = event_tx.peer_set_changes();
+        let rx1 = event_tx.peer_set_changes();
+        let uid = [1; 20];
+
+        event_tx.send_peer_set_change(PeerSetChange::Added(uid, CrustUser::Node));
+        drop(rx1);
+        event_tx.send_peer_set_change(PeerSetChange::Removed(uid, LostPeerReason::Evicted));
+        assert_eq!(unwrap!(event_tx.peer_set_txs.lock()).len(), 1);
+
+        drop(event_tx);
+        let changes = unwrap!(rx0.collect().wait());
+        assert_eq!(
+            changes,
+            vec![
+                PeerSetChange::Added(uid, CrustUser::Node),
+                PeerSetChange::Removed(uid, LostPeerReason::Evicted),
+            ]
+        );
+    }
+}
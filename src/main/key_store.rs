@@ -0,0 +1,91 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use config_file_handler::{self, FileHandler};
+use rand;
+use safe_crypto::{self, gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey, SymmetricKey};
+use std::ffi::OsString;
+
+/// Our keypair as written to the key file. The symmetric key it is sealed with is derived from
+/// the passphrase and the salt, which is random for each file.
+#[derive(Default, Serialize, Deserialize)]
+struct SealedKeys {
+    salt: Vec<u8>,
+    sealed: Vec<u8>,
+}
+
+/// Returns the key file name used if `Config::key_file_name` isn't set.
+pub fn get_default_file_name() -> crate::Res<OsString> {
+    let mut name = config_file_handler::exe_file_stem()?;
+    name.push(".crust.keys");
+    Ok(name)
+}
+
+/// Unseals the keypair in the given file with `passphrase`. If the file doesn't hold a keypair
+/// yet, a new one is generated and written to it. Fails if the passphrase is wrong.
+pub fn load_or_create(
+    file_name: Option<&OsString>,
+    passphrase: &str,
+) -> crate::Res<(PublicEncryptKey, SecretEncryptKey)> {
+    let file_name = match file_name {
+        Some(file_name) => file_name.clone(),
+        None => get_default_file_name()?,
+    };
+    let file_handler = FileHandler::<SealedKeys>::new(&file_name, true)?;
+    let keys = if file_handler.path().exists() {
+        file_handler.read_file()?
+    } else {
+        Default::default()
+    };
+    if !keys.sealed.is_empty() {
+        return unseal(&keys, passphrase);
+    }
+
+    let (our_pk, our_sk) = gen_encrypt_keypair();
+    file_handler.write_file(&seal(&our_pk, &our_sk, passphrase)?)?;
+    Ok((our_pk, our_sk))
+}
+
+fn seal(
+    our_pk: &PublicEncryptKey,
+    our_sk: &SecretEncryptKey,
+    passphrase: &str,
+) -> crate::Res<SealedKeys> {
+    let salt = rand::random::<[u8; 32]>().to_vec();
+    let sealed = derive_key(passphrase, &salt)?.encrypt(&(our_pk, our_sk))?;
+    Ok(SealedKeys { salt, sealed })
+}
+
+fn unseal(keys: &SealedKeys, passphrase: &str) -> crate::Res<(PublicEncryptKey, SecretEncryptKey)> {
+    Ok(derive_key(passphrase, &keys.salt)?.decrypt(&keys.sealed)?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> crate::Res<SymmetricKey> {
+    let mut key = [0; 32];
+    safe_crypto::derive_key_from_pw(passphrase.as_bytes(), salt, None, &mut key)?;
+    Ok(SymmetricKey::from_bytes(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::utils::bootstrap_cache_tmp_file;
+
+    #[test]
+    fn keys_are_created_once_and_unsealed_with_the_passphrase_only() {
+        unwrap!(safe_crypto::init());
+        let file_name: OsString = bootstrap_cache_tmp_file().into();
+
+        let (pk, _) = unwrap!(load_or_create(Some(&file_name), "correct horse"));
+        let (pk_again, _) = unwrap!(load_or_create(Some(&file_name), "correct horse"));
+        assert_eq!(pk_again, pk);
+
+        assert!(load_or_create(Some(&file_name), "battery staple").is_err());
+    }
+}
@@ -7,33 +7,59 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-pub use self::active_connection::{ActiveConnection, INACTIVITY_TIMEOUT_MS};
-pub use self::bootstrap::Bootstrap;
+pub use self::active_connection::{ActiveConnection, HEARTBEAT_PERIOD_MS, INACTIVITY_TIMEOUT_MS};
+pub use self::bandwidth_budget::BandwidthBudget;
+pub use self::blacklist::Blacklist;
 #[cfg(test)]
 pub use self::bootstrap::Cache as BootstrapCache;
+pub use self::bootstrap::{Bootstrap, BootstrapHandle, BootstrapPolicy, DirectConnect};
 pub use self::config_handler::Config;
-pub use self::config_refresher::ConfigRefresher;
+pub use self::config_refresher::{drop_non_whitelisted, reload_config, ConfigRefresher};
 pub use self::connect::Connect;
+pub use self::connection_auditor::ConnectionAuditor;
 pub use self::connection_candidate::ConnectionCandidate;
-pub use self::connection_listener::ConnectionListener;
+pub use self::connection_limits::EvictionPolicy;
+pub use self::connection_listener::{ConnectionListener, ListenerRetry, ListenerSettings};
 pub use self::error::CrustError;
-pub use self::event::Event;
-pub use self::service::Service;
+pub use self::event::{BootstrapError, Event, LostPeerReason, PeerSetChange};
+pub use self::event_sender::EventSender;
+pub use self::peer_scoring::PeerScoring;
+pub use self::rebootstrapper::Rebootstrapper;
+pub use self::service::{EventToken, Service};
+pub use self::service_builder::ServiceBuilder;
+pub use self::stream::IncomingStream;
+pub use self::typed_service::TypedService;
 pub use self::types::{
-    ConfigWrapper, ConnectionId, ConnectionInfoResult, ConnectionMap, CrustConfig, EventLoop,
-    EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    BootstrapAdmission, ConfigWrapper, ConnectStats, ConnectedPeer, ConnectionId, ConnectionInfoResult, ConnectionMap, CrustConfig, EventLoop,
+    EventLoopCore, ListenerState, PeerStats, PrivConnectionInfo, PubConnectionInfo, RelayedConnectionInfo,
+    SendToken, ServiceStats, Whitelists,
 };
 
 mod active_connection;
+mod bandwidth_budget;
+mod blacklist;
 mod bootstrap;
 mod config_handler;
 mod config_refresher;
 mod connect;
+mod connection_auditor;
 mod connection_candidate;
+mod connection_limits;
 mod connection_listener;
 mod error;
 mod event;
+mod event_sender;
+mod key_store;
+mod peer_scoring;
+mod rebootstrapper;
 mod service;
+mod service_builder;
+mod session;
+mod stream;
+mod typed_service;
 mod types;
 
-pub use self::config_handler::read_config_file;
+pub use self::config_handler::{
+    override_with_env_vars, read_config_file, ConfigError, ConfigFormat, ConfigProblem,
+    ConnectOrder, ListenerConfig, Transport,
+};
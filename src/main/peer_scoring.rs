@@ -0,0 +1,40 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+/// How a peer's score changes when it misbehaves, and what happens once it drops too low.
+///
+/// Every connection starts with a score of 0. Penalties are subtracted from it and upper layers
+/// can add their own signals via `Service::adjust_score`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerScoring {
+    /// Subtracted when the peer goes over our inbound limits.
+    pub throttled_penalty: i32,
+    /// Subtracted when the peer sends a message it shouldn't send on an established connection.
+    pub protocol_error_penalty: i32,
+    /// Subtracted for every heartbeat period in which our ping stays unanswered.
+    pub unanswered_ping_penalty: i32,
+    /// A peer whose score drops to or below this is disconnected and blacklisted. If `None`,
+    /// peers are never banned for their score.
+    pub ban_threshold: Option<i32>,
+    /// How long a peer banned for its score stays blacklisted.
+    pub ban_duration_sec: u64,
+}
+
+impl Default for PeerScoring {
+    fn default() -> Self {
+        PeerScoring {
+            throttled_penalty: 10,
+            protocol_error_penalty: 20,
+            unanswered_ping_penalty: 5,
+            ban_threshold: None,
+            ban_duration_sec: 600,
+        }
+    }
+}
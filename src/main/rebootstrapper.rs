@@ -0,0 +1,130 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CoreTimer, CrustUser, State, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{ActiveConnection, ConnectionMap, Event, EventLoopCore, EventSender};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+const CHECK_INTERVAL_MS: u64 = 500;
+
+/// Starts the bootstrap state, unless it's running already.
+pub type StartBootstrap = Box<Fn(&mut EventLoopCore, &Poll)>;
+
+/// Watches the connection map and bootstraps again once we lose our last connection to a node.
+pub struct Rebootstrapper<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    timeout: Timeout,
+    bootstrap_token: Token,
+    cm: ConnectionMap<UID>,
+    start_bootstrap: StartBootstrap,
+    event_tx: EventSender<UID>,
+    had_nodes: bool,
+    bootstrapping: bool,
+}
+
+impl<UID: Uid> Rebootstrapper<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        bootstrap_token: Token,
+        cm: ConnectionMap<UID>,
+        start_bootstrap: StartBootstrap,
+        event_tx: EventSender<UID>,
+    ) {
+        trace!("Entered state Rebootstrapper");
+
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(Duration::from_millis(CHECK_INTERVAL_MS), timer);
+        let had_nodes = node_connections(core, &cm) > 0;
+
+        let state = Rc::new(RefCell::new(Rebootstrapper {
+            token,
+            timer,
+            timeout,
+            bootstrap_token,
+            cm,
+            start_bootstrap,
+            event_tx,
+            had_nodes,
+            bootstrapping: false,
+        }));
+        let _ = core.insert_state(token, state);
+    }
+
+    fn check(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let has_nodes = node_connections(core, &self.cm) > 0;
+
+        if self.bootstrapping {
+            if core.get_state(self.bootstrap_token).is_some() {
+                return;
+            }
+            self.bootstrapping = false;
+            let event = if has_nodes {
+                Event::RebootstrapSucceeded
+            } else {
+                Event::RebootstrapFailed
+            };
+            let _ = self.event_tx.send(event);
+        } else if self.had_nodes && !has_nodes {
+            debug!("Lost our last node connection, bootstrapping again.");
+            self.bootstrapping = true;
+            let _ = self.event_tx.send(Event::RebootstrapStarted);
+            (self.start_bootstrap)(core, poll);
+            return;
+        }
+
+        self.had_nodes = has_nodes;
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for Rebootstrapper<UID> {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(Duration::from_millis(CHECK_INTERVAL_MS), self.timer);
+        self.check(core, poll);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Counts the established connections to peers which connected to us as nodes.
+fn node_connections<UID: Uid>(core: &EventLoopCore, cm: &ConnectionMap<UID>) -> usize {
+    // Collected to avoid keeping the mutex lock alive while looking at the states.
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+
+    tokens
+        .into_iter()
+        .filter(|token| match core.get_state(*token) {
+            Some(state) => {
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    Some(ac) => ac.peer_kind() == CrustUser::Node,
+                    None => false,
+                }
+            }
+            None => false,
+        })
+        .count()
+}
@@ -8,33 +8,49 @@
 // Software.
 
 use crate::common::{
-    self, BootstrapperRole, CoreMessage, CrustUser, NameHash, PeerInfo, Uid, HASH_SIZE,
+    self, BootstrapperRole, CoreMessage, CrustUser, ExternalReachability, NameHash, PeerInfo, Uid,
+    HASH_SIZE,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
 use crate::main::config_handler::{self, Config};
 use crate::main::{
-    ActiveConnection, Bootstrap, ConfigRefresher, ConfigWrapper, Connect, ConnectionId,
-    ConnectionInfoResult, ConnectionListener, ConnectionMap, CrustConfig, CrustError, Event,
-    EventLoop, EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    drop_non_whitelisted, key_store, reload_config, ActiveConnection, BandwidthBudget, Blacklist,
+    Bootstrap, BootstrapAdmission, BootstrapError, BootstrapHandle, BootstrapPolicy,
+    ConfigRefresher, ConfigWrapper, Connect, ConnectedPeer, ConnectionAuditor, ConnectionId,
+    ConnectionInfoResult, ConnectionListener, ConnectionMap, CrustConfig, CrustError,
+    DirectConnect, Event, EventLoop, EventLoopCore, EventSender, ListenerConfig, ListenerRetry,
+    ListenerSettings, ListenerState, PeerSetChange, PeerStats, PrivConnectionInfo,
+    PubConnectionInfo, Rebootstrapper, RelayedConnectionInfo, SendToken, ServiceStats, Whitelists,
+};
+use crate::nat::{
+    ip_addr_is_global, ip_addr_is_shared, MappedTcpSocket, MappingContext, NatInfo, NatProbe,
+    NatType,
 };
-use crate::nat::{ip_addr_is_global, MappedTcpSocket, MappingContext};
 use crate::service_discovery::ServiceDiscovery;
+use bytes::Bytes;
+use futures::Stream;
 use mio::{Poll, Token};
 use safe_crypto::{self, gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
 use socket_collection::Priority;
+use std::any::Any;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Reserved mio `Token` values for Crust speficic events.
 #[derive(Debug, PartialEq)]
 #[repr(usize)]
-enum EventToken {
+pub enum EventToken {
     Bootstrap,
     ServiceDiscovery,
     Listener,
     ConfigRefresher,
+    ConnectionAuditor,
+    Rebootstrapper,
+    BandwidthBudget,
     Unreserved,
 }
 
@@ -46,13 +62,23 @@ impl From<EventToken> for Token {
 
 const SERVICE_DISCOVERY_DEFAULT_PORT: u16 = 5484;
 
+/// What `Service::stop_listening` stopped, so `Service::start_listening` can start it again.
+struct PausedListeners {
+    /// `None` if the main listener wasn't running.
+    main: Option<ListenerConfig>,
+    extras: Vec<ListenerConfig>,
+    settings: ListenerSettings,
+}
+
 const DISABLE_NAT: bool = true;
 
 /// A structure representing all the Crust services. This is the main object through which crust is
 /// used.
 ///
 /// You can construct `Service` using [`try_new`] which searches for config file in default location
-/// or [`with_config`], if you want to provide an in memory config.
+/// or [`with_config`], if you want to provide an in memory config. [`with_keys`] also takes the
+/// encryption keypair, for nodes which keep their identity across restarts, and
+/// [`with_passphrase`] keeps that keypair in a passphrase protected file for you.
 ///
 /// In the terms of networking `Service` exposes both server and client functionality. Meaning
 /// it will listen for incoming connections and establish ones itself.
@@ -74,6 +100,8 @@ const DISABLE_NAT: bool = true;
 ///
 /// [`try_new`]: struct.Service.html#method.try_new
 /// [`with_config`]: struct.Service.html#method.with_config
+/// [`with_keys`]: struct.Service.html#method.with_keys
+/// [`with_passphrase`]: struct.Service.html#method.with_passphrase
 /// [`connect`]: struct.Service.html#method.connect
 /// [`bootstrap`]: struct.Service.html#method.start_bootstrap
 /// [`set_accept_bootstrap`]: struct.Service.html#method.set_accept_bootstrap
@@ -83,41 +111,92 @@ const DISABLE_NAT: bool = true;
 pub struct Service<UID: Uid> {
     config: CrustConfig,
     cm: ConnectionMap<UID>,
-    event_tx: crate::CrustEventSender<UID>,
+    blacklist: Blacklist<UID>,
+    event_tx: EventSender<UID>,
     mc: Arc<MappingContext>,
     el: EventLoop,
     name_hash: NameHash,
     our_uid: UID,
     our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
+    /// Tokens of the listeners started after the main one, see `Config::listener_configs`.
+    extra_listeners: Arc<Mutex<Vec<Token>>>,
+    paused_listeners: Option<PausedListeners>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    /// Source of the tokens identifying the messages we send, see `cancel_send`.
+    send_tokens: AtomicUsize,
 }
 
 impl<UID: Uid> Service<UID> {
     /// Construct a service. `event_tx` is the sending half of the channel which crust will send
-    /// notifications on. Can fail, if can't read config file successfully.
+    /// notifications on. Can fail, if can't read config file successfully. `CRUST_*` environment
+    /// variables override the settings of the config file, see `override_with_env_vars`. The
+    /// config file is watched for changes, see `watch_config_file`.
     pub fn try_new(event_tx: crate::CrustEventSender<UID>, our_uid: UID) -> crate::Res<Self> {
-        Service::with_config(event_tx, config_handler::read_config_file()?, our_uid)
+        let service = Service::with_config(event_tx, config_handler::read_config_file()?, our_uid)?;
+        service.watch_config_file()?;
+        Ok(service)
     }
 
     /// Constructs a service with the given config. User needs to create an asynchronous channel,
     /// and provide the sender half to this method. Receiver will receive all `Event`s from this
-    /// library.
+    /// library. Fails if the config doesn't pass `Config::validate`. The config is kept as it is
+    /// and no config file is read or written, unless `watch_config_file` is called.
     pub fn with_config(
         event_tx: crate::CrustEventSender<UID>,
         config: Config,
         our_uid: UID,
     ) -> crate::Res<Self> {
         safe_crypto::init()?;
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        Service::with_keys(event_tx, config, our_uid, our_pk, our_sk)
+    }
+
+    /// Constructs a service with the encryption keypair kept in `Config::key_file_name`, so we
+    /// keep our public key across restarts. The keypair is encrypted at rest with a key derived
+    /// from `passphrase`. On the first run, a new keypair is generated and written to the file.
+    /// Fails if the passphrase doesn't unlock the file.
+    pub fn with_passphrase(
+        event_tx: crate::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        passphrase: &str,
+    ) -> crate::Res<Self> {
+        safe_crypto::init()?;
+        let (our_pk, our_sk) =
+            key_store::load_or_create(config.key_file_name.as_ref(), passphrase)?;
+        Service::with_keys(event_tx, config, our_uid, our_pk, our_sk)
+    }
+
+    /// Constructs a service with the given config and encryption keypair, so peers which know our
+    /// public key from an earlier run can still reach us. Use this if the application persists
+    /// the keys itself, e.g. in the OS keychain, or see `with_passphrase`.
+    pub fn with_keys(
+        event_tx: crate::CrustEventSender<UID>,
+        config: Config,
+        our_uid: UID,
+        our_pk: PublicEncryptKey,
+        our_sk: SecretEncryptKey,
+    ) -> crate::Res<Self> {
+        config.validate()?;
+        safe_crypto::init()?;
+        if let Some(level) = config
+            .log_level
+            .as_ref()
+            .and_then(|level| level.parse().ok())
+        {
+            log::set_max_level(level);
+        }
 
         let name_hash = name_hash(&config.network_name);
 
         // Form our initial contact info
         let our_listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
-        let mut mc = MappingContext::try_new()?;
+        let mut mc = MappingContext::try_new(!config.disable_igd)?;
         mc.add_peer_stuns(config.hard_coded_contacts.iter().cloned());
 
         let bootstrap_cache_file = config.bootstrap_cache_name.clone();
+        let blacklist = Blacklist::new(config.blacklist_file_name.clone());
         let el = common::spawn_event_loop(
             EventToken::Unreserved as usize,
             Some(&format!("{:?}", our_uid)),
@@ -129,30 +208,43 @@ impl<UID: Uid> Service<UID> {
         )?;
         trace!("Event loop started");
 
-        // TODO(povilas): get from constructor params
-        let (our_pk, our_sk) = gen_encrypt_keypair();
         let service = Service {
             cm: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(ConfigWrapper::new(config))),
-            event_tx,
+            blacklist,
+            event_tx: EventSender::new(event_tx),
             mc: Arc::new(mc),
             el,
             name_hash,
             our_uid,
             our_listeners,
+            extra_listeners: Arc::new(Mutex::new(Vec::new())),
+            paused_listeners: None,
             our_pk,
             our_sk,
+            send_tokens: AtomicUsize::new(0),
         };
 
-        service.start_config_refresher()?;
+        service.start_connection_auditor()?;
+        service.start_bandwidth_budget()?;
 
         Ok(service)
     }
 
+    /// Reads the config file every 30 seconds from now on and takes over what changed in it, see
+    /// `reload_config_from`. Handshakes read it too. The settings of the file replace the ones
+    /// the service was constructed with, so this is meant for services whose config came from
+    /// the file in the first place; `try_new` calls it.
+    pub fn watch_config_file(&self) -> crate::Res<()> {
+        unwrap!(self.config.lock()).watch_file = true;
+        self.start_config_refresher()
+    }
+
     fn start_config_refresher(&self) -> crate::Res<()> {
         let (tx, rx) = mpsc::channel();
         let config = self.config.clone();
         let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
         self.post(move |core, _| {
             if core.get_state(EventToken::ConfigRefresher.into()).is_none() {
                 let _ = tx.send(ConfigRefresher::start(
@@ -160,6 +252,7 @@ impl<UID: Uid> Service<UID> {
                     EventToken::ConfigRefresher.into(),
                     cm,
                     config,
+                    event_tx,
                 ));
             }
             let _ = tx.send(Ok(()));
@@ -167,55 +260,102 @@ impl<UID: Uid> Service<UID> {
         rx.recv()?
     }
 
-    /// Allow (or disallow) peers from bootstrapping off us.
-    pub fn set_accept_bootstrap(&self, accept: bool) -> crate::Res<()> {
+    fn start_connection_auditor(&self) -> crate::Res<()> {
         let (tx, rx) = mpsc::channel();
-        let _ = self.post(move |core, _| {
-            let state = match core.get_state(EventToken::Listener.into()) {
-                Some(state) => state,
-                None => {
-                    let _ = tx.send(Err(CrustError::ListenerNotIntialised));
-                    return;
-                }
-            };
-            let mut state = state.borrow_mut();
-            let listener = match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
-                Some(l) => l,
-                None => {
-                    warn!("Token reserved for ConnectionListener has something else.");
-                    return;
-                }
-            };
-            listener.set_accept_bootstrap(accept);
+        let cm = self.cm.clone();
+        self.post(move |core, _| {
+            if core
+                .get_state(EventToken::ConnectionAuditor.into())
+                .is_none()
+            {
+                let _ = tx.send(ConnectionAuditor::start(
+                    core,
+                    EventToken::ConnectionAuditor.into(),
+                    cm,
+                ));
+            }
             let _ = tx.send(Ok(()));
-        });
-
+        })?;
         rx.recv()?
     }
 
+    fn start_bandwidth_budget(&self) -> crate::Res<()> {
+        let (bytes_per_sec, node_bytes_per_sec, client_bytes_per_sec, relay_bytes_per_sec) = {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            (
+                cfg.upload_bytes_per_sec,
+                cfg.node_upload_bytes_per_sec,
+                cfg.client_upload_bytes_per_sec,
+                cfg.relay_bytes_per_sec,
+            )
+        };
+        let limits = (
+            bytes_per_sec,
+            node_bytes_per_sec,
+            client_bytes_per_sec,
+            relay_bytes_per_sec,
+        );
+        if limits == (None, None, None, None) {
+            return Ok(());
+        }
+        self.post(move |core, _| {
+            if core.get_state(EventToken::BandwidthBudget.into()).is_none() {
+                BandwidthBudget::start(
+                    core,
+                    EventToken::BandwidthBudget.into(),
+                    bytes_per_sec,
+                    node_bytes_per_sec,
+                    client_bytes_per_sec,
+                    relay_bytes_per_sec,
+                );
+            }
+        })
+    }
+
+    /// Allow (or disallow) peers from bootstrapping off us.
+    pub fn set_accept_bootstrap(&self, accept: bool) -> crate::Res<()> {
+        self.with_listeners(move |settings| settings.accept_bootstrap = accept)
+    }
+
     /// Enables/disables peer external reachability test.
     /// When a new peer connects to us, `Service` listener can be configured to test if this
     /// peer is reachable directly over it's public IP. If external reachability test is enabled,
-    /// and peer is not reachable, then we discard such connection.
+    /// and peer is not reachable, then we discard such connection. Listeners start with the test
+    /// enabled unless `Config::disable_external_reachability_test` is set.
     pub fn set_ext_reachability_test(&self, accept: bool) -> crate::Res<()> {
+        self.with_listeners(move |settings| settings.test_ext_reachability = accept)
+    }
+
+    /// Applies `f` to the settings of all our listeners, including the ones waiting to be started
+    /// again. Fails if the main one isn't running, see `Config::listener_configs`.
+    fn with_listeners<F>(&self, f: F) -> crate::Res<()>
+    where
+        F: Fn(&mut ListenerSettings) + Send + 'static,
+    {
         let (tx, rx) = mpsc::channel();
+        let extra_listeners = self.extra_listeners.clone();
         let _ = self.post(move |core, _| {
-            let state = match core.get_state(EventToken::Listener.into()) {
-                Some(state) => state,
-                None => {
-                    let _ = tx.send(Err(CrustError::ListenerNotIntialised));
-                    return;
-                }
-            };
-            let mut state = state.borrow_mut();
-            let listener = match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
-                Some(l) => l,
-                None => {
+            if core.get_state(EventToken::Listener.into()).is_none() {
+                let _ = tx.send(Err(CrustError::ListenerNotIntialised));
+                return;
+            }
+            let mut tokens = vec![EventToken::Listener.into()];
+            tokens.extend(unwrap!(extra_listeners.lock()).iter().cloned());
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                let state = state.as_any();
+                if let Some(listener) = state.downcast_mut::<ConnectionListener<UID>>() {
+                    f(listener.settings_mut());
+                } else if let Some(retry) = state.downcast_mut::<ListenerRetry<UID>>() {
+                    f(retry.settings_mut());
+                } else {
                     warn!("Token reserved for ConnectionListener has something else.");
-                    return;
                 }
-            };
-            listener.set_ext_reachability_test(accept);
+            }
             let _ = tx.send(Ok(()));
         });
 
@@ -236,6 +376,7 @@ impl<UID: Uid> Service<UID> {
             .unwrap_or(remote_port);
 
         let our_pk = self.our_pk;
+        let name_hash = self.name_hash;
         let _ = self.post(move |core, poll| {
             if core
                 .get_state(EventToken::ServiceDiscovery.into())
@@ -249,6 +390,7 @@ impl<UID: Uid> Service<UID> {
                     listener_port,
                     remote_port,
                     our_pk,
+                    name_hash,
                 ) {
                     debug!("Could not start ServiceDiscovery: {:?}", e);
                 }
@@ -375,12 +517,39 @@ impl<UID: Uid> Service<UID> {
     }
 
     /// Start the bootstrapping procedure. It will auto terminate after indicating success or
-    /// failure via the event channel.
+    /// failure via the event channel. The returned handle can be passed to `cancel_bootstrap`.
+    /// If bootstrapping is running already, the handle refers to that run.
     pub fn start_bootstrap(
         &mut self,
         blacklist: HashSet<SocketAddr>,
         crust_user: CrustUser,
-    ) -> crate::Res<()> {
+    ) -> crate::Res<BootstrapHandle> {
+        self.start_bootstrap_with_policy(blacklist, crust_user, Default::default())
+    }
+
+    /// Like `start_bootstrap`, but if all contacts fail, bootstrapping is retried as the given
+    /// policy allows. Every failed attempt but the last one is reported via
+    /// `Event::BootstrapRetrying`.
+    pub fn start_bootstrap_with_policy(
+        &mut self,
+        blacklist: HashSet<SocketAddr>,
+        crust_user: CrustUser,
+        policy: BootstrapPolicy,
+    ) -> crate::Res<BootstrapHandle> {
+        let start_bootstrap = self.bootstrap_starter(blacklist, crust_user, policy);
+        let handle = BootstrapHandle::unique();
+        self.post(move |core, poll| start_bootstrap(core, poll, Some(handle)))?;
+        Ok(handle)
+    }
+
+    /// Returns a function which starts the bootstrap state with the given settings, unless it's
+    /// running already, and lets the given handle cancel it.
+    fn bootstrap_starter(
+        &self,
+        blacklist: HashSet<SocketAddr>,
+        crust_user: CrustUser,
+        policy: BootstrapPolicy,
+    ) -> impl Fn(&mut EventLoopCore, &Poll, Option<BootstrapHandle>) + Send + 'static {
         let config = self.config.clone();
         let our_uid = self.our_uid;
         let name_hash = self.name_hash;
@@ -388,22 +557,27 @@ impl<UID: Uid> Service<UID> {
         let our_sk = self.our_sk.clone();
         let cm = self.cm.clone();
         let event_tx = self.event_tx.clone();
+        let peer_blacklist = self.blacklist.clone();
         let bootstrapper_role = match crust_user {
-            CrustUser::Node => BootstrapperRole::Node(self.our_global_listener_addrs()),
+            CrustUser::Node => {
+                BootstrapperRole::Node(ext_reachability(self.our_global_listener_addrs()))
+            }
             CrustUser::Client => BootstrapperRole::Client,
         };
 
-        self.post(move |core, poll| {
+        move |core, poll, handle| {
             if core.get_state(EventToken::Bootstrap.into()).is_none() {
                 if let Err(e) = Bootstrap::start(
                     core,
                     poll,
                     name_hash,
                     our_uid,
-                    bootstrapper_role,
-                    cm,
-                    config,
-                    blacklist,
+                    bootstrapper_role.clone(),
+                    cm.clone(),
+                    config.clone(),
+                    blacklist.clone(),
+                    peer_blacklist.clone(),
+                    policy,
                     EventToken::Bootstrap.into(),
                     EventToken::ServiceDiscovery.into(),
                     event_tx.clone(),
@@ -414,9 +588,79 @@ impl<UID: Uid> Service<UID> {
                     let _ = event_tx.send(Event::BootstrapFailed);
                 }
             }
+
+            let handle = match handle {
+                Some(handle) => handle,
+                None => return,
+            };
+            if let Some(state) = core.get_state(EventToken::Bootstrap.into()) {
+                let mut state = state.borrow_mut();
+                if let Some(bootstrap) = state.as_any().downcast_mut::<Bootstrap<UID>>() {
+                    bootstrap.add_handle(handle);
+                }
+            }
+        }
+    }
+
+    /// Bootstraps again, with the given settings, whenever we lose our last connection to a
+    /// node. Progress is reported via `Event::RebootstrapStarted` and then either
+    /// `Event::RebootstrapSucceeded` or `Event::RebootstrapFailed`. Replaces the settings of an
+    /// earlier call.
+    pub fn enable_auto_rebootstrap(
+        &mut self,
+        blacklist: HashSet<SocketAddr>,
+        crust_user: CrustUser,
+        policy: BootstrapPolicy,
+    ) -> crate::Res<()> {
+        let start_bootstrap = self.bootstrap_starter(blacklist, crust_user, policy);
+        let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(EventToken::Rebootstrapper.into()) {
+                state.borrow_mut().terminate(core, poll);
+            }
+            Rebootstrapper::start(
+                core,
+                EventToken::Rebootstrapper.into(),
+                EventToken::Bootstrap.into(),
+                cm,
+                Box::new(move |core, poll| start_bootstrap(core, poll, None)),
+                event_tx,
+            );
         })
     }
 
+    /// Stops bootstrapping again automatically, see `enable_auto_rebootstrap`.
+    pub fn disable_auto_rebootstrap(&mut self) -> crate::Res<()> {
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(EventToken::Rebootstrapper.into()) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        })
+    }
+
+    /// Aborts the bootstrapping the given handle was returned for, followed by
+    /// `Event::BootstrapCancelled`. Returns whether it was still running.
+    pub fn cancel_bootstrap(&self, handle: BootstrapHandle) -> crate::Res<bool> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            let state = match core.get_state(EventToken::Bootstrap.into()) {
+                Some(state) => state,
+                None => {
+                    let _ = tx.send(false);
+                    return;
+                }
+            };
+            let mut state = state.borrow_mut();
+            let cancelled = match state.as_any().downcast_mut::<Bootstrap<UID>>() {
+                Some(bootstrap) => bootstrap.cancel(core, poll, handle),
+                None => false,
+            };
+            let _ = tx.send(cancelled);
+        })?;
+        Ok(rx.recv()?)
+    }
+
     /// Stop the bootstraping procedure explicitly
     pub fn stop_bootstrap(&mut self) -> crate::Res<()> {
         self.post(move |core, poll| {
@@ -426,56 +670,166 @@ impl<UID: Uid> Service<UID> {
         })
     }
 
-    /// Starts accepting TCP connections. This is persistant until it errors out or is stopped
-    /// explicitly.
+    /// Starts accepting TCP connections on the listeners of the config, see
+    /// `Config::listener_configs`. Every listener reports `Event::ListenerStarted` or
+    /// `Event::ListenerFailed`. This is persistant until stopped explicitly: a listener which
+    /// fails is started again after a delay, which doubles with every failure in a row.
     pub fn start_listening_tcp(&mut self) -> crate::Res<()> {
+        let (mut listeners, settings) = {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            let settings = ListenerSettings {
+                test_ext_reachability: !cfg.disable_external_reachability_test,
+                ..Default::default()
+            };
+            (cfg.listener_configs().into_iter(), settings)
+        };
+        let main = unwrap!(listeners.next());
+        self.start_listeners(main, listeners.collect(), settings)
+    }
+
+    fn start_listeners(
+        &self,
+        main: ListenerConfig,
+        extras: Vec<ListenerConfig>,
+        settings: ListenerSettings,
+    ) -> crate::Res<()> {
+        let start_listener = self.listener_starter();
+        let extra_listeners = self.extra_listeners.clone();
+
+        self.post(move |core, poll| {
+            if core.get_state(EventToken::Listener.into()).is_none() {
+                start_listener(core, poll, main, EventToken::Listener.into(), settings);
+            }
+            let mut extra_listeners = unwrap!(extra_listeners.lock());
+            if extra_listeners.is_empty() {
+                for listener in extras {
+                    let token = core.get_new_token();
+                    start_listener(core, poll, listener, token, settings);
+                    extra_listeners.push(token);
+                }
+            }
+        })
+    }
+
+    /// Returns a function which starts the given listener as the state with the given token.
+    fn listener_starter(
+        &self,
+    ) -> impl Fn(&mut EventLoopCore, &Poll, ListenerConfig, Token, ListenerSettings) + Send + 'static
+    {
         let cm = self.cm.clone();
+        let blacklist = self.blacklist.clone();
         let mc = self.mc.clone();
         let config = self.config.clone();
-        let port = unwrap!(self.config.lock())
-            .cfg
-            .tcp_acceptor_port
-            .unwrap_or(0);
-        let force_include_port = unwrap!(self.config.lock())
-            .cfg
-            .force_acceptor_port_in_ext_ep;
+        let (force_include_port, ipv6, bind_ip, handshake_timeout_sec) = {
+            let cfg = &unwrap!(self.config.lock()).cfg;
+            (
+                cfg.force_acceptor_port_in_ext_ep,
+                cfg.enable_ipv6,
+                cfg.bind_ip,
+                cfg.handshake_timeout_sec,
+            )
+        };
         let our_uid = self.our_uid;
         let name_hash = self.name_hash;
         let our_listeners = self.our_listeners.clone();
         let event_tx = self.event_tx.clone();
-
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
+
+        move |core, poll, listener, token, settings| {
+            ConnectionListener::start(
+                core,
+                poll,
+                handshake_timeout_sec,
+                listener,
+                force_include_port,
+                ipv6,
+                bind_ip,
+                our_uid,
+                name_hash,
+                cm.clone(),
+                config.clone(),
+                blacklist.clone(),
+                mc.clone(),
+                our_listeners.clone(),
+                token,
+                settings,
+                event_tx.clone(),
+                our_pk,
+                our_sk.clone(),
+            );
+        }
+    }
+
+    /// Stops all listeners explicitly and stops accepting TCP connections.
+    pub fn stop_tcp_listener(&mut self) -> crate::Res<()> {
+        let extra_listeners = self.extra_listeners.clone();
         self.post(move |core, poll| {
-            if core.get_state(EventToken::Listener.into()).is_none() {
-                ConnectionListener::start(
-                    core,
-                    poll,
-                    None,
-                    port,
-                    force_include_port,
-                    our_uid,
-                    name_hash,
-                    cm,
-                    config,
-                    mc,
-                    our_listeners,
-                    EventToken::Listener.into(),
-                    event_tx,
-                    our_pk,
-                    our_sk,
-                );
+            let mut tokens = vec![EventToken::Listener.into()];
+            tokens.extend(unwrap!(extra_listeners.lock()).drain(..));
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    state.borrow_mut().terminate(core, poll);
+                }
             }
         })
     }
 
-    /// Stops Listener explicitly and stops accepting TCP connections.
-    pub fn stop_tcp_listener(&mut self) -> crate::Res<()> {
+    /// Stops accepting connections for now, e.g. while the application is in the background.
+    /// Unlike `stop_tcp_listener`, this remembers the addresses the listeners were bound to and
+    /// whether they accepted bootstrapping peers, so `start_listening` can resume just as before.
+    /// Connections we have stay up. Port mappings made by routers are removed.
+    pub fn stop_listening(&mut self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let extra_listeners = self.extra_listeners.clone();
         self.post(move |core, poll| {
-            if let Some(state) = core.get_state(EventToken::Listener.into()) {
-                state.borrow_mut().terminate(core, poll);
+            let primary = stop_listener::<UID>(core, poll, EventToken::Listener.into());
+            let extras: Vec<_> = unwrap!(extra_listeners.lock())
+                .drain(..)
+                .filter_map(|token| stop_listener::<UID>(core, poll, token))
+                .collect();
+            let paused = match primary.or_else(|| extras.first().cloned()) {
+                Some((_, settings)) => Some(PausedListeners {
+                    main: primary.map(|(listener, _)| listener),
+                    extras: extras.into_iter().map(|(listener, _)| listener).collect(),
+                    settings,
+                }),
+                None => None,
+            };
+            let _ = tx.send(paused);
+        })?;
+        if let Some(paused) = rx.recv()? {
+            self.paused_listeners = Some(paused);
+        }
+        Ok(())
+    }
+
+    /// Resumes accepting connections after `stop_listening`. Every listener reports
+    /// `Event::ListenerStarted` or `Event::ListenerFailed` again. If nothing was stopped by
+    /// `stop_listening`, this is the same as `start_listening_tcp`.
+    pub fn start_listening(&mut self) -> crate::Res<()> {
+        match self.paused_listeners.take() {
+            Some(paused) => {
+                let main = match paused.main {
+                    Some(main) => main,
+                    None => unwrap!(unwrap!(self.config.lock())
+                        .cfg
+                        .listener_configs()
+                        .first()
+                        .cloned()),
+                };
+                self.start_listeners(main, paused.extras, paused.settings)
             }
-        })
+            None => self.start_listening_tcp(),
+        }
+    }
+
+    /// Returns the addresses our listeners are advertised on, e.g. in connection info.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        unwrap!(self.our_listeners.lock())
+            .iter()
+            .map(|listener| listener.addr)
+            .collect()
     }
 
     /// Connect to a peer. To call this method you must follow these steps:
@@ -496,6 +850,9 @@ impl<UID: Uid> Service<UID> {
             );
             return Err(CrustError::RequestedConnectToSelf);
         }
+        if self.blacklist.contains_peer(&their_ci.id) {
+            return Err(CrustError::PeerBlacklisted);
+        }
 
         if unwrap!(self.cm.lock()).contains_key(&their_ci.id) {
             debug!(
@@ -507,7 +864,7 @@ impl<UID: Uid> Service<UID> {
 
         {
             let guard = unwrap!(self.config.lock());
-            if let Some(ref whitelisted_node_ips) = guard.cfg.whitelisted_node_ips {
+            if let Some(whitelisted_node_ips) = guard.whitelisted_node_ips() {
                 let their_direct = their_ci
                     .for_direct
                     .drain(..)
@@ -523,6 +880,7 @@ impl<UID: Uid> Service<UID> {
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
         let config = self.config.clone();
+        let blacklist = self.blacklist.clone();
         let our_global_direct_listeners = self.our_global_listener_addrs();
 
         self.post(move |core, poll| {
@@ -538,20 +896,136 @@ impl<UID: Uid> Service<UID> {
                 &our_sk,
                 our_global_direct_listeners,
                 config,
+                blacklist,
             );
         })?;
 
         Ok(())
     }
 
-    /// Disconnect from the given peer and returns whether there was a connection at all.
+    /// Connects directly to a peer listening on a known address, e.g. a seed node, without
+    /// exchanging connection info first. The peer has to accept bootstraps and sees us as a
+    /// bootstrapping `crust_user`. The result is reported via `Event::DirectConnectSuccess` or
+    /// `Event::DirectConnectFailure`.
+    pub fn connect_to_addr(
+        &self,
+        addr: SocketAddr,
+        their_pk: PublicEncryptKey,
+        crust_user: CrustUser,
+    ) -> crate::Res<()> {
+        let our_role = match crust_user {
+            CrustUser::Node => {
+                BootstrapperRole::Node(ext_reachability(self.our_global_listener_addrs()))
+            }
+            CrustUser::Client => BootstrapperRole::Client,
+        };
+        let config = self.config.clone();
+        let our_uid = self.our_uid;
+        let name_hash = self.name_hash;
+        let our_pk = self.our_pk;
+        let our_sk = self.our_sk.clone();
+        let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
+        let peer_blacklist = self.blacklist.clone();
+
+        self.post(move |core, poll| {
+            if let Err(e) = DirectConnect::start(
+                core,
+                poll,
+                PeerInfo::new(addr, their_pk),
+                name_hash,
+                our_uid,
+                our_role,
+                cm,
+                config,
+                peer_blacklist,
+                event_tx.clone(),
+                our_pk,
+                &our_sk,
+            ) {
+                debug!("Could not connect to {}: {:?}", addr, e);
+                let _ = event_tx.send(Event::DirectConnectFailure(
+                    addr,
+                    BootstrapError::Unreachable,
+                ));
+            }
+        })
+    }
+
+    /// Sends our connection info to `peer_uid`, whose public key is `peer_pk`, through `via_uid`,
+    /// a peer both of us are connected to and which enables `relay_connection_info` in its
+    /// config. Saves exchanging connection info out-of-band. The info is sealed for `peer_uid`,
+    /// so the relay can neither read nor alter it. It arrives as `Event::ConnectionInfoRelayed`.
+    pub fn send_connection_info_via(
+        &self,
+        via_uid: &UID,
+        peer_uid: UID,
+        peer_pk: PublicEncryptKey,
+        our_ci: &PubConnectionInfo<UID>,
+    ) -> crate::Res<()> {
+        let sealed = self.our_sk.shared_secret(&peer_pk).encrypt(our_ci)?;
+        let our_pk = self.our_pk;
+        self.with_active_connection(via_uid, move |ac, core, poll| {
+            ac.send_conn_info_for(core, poll, peer_uid, our_pk, sealed)
+        })
+    }
+
+    /// Opens connection info received via `Event::ConnectionInfoRelayed`, to be passed to
+    /// `Service::connect`.
+    pub fn open_relayed_connection_info(
+        &self,
+        info: &RelayedConnectionInfo<UID>,
+    ) -> crate::Res<PubConnectionInfo<UID>> {
+        info.open(&self.our_sk)
+    }
+
+    /// Reaches `peer_uid`, a node or client as given by `peer_kind` and whose public key is
+    /// `peer_pk`, through `via_uid`, a peer both of us are connected to and which sets
+    /// `relay_bytes_per_sec` in its config. Only nodes relay. Meant for peers we can't connect to,
+    /// e.g. after `Event::ConnectFailure`. The peer has to do the same for us. While we have no
+    /// direct connection to the peer, `send` then goes through the relay, the peer's data arrives
+    /// as `Event::NewMessage` and `connected_peers` lists the peer as relayed. The data is sealed
+    /// for the peer, so the relay can neither read nor alter it, but drops what's over its limit.
+    /// Once the connection to the relay is lost, so is the peer, with `LostPeerReason::RelayLost`.
+    pub fn connect_via(
+        &self,
+        via_uid: &UID,
+        peer_uid: UID,
+        peer_pk: PublicEncryptKey,
+        peer_kind: CrustUser,
+    ) -> crate::Res<()> {
+        let shared_key = self.our_sk.shared_secret(&peer_pk);
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(via_uid, move |ac, _, _| {
+            let is_node = ac.peer_kind() == CrustUser::Node;
+            if is_node {
+                ac.add_relay_route(peer_uid, peer_kind, shared_key);
+            }
+            let _ = tx.send(is_node);
+        })?;
+        if rx.recv().map_err(|_| CrustError::PeerNotFound)? {
+            Ok(())
+        } else {
+            Err(CrustError::RelayNotANode)
+        }
+    }
+
+    /// Disconnect from the given peer and returns whether there was a connection at all. A peer
+    /// reached through a relay, see `connect_via`, is no longer reached through it.
     pub fn disconnect(&self, peer_uid: &UID) -> bool {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
                 active_connection: Some(token),
                 ..
             }) => token,
-            _ => return false,
+            _ => {
+                let peer_uid = *peer_uid;
+                return self
+                    .with_relay_route(&peer_uid, move |ac, _, _| {
+                        let _ = ac.remove_relay_route(&peer_uid);
+                    })
+                    .is_ok();
+            }
         };
 
         let _ = self.post(move |core, poll| {
@@ -563,8 +1037,624 @@ impl<UID: Uid> Service<UID> {
         true
     }
 
-    /// Send data to a peer.
-    pub fn send(&self, peer_uid: &UID, msg: Vec<u8>, priority: Priority) -> crate::Res<()> {
+    /// Disconnect from the given peer after sending all queued messages and exchanging goodbyes,
+    /// so that the peer knows we closed the connection on purpose. Returns whether there was a
+    /// connection at all.
+    pub fn disconnect_gracefully(&self, peer_uid: &UID) -> bool {
+        self.with_active_connection(peer_uid, |ac, core, poll| ac.close_graceful(core, poll))
+            .is_ok()
+    }
+
+    /// Shuts the service down cleanly: stops accepting and bootstrapping, then closes every
+    /// connection gracefully. Connections established by handshakes which were still running are
+    /// closed the same way as soon as they are up. Blocks until all connections are closed or
+    /// `timeout` expires; connections still open then are dropped. Returns whether everything
+    /// closed in time.
+    pub fn drain(&mut self, timeout: Duration) -> crate::Res<bool> {
+        use std::thread;
+
+        let extra_listeners = self.extra_listeners.clone();
+        self.post(move |core, poll| {
+            let mut tokens = vec![EventToken::Listener.into(), EventToken::Bootstrap.into()];
+            tokens.extend(unwrap!(extra_listeners.lock()).drain(..));
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    state.borrow_mut().terminate(core, poll);
+                }
+            }
+        })?;
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let (tx, rx) = mpsc::channel();
+            let cm = self.cm.clone();
+            self.post(move |core, poll| {
+                let _ = tx.send(close_gracefully(core, poll, &cm));
+            })?;
+            if rx.recv()? == 0 {
+                return Ok(true);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let remaining: Vec<_> = unwrap!(self.cm.lock())
+            .iter()
+            .filter(|&(_, cid)| cid.active_connection.is_some())
+            .map(|(peer_uid, _)| *peer_uid)
+            .collect();
+        for peer_uid in &remaining {
+            let _ = self.disconnect(peer_uid);
+        }
+        Ok(false)
+    }
+
+    /// Replaces the IP whitelists of the config at runtime. `None` allows every IP. Connected
+    /// peers which are no longer whitelisted are dropped with `LostPeerReason::NotWhitelisted`
+    /// and new handshakes are checked against the new whitelists. They stay in effect whatever
+    /// the config file says until `clear_whitelist` is called.
+    pub fn set_whitelist(
+        &self,
+        whitelisted_node_ips: Option<HashSet<IpAddr>>,
+        whitelisted_client_ips: Option<HashSet<IpAddr>>,
+    ) -> crate::Res<()> {
+        unwrap!(self.config.lock()).whitelists = Some(Whitelists {
+            node_ips: whitelisted_node_ips,
+            client_ips: whitelisted_client_ips,
+        });
+        self.apply_whitelists()
+    }
+
+    /// Removes the whitelists set via `set_whitelist`, so that the ones of the config are in
+    /// effect again. Connected peers which they don't allow are dropped.
+    pub fn clear_whitelist(&self) -> crate::Res<()> {
+        unwrap!(self.config.lock()).whitelists = None;
+        self.apply_whitelists()
+    }
+
+    fn apply_whitelists(&self) -> crate::Res<()> {
+        let cm = self.cm.clone();
+        let config = self.config.clone();
+        self.post(move |core, poll| drop_non_whitelisted(core, poll, &cm, &config))
+    }
+
+    /// Reads the config file again and takes it over right away instead of waiting for the next
+    /// periodic refresh, see `reload_config_from`.
+    pub fn reload_config(&self) -> crate::Res<()> {
+        self.reload_config_from(config_handler::read_config_file()?)
+    }
+
+    /// Takes over the given config at runtime. Fails if the config doesn't pass
+    /// `Config::validate`, in which case the current one is kept. Once taken over,
+    /// `Event::ConfigReloaded` lists which settings changed, telling apart those which are in
+    /// effect now from those which need a restart. Connected peers which are no longer
+    /// whitelisted are dropped with `LostPeerReason::NotWhitelisted`. Nothing is reported if
+    /// nothing changed.
+    pub fn reload_config_from(&self, config: Config) -> crate::Res<()> {
+        config.validate()?;
+        let cm = self.cm.clone();
+        let crust_config = self.config.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, poll| {
+            reload_config(core, poll, &cm, &crust_config, &event_tx, config);
+        })
+    }
+
+    /// Sets a filter which every peer that wants to bootstrap off us has to pass, after the name
+    /// hash and the whitelists have been checked. Rejected peers are denied bootstrap with the
+    /// returned reason. Replaces any previous filter.
+    pub fn set_bootstrap_filter<F>(&self, filter: F)
+    where
+        F: Fn(&PublicEncryptKey, IpAddr, CrustUser) -> BootstrapAdmission + Send + Sync + 'static,
+    {
+        unwrap!(self.config.lock()).bootstrap_filter = Some(Arc::new(filter));
+    }
+
+    /// Removes the filter set via `set_bootstrap_filter`.
+    pub fn remove_bootstrap_filter(&self) {
+        unwrap!(self.config.lock()).bootstrap_filter = None;
+    }
+
+    /// Disconnects from the given peer and refuses its handshakes for the given duration.
+    pub fn blacklist_peer(&self, peer_uid: &UID, duration: Duration) {
+        self.blacklist
+            .insert_peer(*peer_uid, SystemTime::now() + duration);
+        let _ =
+            self.with_active_connection(peer_uid, |ac, core, poll| ac.drop_blacklisted(core, poll));
+    }
+
+    /// Disconnects from all peers with the given IP address and refuses connections from it for
+    /// the given duration.
+    pub fn blacklist_ip(&self, ip: IpAddr, duration: Duration) -> crate::Res<()> {
+        self.blacklist.insert_ip(ip, SystemTime::now() + duration);
+
+        let cm = self.cm.clone();
+        self.post(move |core, poll| {
+            // Tokens collected to avoid keeping the mutex lock alive while terminating.
+            let tokens: Vec<_> = unwrap!(cm.lock())
+                .values()
+                .filter_map(|cid| cid.active_connection)
+                .collect();
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                        if ac.peer_addr().ok().map(|addr| addr.ip()) == Some(ip) {
+                            ac.drop_blacklisted(core, poll);
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Send data to a peer, through a relay if we reach it via `connect_via` only. The returned
+    /// token can be used to cancel it, see `cancel_send`.
+    pub fn send(&self, peer_uid: &UID, msg: Bytes, priority: Priority) -> crate::Res<SendToken> {
+        self.check_msg_size(&msg)?;
+        let send_token = self.next_send_token();
+        if self.is_connected(peer_uid) {
+            self.with_active_connection(peer_uid, move |ac, core, poll| {
+                ac.send_data(core, poll, send_token, msg, priority, None)
+            })?;
+        } else {
+            let peer_uid = *peer_uid;
+            self.with_relay_route(&peer_uid, move |ac, core, poll| {
+                ac.send_relayed(core, poll, send_token, peer_uid, msg, priority)
+            })?;
+        }
+        Ok(send_token)
+    }
+
+    /// Sends data to all connected peers for which `filter` returns `true`, and returns to how
+    /// many of them. The peers share the data rather than get a copy each.
+    pub fn broadcast<F>(&self, msg: Bytes, priority: Priority, filter: F) -> crate::Res<usize>
+    where
+        F: Fn(&UID) -> bool,
+    {
+        self.check_msg_size(&msg)?;
+        let tokens: Vec<_> = unwrap!(self.cm.lock())
+            .iter()
+            .filter(|&(uid, _)| filter(uid))
+            .filter_map(|(_, cid)| cid.active_connection)
+            .collect();
+        let count = tokens.len();
+        let send_token = self.next_send_token();
+
+        self.post(move |core, poll| {
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                        ac.send_data(core, poll, send_token, msg.clone(), priority, None);
+                    }
+                }
+            }
+        })?;
+        Ok(count)
+    }
+
+    /// Send data to a peer and get notified via `Event::MessageDelivered` with the given `msg_id`
+    /// once the peer has received it. If the connection is lost before, no event is sent for
+    /// this message. The returned token can be used to cancel it, see `cancel_send`.
+    pub fn send_with_ack(
+        &self,
+        peer_uid: &UID,
+        msg: Vec<u8>,
+        priority: Priority,
+        msg_id: u64,
+    ) -> crate::Res<SendToken> {
+        self.check_msg_size(&msg)?;
+        let send_token = self.next_send_token();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.send_with_ack(core, poll, send_token, msg_id, msg, priority)
+        })?;
+        Ok(send_token)
+    }
+
+    /// Send a request to a peer. The peer gets it as `Event::NewRequest` and its answer arrives
+    /// as `Event::NewResponse` carrying the same `request_id`.
+    pub fn request(
+        &self,
+        peer_uid: &UID,
+        msg: Vec<u8>,
+        priority: Priority,
+        request_id: u64,
+    ) -> crate::Res<()> {
+        self.check_msg_size(&msg)?;
+        let send_token = self.next_send_token();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.request(core, poll, send_token, request_id, msg, priority)
+        })
+    }
+
+    /// Answer the request with the given id received from a peer via `Event::NewRequest`.
+    pub fn respond(
+        &self,
+        peer_uid: &UID,
+        request_id: u64,
+        msg: Vec<u8>,
+        priority: Priority,
+    ) -> crate::Res<()> {
+        self.check_msg_size(&msg)?;
+        let send_token = self.next_send_token();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.respond(core, poll, send_token, request_id, msg, priority)
+        })
+    }
+
+    /// Send data to a peer and drop it instead if it's still queued after `ttl`, e.g. because of
+    /// the peer's rate limit. The returned token can be used to cancel it, see `cancel_send`.
+    pub fn send_with_ttl(
+        &self,
+        peer_uid: &UID,
+        msg: Bytes,
+        priority: Priority,
+        ttl: Duration,
+    ) -> crate::Res<SendToken> {
+        self.check_msg_size(&msg)?;
+        let deadline = Instant::now() + ttl;
+        let send_token = self.next_send_token();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.send_data(core, poll, send_token, msg, priority, Some(deadline))
+        })?;
+        Ok(send_token)
+    }
+
+    /// Streams data to a peer, so that a big transfer needn't be held in memory at once. The
+    /// items of `source` are split into chunks of up to 64 KiB, or `max_msg_size`. The peer gets
+    /// them through the `IncomingStream` handed out with `Event::NewStream`. `source` is pulled on
+    /// the event loop, so it shouldn't block for long, and only as fast as the connection takes
+    /// the chunks and the peer's application reads them. The returned token can be used to break
+    /// the stream off, see `cancel_send`.
+    pub fn send_stream<I>(
+        &self,
+        peer_uid: &UID,
+        source: I,
+        priority: Priority,
+    ) -> crate::Res<SendToken>
+    where
+        I: IntoIterator<Item = Bytes>,
+        I::IntoIter: Send + 'static,
+    {
+        let source: Box<Iterator<Item = Bytes> + Send> = Box::new(source.into_iter());
+        let send_token = self.next_send_token();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.send_stream(core, poll, send_token, source, priority)
+        })?;
+        Ok(send_token)
+    }
+
+    /// Opens the logical channel with the given id to a peer, or changes its priority. Messages
+    /// sent on it via `send_on_channel` keep their order and wait in a queue of their own, see
+    /// `Config::channel_queue_limit`, so a busy channel doesn't hold back the others beyond what
+    /// their priorities say. The peer gets them as `Event::NewChannelMessage`, without opening
+    /// the channel itself.
+    pub fn open_channel(&self, peer_uid: &UID, channel: u16, priority: Priority) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, _, _| ac.open_channel(channel, priority))
+    }
+
+    /// Closes the channel with the given id to a peer, dropping what it still holds back.
+    /// Returns whether it was open.
+    pub fn close_channel(&self, peer_uid: &UID, channel: u16) -> crate::Res<bool> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = tx.send(ac.close_channel(channel));
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Sends data on a channel opened via `open_channel`. Fails with `CrustError::ChannelFull`
+    /// if the channel holds as many messages back as the config allows already. The returned
+    /// token can be used to cancel it, see `cancel_send`.
+    pub fn send_on_channel(
+        &self,
+        peer_uid: &UID,
+        channel: u16,
+        msg: Bytes,
+    ) -> crate::Res<SendToken> {
+        self.check_msg_size(&msg)?;
+        let send_token = self.next_send_token();
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            let _ = tx.send(ac.send_on_channel(core, poll, send_token, channel, msg));
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)??;
+        Ok(send_token)
+    }
+
+    /// Measures the round trip time to the given peer. The result is returned via the
+    /// `PingResponse` event on the event channel.
+    pub fn ping(&self, peer_uid: &UID) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, |ac, core, poll| ac.ping(core, poll))
+    }
+
+    /// Returns the smoothed round trip time to the given peer, estimated from heartbeats, or
+    /// `None` if no heartbeat has been answered yet.
+    pub fn rtt(&self, peer_uid: &UID) -> crate::Res<Option<Duration>> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = tx.send(ac.rtt());
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Cancels the message to the given peer with the given token, if crust still holds it back
+    /// because of the peer's rate limit, the bandwidth budget or because the socket hasn't
+    /// written earlier messages yet. Returns whether it was. Messages already handed to the
+    /// socket can't be cancelled. A stream, see `send_stream`, is broken off unless it's sent
+    /// completely.
+    pub fn cancel_send(&self, peer_uid: &UID, send_token: SendToken) -> crate::Res<bool> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            let _ = tx.send(ac.cancel_send(core, poll, send_token));
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Returns traffic statistics of the connection to the given peer.
+    pub fn peer_stats(&self, peer_uid: &UID) -> crate::Res<PeerStats> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = tx.send(ac.stats());
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Returns all peers we currently have an active connection to, and those we reach through
+    /// a relay only, see `connect_via`.
+    pub fn connected_peers(&self) -> crate::Res<Vec<ConnectedPeer<UID>>> {
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        self.post(move |core, _| {
+            // Tokens collected to avoid keeping the mutex lock alive while querying the states.
+            let tokens: Vec<_> = unwrap!(cm.lock())
+                .values()
+                .filter_map(|cid| cid.active_connection)
+                .collect();
+            let mut peers = Vec::new();
+            let mut relayed = Vec::new();
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    peers.extend(ac.connected_peer());
+                    relayed.extend(ac.relayed_peers());
+                }
+            }
+            // Peers we are connected to directly are listed as such.
+            relayed.retain(|route| !peers.iter().any(|peer| peer.id == route.id));
+            peers.extend(relayed);
+            let _ = tx.send(peers);
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns a snapshot of our connections, traffic and listeners, e.g. for dashboards.
+    pub fn stats(&self) -> crate::Res<ServiceStats> {
+        let (tx, rx) = mpsc::channel();
+        let cm = self.cm.clone();
+        let extra_listeners = self.extra_listeners.clone();
+        let paused = self.paused_listeners.is_some();
+        self.post(move |core, _| {
+            // Collected to avoid keeping the mutex lock alive while querying the states.
+            let (tokens, handshakes) = {
+                let cm = unwrap!(cm.lock());
+                let tokens: Vec<_> = cm
+                    .values()
+                    .filter_map(|cid| cid.active_connection)
+                    .collect();
+                let handshakes = cm.values().map(|cid| cid.currently_handshaking).sum();
+                (tokens, handshakes)
+            };
+            let mut stats = ServiceStats {
+                node_connections: 0,
+                client_connections: 0,
+                bytes_sent: 0,
+                bytes_received: 0,
+                handshakes,
+                bootstrap_cache_size: core.user_data().peers().len(),
+                tcp_listener: ListenerState::Stopped,
+            };
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                let ac = match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    Some(ac) => ac,
+                    None => continue,
+                };
+                match ac.peer_kind() {
+                    CrustUser::Node => stats.node_connections += 1,
+                    CrustUser::Client => stats.client_connections += 1,
+                }
+                let peer_stats = ac.stats();
+                stats.bytes_sent += peer_stats.bytes_sent;
+                stats.bytes_received += peer_stats.bytes_received;
+            }
+
+            let mut listener_tokens = vec![EventToken::Listener.into()];
+            listener_tokens.extend(unwrap!(extra_listeners.lock()).iter().cloned());
+            let ports: Vec<_> = listener_tokens
+                .into_iter()
+                .filter_map(|token| {
+                    let state = core.get_state(token)?;
+                    let mut state = state.borrow_mut();
+                    let listener = state.as_any().downcast_mut::<ConnectionListener<UID>>()?;
+                    Some(listener.local_port())
+                })
+                .collect();
+            stats.tcp_listener = if !ports.is_empty() {
+                ListenerState::Listening(ports)
+            } else if paused {
+                ListenerState::Paused
+            } else {
+                ListenerState::Stopped
+            };
+
+            let _ = tx.send(stats);
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Attaches application data to the given peer, replacing any previous data. It lives as
+    /// long as the connection does.
+    pub fn set_peer_data<T: Any + Send>(&self, peer_uid: &UID, data: T) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, _, _| ac.set_user_data(Box::new(data)))
+    }
+
+    /// Returns a copy of the data attached to the given peer via `set_peer_data`, or `None` if
+    /// there is none or it isn't a `T`.
+    pub fn peer_data<T: Any + Clone + Send>(&self, peer_uid: &UID) -> crate::Res<Option<T>> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let data = ac
+                .user_data()
+                .and_then(|data| data.downcast_ref::<T>())
+                .cloned();
+            let _ = tx.send(data);
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Tags the given peer with a group label, e.g. its routing section or a pub/sub topic, so it
+    /// can be addressed via `send_to_group`. A peer can be in any number of groups. The tags live
+    /// as long as the connection does.
+    pub fn add_peer_to_group<S: Into<String>>(&self, peer_uid: &UID, label: S) -> crate::Res<()> {
+        let label = label.into();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = ac.add_to_group(label);
+        })
+    }
+
+    /// Removes the given peer from a group it was added to via `add_peer_to_group`.
+    pub fn remove_peer_from_group(&self, peer_uid: &UID, label: &str) -> crate::Res<()> {
+        let label = label.to_owned();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = ac.remove_from_group(&label);
+        })
+    }
+
+    /// Sends the data to all connected peers in the given group, see `add_peer_to_group`. Returns
+    /// to how many peers it was sent.
+    pub fn send_to_group(&self, label: &str, msg: Bytes, priority: Priority) -> crate::Res<usize> {
+        self.check_msg_size(&msg)?;
+        let (tx, rx) = mpsc::channel();
+        let label = label.to_owned();
+        let cm = self.cm.clone();
+        let send_token = self.next_send_token();
+        self.post(move |core, poll| {
+            // Collected to avoid keeping the mutex lock alive while writing to the states.
+            let tokens: Vec<_> = unwrap!(cm.lock())
+                .values()
+                .filter_map(|cid| cid.active_connection)
+                .collect();
+            let mut count = 0;
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                let ac = match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    Some(ac) => ac,
+                    None => continue,
+                };
+                if ac.is_in_group(&label) {
+                    ac.send_data(core, poll, send_token, msg.clone(), priority, None);
+                    count += 1;
+                }
+            }
+            let _ = tx.send(count);
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns the current score of the given peer. See `PeerScoring` in the config.
+    pub fn peer_score(&self, peer_uid: &UID) -> crate::Res<i32> {
+        let (tx, rx) = mpsc::channel();
+        self.with_active_connection(peer_uid, move |ac, _, _| {
+            let _ = tx.send(ac.score());
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Adds `delta` to the score of the given peer, so that upper layers can report their own
+    /// signals of good or bad behaviour. The peer is banned if its score drops to the ban threshold.
+    pub fn adjust_score(&self, peer_uid: &UID, delta: i32) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            let _ = ac.adjust_score(core, poll, delta);
+        })
+    }
+
+    /// Stops reading from the given peer without closing the connection, so that TCP flow control
+    /// eventually stops it from sending. Sending to the peer still works. The peer isn't dropped
+    /// for inactivity while reading is paused.
+    pub fn pause_recv(&self, peer_uid: &UID) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, |ac, core, poll| ac.pause_recv(core, poll))
+    }
+
+    /// Resumes reading from the given peer after `pause_recv`.
+    pub fn resume_recv(&self, peer_uid: &UID) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, |ac, core, poll| ac.resume_recv(core, poll))
+    }
+
+    /// Limits how many bytes of data per second are sent to the given peer. Messages over the
+    /// limit are queued. `None` removes the limit.
+    ///
+    /// Queued messages of the same priority go in the order they were sent. Priorities take
+    /// turns in rounds: in each round, priority 0 sends up to 16 messages, 1 up to 8, 2 up to 4,
+    /// 3 up to 2 and all lower ones 1 each, with more urgent priorities going first. So every
+    /// priority with queued messages makes progress in each round, however much more urgent data
+    /// is queued.
+    pub fn set_rate_limit(&self, peer_uid: &UID, bytes_per_sec: Option<u64>) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.set_rate_limit(core, poll, bytes_per_sec)
+        })
+    }
+
+    /// Changes how often heartbeats are sent to the given peer. Use `heartbeat_period_ms` in the
+    /// config to change it for all future connections.
+    pub fn set_heartbeat_period(&self, peer_uid: &UID, period: Duration) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.set_heartbeat_period(core, poll, period)
+        })
+    }
+
+    /// Changes how long the given peer may stay silent before the connection is dropped. Use
+    /// `inactivity_timeout_ms` in the config to change it for all future connections.
+    pub fn set_inactivity_timeout(&self, peer_uid: &UID, timeout: Duration) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, move |ac, core, poll| {
+            ac.set_inactivity_timeout(core, poll, timeout)
+        })
+    }
+
+    /// Breaks the socket of the connection to the given peer, as if the network went away.
+    #[cfg(test)]
+    pub fn break_socket(&self, peer_uid: &UID) -> crate::Res<()> {
+        self.with_active_connection(peer_uid, |ac, core, poll| ac.break_socket(core, poll))
+    }
+
+    fn check_msg_size(&self, msg: &[u8]) -> crate::Res<()> {
+        match unwrap!(self.config.lock()).cfg.max_msg_size {
+            Some(max) if msg.len() > max => Err(CrustError::MessageTooLarge(msg.len(), max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn next_send_token(&self) -> SendToken {
+        SendToken(self.send_tokens.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Runs `f` on the event loop with the active connection to the given peer.
+    fn with_active_connection<F>(&self, peer_uid: &UID, f: F) -> crate::Res<()>
+    where
+        F: FnOnce(&mut ActiveConnection<UID>, &mut EventLoopCore, &Poll) + Send + 'static,
+    {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
                 active_connection: Some(token),
@@ -575,7 +1665,62 @@ impl<UID: Uid> Service<UID> {
 
         self.post(move |core, poll| {
             if let Some(state) = core.get_state(token) {
-                state.borrow_mut().write(core, poll, msg, priority);
+                let mut state = state.borrow_mut();
+                if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    f(ac, core, poll);
+                }
+            }
+        })
+    }
+
+    /// Runs `f` on the event loop with the active connection through which we reach the given
+    /// peer, see `connect_via`.
+    fn with_relay_route<F>(&self, peer_uid: &UID, f: F) -> crate::Res<()>
+    where
+        F: FnOnce(&mut ActiveConnection<UID>, &mut EventLoopCore, &Poll) + Send + 'static,
+    {
+        let tokens: Vec<_> = unwrap!(self.cm.lock())
+            .values()
+            .filter_map(|cid| cid.active_connection)
+            .collect();
+        let peer_uid = *peer_uid;
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            for token in tokens {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                        if ac.relays_to(&peer_uid) {
+                            f(ac, core, poll);
+                            let _ = tx.send(());
+                            return;
+                        }
+                    }
+                }
+            }
+        })?;
+        rx.recv().map_err(|_| CrustError::PeerNotFound)
+    }
+
+    /// Probes the NAT we are behind using the hard coded contacts as echo servers. The result is
+    /// returned via the `NatInfo` event on the event channel.
+    pub fn nat_info(&self) -> crate::Res<()> {
+        let event_tx = self.event_tx.clone();
+        let mc = self.mc.clone();
+        let our_pk = self.our_pk;
+        let our_sk = self.our_sk.clone();
+
+        self.post(move |core, poll| {
+            let event_tx_clone = event_tx.clone();
+            let finish = move |_: &mut EventLoopCore, _: &Poll, nat_info| {
+                let _ = event_tx_clone.send(Event::NatInfo(nat_info));
+            };
+            if let Err(e) = NatProbe::<_, UID, _>::start(core, poll, &mc, our_pk, &our_sk, finish) {
+                debug!("Error probing NAT: {}", e);
+                let _ = event_tx.send(Event::NatInfo(NatInfo {
+                    nat_type: NatType::Unknown,
+                    ext_addrs: Vec::new(),
+                }));
             }
         })
     }
@@ -611,11 +1756,16 @@ impl<UID: Uid> Service<UID> {
                 match MappedTcpSocket::<_, UID, _>::start(
                     core,
                     poll,
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
                     0,
                     &mc,
                     our_pk,
                     &our_sk,
-                    move |_, _, _socket, _addrs| {
+                    move |_, _, _socket, _addrs, port_mappings| {
+                        // The socket is dropped, so the mappings are of no use.
+                        for mapping in port_mappings {
+                            mapping.release();
+                        }
                         let event_tx = event_tx_clone;
                         let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
                             result_token,
@@ -649,6 +1799,23 @@ impl<UID: Uid> Service<UID> {
         }
     }
 
+    /// Returns a stream of the events of this service, so async code can `select!` over them
+    /// instead of reading the blocking channel behind the `event_tx` the service was
+    /// constructed with. From now on, events go to the stream only, until it is dropped or
+    /// `event_stream` is called again. Then they go to `event_tx`, or to the new stream,
+    /// respectively.
+    pub fn event_stream(&self) -> impl Stream<Item = Event<UID>, Error = ()> {
+        self.event_tx.event_stream()
+    }
+
+    /// Returns a stream of the peers we gain and lose an active connection to from now on, so
+    /// async code can follow who we are connected to without polling `is_connected`. The same
+    /// changes are reported with the connection events and `Event::LostPeer` too. Each call
+    /// hands out a stream of its own.
+    pub fn peer_set_changes(&self) -> impl Stream<Item = PeerSetChange<UID>, Error = ()> {
+        self.event_tx.peer_set_changes()
+    }
+
     /// Check if we are connected to the given peer
     pub fn is_connected(&self, peer_uid: &UID) -> bool {
         match unwrap!(self.cm.lock()).get(peer_uid) {
@@ -697,6 +1864,71 @@ impl<UID: Uid> Service<UID> {
     }
 }
 
+/// Summarises our global listener addresses for the bootstrap request.
+fn ext_reachability(our_global_addrs: HashSet<SocketAddr>) -> ExternalReachability {
+    if our_global_addrs.is_empty() {
+        ExternalReachability::Unknown
+    } else if our_global_addrs
+        .iter()
+        .all(|addr| ip_addr_is_shared(&addr.ip()))
+    {
+        ExternalReachability::BehindCgnat
+    } else {
+        ExternalReachability::Reachable(
+            our_global_addrs
+                .into_iter()
+                .filter(|addr| !ip_addr_is_shared(&addr.ip()))
+                .collect(),
+        )
+    }
+}
+
+/// Terminates the listener with the given token and returns how to start it again and its
+/// settings, if it was running or waiting to be started again.
+fn stop_listener<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+) -> Option<(ListenerConfig, ListenerSettings)> {
+    let state = core.get_state(token)?;
+    let mut state = state.borrow_mut();
+    let stopped = {
+        let state = state.as_any();
+        if let Some(listener) = state.downcast_mut::<ConnectionListener<UID>>() {
+            Some((listener.listener_config(), listener.settings()))
+        } else if let Some(retry) = state.downcast_mut::<ListenerRetry<UID>>() {
+            Some((retry.listener_config(), retry.settings()))
+        } else {
+            None
+        }
+    };
+    state.terminate(core, poll);
+    stopped
+}
+
+/// Closes all active connections gracefully, see `ActiveConnection::close_graceful`, and returns
+/// how many aren't closed yet. Handshakes don't count, they are closed once they are connected.
+fn close_gracefully<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+) -> usize {
+    // Tokens collected to avoid keeping the mutex lock alive while closing.
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+    for &token in &tokens {
+        if let Some(state) = core.get_state(token) {
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                ac.close_graceful(core, poll);
+            }
+        }
+    }
+    tokens.len()
+}
+
 /// Returns a hash of the network name.
 fn name_hash(network_name: &Option<String>) -> NameHash {
     trace!("Network name: {:?}", network_name);
@@ -727,6 +1959,58 @@ mod tests {
     type PrivConnectionInfo = main::PrivConnectionInfo<UniqueId>;
     type PubConnectionInfo = main::PubConnectionInfo<UniqueId>;
 
+    #[test]
+    fn ext_reachability_from_global_addrs() {
+        let public = common::ipv4_addr(1, 2, 3, 4, 5000);
+        let shared = common::ipv4_addr(100, 64, 1, 2, 5000);
+
+        assert_eq!(
+            ext_reachability(HashSet::new()),
+            ExternalReachability::Unknown
+        );
+        assert_eq!(
+            ext_reachability(vec![shared].into_iter().collect()),
+            ExternalReachability::BehindCgnat
+        );
+        assert_eq!(
+            ext_reachability(vec![public, shared].into_iter().collect()),
+            ExternalReachability::Reachable(vec![public].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn built_service_keeps_its_settings_past_config_refresh() {
+        use crate::main::ServiceBuilder;
+        use crate::tests::gen_config;
+
+        let (event_tx, event_rx) = get_event_sender();
+        let contacts = vec![PeerInfo::new(
+            common::ipv4_addr(1, 2, 3, 4, 5483),
+            gen_encrypt_keypair().0,
+        )];
+        let service: Service = unwrap!(ServiceBuilder::from_config(gen_config())
+            .hard_coded_contacts(contacts.clone())
+            .build(event_tx, rand::random()));
+
+        // Does what a config refresher would do once its timer fires, if there were one.
+        let (tx, rx) = mpsc::channel();
+        unwrap!(service.post(move |core, poll| {
+            let refresher = core.get_state(EventToken::ConfigRefresher.into());
+            if let Some(ref refresher) = refresher {
+                refresher.borrow_mut().timeout(core, poll, 0);
+            }
+            let _ = tx.send(refresher.is_some());
+        }));
+        assert!(!unwrap!(rx.recv()));
+
+        assert_eq!(
+            unwrap!(service.config.lock()).cfg.hard_coded_contacts,
+            contacts
+        );
+        thread::sleep(Duration::from_millis(100));
+        assert!(event_rx.try_recv().is_err());
+    }
+
     #[test]
     fn connect_self() {
         timebomb(Duration::from_secs(30), || {
@@ -834,8 +2118,8 @@ mod tests {
         let data_1: Vec<u8> = iter::repeat(()).take(32).map(|()| rand::random()).collect();
         let send_1 = data_1.clone();
 
-        unwrap!(service_0.send(&id_1, data_0, 0));
-        unwrap!(service_1.send(&id_0, data_1, 0));
+        let _ = unwrap!(service_0.send(&id_1, Bytes::from(data_0), 0));
+        let _ = unwrap!(service_1.send(&id_0, Bytes::from(data_1), 0));
 
         let recv_1 = expect_event!(event_rx_0, Event::NewMessage(id, CrustUser::Node, recv) => {
             assert_eq!(id, id_1);
@@ -952,7 +2236,7 @@ mod tests {
                             for _ in 0..MSG_SIZE {
                                 msg.push(n as u8);
                             }
-                            let _ = self.service.send(their_id, msg, 0);
+                            let _ = self.service.send(their_id, Bytes::from(msg), 0);
                         }
                     }
 
@@ -0,0 +1,131 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{PeerInfo, Uid};
+use crate::main::{Config, ListenerConfig, Service};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Sets up a `Service` in code instead of through a config file.
+///
+/// The most common settings have their own methods, everything else can be changed via
+/// `configure`. The resulting config is checked by `build`, see `Config::validate`. The service
+/// doesn't read or write a config file, unless `Service::watch_config_file` is called.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceBuilder {
+    config: Config,
+}
+
+impl ServiceBuilder {
+    /// Starts off the default config.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts off the given config, e.g. one read via `read_config_file`.
+    pub fn from_config(config: Config) -> Self {
+        ServiceBuilder { config }
+    }
+
+    /// Peers to bootstrap off, see `Config::hard_coded_contacts`.
+    pub fn hard_coded_contacts(mut self, contacts: Vec<PeerInfo>) -> Self {
+        self.config.hard_coded_contacts = contacts;
+        self
+    }
+
+    /// Port of the main TCP listener, see `Config::tcp_acceptor_port`.
+    pub fn tcp_acceptor_port(mut self, port: u16) -> Self {
+        self.config.tcp_acceptor_port = Some(port);
+        self
+    }
+
+    /// Ports of further TCP listeners, see `Config::extra_tcp_acceptor_ports`.
+    pub fn extra_tcp_acceptor_ports(mut self, ports: Vec<u16>) -> Self {
+        self.config.extra_tcp_acceptor_ports = ports;
+        self
+    }
+
+    /// Listeners with their own addresses, replacing the ones on `tcp_acceptor_port` and
+    /// `extra_tcp_acceptor_ports`, see `Config::listeners`.
+    pub fn listeners(mut self, listeners: Vec<ListenerConfig>) -> Self {
+        self.config.listeners = listeners;
+        self
+    }
+
+    /// Interface to listen and connect on, see `Config::bind_ip`.
+    pub fn bind_ip(mut self, ip: IpAddr) -> Self {
+        self.config.bind_ip = Some(ip);
+        self
+    }
+
+    /// Only nodes with these IPs are accepted, see `Config::whitelisted_node_ips`.
+    pub fn whitelisted_node_ips(mut self, ips: HashSet<IpAddr>) -> Self {
+        self.config.whitelisted_node_ips = Some(ips);
+        self
+    }
+
+    /// Only clients with these IPs are accepted, see `Config::whitelisted_client_ips`.
+    pub fn whitelisted_client_ips(mut self, ips: HashSet<IpAddr>) -> Self {
+        self.config.whitelisted_client_ips = Some(ips);
+        self
+    }
+
+    /// See `Config::handshake_timeout_sec`.
+    pub fn handshake_timeout_sec(mut self, timeout_sec: u64) -> Self {
+        self.config.handshake_timeout_sec = Some(timeout_sec);
+        self
+    }
+
+    /// See `Config::heartbeat_period_ms` and `Config::inactivity_timeout_ms`.
+    pub fn heartbeat(mut self, period_ms: u64, inactivity_timeout_ms: u64) -> Self {
+        self.config.heartbeat_period_ms = Some(period_ms);
+        self.config.inactivity_timeout_ms = Some(inactivity_timeout_ms);
+        self
+    }
+
+    /// Limits what each peer may send us, see `Config::inbound_msgs_per_sec` and
+    /// `Config::inbound_bytes_per_sec`.
+    pub fn inbound_rate_limit(
+        mut self,
+        msgs_per_sec: Option<u64>,
+        bytes_per_sec: Option<u64>,
+    ) -> Self {
+        self.config.inbound_msgs_per_sec = msgs_per_sec;
+        self.config.inbound_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Only peers using the same network name can connect to each other, see
+    /// `Config::network_name`.
+    pub fn network_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.config.network_name = Some(name.into());
+        self
+    }
+
+    /// Changes any other setting.
+    pub fn configure<F: FnOnce(&mut Config)>(mut self, f: F) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Returns the config built so far.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Constructs the service with the config built so far, see `Service::with_config`. Fails if
+    /// the config doesn't pass `Config::validate`.
+    pub fn build<UID: Uid>(
+        self,
+        event_tx: crate::CrustEventSender<UID>,
+        our_uid: UID,
+    ) -> crate::Res<Service<UID>> {
+        Service::with_config(event_tx, self.config, our_uid)
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{connect_tcp, Message, SocketOptions, State, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{ActiveConnection, EventLoopCore};
+use mio::{Poll, PollOpt, Ready, Token};
+use safe_crypto::{PublicEncryptKey, SharedSecretKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
+use std::any::Any;
+use std::cell::RefCell;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+/// Lets a bootstrap connection carry on over a new socket once its socket broke, see
+/// `Config::session_resume_timeout_ms`.
+pub struct Session {
+    /// Granted by the bootstrappee with `Message::BootstrapGranted`.
+    pub id: u64,
+    pub their_pk: PublicEncryptKey,
+    /// Set if we bootstrapped off the peer, in which case it's up to us to dial it again.
+    pub redial: Option<Redial>,
+}
+
+/// How to reach the listener of the peer we bootstrapped off again.
+pub struct Redial {
+    pub addr: SocketAddr,
+    pub bind_ip: Option<IpAddr>,
+    pub socket_options: SocketOptions,
+    pub our_pk: PublicEncryptKey,
+    pub shared_key: SharedSecretKey,
+}
+
+/// Dials the listener of the peer we bootstrapped off and asks it to resume the session on the
+/// new socket. Once it agrees, the socket is handed to the connection with the given token. A
+/// failed attempt just ends, the connection tries again, see `ActiveConnection::redial`.
+pub struct ResumeSession<UID: Uid> {
+    token: Token,
+    ac_token: Token,
+    socket: TcpSock,
+    request: Option<(Message<UID>, Priority)>,
+    shared_key: SharedSecretKey,
+}
+
+impl<UID: Uid> ResumeSession<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        ac_token: Token,
+        our_uid: UID,
+        session: &Session,
+        redial: &Redial,
+    ) -> crate::Res<Token> {
+        let mut socket = connect_tcp(&redial.addr, redial.bind_ip, &redial.socket_options)?;
+        socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(session.their_pk))?;
+        socket.set_decrypt_ctx(DecryptContext::authenticated(redial.shared_key.clone()))?;
+        let token = core.get_new_token();
+
+        poll.register(
+            &socket,
+            token,
+            Ready::writable() | Ready::readable(),
+            PollOpt::edge(),
+        )?;
+
+        let state = ResumeSession {
+            token,
+            ac_token,
+            socket,
+            request: Some((
+                Message::ResumeSession(our_uid, redial.our_pk, session.id),
+                0,
+            )),
+            shared_key: redial.shared_key.clone(),
+        };
+
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    fn write(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Option<(Message<UID>, Priority)>,
+    ) {
+        if let Err(e) = self.socket.write(msg) {
+            debug!("Failed to ask for the session to be resumed: {:?}", e);
+            self.terminate(core, poll);
+        }
+    }
+
+    fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        match self.socket.read::<Message<UID>>() {
+            Ok(Some(Message::SessionResumed)) => {
+                self.terminate(core, poll);
+                let mut socket = mem::replace(&mut self.socket, Default::default());
+                if let Err(e) =
+                    socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
+                {
+                    warn!("Failed to set socket encrypt context: {}", e);
+                    return;
+                }
+                if let Some(state) = core.get_state(self.ac_token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                        ac.resume(core, poll, socket);
+                    }
+                }
+            }
+            Ok(None) => (),
+            Ok(Some(_)) | Err(_) => {
+                debug!("The peer didn't resume the session.");
+                self.terminate(core, poll);
+            }
+        }
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for ResumeSession<UID> {
+    fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
+        if kind.is_writable() {
+            let req = self.request.take();
+            self.write(core, poll, req);
+        }
+        if kind.is_readable() && core.get_state(self.token).is_some() {
+            self.read(core, poll)
+        }
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = poll.deregister(&self.socket);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
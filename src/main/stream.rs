@@ -0,0 +1,204 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::CoreMessage;
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{EventLoopCore, SendToken};
+use bytes::Bytes;
+use mio::{Poll, Token};
+use mio_extras::channel::Sender;
+use socket_collection::Priority;
+use std::cmp;
+use std::fmt;
+use std::sync::mpsc::Receiver;
+
+/// Most bytes sent in one `StreamChunk`, unless `Config::max_msg_size` is lower.
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// How many chunks the sender of a stream may send ahead of what the receiving application took.
+/// The receiver lets it send more in batches of half that many.
+pub const STREAM_WINDOW: u32 = 8;
+/// How many chunks of a stream may wait in the send queue at once.
+pub const STREAM_QUEUE_DEPTH: usize = 2;
+
+/// Tells the connection with the given token what the application did with the stream with the
+/// given id: took this many chunks, or dropped it if `None`.
+pub type StreamSignal = fn(&mut EventLoopCore, &Poll, Token, u64, Option<u32>);
+
+/// What the connection passes on to an `IncomingStream`.
+pub enum StreamItem {
+    Chunk(Bytes),
+    /// Whether the stream is complete.
+    End(bool),
+}
+
+/// A stream we send, see `Service::send_stream`.
+pub struct OutgoingStream {
+    source: Box<Iterator<Item = Bytes> + Send>,
+    /// What's left of the item `source` gave us last.
+    pending: Bytes,
+    pub send_token: SendToken,
+    pub priority: Priority,
+    /// How many more chunks the receiver lets us send.
+    pub credit: u32,
+    /// Whether `StreamStart` was written, so the receiver knows of the stream.
+    pub started: bool,
+    /// Whether `StreamEnd` was queued.
+    pub finished: bool,
+}
+
+impl OutgoingStream {
+    pub fn new(
+        source: Box<Iterator<Item = Bytes> + Send>,
+        send_token: SendToken,
+        priority: Priority,
+    ) -> Self {
+        OutgoingStream {
+            source,
+            pending: Bytes::new(),
+            send_token,
+            priority,
+            credit: STREAM_WINDOW,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Returns the next chunk of up to `max_len` bytes, or `None` once `source` is exhausted.
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<Bytes> {
+        while self.pending.is_empty() {
+            self.pending = self.source.next()?;
+        }
+        let len = cmp::min(max_len, self.pending.len());
+        Some(self.pending.split_to(len))
+    }
+}
+
+/// Data a peer streams to us via `Service::send_stream`, as handed out with `Event::NewStream`.
+/// Iterating blocks until the next chunk arrives and ends once the stream does, see
+/// `is_complete`. The peer only sends a few chunks ahead of what we took, so a slow reader holds
+/// the sender back instead of making us buffer the whole stream. Dropping it asks the peer to
+/// stop sending.
+pub struct IncomingStream {
+    id: u64,
+    rx: Receiver<StreamItem>,
+    core_tx: Sender<CoreMessage<BootstrapCache>>,
+    token: Token,
+    signal: StreamSignal,
+    /// Chunks taken since we last let the peer send more.
+    taken: u32,
+    ended: bool,
+    complete: bool,
+}
+
+impl IncomingStream {
+    pub fn new(
+        id: u64,
+        rx: Receiver<StreamItem>,
+        core_tx: Sender<CoreMessage<BootstrapCache>>,
+        token: Token,
+        signal: StreamSignal,
+    ) -> Self {
+        IncomingStream {
+            id,
+            rx,
+            core_tx,
+            token,
+            signal,
+            taken: 0,
+            ended: false,
+            complete: false,
+        }
+    }
+
+    /// Whether the peer sent the whole stream. Only `true` once iterating ended. If `false` then,
+    /// the peer broke the stream off or the connection was lost.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn signal(&self, credit: Option<u32>) {
+        let (signal, token, id) = (self.signal, self.token, self.id);
+        let _ = self.core_tx.send(CoreMessage::new(move |core, poll| {
+            signal(core, poll, token, id, credit)
+        }));
+    }
+}
+
+impl Iterator for IncomingStream {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        if self.ended {
+            return None;
+        }
+        match self.rx.recv() {
+            Ok(StreamItem::Chunk(chunk)) => {
+                self.taken += 1;
+                if self.taken >= STREAM_WINDOW / 2 {
+                    self.signal(Some(self.taken));
+                    self.taken = 0;
+                }
+                Some(chunk)
+            }
+            Ok(StreamItem::End(complete)) => {
+                self.ended = true;
+                self.complete = complete;
+                None
+            }
+            Err(_) => {
+                self.ended = true;
+                None
+            }
+        }
+    }
+}
+
+impl Drop for IncomingStream {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.signal(None);
+        }
+    }
+}
+
+impl fmt::Debug for IncomingStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IncomingStream")
+            .field("id", &self.id)
+            .field("ended", &self.ended)
+            .field("complete", &self.complete)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outgoing_stream_splits_items_into_chunks() {
+        let items = vec![
+            Bytes::from_static(b"abcde"),
+            Bytes::new(),
+            Bytes::from_static(b"fg"),
+        ];
+        let mut stream = OutgoingStream::new(Box::new(items.into_iter()), SendToken(0), 0);
+
+        let chunks: Vec<_> = (0..4).filter_map(|_| stream.next_chunk(3)).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                Bytes::from_static(b"abc"),
+                Bytes::from_static(b"de"),
+                Bytes::from_static(b"fg"),
+            ]
+        );
+        assert_eq!(stream.next_chunk(3), None);
+    }
+}
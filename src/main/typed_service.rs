@@ -0,0 +1,78 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::Uid;
+use crate::main::{SendToken, Service};
+use bytes::Bytes;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use socket_collection::Priority;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// `Service` which sends and receives the application's own message type `M` rather than raw
+/// bytes.
+///
+/// Everything else is available through `Deref` to the wrapped `Service`. Received messages still
+/// arrive as `Event::NewMessage` and are turned back into `M` with `parse_msg`.
+pub struct TypedService<UID: Uid, M> {
+    service: Service<UID>,
+    phantom: PhantomData<fn(M) -> M>,
+}
+
+impl<UID: Uid, M: Serialize + DeserializeOwned> TypedService<UID, M> {
+    /// Wraps the given service.
+    pub fn new(service: Service<UID>) -> Self {
+        TypedService {
+            service,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the wrapped service.
+    pub fn into_inner(self) -> Service<UID> {
+        self.service
+    }
+
+    /// Serialises the message and sends it to a peer, see `Service::send`.
+    pub fn send_msg(&self, peer_uid: &UID, msg: &M, priority: Priority) -> crate::Res<SendToken> {
+        self.service
+            .send(peer_uid, Bytes::from(serialise(msg)?), priority)
+    }
+
+    /// Serialises the message once and sends it to all connected peers for which `filter` returns
+    /// `true`, see `Service::broadcast`.
+    pub fn broadcast_msg<F>(&self, msg: &M, priority: Priority, filter: F) -> crate::Res<usize>
+    where
+        F: Fn(&UID) -> bool,
+    {
+        self.service
+            .broadcast(Bytes::from(serialise(msg)?), priority, filter)
+    }
+
+    /// Deserialises the data of an `Event::NewMessage` sent by a peer's `send_msg`.
+    pub fn parse_msg(&self, data: &[u8]) -> crate::Res<M> {
+        Ok(deserialise(data)?)
+    }
+}
+
+impl<UID: Uid, M> Deref for TypedService<UID, M> {
+    type Target = Service<UID>;
+
+    fn deref(&self) -> &Service<UID> {
+        &self.service
+    }
+}
+
+impl<UID: Uid, M> DerefMut for TypedService<UID, M> {
+    fn deref_mut(&mut self) -> &mut Service<UID> {
+        &mut self.service
+    }
+}
@@ -7,14 +7,19 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{self, Core, Uid};
+use crate::common::{self, BootstrapDenyReason, Core, CrustUser, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::Config;
+use crate::main::{Config, CrustError};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
 use mio::Token;
-use safe_crypto::PublicEncryptKey;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // ========================================================================================
 //                                     ConnectionId
@@ -84,40 +89,302 @@ impl<UID: Uid> PubConnectionInfo<UID> {
     }
 }
 
+/// Prefix of the string encoding of `PubConnectionInfo`. The trailing number is the encoding
+/// version and has to be bumped whenever the serialised format changes.
+const PUB_CONNECTION_INFO_PREFIX: &str = "crust1:";
+
+/// Compact string encoding meant to be copy-pasted over chat or put into QR codes: a version
+/// prefix followed by the URL safe base64 of the serialised connection info.
+impl<UID: Uid> fmt::Display for PubConnectionInfo<UID> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = serialise(self).map_err(|_| fmt::Error)?;
+        write!(
+            formatter,
+            "{}{}",
+            PUB_CONNECTION_INFO_PREFIX,
+            base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl<UID: Uid> FromStr for PubConnectionInfo<UID> {
+    type Err = CrustError;
+
+    fn from_str(s: &str) -> Result<Self, CrustError> {
+        if !s.starts_with(PUB_CONNECTION_INFO_PREFIX) {
+            return Err(CrustError::InvalidConnectionInfo);
+        }
+        let bytes = base64::decode_config(
+            s[PUB_CONNECTION_INFO_PREFIX.len()..].trim(),
+            base64::URL_SAFE_NO_PAD,
+        )
+        .map_err(|_| CrustError::InvalidConnectionInfo)?;
+        Ok(deserialise(&bytes)?)
+    }
+}
+
+// ========================================================================================
+//                                   RelayedConnectionInfo
+// ========================================================================================
+/// Connection info a peer sent us through a common peer via `Service::send_connection_info_via`.
+/// It's still sealed; `Service::open_relayed_connection_info` opens it.
+#[derive(Debug, Clone)]
+pub struct RelayedConnectionInfo<UID> {
+    /// The peer whose connection info this claims to be.
+    pub from: UID,
+    /// The peer which relayed it to us.
+    pub via: UID,
+    sender_pk: PublicEncryptKey,
+    sealed: Vec<u8>,
+}
+
+impl<UID: Uid> RelayedConnectionInfo<UID> {
+    #[doc(hidden)]
+    pub fn new(from: UID, via: UID, sender_pk: PublicEncryptKey, sealed: Vec<u8>) -> Self {
+        RelayedConnectionInfo {
+            from,
+            via,
+            sender_pk,
+            sealed,
+        }
+    }
+
+    /// Opens the connection info with the secret key it was sealed for. Fails unless the sender
+    /// sealed its own connection info.
+    pub fn open(&self, our_sk: &SecretEncryptKey) -> crate::Res<PubConnectionInfo<UID>> {
+        let their_ci: PubConnectionInfo<UID> = our_sk
+            .shared_secret(&self.sender_pk)
+            .decrypt(&self.sealed)?;
+        if their_ci.id != self.from || their_ci.our_pk != self.sender_pk {
+            return Err(CrustError::InvalidConnectionInfo);
+        }
+        Ok(their_ci)
+    }
+}
+
+// ========================================================================================
+//                                     ConnectStats
+// ========================================================================================
+/// Outcome of a `Service::connect` attempt.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectStats {
+    /// Peer addresses we tried to connect to directly, in the order they were tried.
+    pub direct_attempts: Vec<SocketAddr>,
+    /// Peer addresses we tried to reach through the configured HTTP proxy.
+    pub tunnelled_attempts: Vec<SocketAddr>,
+    /// Peer address the connection was established with, if any.
+    pub connected_via: Option<SocketAddr>,
+    /// Time from starting the attempt until it succeeded or failed.
+    pub duration: Duration,
+}
+
+// ========================================================================================
+//                                     PeerStats
+// ========================================================================================
+/// Traffic statistics of a connection, as returned by `Service::peer_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Data messages handed to the socket.
+    pub msgs_sent: u64,
+    /// Payload bytes of the data messages handed to the socket.
+    pub bytes_sent: u64,
+    /// Data messages received.
+    pub msgs_received: u64,
+    /// Payload bytes of the data messages received.
+    pub bytes_received: u64,
+    /// Payload bytes of the data messages which were sent compressed, see
+    /// `Config::compression_threshold`. They are counted in `bytes_sent` too.
+    pub bytes_compressed: u64,
+    /// What `bytes_compressed` came down to after compression.
+    pub compressed_size: u64,
+    /// Messages held back by the rate limit, or because the socket hasn't written earlier
+    /// messages yet.
+    pub queued_msgs: usize,
+    /// Messages dropped because their TTL expired while held back.
+    pub expired_msgs: u64,
+    /// Messages dropped via `Service::cancel_send` while held back.
+    pub cancelled_msgs: u64,
+    /// Smoothed round trip time, if known yet.
+    pub rtt: Option<Duration>,
+    /// Time since the connection was established.
+    pub uptime: Duration,
+}
+
+impl PeerStats {
+    /// `compressed_size` relative to `bytes_compressed`, or `None` if nothing was compressed yet.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.bytes_compressed == 0 {
+            return None;
+        }
+        Some(self.compressed_size as f64 / self.bytes_compressed as f64)
+    }
+}
+
+// ========================================================================================
+//                                     SendToken
+// ========================================================================================
+/// Identifies a message given to `Service::send` and friends, so that it can be cancelled via
+/// `Service::cancel_send` while crust still holds it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SendToken(pub(crate) usize);
+
+// ========================================================================================
+//                                     ServiceStats
+// ========================================================================================
+/// Snapshot of the whole service, as returned by `Service::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStats {
+    /// Established connections to nodes.
+    pub node_connections: usize,
+    /// Established connections to clients.
+    pub client_connections: usize,
+    /// Payload bytes sent over the established connections.
+    pub bytes_sent: u64,
+    /// Payload bytes received over the established connections.
+    pub bytes_received: u64,
+    /// Handshakes in progress with peers whose ID we know already.
+    pub handshakes: usize,
+    /// Peers in the bootstrap cache.
+    pub bootstrap_cache_size: usize,
+    /// State of our TCP listeners.
+    pub tcp_listener: ListenerState,
+}
+
+/// Whether we accept connections, see `ServiceStats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerState {
+    /// Not listening.
+    Stopped,
+    /// Stopped by `Service::stop_listening`, until `Service::start_listening` is called.
+    Paused,
+    /// Listening on these ports.
+    Listening(Vec<u16>),
+}
+
+// ========================================================================================
+//                                     ConnectedPeer
+// ========================================================================================
+/// A peer we have an active connection to, or reach through a common peer, as returned by
+/// `Service::connected_peers`.
+#[derive(Debug, Clone)]
+pub struct ConnectedPeer<UID> {
+    /// The peer's ID.
+    pub id: UID,
+    /// The peer's address, as seen by our socket. For a relayed peer, the relay's address.
+    pub addr: SocketAddr,
+    /// Whether we reach the peer through a common peer, see `Service::connect_via`.
+    pub relayed: bool,
+    /// Whether the peer is a node or a client.
+    pub kind: CrustUser,
+    /// When the connection was established.
+    pub connected_since: Instant,
+    /// Traffic statistics of the connection.
+    pub stats: PeerStats,
+}
+
+// ========================================================================================
+//                                   BootstrapAdmission
+// ========================================================================================
+/// Whether a peer may bootstrap off us, as decided by the filter set via
+/// `Service::set_bootstrap_filter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootstrapAdmission {
+    /// Let the peer bootstrap off us, provided it passes the usual checks.
+    Accept,
+    /// Deny the peer and tell it why.
+    Reject(BootstrapDenyReason),
+}
+
+/// Decides whether a peer with the given public key, IP and role may bootstrap off us.
+pub type BootstrapFilter =
+    Fn(&PublicEncryptKey, IpAddr, CrustUser) -> BootstrapAdmission + Send + Sync;
+
+// ========================================================================================
+//                                     Whitelists
+// ========================================================================================
+/// IP whitelists set via `Service::set_whitelist`. `None` allows every IP.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Whitelists {
+    pub node_ips: Option<HashSet<IpAddr>>,
+    pub client_ips: Option<HashSet<IpAddr>>,
+}
+
 // ========================================================================================
 //                                     ConfigWrapper
 // ========================================================================================
 #[derive(Default)]
 pub struct ConfigWrapper {
     pub cfg: Config,
-    pub is_modified_for_next_refresh: bool,
+    /// The config last read from the config file or given to `Service::reload_config_from`. New
+    /// ones are compared against it, so that only what changed there is taken over into `cfg`.
+    pub loaded_cfg: Config,
+    /// Settings changed since the config was last refreshed, see `refresh`.
+    pub modified_for_next_refresh: Vec<&'static str>,
+    /// Unlike `cfg`, this isn't part of the config file and survives refreshes.
+    pub bootstrap_filter: Option<Arc<BootstrapFilter>>,
+    /// Take precedence over the whitelists of `cfg` if set. Like `bootstrap_filter`, they
+    /// survive refreshes.
+    pub whitelists: Option<Whitelists>,
+    /// Whether `cfg` was read from the config file, which is then read again from time to time
+    /// to pick up changes. A config given in code is kept as it is.
+    pub watch_file: bool,
 }
 impl ConfigWrapper {
     pub fn new(cfg: Config) -> Self {
         Self {
+            loaded_cfg: cfg.clone(),
             cfg,
-            is_modified_for_next_refresh: false,
+            modified_for_next_refresh: Vec::new(),
+            bootstrap_filter: None,
+            whitelists: None,
+            watch_file: false,
         }
     }
 
-    pub fn check_for_update_and_mark_modified(&mut self, new_cfg: Config) {
-        if self.cfg != new_cfg {
-            self.cfg = new_cfg;
-            self.is_modified_for_next_refresh = true;
+    /// Like `new`, but for a config read from the config file, see `watch_file`.
+    pub fn from_file(cfg: Config) -> Self {
+        Self {
+            watch_file: true,
+            ..Self::new(cfg)
         }
     }
 
-    /// Checks if `ActiveConnection` refresh is needed.
-    pub fn check_for_refresh_and_reset_modified(&mut self, new_cfg: Config) -> bool {
-        let should_refresh = if self.cfg != new_cfg {
-            self.cfg = new_cfg;
-            true
-        } else {
-            self.is_modified_for_next_refresh
-        };
+    /// The node whitelist in effect, see `whitelists`.
+    pub fn whitelisted_node_ips(&self) -> Option<&HashSet<IpAddr>> {
+        match self.whitelists {
+            Some(ref whitelists) => whitelists.node_ips.as_ref(),
+            None => self.cfg.whitelisted_node_ips.as_ref(),
+        }
+    }
 
-        self.is_modified_for_next_refresh = false;
-        should_refresh
+    /// The client whitelist in effect, see `whitelists`.
+    pub fn whitelisted_client_ips(&self) -> Option<&HashSet<IpAddr>> {
+        match self.whitelists {
+            Some(ref whitelists) => whitelists.client_ips.as_ref(),
+            None => self.cfg.whitelisted_client_ips.as_ref(),
+        }
+    }
+
+    pub fn check_for_update_and_mark_modified(&mut self, new_cfg: Config) {
+        let changed = self.loaded_cfg.changed_settings(&new_cfg);
+        if changed.is_empty() {
+            return;
+        }
+        self.cfg.take_settings(&new_cfg, &changed);
+        self.loaded_cfg = new_cfg;
+        for setting in changed {
+            if !self.modified_for_next_refresh.contains(&setting) {
+                self.modified_for_next_refresh.push(setting);
+            }
+        }
+    }
+
+    /// Takes over what changed in the new config since the last one loaded and returns which
+    /// settings changed since the last refresh, so that e.g. `ActiveConnection`s can be checked
+    /// against new whitelists.
+    pub fn refresh(&mut self, new_cfg: Config) -> Vec<&'static str> {
+        self.check_for_update_and_mark_modified(new_cfg);
+        mem::replace(&mut self.modified_for_next_refresh, Vec::new())
     }
 }
 
@@ -130,3 +397,55 @@ pub type EventLoop = common::EventLoop<BootstrapCache>;
 
 pub type ConnectionMap<UID> = Arc<Mutex<HashMap<UID, ConnectionId>>>;
 pub type CrustConfig = Arc<Mutex<ConfigWrapper>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use crate::tests::utils::{rand_uid, UniqueId};
+    use safe_crypto::gen_encrypt_keypair;
+
+    #[test]
+    fn pub_connection_info_string_roundtrip() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let info = PubConnectionInfo {
+            id: rand_uid(),
+            for_direct: vec![ipv4_addr(1, 2, 3, 4, 5483)],
+            our_pk,
+        };
+
+        let encoded = info.to_string();
+        assert!(encoded.starts_with(PUB_CONNECTION_INFO_PREFIX));
+
+        let decoded: PubConnectionInfo<UniqueId> = unwrap!(encoded.parse());
+        assert_eq!(decoded.id, info.id);
+        assert_eq!(decoded.for_direct, info.for_direct);
+        assert_eq!(decoded.our_pk, info.our_pk);
+    }
+
+    #[test]
+    fn refresh_takes_over_only_what_changed_in_the_loaded_config() {
+        let mut config = ConfigWrapper::new(Config::default());
+        config.cfg.max_msg_size = Some(1024);
+
+        let mut new_cfg = Config::default();
+        new_cfg.inbound_msgs_per_sec = Some(100);
+        assert_eq!(
+            config.refresh(new_cfg.clone()),
+            vec!["inbound_msgs_per_sec"]
+        );
+        assert_eq!(config.cfg.inbound_msgs_per_sec, Some(100));
+        assert_eq!(config.cfg.max_msg_size, Some(1024));
+
+        assert!(config.refresh(new_cfg).is_empty());
+    }
+
+    #[test]
+    fn pub_connection_info_rejects_unknown_version() {
+        let res = "crust0:AAAA".parse::<PubConnectionInfo<UniqueId>>();
+        match res {
+            Err(CrustError::InvalidConnectionInfo) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -9,32 +9,113 @@
 
 pub use self::get_ext_addr::GetExtAddr;
 use crate::common::{Core, CoreMessage, CoreTimer, State, Uid};
-use crate::nat::{util, MappingContext, NatError};
-use igd::PortMappingProtocol;
+use crate::nat::{nat_pmp, util, MappingContext, NatError, PmpGateway};
+use igd::{Gateway, PortMappingProtocol};
+use maidsafe_utilities::serialisation::serialise;
 use maidsafe_utilities::thread;
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use net2::TcpBuilder;
-use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+use safe_crypto::{self, PublicEncryptKey, SecretEncryptKey};
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 mod get_ext_addr;
 
 const TIMEOUT_SEC: u64 = 3;
 
+/// Port mapping a router made for us.
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    router: Router,
+    ext_port: u16,
+    /// When the mapping should be renewed, if it's leased.
+    renew_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+enum Router {
+    /// IGD mappings aren't leased, so they stay until `release` is called.
+    Igd(Gateway),
+    /// NAT-PMP and PCP mappings are leased and renewed by mapping the port again. PCP keys them
+    /// by `nonce` in addition to our address.
+    Pmp {
+        gateway: PmpGateway,
+        our_addr: SocketAddrV4,
+        nonce: [u8; 12],
+    },
+}
+
+impl PortMapping {
+    /// Returns whether both are the same mapping on the same router.
+    pub fn is_same(&self, other: &PortMapping) -> bool {
+        match (&self.router, &other.router) {
+            (&Router::Igd(ref gateway), &Router::Igd(ref other_gateway)) => {
+                gateway.addr == other_gateway.addr && self.ext_port == other.ext_port
+            }
+            // These routers keep one mapping per address of ours, so mapping it again replaced
+            // the old one even if the external port changed.
+            (
+                &Router::Pmp {
+                    gateway, our_addr, ..
+                },
+                &Router::Pmp {
+                    gateway: other_gateway,
+                    our_addr: other_addr,
+                    ..
+                },
+            ) => gateway.ip == other_gateway.ip && our_addr == other_addr,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the lease of the mapping is half over.
+    pub fn needs_renewal(&self) -> bool {
+        self.renew_at
+            .map_or(false, |renew_at| renew_at <= Instant::now())
+    }
+
+    /// Asks the router to remove the mapping. This blocks, so it's done on a separate thread.
+    pub fn release(self) {
+        let ext_port = self.ext_port;
+        let _ = thread::named("Port-Unmapping", move || {
+            let res = match self.router {
+                Router::Igd(gateway) => gateway
+                    .remove_port(PortMappingProtocol::TCP, ext_port)
+                    .map_err(|e| e.to_string()),
+                Router::Pmp {
+                    gateway,
+                    our_addr,
+                    nonce,
+                } => gateway
+                    .map_tcp(our_addr, &nonce, 0)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+            };
+            if let Err(e) = res {
+                debug!("Failed to remove mapping of port {}: {}", ext_port, e);
+            }
+        });
+    }
+}
+
 /// A state which represents the in-progress mapping of a tcp socket.
 pub struct MappedTcpSocket<F, UID, T> {
     token: Token,
     socket: Option<TcpBuilder>,
-    igd_children: usize,
+    /// Number of routers we are waiting for to map our port.
+    mapping_children: usize,
     stun_children: HashSet<Token>,
     mapped_addrs: Vec<SocketAddr>,
+    port_mappings: Vec<PortMapping>,
+    /// External addresses as reported by echo servers. These are only trusted by quorum, see
+    /// `agreed_ext_addrs`.
+    stun_addrs: Vec<SocketAddr>,
     timeout: Timeout,
     finish: Option<F>,
     phantom: PhantomData<(UID, T)>,
@@ -42,13 +123,16 @@ pub struct MappedTcpSocket<F, UID, T> {
 
 impl<F, UID, T: 'static> MappedTcpSocket<F, UID, T>
 where
-    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>, Vec<PortMapping>) + Any,
     UID: Uid,
 {
-    /// Start mapping a tcp socket
+    /// Start mapping a tcp socket. Only IPv4 ports are mapped by routers, via IGD or, if the
+    /// router doesn't support that, NAT-PMP or PCP. For IPv6 just our interface addresses and the
+    /// ones echo servers see are reported.
     pub fn start(
         core: &mut Core<T>,
         poll: &Poll,
+        ip: IpAddr,
         port: u16,
         mc: &MappingContext,
         our_pk: PublicEncryptKey,
@@ -57,65 +141,91 @@ where
     ) -> Result<(), NatError> {
         let token = core.get_new_token();
 
-        // TODO(Spandan) Ipv6 is not supported in Listener so dealing only with ipv4 right now
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let addr = SocketAddr::new(ip, port);
+        // When bound to a specific interface, only that interface can be mapped.
+        let is_bound_if = |if_ip: IpAddr| ip.is_unspecified() || if_ip == ip;
 
         let socket = util::new_reusably_bound_tcp_socket(&addr)?;
         let addr = socket.local_addr()?;
 
-        // Ask IGD
-        let mut igd_children = 0;
-        for &(ref ip, ref gateway) in mc.ifv4s() {
-            let gateway = match *gateway {
-                Some(ref gateway) => gateway.clone(),
-                None => continue,
-            };
-            let tx = core.sender().clone();
-            let addr_igd = SocketAddrV4::new(*ip, addr.port());
-            let _ = thread::named("IGD-Address-Mapping", move || {
-                let res =
-                    gateway.get_any_address(PortMappingProtocol::TCP, addr_igd, 0, "MaidSafeNat");
-                let ext_addr = match res {
-                    Ok(ext_addr) => ext_addr,
-                    Err(_) => return,
-                };
-                let _ = tx.send(CoreMessage::new(move |core, poll| {
-                    let state = match core.get_state(token) {
-                        Some(state) => state,
-                        None => return,
+        // Ask the routers, via IGD where they support it.
+        let mut mapping_children = 0;
+        let mapped_ifs: &[_] = if ip.is_ipv4() { mc.ifv4s() } else { &[] };
+        let nonce = pcp_nonce(&our_pk);
+        for &(ref ip, ref gateway) in mapped_ifs
+            .iter()
+            .filter(|&&(ip, _)| is_bound_if(IpAddr::V4(ip)))
+        {
+            let our_addr = SocketAddrV4::new(*ip, addr.port());
+            if let Some(ref gateway) = *gateway {
+                let gateway = gateway.clone();
+                Self::map_port(core, token, "IGD-Address-Mapping", move || {
+                    let ext_addr = gateway
+                        .get_any_address(PortMappingProtocol::TCP, our_addr, 0, "MaidSafeNat")
+                        .ok()?;
+                    let mapping = PortMapping {
+                        router: Router::Igd(gateway),
+                        ext_port: ext_addr.port(),
+                        renew_at: None,
                     };
-
-                    let mut state = state.borrow_mut();
-                    let mapping_tcp_sock =
-                        match state.as_any().downcast_mut::<MappedTcpSocket<F, UID, T>>() {
-                            Some(mapping_sock) => mapping_sock,
-                            None => return,
-                        };
-                    mapping_tcp_sock.handle_igd_resp(core, poll, SocketAddr::V4(ext_addr));
-                }));
-            });
-            igd_children += 1;
+                    Some((ext_addr, mapping))
+                });
+            } else if let Some(&(_, gateway)) =
+                mc.pmp_gateways().iter().find(|&&(pmp_ip, _)| pmp_ip == *ip)
+            {
+                Self::map_port(core, token, "NAT-PMP-Address-Mapping", move || {
+                    let (ext_addr, lifetime_sec) = gateway
+                        .map_tcp(our_addr, &nonce, nat_pmp::LIFETIME_SEC)
+                        .ok()?;
+                    let mapping = PortMapping {
+                        router: Router::Pmp {
+                            gateway,
+                            our_addr,
+                            nonce,
+                        },
+                        ext_port: ext_addr.port(),
+                        renew_at: Some(
+                            Instant::now() + Duration::from_secs(u64::from(lifetime_sec) / 2),
+                        ),
+                    };
+                    Some((ext_addr, mapping))
+                });
+            } else {
+                continue;
+            }
+            mapping_children += 1;
         }
 
-        let mapped_addrs = mc
-            .ifv4s()
-            .iter()
-            .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+        let our_ips: Vec<_> = if ip.is_ipv4() {
+            mc.ifv4s().iter().map(|&(ip, _)| IpAddr::V4(ip)).collect()
+        } else {
+            mc.ifv6s().iter().map(|&ip| IpAddr::V6(ip)).collect()
+        };
+        let mapped_addrs = our_ips
+            .into_iter()
+            .filter(|&ip| is_bound_if(ip))
+            .map(|ip| SocketAddr::new(ip, addr.port()))
             .collect();
 
         let state = Rc::new(RefCell::new(Self {
             token,
             socket: Some(socket),
-            igd_children,
+            mapping_children,
             stun_children: HashSet::with_capacity(mc.peer_stuns().len()),
             mapped_addrs,
+            port_mappings: Vec::with_capacity(mapping_children),
+            stun_addrs: Vec::with_capacity(mc.peer_stuns().len()),
             timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
             finish: Some(finish),
             phantom: PhantomData,
         }));
 
-        // Ask Stuns
-        for stun in mc.peer_stuns() {
+        // Ask Stuns, which we can only reach over the same IP version.
+        for stun in mc
+            .peer_stuns()
+            .iter()
+            .filter(|stun| stun.addr.is_ipv4() == addr.is_ipv4())
+        {
             let self_weak = Rc::downgrade(&state);
             let handler = move |core: &mut Core<T>, poll: &Poll, child_token, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
@@ -139,7 +249,7 @@ where
             }
         }
 
-        if state.borrow().stun_children.is_empty() && state.borrow().igd_children == 0 {
+        if state.borrow().stun_children.is_empty() && state.borrow().mapping_children == 0 {
             state.borrow_mut().terminate(core, poll);
             return Ok(());
         }
@@ -149,6 +259,36 @@ where
         Ok(())
     }
 
+    /// Runs `map` on a separate thread, since talking to the router blocks, and hands its result
+    /// to the state with the given token.
+    fn map_port<M>(core: &Core<T>, token: Token, thread_name: &str, map: M)
+    where
+        M: FnOnce() -> Option<(SocketAddrV4, PortMapping)> + Send + 'static,
+    {
+        let tx = core.sender().clone();
+        let _ = thread::named(thread_name, move || {
+            let (ext_addr, mapping) = match map() {
+                Some(res) => res,
+                None => return,
+            };
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                // If we gave up waiting already, nobody is going to use the mapping.
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => return mapping.release(),
+                };
+
+                let mut state = state.borrow_mut();
+                let mapping_tcp_sock =
+                    match state.as_any().downcast_mut::<MappedTcpSocket<F, UID, T>>() {
+                        Some(mapping_sock) => mapping_sock,
+                        None => return mapping.release(),
+                    };
+                mapping_tcp_sock.handle_mapping_resp(core, poll, SocketAddr::V4(ext_addr), mapping);
+            }));
+        });
+    }
+
     fn handle_stun_resp(
         &mut self,
         core: &mut Core<T>,
@@ -158,17 +298,24 @@ where
     ) {
         let _ = self.stun_children.remove(&child);
         if let Ok(our_ext_addr) = res {
-            self.mapped_addrs.push(our_ext_addr);
+            self.stun_addrs.push(our_ext_addr);
         }
-        if self.stun_children.is_empty() && self.igd_children == 0 {
+        if self.stun_children.is_empty() && self.mapping_children == 0 {
             self.terminate(core, poll);
         }
     }
 
-    fn handle_igd_resp(&mut self, core: &mut Core<T>, poll: &Poll, our_ext_addr: SocketAddr) {
-        self.igd_children -= 1;
+    fn handle_mapping_resp(
+        &mut self,
+        core: &mut Core<T>,
+        poll: &Poll,
+        our_ext_addr: SocketAddr,
+        mapping: PortMapping,
+    ) {
+        self.mapping_children -= 1;
         self.mapped_addrs.push(our_ext_addr);
-        if self.stun_children.is_empty() && self.igd_children == 0 {
+        self.port_mappings.push(mapping);
+        if self.stun_children.is_empty() && self.mapping_children == 0 {
             self.terminate(core, poll);
         }
     }
@@ -187,7 +334,7 @@ where
 
 impl<F, UID, T: 'static> State<T> for MappedTcpSocket<F, UID, T>
 where
-    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>, Vec<PortMapping>) + Any,
     UID: Uid,
 {
     fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, _: u8) {
@@ -200,11 +347,78 @@ where
         let _ = core.cancel_timeout(&self.timeout);
 
         let socket = unwrap!(self.socket.take());
-        let mapped_addrs = self.mapped_addrs.drain(..).collect();
-        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
+        let stun_addrs = self.stun_addrs.drain(..).collect();
+        let mut mapped_addrs: Vec<_> = self.mapped_addrs.drain(..).collect();
+        mapped_addrs.extend(agreed_ext_addrs(stun_addrs));
+        let port_mappings = self.port_mappings.drain(..).collect();
+        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs, port_mappings);
     }
 
     fn as_any(&mut self) -> &mut Any {
         self
     }
 }
+
+/// PCP routers only let us renew or remove a mapping with the nonce it was made with. Deriving it
+/// from our public key keeps it the same for as long as we are the same peer.
+fn pcp_nonce(our_pk: &PublicEncryptKey) -> [u8; 12] {
+    let hash = safe_crypto::hash(&serialise(our_pk).unwrap_or_default());
+    let mut nonce = [0; 12];
+    nonce.copy_from_slice(&hash[..12]);
+    nonce
+}
+
+/// A single broken or lying echo server must not be able to make us advertise a wrong external
+/// address. Hence addresses reported by a majority of echo servers are trusted exclusively. If
+/// there is no majority, all reported addresses are listed as candidates.
+fn agreed_ext_addrs(reported: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let total = reported.len();
+    let mut votes = HashMap::with_capacity(total);
+    for addr in &reported {
+        *votes.entry(*addr).or_insert(0) += 1;
+    }
+
+    let mut seen = HashSet::with_capacity(votes.len());
+    let candidates: Vec<_> = reported
+        .into_iter()
+        .filter(|addr| seen.insert(*addr))
+        .collect();
+    let agreed: Vec<_> = candidates
+        .iter()
+        .filter(|addr| votes[*addr] * 2 > total)
+        .cloned()
+        .collect();
+    if agreed.is_empty() {
+        candidates
+    } else {
+        agreed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+
+    #[test]
+    fn agreed_ext_addrs_prefers_majority() {
+        let good = ipv4_addr(1, 2, 3, 4, 5000);
+        let bad = ipv4_addr(6, 6, 6, 6, 6666);
+
+        assert_eq!(agreed_ext_addrs(vec![good, bad, good]), vec![good]);
+        assert_eq!(agreed_ext_addrs(vec![good]), vec![good]);
+        assert!(agreed_ext_addrs(vec![]).is_empty());
+    }
+
+    #[test]
+    fn agreed_ext_addrs_lists_all_candidates_without_majority() {
+        let addr1 = ipv4_addr(1, 2, 3, 4, 5000);
+        let addr2 = ipv4_addr(1, 2, 3, 4, 5001);
+        let addr3 = ipv4_addr(1, 2, 3, 4, 5002);
+
+        assert_eq!(
+            agreed_ext_addrs(vec![addr1, addr2, addr1, addr2, addr3]),
+            vec![addr1, addr2, addr3]
+        );
+    }
+}
@@ -11,7 +11,7 @@
 
 use super::NatError;
 use crate::common::PeerInfo;
-use crate::nat;
+use crate::nat::{self, nat_pmp, PmpGateway};
 use crossbeam;
 use get_if_addrs::{self, IfAddr};
 use igd::{self, Gateway};
@@ -23,12 +23,16 @@ use std::time::Duration;
 pub struct MappingContext {
     our_ifv4s: Vec<(Ipv4Addr, Option<Gateway>)>,
     our_ifv6s: Vec<Ipv6Addr>,
+    /// Interfaces from which the default gateway answered in NAT-PMP or PCP.
+    pmp_gateways: Vec<(Ipv4Addr, PmpGateway)>,
     peer_stuns: Vec<PeerInfo>,
 }
 
 impl MappingContext {
-    /// Create a new `MappingContext`
-    pub fn try_new() -> Result<Self, NatError> {
+    /// Create a new `MappingContext`. If `search_igd` is false, neither IGD gateways are looked up
+    /// nor the default gateway asked whether it speaks NAT-PMP or PCP, and hence no port mappings
+    /// will be requested from routers.
+    pub fn try_new(search_igd: bool) -> Result<Self, NatError> {
         let ifs = get_if_addrs::get_if_addrs()?;
         let (mut ifv4s, mut ifv6s) = (Vec::with_capacity(5), Vec::with_capacity(5));
         for interface in ifs {
@@ -38,21 +42,41 @@ impl MappingContext {
             }
         }
 
+        let default_gateway = if search_igd {
+            nat_pmp::default_gateway()
+        } else {
+            None
+        };
+        let mut pmp_gateways: Vec<_> = ifv4s.iter().map(|&(ip, _)| (ip, None)).collect();
+
         crossbeam::scope(|scope| {
-            let mut guards = Vec::with_capacity(ifv4s.len());
+            let mut guards = Vec::with_capacity(2 * ifv4s.len());
             for ifv4 in &mut ifv4s {
-                if !ifv4.0.is_loopback() {
+                if search_igd && !ifv4.0.is_loopback() {
                     guards.push(scope.spawn(move || {
                         ifv4.1 =
                             igd::search_gateway_from_timeout(ifv4.0, Duration::from_secs(1)).ok();
                     }));
                 }
             }
+            if let Some(gateway_ip) = default_gateway {
+                for pmp_gateway in &mut pmp_gateways {
+                    if !pmp_gateway.0.is_loopback() {
+                        guards.push(scope.spawn(move || {
+                            pmp_gateway.1 = PmpGateway::probe(gateway_ip, pmp_gateway.0).ok();
+                        }));
+                    }
+                }
+            }
         });
 
         Ok(MappingContext {
             our_ifv4s: ifv4s,
             our_ifv6s: ifv6s,
+            pmp_gateways: pmp_gateways
+                .into_iter()
+                .filter_map(|(ip, gateway)| gateway.map(|gateway| (ip, gateway)))
+                .collect(),
             peer_stuns: Vec::with_capacity(10),
         })
     }
@@ -71,6 +95,16 @@ impl MappingContext {
         &self.our_ifv4s
     }
 
+    /// Get v6 interfaces
+    pub fn ifv6s(&self) -> &Vec<Ipv6Addr> {
+        &self.our_ifv6s
+    }
+
+    /// Get the NAT-PMP or PCP gateway of each v4 interface which has one
+    pub fn pmp_gateways(&self) -> &Vec<(Ipv4Addr, PmpGateway)> {
+        &self.pmp_gateways
+    }
+
     /// Iterate over the known servers
     pub fn peer_stuns(&self) -> &Vec<PeerInfo> {
         &self.peer_stuns
@@ -85,7 +119,7 @@ mod tests {
     #[test]
     #[ignore]
     fn igd_gateway_available() {
-        let mc = unwrap!(MappingContext::try_new(), "Could not instantiate MC");
+        let mc = unwrap!(MappingContext::try_new(true), "Could not instantiate MC");
         assert!(!mc.our_ifv4s.is_empty());
 
         let mut loopback_found = false;
@@ -8,11 +8,15 @@
 // Software.
 
 pub use self::error::NatError;
-pub use self::mapped_tcp_socket::{GetExtAddr, MappedTcpSocket};
+pub use self::mapped_tcp_socket::{GetExtAddr, MappedTcpSocket, PortMapping};
 pub use self::mapping_context::MappingContext;
-pub use self::util::ip_addr_is_global;
+pub use self::nat_pmp::PmpGateway;
+pub use self::nat_probe::{NatInfo, NatProbe, NatType};
+pub use self::util::{ip_addr_is_global, ip_addr_is_shared};
 
 mod error;
 mod mapped_tcp_socket;
 mod mapping_context;
+mod nat_pmp;
+mod nat_probe;
 mod util;
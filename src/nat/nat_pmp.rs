@@ -0,0 +1,350 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Port mappings via NAT-PMP (RFC 6886) and its successor PCP (RFC 6887), for routers which don't
+//! implement UPnP IGD. Both are spoken with the default gateway on UDP port 5351. A gateway which
+//! only speaks NAT-PMP answers PCP requests with an error in its own version, so we ask in PCP
+//! first and fall back to NAT-PMP.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+/// Lifetime we ask for. Mappings are renewed once half of it has passed.
+pub const LIFETIME_SEC: u32 = 7200;
+
+const PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const NAT_PMP_OP_EXT_ADDR: u8 = 0;
+const NAT_PMP_OP_MAP_TCP: u8 = 2;
+const PCP_OP_ANNOUNCE: u8 = 0;
+const PCP_OP_MAP: u8 = 1;
+/// Set in the opcode of responses.
+const RESPONSE_BIT: u8 = 0x80;
+const TCP_PROTOCOL_NUMBER: u8 = 6;
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_LEN: usize = 60;
+/// Longest message either protocol allows.
+const MAX_MSG_LEN: usize = 1100;
+/// Both RFCs ask to send requests again after 250ms, 500ms and so on. We give up after the third
+/// try already, so that mapping a port fits into the timeout of `MappedTcpSocket`.
+const TRIES: u32 = 3;
+/// Probing gives up sooner still, within the time we search for IGD gateways.
+const PROBE_TRIES: u32 = 2;
+const FIRST_TRY_TIMEOUT_MS: u64 = 250;
+
+/// Which of the two protocols a gateway speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmpProtocol {
+    /// NAT-PMP, RFC 6886.
+    NatPmp,
+    /// PCP, RFC 6887.
+    Pcp,
+}
+
+/// Gateway which answered our NAT-PMP or PCP probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmpGateway {
+    /// IP of the gateway.
+    pub ip: Ipv4Addr,
+    /// Protocol the gateway speaks.
+    pub protocol: PmpProtocol,
+}
+
+impl PmpGateway {
+    /// Finds out whether the gateway speaks PCP or NAT-PMP, asking from our interface with the
+    /// given IP. Blocks until the gateway answers or we give up.
+    pub fn probe(ip: Ipv4Addr, our_ip: Ipv4Addr) -> io::Result<Self> {
+        let socket = connect(ip, our_ip)?;
+        let mut resp = [0; MAX_MSG_LEN];
+        let len = exchange(
+            &socket,
+            &pcp_header(PCP_OP_ANNOUNCE, 0, our_ip),
+            &mut resp,
+            PROBE_TRIES,
+        )?;
+        let protocol = match resp[..len].first() {
+            Some(&PCP_VERSION) => {
+                let _ = parse_pcp_header(&resp[..len], PCP_OP_ANNOUNCE)?;
+                PmpProtocol::Pcp
+            }
+            Some(&NAT_PMP_VERSION) => PmpProtocol::NatPmp,
+            _ => return Err(invalid_resp("unknown protocol version")),
+        };
+        Ok(PmpGateway { ip, protocol })
+    }
+
+    /// Asks the gateway to map the TCP port of `our_addr` for `lifetime_sec` seconds, or to remove
+    /// the mapping if that is 0. PCP mappings are keyed by `nonce` too, so it must be the same
+    /// whenever the port is mapped. Returns the external address and the lifetime granted.
+    pub fn map_tcp(
+        &self,
+        our_addr: SocketAddrV4,
+        nonce: &[u8; 12],
+        lifetime_sec: u32,
+    ) -> io::Result<(SocketAddrV4, u32)> {
+        let socket = connect(self.ip, *our_addr.ip())?;
+        let mut resp = [0; MAX_MSG_LEN];
+        match self.protocol {
+            PmpProtocol::Pcp => {
+                let req = pcp_map_request(our_addr, nonce, lifetime_sec);
+                let len = exchange(&socket, &req, &mut resp, TRIES)?;
+                parse_pcp_map_response(&resp[..len], nonce)
+            }
+            PmpProtocol::NatPmp => {
+                // NAT-PMP doesn't tell the external IP along with the mapping.
+                let len = exchange(
+                    &socket,
+                    &[NAT_PMP_VERSION, NAT_PMP_OP_EXT_ADDR],
+                    &mut resp,
+                    TRIES,
+                )?;
+                let ext_ip = parse_nat_pmp_ext_ip(&resp[..len])?;
+                let req = nat_pmp_map_request(our_addr.port(), lifetime_sec);
+                let len = exchange(&socket, &req, &mut resp, TRIES)?;
+                let (ext_port, lifetime_sec) =
+                    parse_nat_pmp_map_response(&resp[..len], our_addr.port())?;
+                Ok((SocketAddrV4::new(ext_ip, ext_port), lifetime_sec))
+            }
+        }
+    }
+}
+
+/// Reads the IPv4 default gateway from the kernel's routing table.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+/// Finding the default gateway is only implemented for Linux so far.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// The routing table lists destinations and gateways as hex numbers in host byte order.
+#[cfg(any(target_os = "linux", test))]
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let cols: Vec<_> = line.split_whitespace().collect();
+            if cols.len() > 2 && cols[1] == "00000000" {
+                u32::from_str_radix(cols[2], 16).ok()
+            } else {
+                None
+            }
+        })
+        .map(|gateway| Ipv4Addr::from(u32::from_be(gateway)))
+        .find(|gateway| !gateway.is_unspecified())
+}
+
+fn connect(ip: Ipv4Addr, our_ip: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((our_ip, 0))?;
+    socket.connect((ip, PORT))?;
+    Ok(socket)
+}
+
+/// Sends the request until the gateway answers or we run out of tries.
+fn exchange(socket: &UdpSocket, req: &[u8], resp: &mut [u8], tries: u32) -> io::Result<usize> {
+    let mut timeout = Duration::from_millis(FIRST_TRY_TIMEOUT_MS);
+    for _ in 0..tries {
+        let _ = socket.send(req)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(resp) {
+            Ok(len) => return Ok(len),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                timeout *= 2
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "gateway didn't answer",
+    ))
+}
+
+fn pcp_header(opcode: u8, lifetime_sec: u32, our_ip: Ipv4Addr) -> Vec<u8> {
+    let mut req = Vec::with_capacity(PCP_MAP_LEN);
+    req.extend_from_slice(&[PCP_VERSION, opcode, 0, 0]);
+    put_u32(&mut req, lifetime_sec);
+    req.extend_from_slice(&our_ip.to_ipv6_mapped().octets());
+    req
+}
+
+fn pcp_map_request(our_addr: SocketAddrV4, nonce: &[u8; 12], lifetime_sec: u32) -> Vec<u8> {
+    let mut req = pcp_header(PCP_OP_MAP, lifetime_sec, *our_addr.ip());
+    req.extend_from_slice(nonce);
+    req.extend_from_slice(&[TCP_PROTOCOL_NUMBER, 0, 0, 0]);
+    put_u16(&mut req, our_addr.port());
+    // We'd like the same port outside, on whatever external IP the gateway has.
+    put_u16(&mut req, our_addr.port());
+    req.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    req
+}
+
+/// Checks the response header and returns the lifetime granted.
+fn parse_pcp_header(resp: &[u8], opcode: u8) -> io::Result<u32> {
+    if resp.len() < PCP_HEADER_LEN || resp[0] != PCP_VERSION || resp[1] != opcode | RESPONSE_BIT {
+        return Err(invalid_resp("not a PCP response to our request"));
+    }
+    if resp[3] != 0 {
+        return Err(refused(u16::from(resp[3])));
+    }
+    Ok(read_u32(&resp[4..8]))
+}
+
+fn parse_pcp_map_response(resp: &[u8], nonce: &[u8; 12]) -> io::Result<(SocketAddrV4, u32)> {
+    let lifetime_sec = parse_pcp_header(resp, PCP_OP_MAP)?;
+    if resp.len() < PCP_MAP_LEN || resp[24..36] != nonce[..] {
+        return Err(invalid_resp("PCP response to someone else's request"));
+    }
+    let ext_port = read_u16(&resp[42..44]);
+    let ext_ip = Ipv4Addr::new(resp[56], resp[57], resp[58], resp[59]);
+    Ok((SocketAddrV4::new(ext_ip, ext_port), lifetime_sec))
+}
+
+fn nat_pmp_map_request(port: u16, lifetime_sec: u32) -> Vec<u8> {
+    let mut req = vec![NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP, 0, 0];
+    put_u16(&mut req, port);
+    // Removing a mapping requires the suggested external port to be 0.
+    put_u16(&mut req, if lifetime_sec == 0 { 0 } else { port });
+    put_u32(&mut req, lifetime_sec);
+    req
+}
+
+/// Checks the response header, which is followed by the seconds since the gateway's epoch.
+fn check_nat_pmp_header(resp: &[u8], opcode: u8, len: usize) -> io::Result<()> {
+    if resp.len() < len || resp[0] != NAT_PMP_VERSION || resp[1] != opcode | RESPONSE_BIT {
+        return Err(invalid_resp("not a NAT-PMP response to our request"));
+    }
+    match read_u16(&resp[2..4]) {
+        0 => Ok(()),
+        result => Err(refused(result)),
+    }
+}
+
+fn parse_nat_pmp_ext_ip(resp: &[u8]) -> io::Result<Ipv4Addr> {
+    check_nat_pmp_header(resp, NAT_PMP_OP_EXT_ADDR, 12)?;
+    Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+}
+
+/// Returns the external port and the lifetime granted.
+fn parse_nat_pmp_map_response(resp: &[u8], port: u16) -> io::Result<(u16, u32)> {
+    check_nat_pmp_header(resp, NAT_PMP_OP_MAP_TCP, 16)?;
+    if read_u16(&resp[8..10]) != port {
+        return Err(invalid_resp("NAT-PMP mapping of another port"));
+    }
+    Ok((read_u16(&resp[10..12]), read_u32(&resp[12..16])))
+}
+
+fn invalid_resp(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn refused(result: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("gateway refused with result code {}", result),
+    )
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    put_u16(buf, (value >> 16) as u16);
+    put_u16(buf, value as u16);
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) << 8 | u16::from(bytes[1])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from(read_u16(&bytes[..2])) << 16 | u32::from(read_u16(&bytes[2..4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_gateway_is_read_from_routing_table() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        let expected = Ipv4Addr::from(u32::from_be(0x0101_A8C0));
+        assert_eq!(parse_default_gateway(routes), Some(expected));
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn pcp_map_response_is_checked_against_our_nonce() {
+        let our_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 5483);
+        let nonce = [7; 12];
+        let req = pcp_map_request(our_addr, &nonce, LIFETIME_SEC);
+        assert_eq!(req.len(), PCP_MAP_LEN);
+
+        // The gateway echoes the request, with the lifetime and external address it granted.
+        let mut resp = req.clone();
+        resp[1] |= RESPONSE_BIT;
+        resp[4..8].copy_from_slice(&[0, 0, 0x0e, 0x10]);
+        resp[42..44].copy_from_slice(&[0x1f, 0x90]);
+        resp[56..60].copy_from_slice(&[1, 2, 3, 4]);
+        let ext_addr = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 8080);
+        assert_eq!(
+            unwrap!(parse_pcp_map_response(&resp, &nonce)),
+            (ext_addr, 3600)
+        );
+        assert!(parse_pcp_map_response(&resp, &[8; 12]).is_err());
+
+        // Result code 2 is NOT_AUTHORIZED.
+        resp[3] = 2;
+        assert!(parse_pcp_map_response(&resp, &nonce).is_err());
+    }
+
+    #[test]
+    fn nat_pmp_requests_and_responses() {
+        assert_eq!(
+            nat_pmp_map_request(5483, LIFETIME_SEC),
+            vec![0, 2, 0, 0, 0x15, 0x6b, 0x15, 0x6b, 0, 0, 0x1c, 0x20]
+        );
+        assert_eq!(
+            nat_pmp_map_request(5483, 0),
+            vec![0, 2, 0, 0, 0x15, 0x6b, 0, 0, 0, 0, 0, 0]
+        );
+
+        let resp = [0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4];
+        assert_eq!(
+            unwrap!(parse_nat_pmp_ext_ip(&resp)),
+            Ipv4Addr::new(1, 2, 3, 4)
+        );
+
+        let resp = [
+            0, 130, 0, 0, 0, 0, 0, 1, 0x15, 0x6b, 0x1f, 0x90, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            unwrap!(parse_nat_pmp_map_response(&resp, 5483)),
+            (8080, 3600)
+        );
+        assert!(parse_nat_pmp_map_response(&resp, 5484).is_err());
+
+        // Result code 2 means the gateway has mappings disabled.
+        let resp = [0, 130, 0, 2, 0, 0, 0, 1, 0x15, 0x6b, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_map_response(&resp, 5483).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{Core, CoreTimer, State, Uid};
+use crate::nat::{util, GetExtAddr, MappingContext, NatError};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use net2::TcpBuilder;
+use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+const TIMEOUT_SEC: u64 = 3;
+
+/// How our NAT maps outgoing TCP connections, as far as it can be told from the echo servers we
+/// know of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Not enough echo servers responded to tell.
+    Unknown,
+    /// Echo servers see our local address, i.e. we are not behind a NAT.
+    None,
+    /// All echo servers see the same external address (full cone, restricted cone or port
+    /// restricted cone NAT).
+    EndpointIndependent,
+    /// Echo servers see different external addresses (symmetric NAT). Hole punching is unlikely
+    /// to work.
+    EndpointDependent,
+}
+
+/// Result of probing the NAT we are behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatInfo {
+    /// Detected NAT type.
+    pub nat_type: NatType,
+    /// External addresses of our probe socket as seen by each responding echo server.
+    pub ext_addrs: Vec<SocketAddr>,
+}
+
+/// Connects to every known echo server from the same local address and compares the external
+/// addresses they report.
+pub struct NatProbe<F, UID, T> {
+    token: Token,
+    socket: Option<TcpBuilder>,
+    local_addrs: Vec<SocketAddr>,
+    children: HashSet<Token>,
+    ext_addrs: Vec<SocketAddr>,
+    timeout: Timeout,
+    finish: Option<F>,
+    phantom: PhantomData<(UID, T)>,
+}
+
+impl<F, UID, T: 'static> NatProbe<F, UID, T>
+where
+    F: FnOnce(&mut Core<T>, &Poll, NatInfo) + Any,
+    UID: Uid,
+{
+    /// Start probing
+    pub fn start(
+        core: &mut Core<T>,
+        poll: &Poll,
+        mc: &MappingContext,
+        our_pk: PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        finish: F,
+    ) -> Result<(), NatError> {
+        let token = core.get_new_token();
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let socket = util::new_reusably_bound_tcp_socket(&addr)?;
+        let addr = socket.local_addr()?;
+
+        let local_addrs = mc
+            .ifv4s()
+            .iter()
+            .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+            .collect();
+
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            socket: Some(socket),
+            local_addrs,
+            children: HashSet::with_capacity(mc.peer_stuns().len()),
+            ext_addrs: Vec::with_capacity(mc.peer_stuns().len()),
+            timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
+            finish: Some(finish),
+            phantom: PhantomData,
+        }));
+
+        for stun in mc.peer_stuns() {
+            let self_weak = Rc::downgrade(&state);
+            let handler = move |core: &mut Core<T>, poll: &Poll, child_token, res| {
+                if let Some(self_rc) = self_weak.upgrade() {
+                    self_rc
+                        .borrow_mut()
+                        .handle_stun_resp(core, poll, child_token, res)
+                }
+            };
+
+            if let Ok(child) = GetExtAddr::<UID, _>::start(
+                core,
+                poll,
+                addr,
+                stun,
+                our_pk,
+                our_sk,
+                None,
+                Box::new(handler),
+            ) {
+                let _ = state.borrow_mut().children.insert(child);
+            }
+        }
+
+        if state.borrow().children.is_empty() {
+            state.borrow_mut().terminate(core, poll);
+            return Ok(());
+        }
+
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    fn handle_stun_resp(
+        &mut self,
+        core: &mut Core<T>,
+        poll: &Poll,
+        child: Token,
+        res: Result<SocketAddr, ()>,
+    ) {
+        let _ = self.children.remove(&child);
+        if let Ok(our_ext_addr) = res {
+            self.ext_addrs.push(our_ext_addr);
+        }
+        if self.children.is_empty() {
+            self.terminate(core, poll);
+        }
+    }
+
+    fn terminate_children(&mut self, core: &mut Core<T>, poll: &Poll) {
+        for token in self.children.drain() {
+            let child = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            child.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+impl<F, UID, T: 'static> State<T> for NatProbe<F, UID, T>
+where
+    F: FnOnce(&mut Core<T>, &Poll, NatInfo) + Any,
+    UID: Uid,
+{
+    fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, _: u8) {
+        self.terminate(core, poll)
+    }
+
+    fn terminate(&mut self, core: &mut Core<T>, poll: &Poll) {
+        self.terminate_children(core, poll);
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = self.socket.take();
+
+        let ext_addrs: Vec<_> = self.ext_addrs.drain(..).collect();
+        let nat_info = NatInfo {
+            nat_type: classify(&self.local_addrs, &ext_addrs),
+            ext_addrs,
+        };
+        (unwrap!(self.finish.take()))(core, poll, nat_info);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn classify(local_addrs: &[SocketAddr], ext_addrs: &[SocketAddr]) -> NatType {
+    let first = match ext_addrs.first() {
+        Some(addr) => addr,
+        None => return NatType::Unknown,
+    };
+    if ext_addrs.iter().all(|addr| local_addrs.contains(addr)) {
+        NatType::None
+    } else if ext_addrs.len() < 2 {
+        NatType::Unknown
+    } else if ext_addrs.iter().all(|addr| addr == first) {
+        NatType::EndpointIndependent
+    } else {
+        NatType::EndpointDependent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+
+    #[test]
+    fn classify_nat_types() {
+        let local = [ipv4_addr(192, 168, 0, 2, 5000)];
+
+        assert_eq!(classify(&local, &[]), NatType::Unknown);
+        assert_eq!(
+            classify(&local, &[ipv4_addr(1, 2, 3, 4, 6000)]),
+            NatType::Unknown
+        );
+        assert_eq!(
+            classify(&local, &[ipv4_addr(192, 168, 0, 2, 5000)]),
+            NatType::None
+        );
+        assert_eq!(
+            classify(
+                &local,
+                &[ipv4_addr(1, 2, 3, 4, 6000), ipv4_addr(1, 2, 3, 4, 6000)]
+            ),
+            NatType::EndpointIndependent
+        );
+        assert_eq!(
+            classify(
+                &local,
+                &[ipv4_addr(1, 2, 3, 4, 6000), ipv4_addr(1, 2, 3, 4, 6001)]
+            ),
+            NatType::EndpointDependent
+        );
+    }
+}
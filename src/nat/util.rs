@@ -54,6 +54,18 @@ pub fn ipv4_addr_is_global(ipv4: Ipv4Addr) -> bool {
         || ipv4.octets() == [0, 0, 0, 0])
 }
 
+/// Checks if the address belongs to the shared address space (100.64.0.0/10) used by carrier
+/// grade NATs.
+pub fn ip_addr_is_shared(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(addr_v4) => {
+            let octets = addr_v4.octets();
+            octets[0] == 100 && octets[1] & 0xc0 == 64
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
 /// A replacement for `Ipv6Addr::is_global` while we wait for that to enter stable.
 pub fn ipv6_addr_is_global(ipv6: Ipv6Addr) -> bool {
     // TODO(canndrew): This function is incomplete and may return false-positives.
@@ -11,7 +11,7 @@ pub use self::errors::ServiceDiscoveryError;
 
 mod errors;
 
-use crate::common::{ipv4_addr, Core, PeerInfo, State};
+use crate::common::{ipv4_addr, Core, NameHash, PeerInfo, State};
 use mio::net::UdpSocket;
 use mio::{Poll, PollOpt, Ready, Token};
 use safe_crypto::PublicEncryptKey;
@@ -27,9 +27,10 @@ use std::u16;
 
 #[derive(Serialize, Deserialize)]
 enum DiscoveryMsg {
-    /// Service discovery request with requestor's public key.
+    /// Service discovery request with requestor's public key and the hash of its network name.
     Request {
         our_pk: PublicEncryptKey,
+        name_hash: NameHash,
     },
     Response(Vec<PeerInfo>),
 }
@@ -45,6 +46,7 @@ pub struct ServiceDiscovery<T> {
     seek_peers_req: DiscoveryMsg,
     observers: Vec<Sender<Vec<PeerInfo>>>,
     our_pk: PublicEncryptKey,
+    name_hash: NameHash,
     phantom: PhantomData<T>,
 }
 
@@ -55,6 +57,8 @@ impl<T: 'static> ServiceDiscovery<T> {
     ///
     /// - listener_port - port we will be litening for incoming service discovery requests.
     /// - remote_port - port we will broadcasting service discovery requests to.
+    /// - name_hash - only peers of the network with this name hash are answered, so that several
+    ///   networks can share the ports.
     pub fn start(
         core: &mut Core<T>,
         poll: &Poll,
@@ -63,6 +67,7 @@ impl<T: 'static> ServiceDiscovery<T> {
         listener_port: u16,
         remote_port: u16,
         our_pk: PublicEncryptKey,
+        name_hash: NameHash,
     ) -> Result<(), ServiceDiscoveryError> {
         let udp_socket = UdpSocket::bind(&ipv4_addr(0, 0, 0, 0, listener_port))?;
         udp_socket.set_broadcast(true)?;
@@ -76,9 +81,10 @@ impl<T: 'static> ServiceDiscovery<T> {
             remote_addr,
             listen: false,
             our_listeners,
-            seek_peers_req: DiscoveryMsg::Request { our_pk },
+            seek_peers_req: DiscoveryMsg::Request { our_pk, name_hash },
             observers: Vec::new(),
             our_pk,
+            name_hash,
             phantom: PhantomData,
         };
 
@@ -143,7 +149,14 @@ impl<T: 'static> ServiceDiscovery<T> {
         peer_addr: SocketAddr,
     ) {
         match msg {
-            DiscoveryMsg::Request { our_pk: their_pk } => {
+            DiscoveryMsg::Request {
+                our_pk: their_pk,
+                name_hash,
+            } => {
+                if name_hash != self.name_hash {
+                    trace!("Ignoring service discovery request from another network");
+                    return;
+                }
                 if self.listen && self.our_pk != their_pk {
                     let our_current_listeners =
                         unwrap!(self.our_listeners.lock()).iter().cloned().collect();
@@ -194,7 +207,7 @@ impl<T: 'static> State<T> for ServiceDiscovery<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::{self, CoreMessage};
+    use crate::common::{self, CoreMessage, EventLoop};
     use mio::Token;
     use safe_crypto::gen_encrypt_keypair;
     use std::str::FromStr;
@@ -203,11 +216,33 @@ mod tests {
     use std::time::Duration;
     use std::{net, thread};
 
+    const SERVICE_DISCOVERY_TOKEN: usize = 0;
+    const NAME_HASH: NameHash = [0; 32];
+
     #[test]
     fn service_discovery() {
-        const SERVICE_DISCOVERY_TOKEN: usize = 0;
+        let (_el0, listeners_0) = start_listening_service_discovery(65_530, NAME_HASH);
+        let (_el1, rx) = seek_peers(65_530, NAME_HASH);
+        let peer_listeners = unwrap!(rx.recv_timeout(Duration::from_secs(30)));
+        assert_eq!(
+            peer_listeners.into_iter().collect::<Vec<_>>(),
+            *unwrap!(listeners_0.lock())
+        );
+    }
+
+    #[test]
+    fn service_discovery_ignores_other_networks() {
+        let (_el0, _listeners) = start_listening_service_discovery(65_529, NAME_HASH);
+        let (_el1, rx) = seek_peers(65_529, [1; 32]);
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_err());
+    }
 
-        // Poll-0
+    /// Starts a service discovery which answers on the given port with a made up listener and
+    /// returns its event loop and listeners.
+    fn start_listening_service_discovery(
+        port: u16,
+        name_hash: NameHash,
+    ) -> (EventLoop<()>, Arc<Mutex<Vec<PeerInfo>>>) {
         let el0 = unwrap!(
             common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL0"), || ()),
             "Could not run el0"
@@ -219,38 +254,43 @@ mod tests {
         let listeners_0 = Arc::new(Mutex::new(vec![conn_info]));
         let listeners_0_clone = listeners_0.clone();
 
-        // ServiceDiscovery-0
-        {
-            let token_0 = Token(SERVICE_DISCOVERY_TOKEN);
-            unwrap!(
-                el0.send(CoreMessage::new(move |core, poll| {
-                    unwrap!(
-                        ServiceDiscovery::start(
-                            core,
-                            poll,
-                            listeners_0_clone,
-                            token_0,
-                            65_530,
-                            65_530,
-                            service0_pk,
-                        ),
-                        "Could not spawn ServiceDiscovery_0"
-                    );
-                })),
-                "Could not send to el0"
-            );
-
-            // Start listening for peers
-            unwrap!(el0.send(CoreMessage::new(move |core, _| {
-                let state = unwrap!(core.get_state(token_0));
-                let mut inner = state.borrow_mut();
-                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>()).set_listen(true);
-            })));
-        }
+        let token_0 = Token(SERVICE_DISCOVERY_TOKEN);
+        unwrap!(
+            el0.send(CoreMessage::new(move |core, poll| {
+                unwrap!(
+                    ServiceDiscovery::start(
+                        core,
+                        poll,
+                        listeners_0_clone,
+                        token_0,
+                        port,
+                        port,
+                        service0_pk,
+                        name_hash,
+                    ),
+                    "Could not spawn ServiceDiscovery_0"
+                );
+            })),
+            "Could not send to el0"
+        );
+
+        // Start listening for peers
+        unwrap!(el0.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token_0));
+            let mut inner = state.borrow_mut();
+            unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>()).set_listen(true);
+        })));
 
         thread::sleep(Duration::from_millis(100));
+        (el0, listeners_0)
+    }
 
-        // Poll-1
+    /// Starts another service discovery which seeks peers on the given port and returns its event
+    /// loop and where the responses arrive.
+    fn seek_peers(
+        port: u16,
+        name_hash: NameHash,
+    ) -> (EventLoop<()>, mpsc::Receiver<Vec<PeerInfo>>) {
         let el1 = unwrap!(
             common::spawn_event_loop(SERVICE_DISCOVERY_TOKEN + 1, Some("EL1"), || ()),
             "Could not run el1"
@@ -258,53 +298,46 @@ mod tests {
 
         let (tx, rx) = mpsc::channel();
 
-        // ServiceDiscovery-1
-        {
-            let listeners_1 = Arc::new(Mutex::new(vec![]));
-            let token_1 = Token(SERVICE_DISCOVERY_TOKEN);
-            let (our_pk, _our_sk) = gen_encrypt_keypair();
-            unwrap!(
-                el1.send(CoreMessage::new(move |core, poll| {
-                    unwrap!(
-                        ServiceDiscovery::start(
-                            core,
-                            poll,
-                            listeners_1,
-                            token_1,
-                            0,
-                            65_530,
-                            our_pk
-                        ),
-                        "Could not spawn ServiceDiscovery_1"
-                    );
-                })),
-                "Could not send to el1"
-            );
-
-            // Register observer
-            unwrap!(el1.send(CoreMessage::new(move |core, _| {
+        let listeners_1 = Arc::new(Mutex::new(vec![]));
+        let token_1 = Token(SERVICE_DISCOVERY_TOKEN);
+        let (our_pk, _our_sk) = gen_encrypt_keypair();
+        unwrap!(
+            el1.send(CoreMessage::new(move |core, poll| {
+                unwrap!(
+                    ServiceDiscovery::start(
+                        core,
+                        poll,
+                        listeners_1,
+                        token_1,
+                        0,
+                        port,
+                        our_pk,
+                        name_hash,
+                    ),
+                    "Could not spawn ServiceDiscovery_1"
+                );
+            })),
+            "Could not send to el1"
+        );
+
+        // Register observer
+        unwrap!(el1.send(CoreMessage::new(move |core, _| {
+            let state = unwrap!(core.get_state(token_1));
+            let mut inner = state.borrow_mut();
+            unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>()).register_observer(tx);
+        })));
+
+        // Seek peers
+        unwrap!(
+            el1.send(CoreMessage::new(move |core, _| {
                 let state = unwrap!(core.get_state(token_1));
                 let mut inner = state.borrow_mut();
-                unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>())
-                    .register_observer(tx);
-            })));
-
-            // Seek peers
-            unwrap!(
-                el1.send(CoreMessage::new(move |core, _| {
-                    let state = unwrap!(core.get_state(token_1));
-                    let mut inner = state.borrow_mut();
-                    let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>());
-                    unwrap!(sd.seek_peers());
-                })),
-                "Could not send to el1"
-            );
-        }
-
-        let peer_listeners = unwrap!(rx.recv_timeout(Duration::from_secs(30)));
-        assert_eq!(
-            peer_listeners.into_iter().collect::<Vec<_>>(),
-            *unwrap!(listeners_0.lock())
+                let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>());
+                unwrap!(sd.seek_peers());
+            })),
+            "Could not send to el1"
         );
+
+        (el1, rx)
     }
 }
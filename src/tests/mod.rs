@@ -12,8 +12,10 @@ pub mod utils;
 
 pub use self::utils::{gen_config, get_event_sender, timebomb, UniqueId};
 
-use crate::common::{CrustUser, PeerInfo};
-use crate::main::{self, Config, Event};
+use crate::common::{CrustUser, DisconnectReason, PeerInfo};
+use crate::main::{self, Config, CrustError, Event, EvictionPolicy, LostPeerReason, PeerSetChange};
+use bytes::Bytes;
+use futures::Stream;
 use mio;
 use rand;
 use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey};
@@ -23,7 +25,7 @@ use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::sync::mpsc::{self, Receiver};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 type Service = main::Service<UniqueId>;
 
@@ -47,6 +49,42 @@ fn test_service() -> (Service, Receiver<Event<UniqueId>>) {
     (service, event_rx)
 }
 
+/// Starts a listening service with `config0` and bootstraps a client service with `config1` off
+/// it. Both bootstrap events are consumed; the returned id is the one the client got for the
+/// listener.
+fn bootstrap_pair(
+    config0: Config,
+    mut config1: Config,
+) -> (
+    Service,
+    Receiver<Event<UniqueId>>,
+    Service,
+    Receiver<Event<UniqueId>>,
+    UniqueId,
+) {
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+    expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        assert_eq!(peer_id, service1.id())
+    });
+
+    (service0, event_rx0, service1, event_rx1, peer_id0)
+}
+
 mod connect {
     use super::*;
 
@@ -132,73 +170,1016 @@ mod connect {
         let (mut service1, event_rx1) = test_service();
         let (service2, event_rx2) = test_service();
 
-        unwrap!(service1.start_listening_tcp());
-        expect_event!(event_rx1, Event::ListenerStarted(_port) => ());
-        unwrap!(service1.set_ext_reachability_test(true));
-        let uid1 = service1.id();
+        unwrap!(service1.start_listening_tcp());
+        expect_event!(event_rx1, Event::ListenerStarted(_port) => ());
+        unwrap!(service1.set_ext_reachability_test(true));
+        let uid1 = service1.id();
+
+        let (ci_tx1, ci_rx1) = mpsc::channel();
+
+        let token = rand::random();
+        service1.prepare_connection_info(token);
+        let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            assert_eq!(res.result_token, token);
+            unwrap!(res.result)
+        });
+        unwrap!(ci_tx1.send(ci1.to_pub_connection_info()));
+
+        let token = rand::random();
+        service2.prepare_connection_info(token);
+        let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
+            assert_eq!(res.result_token, token);
+            unwrap!(res.result)
+        });
+        let pub_ci1 = unwrap!(ci_rx1.recv());
+
+        unwrap!(service2.connect(ci2, pub_ci1));
+        expect_event!(event_rx2, Event::ConnectFailure(id) => {
+            assert_eq!(id, uid1);
+        });
+    }
+}
+
+#[test]
+fn bootstrap_two_services_and_exchange_messages() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+
+    let peer_id1 = expect_event!(event_rx0,
+                                 Event::BootstrapAccept(peer_id, CrustUser::Client) => peer_id);
+    assert_eq!(peer_id1, service1.id());
+
+    let message0 = Bytes::from_static(b"hello from 0");
+    let _ = unwrap!(service0.send(&peer_id1, message0.clone(), 0));
+
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, message0);
+    });
+
+    let message1 = Bytes::from_static(b"hello from 1");
+    let _ = unwrap!(service1.send(&peer_id0, message1.clone(), 0));
+
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, message1);
+    });
+}
+
+#[test]
+fn service_with_given_keys_is_reachable_by_its_public_key() {
+    let (pk0, sk0) = gen_encrypt_keypair();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_keys(
+        event_tx0,
+        gen_config(),
+        rand::random(),
+        pk0,
+        sk0
+    ));
+    assert_eq!(service0.pub_key(), pk0);
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, pk0)];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+}
+
+#[test]
+fn service_with_passphrase_keeps_its_keys_across_restarts() {
+    let mut config = gen_config();
+    config.key_file_name = Some(utils::bootstrap_cache_tmp_file().into());
+
+    let (event_tx, _event_rx) = get_event_sender();
+    let service = unwrap!(Service::with_passphrase(
+        event_tx,
+        config.clone(),
+        rand::random(),
+        "passphrase"
+    ));
+    let pk = service.pub_key();
+    drop(service);
+
+    let (event_tx, _event_rx) = get_event_sender();
+    let service = unwrap!(Service::with_passphrase(
+        event_tx,
+        config.clone(),
+        rand::random(),
+        "passphrase"
+    ));
+    assert_eq!(service.pub_key(), pk);
+
+    let (event_tx, _event_rx) = get_event_sender();
+    assert!(Service::with_passphrase(event_tx, config, rand::random(), "wrong").is_err());
+}
+
+#[test]
+fn typed_services_exchange_application_messages() {
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum AppMsg {
+        Hello(String),
+        Numbers(Vec<u32>),
+    }
+
+    let (service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+    let service0 = main::TypedService::<_, AppMsg>::new(service0);
+    let service1 = main::TypedService::<_, AppMsg>::new(service1);
+
+    let hello = AppMsg::Hello("hello from 0".to_owned());
+    let _ = unwrap!(service0.send_msg(&peer_id1, &hello, 0));
+    expect_event!(event_rx1, Event::NewMessage(_, _, data) => {
+        assert_eq!(unwrap!(service1.parse_msg(&data)), hello);
+    });
+
+    let numbers = AppMsg::Numbers(vec![1, 2, 3]);
+    let _ = unwrap!(service1.send_msg(&peer_id0, &numbers, 0));
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => {
+        assert_eq!(unwrap!(service0.parse_msg(&data)), numbers);
+    });
+    assert!(service0.parse_msg(b"garbage").is_err());
+}
+
+#[test]
+fn send_with_ack_reports_delivery() {
+    let (_service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    let message = b"hello from 1".to_vec();
+    let _ = unwrap!(service1.send_with_ack(&peer_id0, message.clone(), 0, 7));
+
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, message);
+    });
+    expect_event!(event_rx1, Event::MessageDelivered(peer_id, msg_id) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(msg_id, 7);
+    });
+}
+
+#[test]
+fn request_gets_response_with_same_id() {
+    let (service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    unwrap!(service1.request(&peer_id0, b"question".to_vec(), 0, 3));
+
+    let request_id = expect_event!(event_rx0, Event::NewRequest(peer_id, request_id, data) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, b"question".to_vec());
+        request_id
+    });
+    unwrap!(service0.respond(&peer_id1, request_id, b"answer".to_vec(), 0));
+
+    expect_event!(event_rx1, Event::NewResponse(peer_id, request_id, data) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(request_id, 3);
+        assert_eq!(data, b"answer".to_vec());
+    });
+}
+
+#[test]
+fn peer_stats_count_data_messages() {
+    let (service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    let message = Bytes::from_static(b"hello from 1");
+    let _ = unwrap!(service1.send(&peer_id0, message.clone(), 0));
+    expect_event!(event_rx0, Event::NewMessage(_peer_id, CrustUser::Client, _data));
+
+    let stats1 = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats1.msgs_sent, 1);
+    assert_eq!(stats1.bytes_sent, message.len() as u64);
+
+    let stats0 = unwrap!(service0.peer_stats(&peer_id1));
+    assert_eq!(stats0.msgs_received, 1);
+    assert_eq!(stats0.bytes_received, message.len() as u64);
+    assert_eq!(stats0.msgs_sent, 0);
+}
+
+#[test]
+fn data_over_compression_threshold_is_compressed() {
+    let mut config0 = gen_config();
+    config0.compression_threshold = Some(100);
+    let mut config1 = gen_config();
+    config1.compression_threshold = Some(100);
+    let (service0, event_rx0, service1, _event_rx1, peer_id0) = bootstrap_pair(config0, config1);
+    let peer_id1 = service1.id();
+
+    for message in vec![Bytes::from_static(b"short"), Bytes::from(vec![7; 10_000])] {
+        let _ = unwrap!(service1.send(&peer_id0, message.clone(), 0));
+        expect_event!(event_rx0, Event::NewMessage(_peer_id, CrustUser::Client, data) => {
+            assert_eq!(data, message);
+        });
+    }
+
+    let stats1 = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats1.bytes_sent, 10_005);
+    assert_eq!(stats1.bytes_compressed, 10_000);
+    assert!(unwrap!(stats1.compression_ratio()) < 0.1);
+    assert_eq!(
+        unwrap!(service0.peer_stats(&peer_id1)).bytes_received,
+        10_005
+    );
+}
+
+#[test]
+fn data_is_not_compressed_for_peers_without_compression() {
+    let mut config1 = gen_config();
+    config1.compression_threshold = Some(100);
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), config1);
+
+    let message = Bytes::from(vec![7; 10_000]);
+    let _ = unwrap!(service1.send(&peer_id0, message.clone(), 0));
+    expect_event!(event_rx0, Event::NewMessage(_peer_id, CrustUser::Client, data) => {
+        assert_eq!(data, message);
+    });
+    assert_eq!(
+        unwrap!(service1.peer_stats(&peer_id0)).compression_ratio(),
+        None
+    );
+}
+
+#[test]
+fn messages_over_send_queue_limit_are_returned() {
+    let mut config1 = gen_config();
+    config1.send_queue_limit = Some(1);
+    let (_service0, _event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), config1);
+
+    // The first message uses up the allowance, the second one is queued.
+    unwrap!(service1.set_rate_limit(&peer_id0, Some(1)));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"first"), 0));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"second"), 0));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"third"), 0));
+
+    expect_event!(event_rx1, Event::WriteBlocked(peer_id, data) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(data, &b"third"[..]);
+    });
+}
+
+#[test]
+fn upload_budget_holds_back_data() {
+    let mut config1 = gen_config();
+    config1.upload_bytes_per_sec = Some(1000);
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), config1);
+
+    // The first two messages use up the allowance, the third one waits for it to recover.
+    let start = Instant::now();
+    for _ in 0..3 {
+        let _ = unwrap!(service1.send(&peer_id0, Bytes::from(vec![0; 600]), 0));
+    }
+    for _ in 0..3 {
+        expect_event!(event_rx0, Event::NewMessage(..));
+    }
+    assert!(start.elapsed() >= Duration::from_millis(150));
+}
+
+#[test]
+fn peer_over_inbound_limit_is_throttled() {
+    let mut config0 = gen_config();
+    config0.inbound_msgs_per_sec = Some(1);
+
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(config0, gen_config());
+    let peer_id1 = service1.id();
+
+    for msg in &[b"first", b"secnd", b"third"] {
+        let _ = unwrap!(service1.send(&peer_id0, Bytes::from(&msg[..]), 0));
+    }
+
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => assert_eq!(data, &b"first"[..]));
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => assert_eq!(data, &b"secnd"[..]));
+    expect_event!(event_rx0, Event::PeerThrottled(peer_id) => assert_eq!(peer_id, peer_id1));
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => assert_eq!(data, &b"third"[..]));
+}
+
+#[test]
+fn idlest_peer_is_evicted_at_connection_limit() {
+    let mut config0 = gen_config();
+    config0.max_connections = Some(1);
+    config0.eviction_policy = EvictionPolicy::LeastRecentlyActive;
+
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let bootstrap_client = || {
+        let mut config = gen_config();
+        config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+        expect_event!(event_rx, Event::BootstrapConnect(..));
+        (service, event_rx)
+    };
+
+    let (service1, _event_rx1) = bootstrap_client();
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+
+    let (service2, _event_rx2) = bootstrap_client();
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::ConnectionLimit) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => {
+        assert_eq!(peer_id, service2.id());
+    });
+    assert!(!service0.is_connected(&service1.id()));
+}
+
+#[test]
+fn blacklisted_peer_is_dropped_and_refused() {
+    let (service0, event_rx0, mut service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    service0.blacklist_peer(&peer_id1, Duration::from_secs(60));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::Evicted) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx1, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(reason, LostPeerReason::DroppedByPeer(DisconnectReason::Blacklisted));
+    });
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed);
+}
+
+#[test]
+fn connected_peers_are_listed() {
+    let (service, _event_rx) = test_service();
+    assert!(unwrap!(service.connected_peers()).is_empty());
+
+    let (service0, _event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+    let port0 = service0.addresses()[0].port();
+
+    let peers0 = unwrap!(service0.connected_peers());
+    assert_eq!(peers0.len(), 1);
+    assert_eq!(peers0[0].id, peer_id1);
+    assert_eq!(peers0[0].kind, CrustUser::Client);
+    assert!(peers0[0].addr.ip().is_loopback());
+
+    let peers1 = unwrap!(service1.connected_peers());
+    assert_eq!(peers1.len(), 1);
+    assert_eq!(peers1[0].id, peer_id0);
+    assert_eq!(peers1[0].kind, CrustUser::Node);
+    assert_eq!(peers1[0].addr.port(), port0);
+}
+
+#[test]
+fn events_can_be_streamed() {
+    let (mut service, event_rx) = test_service();
+    let mut events = service.event_stream().wait();
+    unwrap!(service.start_listening_tcp());
+
+    match unwrap!(unwrap!(events.next())) {
+        Event::ListenerStarted(port) => assert_eq!(service.addresses()[0].port(), port),
+        event => panic!("Unexpected event: {:?}", event),
+    }
+    assert!(event_rx.try_recv().is_err());
+}
+
+#[test]
+fn peer_set_changes_are_streamed() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let mut changes0 = service0.peer_set_changes().wait();
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    let (event_tx1, _event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    let peer_id1 = service1.id();
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    assert_eq!(
+        unwrap!(unwrap!(changes0.next())),
+        PeerSetChange::Added(peer_id1, CrustUser::Client)
+    );
+    assert!(service0.disconnect(&peer_id1));
+    assert_eq!(
+        unwrap!(unwrap!(changes0.next())),
+        PeerSetChange::Removed(peer_id1, LostPeerReason::Evicted)
+    );
+}
+
+#[test]
+fn application_data_can_be_attached_to_peers() {
+    let (_service0, _event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+
+    assert_eq!(unwrap!(service1.peer_data::<String>(&peer_id0)), None);
+    unwrap!(service1.set_peer_data(&peer_id0, "section 7".to_owned()));
+    assert_eq!(
+        unwrap!(service1.peer_data::<String>(&peer_id0)),
+        Some("section 7".to_owned())
+    );
+    assert_eq!(unwrap!(service1.peer_data::<u32>(&peer_id0)), None);
+
+    assert!(service1.disconnect(&peer_id0));
+    match service1.peer_data::<String>(&peer_id0) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn broadcast_reaches_filtered_peers() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config.clone(),
+        rand::random()
+    ));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        peer_id
+    });
+
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapConnect(_peer_id, _));
+    expect_event!(
+        event_rx0,
+        Event::BootstrapAccept(_peer_id, CrustUser::Client)
+    );
+
+    let message = Bytes::from_static(b"hello everyone");
+    assert_eq!(unwrap!(service0.broadcast(message.clone(), 0, |_| true)), 2);
+    expect_event!(event_rx1, Event::NewMessage(_, _, data) => assert_eq!(data, message));
+    expect_event!(event_rx2, Event::NewMessage(_, _, data) => assert_eq!(data, message));
+
+    let message = Bytes::from_static(b"hello 1");
+    let sent_to = unwrap!(service0.broadcast(message.clone(), 0, |id| *id == peer_id1));
+    assert_eq!(sent_to, 1);
+    expect_event!(event_rx1, Event::NewMessage(_, _, data) => assert_eq!(data, message));
+    thread::sleep(Duration::from_millis(100));
+    assert!(event_rx2.try_recv().is_err());
+}
+
+#[test]
+fn send_to_group_reaches_tagged_peers_only() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config.clone(),
+        rand::random()
+    ));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        peer_id
+    });
+
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapConnect(_peer_id, _));
+    expect_event!(
+        event_rx0,
+        Event::BootstrapAccept(_peer_id, CrustUser::Client)
+    );
+
+    unwrap!(service0.add_peer_to_group(&peer_id1, "section"));
+    let message = Bytes::from_static(b"hello section");
+    assert_eq!(
+        unwrap!(service0.send_to_group("section", message.clone(), 0)),
+        1
+    );
+    expect_event!(event_rx1, Event::NewMessage(_, _, data) => assert_eq!(data, message));
+    assert_eq!(unwrap!(service0.send_to_group("other", message, 0)), 0);
+
+    unwrap!(service0.remove_peer_from_group(&peer_id1, "section"));
+    assert_eq!(
+        unwrap!(service0.send_to_group("section", Bytes::from(vec![1]), 0)),
+        0
+    );
+    thread::sleep(Duration::from_millis(100));
+    assert!(event_rx1.try_recv().is_err());
+    assert!(event_rx2.try_recv().is_err());
+}
+
+#[test]
+fn drain_closes_all_connections() {
+    let (mut service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    let message = Bytes::from_static(b"last words");
+    let _ = unwrap!(service0.send(&peer_id1, message.clone(), 0));
+    assert!(unwrap!(service0.drain(Duration::from_secs(5))));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::Evicted) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+
+    expect_event!(event_rx1, Event::NewMessage(_, _, data) => assert_eq!(data, message));
+    expect_event!(event_rx1, Event::LostPeer(peer_id, LostPeerReason::RemoteClosed) => {
+        assert_eq!(peer_id, peer_id0);
+    });
+    assert!(!service0.is_connected(&peer_id1));
+}
+
+#[test]
+fn peers_removed_from_whitelist_are_dropped() {
+    let (service0, event_rx0, mut service1, event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+
+    use std::net::IpAddr;
+
+    // Only the node whitelist is set, so the client is kept.
+    let whitelist: HashSet<_> = vec![unwrap!(IpAddr::from_str("1.2.3.4"))]
+        .into_iter()
+        .collect();
+    unwrap!(service0.set_whitelist(Some(whitelist.clone()), None));
+    thread::sleep(Duration::from_millis(100));
+    assert!(service0.is_connected(&peer_id1));
+
+    unwrap!(service0.set_whitelist(None, Some(whitelist)));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::NotWhitelisted) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx1, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(reason, LostPeerReason::DroppedByPeer(DisconnectReason::NotWhitelisted));
+    });
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed);
+}
+
+#[test]
+fn runtime_whitelist_outlasts_config_reloads() {
+    use std::net::IpAddr;
+
+    let config0 = gen_config();
+    let (service0, event_rx0, mut service1, event_rx1, peer_id0) =
+        bootstrap_pair(config0.clone(), gen_config());
+    let peer_id1 = service1.id();
+
+    let whitelist: HashSet<_> = vec![unwrap!(IpAddr::from_str("1.2.3.4"))]
+        .into_iter()
+        .collect();
+    unwrap!(service0.set_whitelist(None, Some(whitelist)));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::NotWhitelisted) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx1, Event::LostPeer(peer_id, _) => assert_eq!(peer_id, peer_id0));
+
+    let mut new_config = config0;
+    new_config.whitelisted_client_ips = Some(
+        vec![unwrap!(IpAddr::from_str("127.0.0.1"))]
+            .into_iter()
+            .collect(),
+    );
+    unwrap!(service0.reload_config_from(new_config));
+    expect_event!(event_rx0, Event::ConfigReloaded { applied, .. } => {
+        assert_eq!(applied, vec!["whitelisted_client_ips"]);
+    });
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed);
+
+    // The config's whitelist allows us again.
+    unwrap!(service0.clear_whitelist());
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => {
+        assert_eq!(peer_id, peer_id0);
+    });
+}
+
+#[test]
+fn reloaded_config_is_applied_and_reported() {
+    use std::net::IpAddr;
+
+    let config0 = gen_config();
+    let (service0, event_rx0, service1, _event_rx1, _) =
+        bootstrap_pair(config0.clone(), gen_config());
+    let peer_id1 = service1.id();
+    let port0 = service0.addresses()[0].port();
+
+    // Unchanged configs aren't reported and invalid ones aren't taken over.
+    unwrap!(service0.reload_config_from(config0.clone()));
+    let mut invalid_config = config0.clone();
+    invalid_config.max_msg_size = Some(0);
+    assert!(service0.reload_config_from(invalid_config).is_err());
+    thread::sleep(Duration::from_millis(100));
+    assert!(event_rx0.try_recv().is_err());
+
+    let mut new_config = config0;
+    new_config.whitelisted_client_ips = Some(
+        vec![unwrap!(IpAddr::from_str("1.2.3.4"))]
+            .into_iter()
+            .collect(),
+    );
+    new_config.tcp_acceptor_port = Some(port0);
+    unwrap!(service0.reload_config_from(new_config));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::NotWhitelisted) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx0, Event::ConfigReloaded { applied, requires_restart } => {
+        assert_eq!(applied, vec!["whitelisted_client_ips"]);
+        assert_eq!(requires_restart, vec!["tcp_acceptor_port"]);
+    });
+}
+
+#[test]
+fn bootstrap_filter_can_reject_peers() {
+    use crate::common::BootstrapDenyReason;
+    use crate::main::{BootstrapAdmission, BootstrapError};
+
+    let config0 = gen_config();
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    service0.set_bootstrap_filter(|_, _, kind| match kind {
+        CrustUser::Client => BootstrapAdmission::Reject(BootstrapDenyReason::TooManyConnections),
+        CrustUser::Node => BootstrapAdmission::Accept,
+    });
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.report_bootstrap_progress = true;
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapProgress { tried: 0, .. });
+    expect_event!(event_rx1, Event::BootstrapProgress { last_error, .. } => {
+        assert_eq!(
+            last_error,
+            Some(BootstrapError::Denied(BootstrapDenyReason::TooManyConnections))
+        );
+    });
+    expect_event!(event_rx1, Event::BootstrapFailed);
+
+    service0.remove_bootstrap_filter();
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapProgress { tried: 0, .. });
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    expect_event!(
+        event_rx0,
+        Event::BootstrapAccept(_peer_id, CrustUser::Client)
+    );
+}
+
+#[test]
+fn bootstrap_with_proof_of_work_challenge() {
+    let mut config0 = gen_config();
+    config0.bootstrap_pow_difficulty = Some(8);
+    let (_service0, _event_rx0, _service1, _event_rx1, _) = bootstrap_pair(config0, gen_config());
+}
+
+#[test]
+fn peer_exchange_fills_bootstrap_cache() {
+    use crate::main::BootstrapCache;
+
+    let shared_contact = localhost_contact_info(1234, gen_encrypt_keypair().0);
+    let mut config0 = gen_config();
+    config0.peer_exchange = true;
+    {
+        let cache = BootstrapCache::new(config0.bootstrap_cache_name.clone());
+        cache.put(shared_contact);
+        unwrap!(cache.commit());
+    }
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.peer_exchange = true;
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+
+    thread::sleep(Duration::from_millis(100));
+    let cached_peers = unwrap!(service1.bootstrap_cached_peers());
+    assert!(cached_peers.contains(&shared_contact));
+}
+
+#[test]
+fn lost_node_connection_triggers_rebootstrap() {
+    let (service0, event_rx0, mut service1, event_rx1, _) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
+    unwrap!(service1.enable_auto_rebootstrap(
+        HashSet::new(),
+        CrustUser::Client,
+        Default::default()
+    ));
+    thread::sleep(Duration::from_secs(1));
+
+    assert!(service0.disconnect(&peer_id1));
+    expect_event!(event_rx1, Event::LostPeer(..));
+    expect_event!(event_rx1, Event::RebootstrapStarted);
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    expect_event!(event_rx1, Event::RebootstrapSucceeded);
+    expect_event!(event_rx0, Event::BootstrapAccept(..));
+}
+
+#[test]
+fn peer_below_score_threshold_is_banned() {
+    let mut config0 = gen_config();
+    config0.peer_scoring.ban_threshold = Some(-50);
+
+    let (service0, event_rx0, mut service1, event_rx1, peer_id0) =
+        bootstrap_pair(config0, gen_config());
+    let peer_id1 = service1.id();
+
+    assert_eq!(unwrap!(service0.peer_score(&peer_id1)), 0);
+    unwrap!(service0.adjust_score(&peer_id1, -30));
+    assert_eq!(unwrap!(service0.peer_score(&peer_id1)), -30);
+
+    unwrap!(service0.adjust_score(&peer_id1, -20));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::LowScore) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+    expect_event!(event_rx1, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(reason, LostPeerReason::DroppedByPeer(DisconnectReason::LowScore));
+    });
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed);
+}
+
+#[test]
+fn queued_messages_can_be_cancelled() {
+    let (_service0, _event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+
+    // The first message uses up the allowance, the second one is queued.
+    unwrap!(service1.set_rate_limit(&peer_id0, Some(1)));
+    let first = unwrap!(service1.send_with_ack(&peer_id0, b"first".to_vec(), 0, 1));
+    let second = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"second"), 0));
+
+    assert!(!unwrap!(service1.cancel_send(&peer_id0, first)));
+    assert!(unwrap!(service1.cancel_send(&peer_id0, second)));
+    assert!(!unwrap!(service1.cancel_send(&peer_id0, second)));
+
+    let stats = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats.msgs_sent, 1);
+    assert_eq!(stats.queued_msgs, 0);
+    assert_eq!(stats.cancelled_msgs, 1);
+}
+
+#[test]
+fn messages_held_back_without_rate_limit_can_be_cancelled() {
+    let mut config1 = gen_config();
+    config1.upload_bytes_per_sec = Some(1000);
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), config1);
+
+    // Only the upload budget holds the third message back.
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from(vec![0; 600]), 0));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from(vec![1; 600]), 0));
+    let third = unwrap!(service1.send(&peer_id0, Bytes::from(vec![2; 600]), 0));
+    assert!(unwrap!(service1.cancel_send(&peer_id0, third)));
+
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => assert_eq!(data[0], 0));
+    expect_event!(event_rx0, Event::NewMessage(_, _, data) => assert_eq!(data[0], 1));
+    thread::sleep(Duration::from_secs(1));
+    assert!(event_rx0.try_recv().is_err());
+
+    let stats = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats.msgs_sent, 2);
+    assert_eq!(stats.cancelled_msgs, 1);
+}
+
+#[test]
+fn streamed_data_arrives_in_order_and_waits_for_the_reader() {
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+
+    let items: Vec<_> = (0..100u8).map(|i| Bytes::from(vec![i; 1000])).collect();
+    let _ = unwrap!(service1.send_stream(&peer_id0, items.clone(), 0));
+
+    let mut stream = expect_event!(event_rx0, Event::NewStream(peer_id, stream) => {
+        assert_eq!(peer_id, service1.id());
+        stream
+    });
+    // While nobody reads, no more than the receiver's window of chunks is sent.
+    thread::sleep(Duration::from_millis(500));
+    assert!(unwrap!(service1.peer_stats(&peer_id0)).msgs_sent <= 8);
+
+    let received: Vec<_> = stream.by_ref().collect();
+    assert_eq!(received, items);
+    assert!(stream.is_complete());
+}
 
-        let (ci_tx1, ci_rx1) = mpsc::channel();
+#[test]
+fn cancelled_stream_ends_incomplete() {
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
 
-        let token = rand::random();
-        service1.prepare_connection_info(token);
-        let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
-            assert_eq!(res.result_token, token);
-            unwrap!(res.result)
-        });
-        unwrap!(ci_tx1.send(ci1.to_pub_connection_info()));
+    let items = (0..).map(|_| Bytes::from(vec![0; 1000]));
+    let send_token = unwrap!(service1.send_stream(&peer_id0, items, 0));
+    let mut stream = expect_event!(event_rx0, Event::NewStream(_peer_id, stream) => stream);
+    assert!(unwrap!(service1.cancel_send(&peer_id0, send_token)));
 
-        let token = rand::random();
-        service2.prepare_connection_info(token);
-        let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
-            assert_eq!(res.result_token, token);
-            unwrap!(res.result)
-        });
-        let pub_ci1 = unwrap!(ci_rx1.recv());
+    assert!(stream.by_ref().count() <= 8);
+    assert!(!stream.is_complete());
+}
 
-        unwrap!(service2.connect(ci2, pub_ci1));
-        expect_event!(event_rx2, Event::ConnectFailure(id) => {
-            assert_eq!(id, uid1);
+#[test]
+fn channels_keep_their_order_and_queue_separately() {
+    let mut config1 = gen_config();
+    config1.channel_queue_limit = Some(2);
+    let (_service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), config1);
+
+    let (control, bulk) = (1, 2);
+    unwrap!(service1.open_channel(&peer_id0, control, 0));
+    unwrap!(service1.open_channel(&peer_id0, bulk, 5));
+    match service1.send_on_channel(&peer_id0, 3, Bytes::from_static(b"nowhere")) {
+        Err(CrustError::ChannelNotOpen(3)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    // The first message uses up the allowance. Of the others, two wait in the send queue and
+    // two in the channel's queue, which is full then.
+    unwrap!(service1.set_rate_limit(&peer_id0, Some(1)));
+    for i in 0..5 {
+        let _ = unwrap!(service1.send_on_channel(&peer_id0, bulk, Bytes::from(vec![i])));
+    }
+    match service1.send_on_channel(&peer_id0, bulk, Bytes::from(vec![5])) {
+        Err(CrustError::ChannelFull(2)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    // Other channels still take messages.
+    let _ = unwrap!(service1.send_on_channel(&peer_id0, control, Bytes::from_static(b"stop")));
+    assert_eq!(unwrap!(service1.peer_stats(&peer_id0)).queued_msgs, 5);
+
+    unwrap!(service1.set_rate_limit(&peer_id0, None));
+    let mut bulk_received = Vec::new();
+    for _ in 0..6 {
+        expect_event!(event_rx0, Event::NewChannelMessage(_peer_id, channel, data) => {
+            if channel == bulk {
+                bulk_received.push(data[0]);
+            } else {
+                assert_eq!(channel, control);
+                assert_eq!(&data[..], b"stop");
+            }
         });
     }
+    assert_eq!(bulk_received, vec![0, 1, 2, 3, 4]);
 }
 
 #[test]
-fn bootstrap_two_services_and_exchange_messages() {
-    let (mut service0, event_rx0) = test_service();
-    unwrap!(service0.start_listening_tcp());
+fn peer_sending_message_over_size_limit_is_dropped() {
+    let mut config0 = gen_config();
+    config0.max_msg_size = Some(4);
 
-    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
-    unwrap!(service0.set_accept_bootstrap(true));
+    let (service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(config0, gen_config());
+    let peer_id1 = service1.id();
 
-    let mut config1 = gen_config();
-    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    match service0.send(&peer_id1, Bytes::from_static(b"too long"), 0) {
+        Err(CrustError::MessageTooLarge(8, 4)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
 
-    let (event_tx1, event_rx1) = get_event_sender();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"too long"), 0));
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::MessageTooLarge) => {
+        assert_eq!(peer_id, peer_id1);
+    });
+}
 
-    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+#[test]
+fn paused_peer_is_kept_and_read_after_resume() {
+    use crate::main::INACTIVITY_TIMEOUT_MS;
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
-    assert_eq!(peer_id0, service0.id());
+    let (service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id1 = service1.id();
 
-    let peer_id1 = expect_event!(event_rx0,
-                                 Event::BootstrapAccept(peer_id, CrustUser::Client) => peer_id);
-    assert_eq!(peer_id1, service1.id());
+    unwrap!(service0.pause_recv(&peer_id1));
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"hello"), 0));
+
+    // Longer than the inactivity timeout.
+    thread::sleep(Duration::from_millis(2 * INACTIVITY_TIMEOUT_MS));
+    assert!(event_rx0.try_recv().is_err());
+    assert!(service0.is_connected(&peer_id1));
 
-    let message0 = b"hello from 0".to_vec();
-    unwrap!(service0.send(&peer_id1, message0.clone(), 0));
+    unwrap!(service0.resume_recv(&peer_id1));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, &b"hello"[..]);
+    });
+}
 
+#[test]
+fn session_is_resumed_after_its_socket_broke() {
+    let mut config = gen_config();
+    config.session_resume_timeout_ms = Some(5_000);
+    let (service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(config.clone(), config);
+    let peer_id1 = service1.id();
+
+    unwrap!(service1.break_socket(&peer_id0));
+    // Waits in the queue until the session is resumed.
+    let _ = unwrap!(service1.send(&peer_id0, Bytes::from_static(b"still here"), 0));
+    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+        assert_eq!(peer_id, peer_id1);
+        assert_eq!(data, &b"still here"[..]);
+    });
+    let _ = unwrap!(service0.send(&peer_id1, Bytes::from_static(b"so am I"), 0));
     expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
         assert_eq!(peer_id, peer_id0);
-        assert_eq!(data, message0);
+        assert_eq!(data, &b"so am I"[..]);
     });
 
-    let message1 = b"hello from 1".to_vec();
-    unwrap!(service1.send(&peer_id0, message1.clone(), 0));
+    thread::sleep(Duration::from_millis(100));
+    assert!(event_rx0.try_recv().is_err());
+    assert!(event_rx1.try_recv().is_err());
+}
 
-    expect_event!(event_rx0, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+#[test]
+fn peer_is_lost_if_its_session_is_not_resumed_in_time() {
+    use std::io::ErrorKind;
+
+    let mut config = gen_config();
+    config.session_resume_timeout_ms = Some(500);
+    let (mut service0, event_rx0, service1, event_rx1, peer_id0) =
+        bootstrap_pair(config.clone(), config);
+    let peer_id1 = service1.id();
+
+    // Leaves nothing to dial.
+    unwrap!(service0.stop_listening());
+    unwrap!(service1.break_socket(&peer_id0));
+    expect_event!(event_rx1, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id0);
+        assert_eq!(reason, LostPeerReason::IoError(ErrorKind::ConnectionReset));
+    });
+    expect_event!(event_rx0, Event::LostPeer(peer_id, LostPeerReason::RemoteClosed) => {
         assert_eq!(peer_id, peer_id1);
-        assert_eq!(data, message1);
     });
 }
 
@@ -397,6 +1378,375 @@ fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
     expect_event!(event_rx, Event::BootstrapFailed);
 }
 
+#[test]
+fn bootstrap_can_be_cancelled() {
+    use std::net::TcpListener;
+
+    let deaf_listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+    let (pk, _sk) = gen_encrypt_keypair();
+    let address = PeerInfo::new(unwrap!(deaf_listener.local_addr()), pk);
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![address];
+
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+
+    let handle = unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let other_handle = main::BootstrapHandle::unique();
+    assert!(!unwrap!(service.cancel_bootstrap(other_handle)));
+    assert!(unwrap!(service.cancel_bootstrap(handle)));
+    expect_event!(event_rx, Event::BootstrapCancelled);
+    assert!(!unwrap!(service.cancel_bootstrap(handle)));
+}
+
+#[test]
+fn connect_to_addr_dials_known_listener() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let addr0 = localhost_contact_info(port0, service0.pub_key()).addr;
+
+    let (service1, event_rx1) = test_service();
+    unwrap!(service1.connect_to_addr(addr0, service0.pub_key(), CrustUser::Client));
+    expect_event!(event_rx1, Event::DirectConnectSuccess(peer_id, addr) => {
+        assert_eq!(peer_id, service0.id());
+        assert_eq!(addr, addr0);
+    });
+    expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        assert_eq!(peer_id, service1.id());
+    });
+
+    // The listener can't decrypt our request if we expect the wrong key.
+    let (service2, event_rx2) = test_service();
+    let (wrong_pk, _) = gen_encrypt_keypair();
+    unwrap!(service2.connect_to_addr(addr0, wrong_pk, CrustUser::Client));
+    expect_event!(event_rx2, Event::DirectConnectFailure(addr, _) => assert_eq!(addr, addr0));
+}
+
+#[test]
+fn connection_info_is_relayed_through_common_peer() {
+    let mut config0 = gen_config();
+    config0.relay_connection_info = true;
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config.clone(),
+        rand::random()
+    ));
+    unwrap!(service1.start_listening_tcp());
+    expect_event!(event_rx1, Event::ListenerStarted(_port));
+    unwrap!(service1.set_ext_reachability_test(false));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
+
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapConnect(_peer_id, _));
+
+    let token = rand::random();
+    service1.prepare_connection_info(token);
+    let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    unwrap!(service1.send_connection_info_via(
+        &service0.id(),
+        service2.id(),
+        service2.pub_key(),
+        &ci1.to_pub_connection_info()
+    ));
+
+    let info = expect_event!(event_rx2, Event::ConnectionInfoRelayed(info) => info);
+    assert_eq!(info.from, service1.id());
+    assert_eq!(info.via, service0.id());
+    // Only the peer it was sealed for can open it, not the relay.
+    assert!(service0.open_relayed_connection_info(&info).is_err());
+    let pub_ci1 = unwrap!(service2.open_relayed_connection_info(&info));
+    assert_eq!(pub_ci1.id(), service1.id());
+
+    let token = rand::random();
+    service2.prepare_connection_info(token);
+    let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    unwrap!(service2.connect(ci2, pub_ci1));
+    expect_event!(event_rx2, Event::ConnectSuccess(id) => assert_eq!(id, service1.id()));
+}
+
+#[test]
+fn data_is_relayed_through_common_peer() {
+    let mut config0 = gen_config();
+    config0.relay_bytes_per_sec = Some(100_000);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let relay_id = service0.id();
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config.clone(),
+        rand::random()
+    ));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
+
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapConnect(_peer_id, _));
+
+    unwrap!(service1.connect_via(
+        &relay_id,
+        service2.id(),
+        service2.pub_key(),
+        CrustUser::Client
+    ));
+    unwrap!(service2.connect_via(
+        &relay_id,
+        service1.id(),
+        service1.pub_key(),
+        CrustUser::Client
+    ));
+
+    let peers2 = unwrap!(service2.connected_peers());
+    assert_eq!(peers2.len(), 2);
+    let relayed = unwrap!(peers2.iter().find(|peer| peer.id == service1.id()));
+    assert!(relayed.relayed);
+    assert_eq!(relayed.addr.port(), port0);
+    assert!(!unwrap!(peers2.iter().find(|peer| peer.id == relay_id)).relayed);
+
+    let message = Bytes::from_static(b"hello through the relay");
+    let _ = unwrap!(service1.send(&service2.id(), message.clone(), 0));
+    expect_event!(event_rx2, Event::NewMessage(peer_id, CrustUser::Client, data) => {
+        assert_eq!(peer_id, service1.id());
+        assert_eq!(data, message);
+    });
+
+    // Peers we reach through the relay are lost along with it.
+    drop(service0);
+    expect_event!(event_rx1, Event::LostPeer(peer_id, _) => assert_eq!(peer_id, relay_id));
+    expect_event!(event_rx1, Event::LostPeer(peer_id, LostPeerReason::RelayLost) => {
+        assert_eq!(peer_id, service2.id())
+    });
+    match service1.send(&service2.id(), message, 0) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn extra_listeners_are_started_and_advertised() {
+    let mut config0 = gen_config();
+    config0.extra_tcp_acceptor_ports = vec![0];
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+
+    let port_a = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    let port_b = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    assert_ne!(port_a, port_b);
+    let ports: HashSet<_> = service0
+        .addresses()
+        .iter()
+        .map(|addr| addr.port())
+        .collect();
+    assert!(ports.contains(&port_a));
+    assert!(ports.contains(&port_b));
+
+    // Settings apply to all listeners, so we can bootstrap off either.
+    unwrap!(service0.set_accept_bootstrap(true));
+    for &port in &[port_a, port_b] {
+        let mut config = gen_config();
+        config.hard_coded_contacts = vec![localhost_contact_info(port, service0.pub_key())];
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+        expect_event!(event_rx, Event::BootstrapConnect(_peer_id, addr) => {
+            assert_eq!(addr.port(), port)
+        });
+    }
+
+    unwrap!(service0.stop_tcp_listener());
+    thread::sleep(Duration::from_millis(100));
+    assert!(service0.addresses().is_empty());
+}
+
+#[test]
+fn configured_listeners_advertise_their_external_addr() {
+    use crate::main::ListenerConfig;
+    use std::net::IpAddr;
+
+    let external_addr = unwrap!(SocketAddr::from_str("1.2.3.4:5483"));
+    let mut config0 = gen_config();
+    config0.listeners = vec![ListenerConfig {
+        ip: Some(unwrap!(IpAddr::from_str("127.0.0.1"))),
+        external_addr: Some(external_addr),
+        ..ListenerConfig::tcp(0)
+    }];
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    assert_eq!(service0.addresses(), vec![external_addr]);
+
+    // The override survives pausing, as does the port.
+    unwrap!(service0.stop_listening());
+    assert!(service0.addresses().is_empty());
+    unwrap!(service0.start_listening());
+    expect_event!(event_rx0, Event::ListenerStarted(new_port) => assert_eq!(new_port, port));
+    assert_eq!(service0.addresses(), vec![external_addr]);
+
+    unwrap!(service0.set_accept_bootstrap(true));
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port, service0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
+}
+
+#[test]
+fn ipv6_listener_listens_on_its_own_address() {
+    use crate::main::ListenerConfig;
+    use std::net::{IpAddr, Ipv6Addr, TcpStream};
+
+    let mut config = gen_config();
+    config.listeners = vec![ListenerConfig {
+        ip: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ..ListenerConfig::tcp(0)
+    }];
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    unwrap!(service.start_listening_tcp());
+
+    let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+    assert_eq!(service.addresses(), vec![addr]);
+    let _ = unwrap!(TcpStream::connect(addr));
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn listener_picks_first_free_port_of_range() {
+    use crate::main::ListenerConfig;
+    use std::net::TcpListener;
+
+    let blocker = unwrap!(TcpListener::bind("0.0.0.0:0"));
+    let blocked_port = unwrap!(blocker.local_addr()).port();
+    let last_port = blocked_port.saturating_add(20);
+    let mut config0 = gen_config();
+    config0.listeners = vec![ListenerConfig {
+        port_range_end: Some(last_port),
+        ..ListenerConfig::tcp(blocked_port)
+    }];
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+
+    let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    assert!(port > blocked_port && port <= last_port);
+    assert!(service0.addresses().iter().any(|addr| addr.port() == port));
+}
+
+#[test]
+fn failed_listener_is_started_again() {
+    // Our listener can't share the port with a socket without `SO_REUSEPORT`.
+    let blocker = unwrap!(std::net::TcpListener::bind("0.0.0.0:0"));
+    let port = unwrap!(blocker.local_addr()).port();
+
+    let mut config = gen_config();
+    config.tcp_acceptor_port = Some(port);
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    unwrap!(service.start_listening_tcp());
+    expect_event!(event_rx, Event::ListenerFailed(addr, _error) => assert_eq!(addr.port(), port));
+
+    drop(blocker);
+    expect_event!(event_rx, Event::ListenerStarted(started_port) => {
+        assert_eq!(started_port, port)
+    });
+}
+
+#[test]
+fn stats_sum_up_connections_and_traffic() {
+    use crate::main::ListenerState;
+
+    let (mut service0, event_rx0, service1, _event_rx1, peer_id0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let port = service0.addresses()[0].port();
+
+    let message = Bytes::from(vec![1, 2, 3]);
+    let _ = unwrap!(service1.send(&peer_id0, message.clone(), 0));
+    expect_event!(event_rx0, Event::NewMessage(..));
+
+    let stats0 = unwrap!(service0.stats());
+    assert_eq!(stats0.client_connections, 1);
+    assert_eq!(stats0.node_connections, 0);
+    assert_eq!(stats0.bytes_received, message.len() as u64);
+    assert_eq!(stats0.tcp_listener, ListenerState::Listening(vec![port]));
+
+    let stats1 = unwrap!(service1.stats());
+    assert_eq!(stats1.node_connections, 1);
+    assert_eq!(stats1.bytes_sent, message.len() as u64);
+    assert_eq!(stats1.tcp_listener, ListenerState::Stopped);
+
+    unwrap!(service0.stop_listening());
+    let stats0 = unwrap!(service0.stats());
+    assert_eq!(stats0.tcp_listener, ListenerState::Paused);
+}
+
+#[test]
+fn listening_can_be_paused_without_losing_connections() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+    let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let bootstrap_off_service0 = |service0: &Service| {
+        let mut config = gen_config();
+        config.hard_coded_contacts = vec![localhost_contact_info(port, service0.pub_key())];
+        let (event_tx, event_rx) = get_event_sender();
+        let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+        unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+        (service, event_rx)
+    };
+
+    let (service1, event_rx1) = bootstrap_off_service0(&service0);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
+
+    unwrap!(service0.stop_listening());
+    assert!(service0.addresses().is_empty());
+    assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    let message = Bytes::from(vec![1, 2, 3]);
+    let _ = unwrap!(service1.send(&peer_id0, message.clone(), 0));
+    expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, data) => {
+        assert_eq!(data, message)
+    });
+
+    // Resumes on the same port, still accepting bootstrapping peers.
+    unwrap!(service0.start_listening());
+    expect_event!(event_rx0, Event::ListenerStarted(new_port) => assert_eq!(new_port, port));
+    let (_service2, event_rx2) = bootstrap_off_service0(&service0);
+    expect_event!(event_rx2, Event::BootstrapConnect(peer_id, _) => {
+        assert_eq!(peer_id, peer_id0)
+    });
+}
+
 #[test]
 fn drop_disconnects() {
     let config_0 = gen_config();
@@ -420,11 +1770,34 @@ fn drop_disconnects() {
 
     // Dropping service_0 should make service_1 receive a LostPeer event.
     drop(service_0);
-    expect_event!(event_rx_1, Event::LostPeer(peer_id) => {
+    expect_event!(event_rx_1, Event::LostPeer(peer_id, _reason) => {
         assert_eq!(peer_id, peer_id_0)
     });
 }
 
+#[test]
+fn graceful_disconnect_is_seen_by_both_peers() {
+    let (_service_0, event_rx_0, service_1, event_rx_1, peer_id_0) =
+        bootstrap_pair(gen_config(), gen_config());
+    let peer_id_1 = service_1.id();
+
+    let message = Bytes::from_static(b"last words");
+    let _ = unwrap!(service_1.send(&peer_id_0, message.clone(), 0));
+    assert!(service_1.disconnect_gracefully(&peer_id_0));
+
+    expect_event!(event_rx_0, Event::NewMessage(_peer_id, _, data) => {
+        assert_eq!(data, message)
+    });
+    expect_event!(event_rx_0, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id_1);
+        assert_eq!(reason, LostPeerReason::RemoteClosed);
+    });
+    expect_event!(event_rx_1, Event::LostPeer(peer_id, reason) => {
+        assert_eq!(peer_id, peer_id_0);
+        assert_eq!(reason, LostPeerReason::Evicted);
+    });
+}
+
 // This module implements a simulated crust peer which accepts incomming
 // connections but then does nothing. It's purpose is to test that we detect
 // and handle non-responsive peers correctly.
@@ -516,7 +1889,7 @@ mod broken_peer {
         fn ready(&mut self, core: &mut Core<()>, poll: &Poll, kind: Ready) {
             if kind.is_readable() {
                 match self.socket.read::<Message<UniqueId>>() {
-                    Ok(Some(Message::BootstrapRequest(_, _, _, their_pk))) => {
+                    Ok(Some(Message::BootstrapRequest(_, _, _, _, their_pk, _))) => {
                         let shared_key = self.our_sk.shared_secret(&their_pk);
                         unwrap!(self
                             .socket
@@ -524,7 +1897,7 @@ mod broken_peer {
                         let public_id: UniqueId = rand::random();
                         let _ = unwrap!(self
                             .socket
-                            .write(Some((Message::BootstrapGranted(public_id), 0))));
+                            .write(Some((Message::BootstrapGranted(public_id, false, None), 0))));
                     }
                     Ok(Some(_)) | Ok(None) => (),
                     Err(_) => self.terminate(core, poll),
@@ -576,8 +1949,9 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
     let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
 
     // The peer should drop after inactivity.
-    expect_event!(event_rx, Event::LostPeer(lost_peer_id) => {
-        assert_eq!(lost_peer_id, peer_id)
+    expect_event!(event_rx, Event::LostPeer(lost_peer_id, reason) => {
+        assert_eq!(lost_peer_id, peer_id);
+        assert_eq!(reason, LostPeerReason::InactivityTimeout);
     });
 }
 
@@ -615,3 +1989,33 @@ fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_p
         panic!("peer lost unexpectedly");
     }
 }
+
+#[test]
+fn drop_peer_which_sends_heartbeats_less_often_than_our_inactivity_timeout() {
+    use crate::main::INACTIVITY_TIMEOUT_MS;
+
+    let mut config0 = gen_config();
+    config0.heartbeat_period_ms = Some(10 * INACTIVITY_TIMEOUT_MS);
+    config0.inactivity_timeout_ms = Some(20 * INACTIVITY_TIMEOUT_MS);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
+
+    expect_event!(event_rx1, Event::LostPeer(lost_peer_id, reason) => {
+        assert_eq!(lost_peer_id, peer_id);
+        assert_eq!(reason, LostPeerReason::InactivityTimeout);
+    });
+}